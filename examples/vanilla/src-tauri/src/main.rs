@@ -3,6 +3,8 @@
   windows_subsystem = "windows"
 )]
 
+use tauri_invoke_http::BuilderExt;
+
 #[tauri::command]
 fn my_command(args: u64) -> Result<String, ()> {
   println!("executed command with args {:?}", args);
@@ -12,13 +14,9 @@ fn my_command(args: u64) -> Result<String, ()> {
 fn main() {
   // Allow from all origins for testing purposes. 
   // Should be allow listed to reduce risks of accidential exposure to other networks.
-  let http = tauri_invoke_http::Invoke::new(["*"]);
+  let http = tauri_invoke_http::Invoke::new(["*"]).expect("failed to create the invoke server");
   tauri::Builder::default()
-    .invoke_system(http.initialization_script(), http.responder())
-    .setup(move |app| {
-      http.start(app.handle().clone());
-      Ok(())
-    })
+    .invoke_http(http)
     .invoke_handler(tauri::generate_handler![my_command])
     .run(tauri::generate_context!())
     .expect("error while running tauri application")
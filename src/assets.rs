@@ -0,0 +1,98 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Serving of the frontend assets next to the invoke endpoints.
+
+use {
+  crate::Assets,
+  std::path::{Component, Path},
+  tauri::{AppHandle, Runtime},
+};
+
+pub(crate) struct Asset {
+  pub(crate) bytes: Vec<u8>,
+  pub(crate) mime_type: String,
+  pub(crate) csp: Option<String>,
+}
+
+/// Resolves the asset requested at `path`, falling back to `index.html` for client side routes.
+pub(crate) fn resolve<R: Runtime>(
+  app: &AppHandle<R>,
+  assets: &Assets,
+  path: &str,
+) -> Option<Asset> {
+  let path = decode(path.split('?').next().unwrap_or_default())?;
+  match assets {
+    Assets::Embedded => app.asset_resolver().get(path).map(|asset| Asset {
+      bytes: asset.bytes,
+      mime_type: asset.mime_type,
+      csp: asset.csp_header,
+    }),
+    Assets::Dir(dir) => {
+      let relative = Path::new(path.trim_start_matches('/'));
+      // only plain names, so requests can't escape the directory
+      if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+      {
+        return None;
+      }
+      let mut file = dir.join(relative);
+      if file.is_dir() {
+        file.push("index.html");
+      } else if !file.exists() && relative.extension().is_none() {
+        file = dir.join("index.html");
+      }
+      let bytes = std::fs::read(&file).ok()?;
+      Some(Asset {
+        bytes,
+        mime_type: mime_type(&file).to_string(),
+        csp: None,
+      })
+    }
+  }
+}
+
+/// Decodes the percent-encoded bytes of a URL path.
+pub(crate) fn decode(path: &str) -> Option<String> {
+  let mut bytes = Vec::with_capacity(path.len());
+  let mut rest = path.as_bytes();
+  while let Some((&byte, tail)) = rest.split_first() {
+    if byte == b'%' {
+      let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+      bytes.push(u8::from_str_radix(hex, 16).ok()?);
+      rest = &tail[2..];
+    } else {
+      bytes.push(byte);
+      rest = tail;
+    }
+  }
+  String::from_utf8(bytes).ok()
+}
+
+fn mime_type(file: &Path) -> &'static str {
+  let extension = file
+    .extension()
+    .and_then(|e| e.to_str())
+    .unwrap_or_default()
+    .to_ascii_lowercase();
+  match extension.as_str() {
+    "html" | "htm" => "text/html",
+    "js" | "mjs" => "text/javascript",
+    "css" => "text/css",
+    "json" | "map" => "application/json",
+    "wasm" => "application/wasm",
+    "svg" => "image/svg+xml",
+    "png" => "image/png",
+    "jpg" | "jpeg" => "image/jpeg",
+    "gif" => "image/gif",
+    "webp" => "image/webp",
+    "ico" => "image/x-icon",
+    "woff" => "font/woff",
+    "woff2" => "font/woff2",
+    "ttf" => "font/ttf",
+    "txt" => "text/plain",
+    _ => "application/octet-stream",
+  }
+}
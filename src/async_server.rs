@@ -0,0 +1,834 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! A hyper server running on [`tauri::async_runtime`], used instead of tiny_http with the
+//! `async-server` feature.
+
+use {
+  crate::{
+    acl_origin, assets, body_decoder, cancel, cors_headers, csrf, encode_response, events,
+    invoke_request, is_acl_denial, poll, preflight_headers, read_error_status, reload::LiveContext,
+    signing, sse, status_route, strip_forwarded, trace, BodyKind, Context, Envelope, LockExt,
+    MiddlewareRequest, Socket, INVOKE_ERROR_HEADER, INVOKE_KEY_HEADER, INVOKE_METHODS,
+    SESSION_HEADER, WINDOW_POLL_INTERVAL,
+  },
+  http_body_util::BodyExt,
+  hyper::{
+    body::{Bytes, Frame, Incoming, SizeHint},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response,
+  },
+  hyper_util::rt::{TokioIo, TokioTimer},
+  std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    io::{Result as IoResult, Write},
+    net::IpAddr,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+  },
+  tauri::{ipc::InvokeResponse, AppHandle, Runtime},
+  tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+  },
+};
+
+/// The invokes waiting for a response, by callback, which is sent with whether it's the
+/// capabilities' rejection of the invoke.
+pub(crate) type Pending = Arc<Mutex<HashMap<u32, oneshot::Sender<(InvokeResponse, bool)>>>>;
+
+/// A response body, either held in memory or fed through a channel.
+///
+/// Channel bodies have no known length, so hyper sends them with chunked transfer encoding.
+enum Body {
+  Full(Option<Bytes>),
+  Stream(mpsc::UnboundedReceiver<Bytes>),
+}
+
+impl Body {
+  fn empty() -> Self {
+    Self::Full(None)
+  }
+}
+
+impl hyper::body::Body for Body {
+  type Data = Bytes;
+  type Error = Infallible;
+
+  fn poll_frame(
+    self: Pin<&mut Self>,
+    cx: &mut TaskContext<'_>,
+  ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+    match self.get_mut() {
+      Self::Full(data) => Poll::Ready(data.take().map(|d| Ok(Frame::data(d)))),
+      Self::Stream(receiver) => receiver
+        .poll_recv(cx)
+        .map(|d| d.map(|d| Ok(Frame::data(d)))),
+    }
+  }
+
+  fn is_end_stream(&self) -> bool {
+    matches!(self, Self::Full(None))
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    match self {
+      Self::Full(data) => SizeHint::with_exact(data.as_ref().map_or(0, |d| d.len() as u64)),
+      Self::Stream(_) => SizeHint::default(),
+    }
+  }
+}
+
+/// Forwards what an [`sse::EventStream`] writes to a [`Body::Stream`].
+struct ChannelWriter(mpsc::UnboundedSender<Bytes>);
+
+impl Write for ChannelWriter {
+  fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+    self
+      .0
+      .send(Bytes::copy_from_slice(buf))
+      .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "stream closed"))?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> IoResult<()> {
+    Ok(())
+  }
+}
+
+/// Accepts connections on `socket` until the returned task is aborted.
+pub(crate) fn start<R: Runtime>(
+  app: AppHandle<R>,
+  socket: Socket,
+  live: LiveContext,
+) -> tauri::async_runtime::JoinHandle<()> {
+  // the connections open on this listener
+  let connections = Arc::new(AtomicUsize::new(0));
+  tauri::async_runtime::spawn(async move {
+    match socket {
+      Socket::Tcp(listener) => {
+        let listener = match listener
+          .set_nonblocking(true)
+          .and_then(|_| tokio::net::TcpListener::from_std(listener))
+        {
+          Ok(listener) => listener,
+          Err(e) => {
+            log::error!("failed to serve the listener: {}", e);
+            return;
+          }
+        };
+        while let Ok((stream, addr)) = listener.accept().await {
+          let context = live.current();
+          if !context.ip_filter.allows(Some(addr.ip())) {
+            continue;
+          }
+          let _ = stream.set_nodelay(context.socket_options.nodelay);
+          trace::spawn_connection(
+            Some(addr.ip()),
+            serve(
+              app.clone(),
+              live.clone(),
+              connections.clone(),
+              Some(addr.ip()),
+              stream,
+            ),
+          );
+        }
+      }
+      #[cfg(unix)]
+      Socket::Unix(listener) => {
+        let listener = match listener
+          .set_nonblocking(true)
+          .and_then(|_| tokio::net::UnixListener::from_std(listener))
+        {
+          Ok(listener) => listener,
+          Err(e) => {
+            log::error!("failed to serve the listener: {}", e);
+            return;
+          }
+        };
+        while let Ok((stream, _)) = listener.accept().await {
+          trace::spawn_connection(
+            None,
+            serve(app.clone(), live.clone(), connections.clone(), None, stream),
+          );
+        }
+      }
+    }
+  })
+}
+
+async fn serve<R: Runtime, S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+  app: AppHandle<R>,
+  live: LiveContext,
+  connections: Arc<AtomicUsize>,
+  peer: Option<IpAddr>,
+  stream: S,
+) {
+  connections.fetch_add(1, Ordering::SeqCst);
+  let context = live.current();
+  let keep_alive = *context.keep_alive.config();
+  let header_timeout = context.read_timeouts.header;
+  let served = Arc::new(AtomicUsize::new(0));
+  let open = connections.clone();
+  let service = service_fn(move |request| {
+    CatchUnwind(Box::pin(handle(
+      app.clone(),
+      live.current(),
+      open.load(Ordering::SeqCst),
+      peer,
+      served.clone(),
+      request,
+    )))
+  });
+  let mut builder = http1::Builder::new();
+  // the header read timeout also covers the wait for the next request on an idle connection
+  builder
+    .timer(TokioTimer::new())
+    .header_read_timeout(header_timeout.or(keep_alive.timeout))
+    .keep_alive(keep_alive.max_requests != Some(1));
+  let _ = builder
+    .serve_connection(TokioIo::new(stream), service)
+    .await;
+  connections.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Answers requests whose handler panicked with `500 Internal Server Error`, keeping the
+/// connection and the server alive.
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future<Output = Result<Response<Body>, Infallible>>> Future for CatchUnwind<F> {
+  type Output = F::Output;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+    match panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+      Ok(poll) => poll,
+      Err(_) => {
+        log::error!("a request handler panicked, its request is answered with 500");
+        Poll::Ready(Ok(response(500, &[], &[], Body::empty())))
+      }
+    }
+  }
+}
+
+fn response(
+  status: u16,
+  cors: &[(&'static str, String)],
+  headers: &[(&'static str, String)],
+  body: Body,
+) -> Response<Body> {
+  let mut builder = Response::builder().status(status);
+  for (field, value) in cors.iter().chain(headers) {
+    builder = builder.header(*field, value.as_str());
+  }
+  // a header value that can't be sent fails the response rather than the connection
+  builder.body(body).unwrap_or_else(|_| {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+    response
+  })
+}
+
+async fn handle<R: Runtime>(
+  app: AppHandle<R>,
+  context: Context,
+  connections: usize,
+  peer: Option<IpAddr>,
+  served: Arc<AtomicUsize>,
+  request: Request<Incoming>,
+) -> Result<Response<Body>, Infallible> {
+  let (parts, body) = request.into_parts();
+  log::debug!("{} {} from {:?}", parts.method, parts.uri, peer);
+  let host = parts.headers.get("Host").and_then(|v| v.to_str().ok());
+  if !context.is_local_request(peer, host) {
+    return Ok(response(
+      403,
+      &[],
+      &[],
+      Body::Full(Some("invalid host".into())),
+    ));
+  }
+  let url = parts.uri.to_string();
+  if let Some(route) = context.custom_route(parts.method.as_str(), &url) {
+    let limit = context.limits.max_raw_size.unwrap_or(usize::MAX);
+    let cors = cors_headers(
+      parts.headers.get("Origin").and_then(|v| v.to_str().ok()),
+      &context,
+    );
+    let body = match http_body_util::Limited::new(body, limit).collect().await {
+      Ok(body) => body.to_bytes(),
+      Err(_) => return Ok(response(413, &cors, &[], Body::empty())),
+    };
+    let request = MiddlewareRequest {
+      method: parts.method.to_string(),
+      url,
+      headers: parts.headers.clone(),
+      peer,
+    };
+    let routed = (route.handler)(&request, &body);
+    let mut r = response(
+      routed.status,
+      &cors,
+      &[],
+      Body::Full(Some(routed.body.into())),
+    );
+    r.headers_mut().extend(routed.headers);
+    return Ok(r);
+  }
+  if parts.method == Method::GET && context.is_asset(&parts.uri.to_string()) {
+    let path = parts.uri.path();
+    let asset = context
+      .assets
+      .as_ref()
+      .and_then(|assets| assets::resolve(&app, assets, path));
+    return Ok(match asset {
+      Some(asset) => {
+        let mut headers = vec![("Content-Type", asset.mime_type)];
+        if let Some(csp) = asset.csp {
+          headers.push(("Content-Security-Policy", csp));
+        }
+        let cookie = parts.headers.get("Cookie").and_then(|v| v.to_str().ok());
+        if let Some((_, Some(cookie))) = context.session(cookie) {
+          headers.push(("Set-Cookie", cookie));
+        }
+        response(200, &[], &headers, Body::Full(Some(asset.bytes.into())))
+      }
+      None => response(404, &[], &[], Body::empty()),
+    });
+  }
+  let header = |name: &'static str| {
+    parts
+      .headers
+      .get(name)
+      .and_then(|v| v.to_str().ok())
+      .map(str::to_string)
+  };
+  let cors = cors_headers(header("Origin").as_deref(), &context);
+  if parts.method != Method::OPTIONS
+    && !context.is_authorized(header("Authorization").as_deref(), &parts.uri.to_string())
+  {
+    return Ok(response(401, &cors, &[], Body::empty()));
+  }
+  let trusted = context.is_trusted_proxy(peer);
+  let origin = context.origin(trusted, &header);
+
+  if parts.method == Method::OPTIONS {
+    let preflight = preflight_headers(
+      header("Origin").as_deref(),
+      header("Access-Control-Request-Headers").as_deref(),
+      &context,
+    );
+    return Ok(response(200, &preflight, &[], Body::empty()));
+  }
+  let path = match context.route(parts.uri.path()) {
+    Some(path) => path,
+    None => return Ok(response(404, &cors, &[], Body::empty())),
+  };
+  // the headers as changed by the middleware
+  let mut intercepted = None;
+  if !context.middleware.is_empty() {
+    let mut request = MiddlewareRequest {
+      method: parts.method.to_string(),
+      url: parts.uri.to_string(),
+      headers: parts.headers.clone(),
+      peer,
+    };
+    if let Some(intercepted) = context.intercept(&app, &mut request) {
+      let mut r = response(
+        intercepted.status,
+        &cors,
+        &[],
+        Body::Full(Some(intercepted.body.into())),
+      );
+      r.headers_mut().extend(intercepted.headers);
+      return Ok(r);
+    }
+    intercepted = Some(request.headers);
+  }
+  if parts.method == Method::GET {
+    let pending = context.pending.locked().len();
+    let base_url = context.public_base_url(trusted, &header);
+    if let Some((status, body)) = status_route(&app, &context, path, &base_url, pending) {
+      return Ok(response(
+        status,
+        &cors,
+        &[("Content-Type", "application/json".into())],
+        Body::Full(Some(body.into())),
+      ));
+    }
+  }
+  // shed load by closing the connection along with the rejection
+  let rejected = [
+    ("Retry-After", context.limits.retry_after_secs()),
+    ("Connection", "close".to_string()),
+  ];
+  // `connections` includes the one of this request
+  if context
+    .limits
+    .too_many_connections(connections.saturating_sub(1))
+  {
+    return Ok(response(429, &cors, &rejected, Body::empty()));
+  }
+  let pieces = path.split('/').collect::<Vec<_>>();
+  let window_label = pieces.get(1).copied().unwrap_or_default();
+
+  if parts.method == Method::GET && pieces.get(2) == Some(&"nonce") {
+    if let Some(nonces) = &context.nonces {
+      if !context.allows_origin(origin.as_deref()) {
+        return Ok(response(403, &[], &[], Body::empty()));
+      }
+      return Ok(response(
+        200,
+        &cors,
+        &[("Cache-Control", "no-store".into())],
+        Body::Full(Some(nonces.issue().into())),
+      ));
+    }
+  }
+
+  if parts.method == Method::GET
+    && (pieces.get(2) == Some(&"channel") || pieces.get(2) == Some(&"poll"))
+  {
+    if !context.allows_origin(origin.as_deref()) {
+      return Ok(response(403, &[], &[], Body::empty()));
+    }
+    if pieces[2] == "poll" {
+      let (sender, receiver) = oneshot::channel();
+      poll::poll(
+        &context.mailboxes,
+        window_label,
+        Box::new(move |messages| {
+          let _ = sender.send(messages);
+        }),
+      );
+      let messages = receiver.await.unwrap_or_else(|_| "[]".into());
+      return Ok(response(
+        200,
+        &cors,
+        &[("Content-Type", "application/json".into())],
+        Body::Full(Some(messages.into())),
+      ));
+    }
+    let format = sse::StreamFormat::from_accept(header("Accept").as_deref());
+    let (sender, receiver) = mpsc::unbounded_channel();
+    context
+      .channels
+      .locked()
+      .entry(window_label.to_string())
+      .or_default()
+      .push(sse::EventStream::new(
+        Box::new(ChannelWriter(sender)),
+        format,
+      ));
+    return Ok(response(
+      200,
+      &cors,
+      &[
+        ("Content-Type", format.content_type().into()),
+        ("Cache-Control", "no-cache".into()),
+      ],
+      Body::Stream(receiver),
+    ));
+  }
+
+  if parts.method == Method::GET && pieces.get(2) == Some(&"events") {
+    if let Some(events) = &context.events {
+      if !context.allows_origin(origin.as_deref()) {
+        return Ok(response(403, &[], &[], Body::empty()));
+      }
+      let names = match events::requested(&parts.uri.to_string()) {
+        Some(names) => names,
+        None => {
+          return Ok(response(
+            400,
+            &cors,
+            &[],
+            Body::Full(Some("invalid event name".into())),
+          ))
+        }
+      };
+      let webview = match crate::find_webview(&app, window_label) {
+        Some(webview) => webview,
+        None => return Ok(response(404, &cors, &[], Body::empty())),
+      };
+      let format = sse::StreamFormat::from_accept(header("Accept").as_deref());
+      let (sender, receiver) = mpsc::unbounded_channel();
+      events.subscribe(
+        &webview,
+        names,
+        sse::EventStream::new(Box::new(ChannelWriter(sender)), format),
+      );
+      return Ok(response(
+        200,
+        &cors,
+        &[
+          ("Content-Type", format.content_type().into()),
+          ("Cache-Control", "no-cache".into()),
+        ],
+        Body::Stream(receiver),
+      ));
+    }
+  }
+
+  if parts.method == Method::POST && pieces.len() == 4 && pieces[2] == "events" {
+    if let Some(events) = &context.events {
+      if !context.allows_origin(origin.as_deref()) {
+        return Ok(response(
+          403,
+          &[],
+          &[],
+          Body::Full(Some("origin not allowed".into())),
+        ));
+      }
+      if header(INVOKE_KEY_HEADER).as_deref() != Some(app.invoke_key()) {
+        return Ok(response(
+          403,
+          &cors,
+          &[],
+          Body::Full(Some("invalid invoke key".into())),
+        ));
+      }
+      let limit = context.limits.max_raw_size.unwrap_or(usize::MAX);
+      let body = match http_body_util::Limited::new(body, limit).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(response(413, &cors, &[], Body::empty())),
+      };
+      let (status, body) = events.handle(&app, &context, window_label, pieces[3], &body);
+      let headers = if status == 200 {
+        vec![("Content-Type", "application/json".into())]
+      } else {
+        Vec::new()
+      };
+      return Ok(response(
+        status,
+        &cors,
+        &headers,
+        Body::Full(Some(body.into())),
+      ));
+    }
+  }
+
+  if parts.method != Method::POST {
+    return Ok(response(
+      405,
+      &cors,
+      &[("Allow", INVOKE_METHODS.into())],
+      Body::empty(),
+    ));
+  }
+
+  if context.closing.load(Ordering::SeqCst) {
+    return Ok(response(
+      503,
+      &cors,
+      &[("Connection", "close".into())],
+      Body::empty(),
+    ));
+  }
+  let mut entry = context
+    .auditor
+    .begin(&context.metrics, origin.as_deref(), window_label);
+  let response = async {
+    if origin.is_none() {
+      return Ok(response(
+        400,
+        &[],
+        &[],
+        Body::Full(Some("missing origin".into())),
+      ));
+    }
+    // browsers enforce the allowed origins through CORS, other clients are stopped here
+    if !context.allows_origin(origin.as_deref()) {
+      return Ok(response(
+        403,
+        &[],
+        &[],
+        Body::Full(Some("origin not allowed".into())),
+      ));
+    }
+    let deadline = Instant::now() + context.window_grace.unwrap_or_default();
+    let window = loop {
+      if let Some(window) = crate::find_webview(&app, context.dispatching_webview(window_label)) {
+        break window;
+      }
+      if Instant::now() >= deadline || context.closing.load(Ordering::SeqCst) {
+        log::warn!("no window `{}` to send the invoke to", window_label);
+        return Ok(response(404, &cors, &[], Body::empty()));
+      }
+      tokio::time::sleep(WINDOW_POLL_INTERVAL).await;
+    };
+    // Tauri drops invokes with a wrong key without answering them
+    if header(INVOKE_KEY_HEADER).as_deref() != Some(app.invoke_key()) {
+      return Ok(response(
+        403,
+        &cors,
+        &[],
+        Body::Full(Some("invalid invoke key".into())),
+      ));
+    }
+    if let Some(nonces) = &context.nonces {
+      if !nonces.spend(header(csrf::NONCE_HEADER).as_deref()) {
+        return Ok(response(
+          403,
+          &cors,
+          &[],
+          Body::Full(Some("invalid nonce".into())),
+        ));
+      }
+    }
+    if let Err(e) = context.check_replay(&header) {
+      return Ok(response(403, &cors, &[], Body::Full(Some(e.into()))));
+    }
+    if let Err(retry_after) =
+      context.rate_limit(window_label, peer, trusted, origin.as_deref(), &header)
+    {
+      return Ok(response(
+        429,
+        &cors,
+        &[("Retry-After", retry_after)],
+        Body::empty(),
+      ));
+    }
+    if context
+      .limits
+      .too_many_pending(context.pending.locked().len())
+    {
+      return Ok(response(429, &cors, &rejected, Body::empty()));
+    }
+    let kind = BodyKind::from_content_type(header("Content-Type").as_deref());
+    let body_length = header("Content-Length").and_then(|l| l.parse().ok());
+    let limit = context.limits.body_limit(&kind);
+    if limit.map_or(false, |limit| body_length.map_or(false, |len| len > limit)) {
+      return Ok(response(
+        413,
+        &cors,
+        &[("Connection", "close".into())],
+        Body::empty(),
+      ));
+    }
+    let spool = context.spool_path(&kind, body_length);
+    let encoding = header("Content-Encoding");
+    let mut content = Vec::new();
+    let timeout = context.read_timeouts.body;
+    let signature = header(signing::SIGNATURE_HEADER);
+    let stamp = context.replay_stamp(&header);
+    let envelope = context.envelope(signature.as_deref(), stamp.as_deref());
+    let read = match &spool {
+      Some(path) => match std::fs::File::create(path) {
+        Ok(file) => read_body(body, encoding.as_deref(), file, limit, envelope, timeout).await,
+        Err(e) => Err(e),
+      },
+      None => {
+        read_body(
+          body,
+          encoding.as_deref(),
+          &mut content,
+          limit,
+          envelope,
+          timeout,
+        )
+        .await
+      }
+    };
+    let (session, cookie) = match context.session(header("Cookie").as_deref()) {
+      Some((session, cookie)) => (Some(session), cookie),
+      None => (None, None),
+    };
+    let parse = entry.parse();
+    let payload = read
+      .map_err(|e| (read_error_status(&e), e.to_string()))
+      .and_then(|_| {
+        let mut headers = intercepted.unwrap_or_else(|| parts.headers.clone());
+        if !trusted {
+          strip_forwarded(&mut headers);
+        }
+        if let Some(session) = &session {
+          if let Ok(session) = session.parse() {
+            headers.insert(
+              hyper::header::HeaderName::from_bytes(SESSION_HEADER.as_bytes()).unwrap(),
+              session,
+            );
+          }
+        }
+        invoke_request(
+          app.invoke_key(),
+          context.isolation_key.as_ref(),
+          origin.map(|origin| acl_origin(origin, &context.acl_origins)),
+          header,
+          pieces.get(2).copied(),
+          headers,
+          &kind,
+          content,
+          spool.as_deref(),
+        )
+        .map_err(|e| (400, e))
+      });
+    parse.end();
+    let payload = payload.and_then(|payload| {
+      entry.invoke(&payload.cmd, Some(&payload.body));
+      if context.is_allowed_command(window_label, &payload.cmd) {
+        Ok(payload)
+      } else {
+        Err((403, format!("command {} is not allowed", payload.cmd)))
+      }
+    });
+    let payload = match payload {
+      Ok(payload) => payload,
+      Err((status, e)) => {
+        if let Some(path) = &spool {
+          let _ = std::fs::remove_file(path);
+        }
+        // the rest of a stalled or oversized body isn't read, so the connection can't be reused
+        let headers = if status == 408 || status == 413 {
+          vec![("Connection", "close".to_string())]
+        } else {
+          Vec::new()
+        };
+        return Ok(response(
+          status,
+          &cors,
+          &headers,
+          Body::Full(Some(e.into())),
+        ));
+      }
+    };
+
+    let cmd = payload.cmd.clone();
+    let callback = payload.callback.0;
+    let mut payload = payload;
+    payload.headers.insert(
+      hyper::header::HeaderName::from_bytes(cancel::INVOCATION_HEADER.as_bytes()).unwrap(),
+      callback.into(),
+    );
+    context.cancellations.register(callback, &cmd, window_label);
+    // hyper drops this future when the client disconnects, which cancels the command
+    let guard = context.cancellations.guard(callback);
+    let (sender, receiver) = oneshot::channel();
+    context.pending.locked().insert(callback, sender);
+    let pending = context.pending.clone();
+    let url = payload.url.to_string();
+    entry.dispatch();
+    window.on_message(
+      payload,
+      Box::new(move |webview, cmd, response, callback, _error| {
+        if let Some(sender) = pending.locked().remove(&callback.0) {
+          let denied = is_acl_denial(&webview, &cmd, &url, &response);
+          let _ = sender.send((response, denied));
+        }
+      }),
+    );
+    let result = match context.invoke_timeout {
+      Some(timeout) => match tokio::time::timeout(timeout, receiver).await {
+        Ok(result) => result,
+        Err(_) => {
+          context.pending.locked().remove(&callback);
+          if let Some(path) = &spool {
+            let _ = std::fs::remove_file(path);
+          }
+          return Ok(response(504, &cors, &[], Body::empty()));
+        }
+      },
+      None => receiver.await,
+    };
+    guard.finish();
+    let _respond = entry.respond();
+    if let Some(path) = &spool {
+      let _ = std::fs::remove_file(path);
+    }
+    let (status, content_type, body) = match result {
+      Ok((result, denied)) => match encode_response(
+        header("Accept").as_deref(),
+        result,
+        denied,
+        context.error_format,
+      ) {
+        Some(encoded) => encoded,
+        None => return Ok(response(406, &cors, &[], Body::empty())),
+      },
+      Err(_) => return Ok(response(500, &cors, &[], Body::empty())),
+    };
+
+    let mut headers = vec![("Content-Type", content_type.to_string())];
+    let (body, sealed) = context.seal(body);
+    if let Some((field, value)) = sealed {
+      headers.push((field, value.to_string()));
+    }
+    if let Some(nonces) = &context.nonces {
+      headers.push((csrf::NEXT_NONCE_HEADER, nonces.issue()));
+    }
+    if let Some(cookie) = cookie {
+      headers.push(("Set-Cookie", cookie));
+    }
+    if status != 200 {
+      headers.push((INVOKE_ERROR_HEADER, "true".into()));
+    }
+    let max_requests = context.keep_alive.config().max_requests;
+    let remaining =
+      max_requests.map(|max| max.saturating_sub(served.fetch_add(1, Ordering::Relaxed) + 1));
+    if remaining == Some(0) {
+      headers.push(("Connection", "close".into()));
+    } else if let Some(value) = context.keep_alive.value(remaining) {
+      headers.push(("Keep-Alive", value));
+    }
+    let body = if body.len() >= context.stream_threshold {
+      let (sender, receiver) = mpsc::unbounded_channel();
+      let _ = sender.send(body.into());
+      Body::Stream(receiver)
+    } else {
+      Body::Full(Some(body.into()))
+    };
+    let mut r = response(status, &cors, &headers, body);
+    r.headers_mut().extend(context.annotate(&app, &cmd, status));
+    Ok(r)
+  }
+  .await;
+  if let Ok(response) = &response {
+    entry.finish(response.status().as_u16());
+  }
+  response
+}
+
+/// Writes the frames of `body` to `sink`, undoing its `Content-Encoding` and checking its
+/// signature.
+///
+/// Fails with [`std::io::ErrorKind::TimedOut`] if the body takes longer than `timeout`.
+async fn read_body<W: Write + Send>(
+  mut body: Incoming,
+  encoding: Option<&str>,
+  sink: W,
+  limit: Option<usize>,
+  envelope: Envelope<'_>,
+  timeout: Option<Duration>,
+) -> IoResult<()> {
+  let mut sink = body_decoder(encoding, sink, limit, envelope)?;
+  let read = async {
+    while let Some(frame) = body.frame().await {
+      let frame = frame.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+      if let Ok(data) = frame.into_data() {
+        sink.write_all(&data)?;
+      }
+    }
+    sink.flush()
+  };
+  match timeout {
+    Some(timeout) => tokio::time::timeout(timeout, read)
+      .await
+      .unwrap_or_else(|_| {
+        Err(std::io::Error::new(
+          std::io::ErrorKind::TimedOut,
+          "timed out reading the request body",
+        ))
+      }),
+    None => read.await,
+  }
+}
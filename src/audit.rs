@@ -0,0 +1,172 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Recording of invokes to the sink of an [`AuditLog`], to the server's metrics and to their
+//! [`trace::InvokeSpan`].
+
+use {
+  crate::{matches_origin, metrics, trace, AuditLog, AuditRecord, AuditSink, LockExt},
+  serde_json::Value as JsonValue,
+  std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+  },
+  tauri::ipc::InvokeBody,
+};
+
+/// Replaces the values of redacted payload fields.
+const REDACTED: &str = "[redacted]";
+
+struct Log {
+  config: AuditLog,
+  /// The file sink, opened with the first record.
+  file: Mutex<Option<File>>,
+}
+
+/// Hands out the [`Entry`] of each invoke, doing nothing if no [`AuditLog`] is set.
+#[derive(Clone, Default)]
+pub(crate) struct Auditor(Option<Arc<Log>>);
+
+impl Auditor {
+  pub(crate) fn new(config: AuditLog) -> Self {
+    Self(Some(Arc::new(Log {
+      config,
+      file: Mutex::new(None),
+    })))
+  }
+
+  /// Starts the record of an invoke from `origin` to the window `window`, counting it in
+  /// `metrics`.
+  pub(crate) fn begin(
+    &self,
+    metrics: &metrics::Recorder,
+    origin: Option<&str>,
+    window: &str,
+  ) -> Entry {
+    metrics.request();
+    let log = self.0.clone().map(|log| {
+      let record = AuditRecord {
+        timestamp: SystemTime::now(),
+        origin: origin.map(Into::into),
+        window: window.into(),
+        cmd: None,
+        status: 0,
+        latency: Default::default(),
+        payload: None,
+      };
+      (log, record)
+    });
+    Entry {
+      log,
+      started: Instant::now(),
+      metrics: metrics.clone(),
+      span: trace::InvokeSpan::new(origin, window),
+    }
+  }
+}
+
+/// The record of an invoke, written once it is answered.
+pub(crate) struct Entry {
+  log: Option<(Arc<Log>, AuditRecord)>,
+  started: Instant,
+  metrics: metrics::Recorder,
+  span: trace::InvokeSpan,
+}
+
+impl Entry {
+  /// Records the command and payload once they are known.
+  pub(crate) fn invoke(&mut self, cmd: &str, body: Option<&InvokeBody>) {
+    self.span.cmd(cmd);
+    if let Some((log, record)) = &mut self.log {
+      record.cmd = Some(cmd.into());
+      if log.config.payloads {
+        if let Some(InvokeBody::Json(payload)) = body {
+          let mut payload = payload.clone();
+          redact(&mut payload, &log.config.redact);
+          record.payload = Some(payload);
+        }
+      }
+    }
+  }
+
+  /// Enters the parsing of the invoke message.
+  pub(crate) fn parse(&self) -> trace::Phase {
+    self.span.parse()
+  }
+
+  /// Marks the invoke as handed to its command.
+  pub(crate) fn dispatch(&mut self) {
+    self.span.dispatch();
+  }
+
+  /// Enters the writing of the response, once the command answered.
+  pub(crate) fn respond(&mut self) -> trace::Phase {
+    self.span.respond()
+  }
+
+  /// Writes the record with the `status` the invoke was answered with.
+  pub(crate) fn finish(self, status: u16) {
+    let latency = self.started.elapsed();
+    self.metrics.response(status, latency);
+    self.span.finish(status, latency);
+    let (log, mut record) = match self.log {
+      Some(entry) => entry,
+      None => return,
+    };
+    record.status = status;
+    record.latency = latency;
+    match &log.config.sink {
+      AuditSink::Callback(callback) => callback(&record),
+      AuditSink::File(path) => {
+        let mut file = log.file.locked();
+        if file.is_none() {
+          *file = OpenOptions::new().create(true).append(true).open(path).ok();
+        }
+        if let Some(file) = file.as_mut() {
+          let mut line = to_json(&record).to_string();
+          line.push('\n');
+          // records that can't be written are dropped rather than failing the invoke
+          let _ = file.write_all(line.as_bytes());
+        }
+      }
+    }
+  }
+}
+
+/// Replaces the values of the fields matching one of `rules`, at any depth.
+fn redact(value: &mut JsonValue, rules: &[String]) {
+  match value {
+    JsonValue::Object(fields) => {
+      for (name, value) in fields.iter_mut() {
+        if rules.iter().any(|rule| matches_origin(rule, name)) {
+          *value = REDACTED.into();
+        } else {
+          redact(value, rules);
+        }
+      }
+    }
+    JsonValue::Array(values) => values.iter_mut().for_each(|value| redact(value, rules)),
+    _ => {}
+  }
+}
+
+/// The JSON line a record is written to a file as.
+fn to_json(record: &AuditRecord) -> JsonValue {
+  let timestamp = record
+    .timestamp
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis() as u64;
+  serde_json::json!({
+    "timestamp": timestamp,
+    "origin": record.origin,
+    "window": record.window,
+    "cmd": record.cmd,
+    "status": record.status,
+    "latencyMs": record.latency.as_secs_f64() * 1000.0,
+    "payload": record.payload,
+  })
+}
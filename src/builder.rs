@@ -0,0 +1,321 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Step by step configuration of an [`Invoke`].
+
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+use {
+  crate::{
+    AuditLog, Cidr, ClientRetry, CorsConfig, ErrorFormat, Invoke, InvokeHttpError, KeepAlive,
+    LifecycleEvent, Limits, Listener, Middleware, MiddlewareRequest, MiddlewareResponse,
+    MissingOrigin, RateLimit, ReadTimeouts, SocketOptions, Transport, WindowConfig,
+  },
+  std::{net::IpAddr, time::Duration},
+};
+
+/// Configures an [`Invoke`] server, returned by [`Invoke::builder`].
+///
+/// Each method matches the `Invoke::with_*` method of the same name, and
+/// [`InvokeBuilder::configure`] reaches the others. Unlike [`Invoke::new`], no port is picked
+/// until [`InvokeBuilder::build`], which also reports settings that would fail to start.
+///
+/// ```rust,ignore
+/// let http = tauri_invoke_http::Invoke::builder()
+///   .allowed_origins(["tauri://localhost"])
+///   .port(18436)
+///   .strict_local()
+///   .build()?;
+/// ```
+pub struct InvokeBuilder {
+  invoke: Invoke,
+}
+
+impl InvokeBuilder {
+  pub(crate) fn new() -> Self {
+    Self {
+      invoke: Invoke::unbound(Vec::<String>::new(), 0),
+    }
+  }
+
+  /// Sets the origins allowed to invoke, none by default.
+  pub fn allowed_origins<I: Into<String>, O: IntoIterator<Item = I>>(
+    mut self,
+    allowed_origins: O,
+  ) -> Self {
+    self.invoke.allowed_origins = allowed_origins.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// See [`Invoke::with_port`]. A free port is picked when building if none is set.
+  pub fn port(mut self, port: u16) -> Self {
+    self.invoke = self.invoke.with_port(port);
+    self
+  }
+
+  /// See [`Invoke::with_ports`].
+  pub fn ports<I: IntoIterator<Item = u16>>(mut self, ports: I) -> Self {
+    self.invoke = self.invoke.with_ports(ports);
+    self
+  }
+
+  /// See [`Invoke::with_bind_address`].
+  pub fn bind_address<A: Into<IpAddr>>(mut self, address: A) -> Self {
+    self.invoke = self.invoke.with_bind_address(address);
+    self
+  }
+
+  /// See [`Invoke::with_url_host`].
+  pub fn url_host<H: Into<String>>(mut self, host: H) -> Self {
+    self.invoke = self.invoke.with_url_host(host);
+    self
+  }
+
+  /// See [`Invoke::with_url_scheme`].
+  pub fn url_scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+    self.invoke = self.invoke.with_url_scheme(scheme);
+    self
+  }
+
+  /// See [`Invoke::with_dual_stack`].
+  pub fn dual_stack(mut self) -> Self {
+    self.invoke = self.invoke.with_dual_stack();
+    self
+  }
+
+  /// See [`Invoke::with_strict_local`].
+  pub fn strict_local(mut self) -> Self {
+    self.invoke = self.invoke.with_strict_local();
+    self
+  }
+
+  /// See [`Invoke::with_listener`].
+  pub fn listener(mut self, listener: Listener) -> Self {
+    self.invoke = self.invoke.with_listener(listener);
+    self
+  }
+
+  /// See [`Invoke::with_transport`].
+  pub fn transport(mut self, transport: Transport) -> Self {
+    self.invoke = self.invoke.with_transport(transport);
+    self
+  }
+
+  /// See [`Invoke::with_path_prefix`].
+  pub fn path_prefix<P: AsRef<str>>(mut self, prefix: P) -> Self {
+    self.invoke = self.invoke.with_path_prefix(prefix);
+    self
+  }
+
+  /// See [`Invoke::with_cors`].
+  pub fn cors(mut self, cors: CorsConfig) -> Self {
+    self.invoke = self.invoke.with_cors(cors);
+    self
+  }
+
+  /// See [`Invoke::with_preflight_max_age`].
+  pub fn preflight_max_age(mut self, max_age: Option<Duration>) -> Self {
+    self.invoke = self.invoke.with_preflight_max_age(max_age);
+    self
+  }
+
+  /// See [`Invoke::with_limits`].
+  pub fn limits(mut self, limits: Limits) -> Self {
+    self.invoke = self.invoke.with_limits(limits);
+    self
+  }
+
+  /// See [`Invoke::with_rate_limit`].
+  pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+    self.invoke = self.invoke.with_rate_limit(rate_limit);
+    self
+  }
+
+  /// See [`Invoke::with_window_config`].
+  pub fn window_config<L: Into<String>>(mut self, label: L, config: WindowConfig) -> Self {
+    self.invoke = self.invoke.with_window_config(label, config);
+    self
+  }
+
+  /// See [`Invoke::with_virtual_window`].
+  pub fn virtual_window<L: Into<String>, H: Into<String>>(mut self, label: L, host: H) -> Self {
+    self.invoke = self.invoke.with_virtual_window(label, host);
+    self
+  }
+
+  /// See [`Invoke::with_read_timeouts`].
+  pub fn read_timeouts(mut self, read_timeouts: ReadTimeouts) -> Self {
+    self.invoke = self.invoke.with_read_timeouts(read_timeouts);
+    self
+  }
+
+  /// See [`Invoke::with_invoke_timeout`].
+  pub fn invoke_timeout(mut self, timeout: Duration) -> Self {
+    self.invoke = self.invoke.with_invoke_timeout(timeout);
+    self
+  }
+
+  /// See [`Invoke::with_keep_alive`].
+  pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+    self.invoke = self.invoke.with_keep_alive(keep_alive);
+    self
+  }
+
+  /// See [`Invoke::with_socket_options`].
+  pub fn socket_options(mut self, socket_options: SocketOptions) -> Self {
+    self.invoke = self.invoke.with_socket_options(socket_options);
+    self
+  }
+
+  /// See [`Invoke::with_workers`].
+  pub fn workers(mut self, workers: usize) -> Self {
+    self.invoke = self.invoke.with_workers(workers);
+    self
+  }
+
+  /// See [`Invoke::with_allowed_ips`].
+  pub fn allowed_ips<C: Into<Cidr>, I: IntoIterator<Item = C>>(mut self, ips: I) -> Self {
+    self.invoke = self.invoke.with_allowed_ips(ips);
+    self
+  }
+
+  /// See [`Invoke::with_tls`].
+  #[cfg(feature = "tls")]
+  pub fn tls(mut self, tls: TlsConfig) -> Self {
+    self.invoke = self.invoke.with_tls(tls);
+    self
+  }
+
+  /// See [`Invoke::with_session_token`].
+  pub fn session_token(mut self, token: Option<String>) -> Self {
+    self.invoke = self.invoke.with_session_token(token);
+    self
+  }
+
+  /// See [`Invoke::with_client_retry`].
+  pub fn client_retry(mut self, retry: Option<ClientRetry>) -> Self {
+    self.invoke = self.invoke.with_client_retry(retry);
+    self
+  }
+
+  /// See [`Invoke::with_client_timeout`].
+  pub fn client_timeout(mut self, timeout: Duration) -> Self {
+    self.invoke = self.invoke.with_client_timeout(timeout);
+    self
+  }
+
+  /// See [`Invoke::with_ready_handshake`].
+  pub fn ready_handshake(mut self) -> Self {
+    self.invoke = self.invoke.with_ready_handshake();
+    self
+  }
+
+  /// See [`Invoke::with_native_ipc_first`].
+  pub fn native_ipc_first(mut self) -> Self {
+    self.invoke = self.invoke.with_native_ipc_first();
+    self
+  }
+
+  /// See [`Invoke::with_rejected_origin_hook`].
+  pub fn rejected_origin_hook<F: Fn(Option<&str>) + Send + Sync + 'static>(
+    mut self,
+    hook: F,
+  ) -> Self {
+    self.invoke = self.invoke.with_rejected_origin_hook(hook);
+    self
+  }
+
+  /// See [`Invoke::with_lifecycle_hook`].
+  pub fn lifecycle_hook<F: Fn(&LifecycleEvent<'_>) + Send + Sync + 'static>(
+    mut self,
+    hook: F,
+  ) -> Self {
+    self.invoke = self.invoke.with_lifecycle_hook(hook);
+    self
+  }
+
+  /// See [`Invoke::with_middleware`].
+  pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+    self.invoke = self.invoke.with_middleware(middleware);
+    self
+  }
+
+  /// See [`Invoke::with_response_hook`].
+  pub fn response_hook<F: Fn(&str, u16, &mut tauri::http::HeaderMap) + Send + Sync + 'static>(
+    mut self,
+    hook: F,
+  ) -> Self {
+    self.invoke = self.invoke.with_response_hook(hook);
+    self
+  }
+
+  /// See [`Invoke::with_route`].
+  pub fn route<F: Fn(&MiddlewareRequest, &[u8]) -> MiddlewareResponse + Send + Sync + 'static>(
+    mut self,
+    method: &str,
+    path: &str,
+    handler: F,
+  ) -> Self {
+    self.invoke = self.invoke.with_route(method, path, handler);
+    self
+  }
+
+  /// See [`Invoke::with_missing_origin`].
+  pub fn missing_origin(mut self, policy: MissingOrigin) -> Self {
+    self.invoke = self.invoke.with_missing_origin(policy);
+    self
+  }
+
+  /// See [`Invoke::with_error_format`].
+  pub fn error_format(mut self, format: ErrorFormat) -> Self {
+    self.invoke = self.invoke.with_error_format(format);
+    self
+  }
+
+  /// See [`Invoke::with_audit_log`].
+  pub fn audit_log(mut self, audit_log: AuditLog) -> Self {
+    self.invoke = self.invoke.with_audit_log(audit_log);
+    self
+  }
+
+  /// Applies the `Invoke::with_*` methods that have no counterpart here.
+  ///
+  /// ```rust,ignore
+  /// let http = tauri_invoke_http::Invoke::builder()
+  ///   .allowed_origins(["tauri://localhost"])
+  ///   .configure(|invoke| invoke.with_csrf_protection().with_sessions())
+  ///   .build()?;
+  /// ```
+  pub fn configure<F: FnOnce(Invoke) -> Invoke>(mut self, configure: F) -> Self {
+    self.invoke = configure(self.invoke);
+    self
+  }
+
+  /// Returns the configured server, picking a free port unless one was set.
+  ///
+  /// Fails with [`InvokeHttpError::NoFreePort`] if no port is free for the server or the
+  /// WebSocket transport, with [`InvokeHttpError::NotLocal`] if strict local mode would listen
+  /// beyond loopback, with [`InvokeHttpError::InvalidCors`] if the CORS config names a header
+  /// or method that can't be sent, and with [`InvokeHttpError::WebSocketUnsupported`] if the
+  /// WebSocket transport is combined with a protection it can't apply.
+  pub fn build(self) -> Result<Invoke, InvokeHttpError> {
+    let mut invoke = self.invoke;
+    if invoke.port() == 0 {
+      let port = portpicker::pick_unused_port().ok_or(InvokeHttpError::NoFreePort)?;
+      invoke = invoke.with_port(port);
+    }
+    if invoke.ws_port == Some(0) {
+      return Err(InvokeHttpError::NoFreePort);
+    }
+    invoke.cors.validate()?;
+    invoke.ensure_transport()?;
+    if let (Some(address), false) = (invoke.address, invoke.dual_stack) {
+      invoke.ensure_local(address)?;
+    }
+    for listener in &invoke.extra_listeners {
+      invoke.ensure_local(listener.address.ip())?;
+    }
+    Ok(invoke)
+  }
+}
@@ -0,0 +1,120 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Cancellation of the commands whose client went away.
+//!
+//! Each dispatched invoke gets a [`CancellationToken`], found again by the callback id sent in
+//! `Tauri-Invocation-Id`. The token is cancelled if the invoke is abandoned before its command
+//! resolves, and forgotten once the command answers it. The tokens also describe the invokes
+//! still pending, for [`crate::Invoke::pending_invokes`].
+
+use {
+  crate::{CancellationToken, LockExt, PendingInvoke},
+  std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+  },
+};
+
+pub(crate) const INVOCATION_HEADER: &str = "Tauri-Invocation-Id";
+
+struct Dispatched {
+  token: CancellationToken,
+  cmd: String,
+  window: String,
+  since: Instant,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Tokens(Arc<Mutex<HashMap<u32, Dispatched>>>);
+
+impl Tokens {
+  /// Registers a token for the invoke of `cmd` with `callback`, sent to the window `window`.
+  pub(crate) fn register(&self, callback: u32, cmd: &str, window: &str) -> CancellationToken {
+    let token = CancellationToken::default();
+    self.0.locked().insert(
+      callback,
+      Dispatched {
+        token: token.clone(),
+        cmd: cmd.into(),
+        window: window.into(),
+        since: Instant::now(),
+      },
+    );
+    token
+  }
+
+  pub(crate) fn get(&self, callback: u32) -> Option<CancellationToken> {
+    self
+      .0
+      .locked()
+      .get(&callback)
+      .map(|dispatched| dispatched.token.clone())
+  }
+
+  /// The invokes whose command didn't answer yet, the oldest first.
+  pub(crate) fn pending(&self) -> Vec<PendingInvoke> {
+    let mut pending = self
+      .0
+      .locked()
+      .iter()
+      .map(|(callback, dispatched)| PendingInvoke {
+        callback: *callback,
+        cmd: dispatched.cmd.clone(),
+        window: dispatched.window.clone(),
+        age: dispatched.since.elapsed(),
+      })
+      .collect::<Vec<_>>();
+    pending.sort_by_key(|invoke| std::cmp::Reverse(invoke.age));
+    pending
+  }
+
+  /// Forgets the token of an answered invoke.
+  pub(crate) fn finish(&self, callback: u32) {
+    self.0.locked().remove(&callback);
+  }
+
+  /// Cancels and forgets the token of an abandoned invoke, if it wasn't answered yet.
+  pub(crate) fn cancel(&self, callback: u32) {
+    if let Some(dispatched) = self.0.locked().remove(&callback) {
+      dispatched.token.cancel();
+    }
+  }
+
+  /// Returns a guard cancelling the invoke with `callback` when dropped before
+  /// [`Guard::finish`], e.g. because its connection closed.
+  #[cfg(feature = "async-server")]
+  pub(crate) fn guard(&self, callback: u32) -> Guard {
+    Guard {
+      tokens: self.clone(),
+      callback,
+      finished: false,
+    }
+  }
+}
+
+#[cfg(feature = "async-server")]
+pub(crate) struct Guard {
+  tokens: Tokens,
+  callback: u32,
+  finished: bool,
+}
+
+#[cfg(feature = "async-server")]
+impl Guard {
+  pub(crate) fn finish(mut self) {
+    self.finished = true;
+    self.tokens.finish(self.callback);
+  }
+}
+
+#[cfg(feature = "async-server")]
+impl Drop for Guard {
+  fn drop(&mut self) {
+    if !self.finished {
+      self.tokens.cancel(self.callback);
+    }
+  }
+}
@@ -0,0 +1,401 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! CBOR (RFC 8949) encoding of JSON values, used as an alternative wire format.
+//!
+//! Byte strings decode to arrays of numbers, tags are skipped, map keys must be text and
+//! `undefined` decodes to `null`.
+
+use serde_json::{Map, Number, Value as JsonValue};
+
+pub(crate) const CONTENT_TYPE: &str = "application/cbor";
+
+const BREAK: u8 = 0xff;
+
+/// How deep arrays, maps and tags may nest, so hostile bodies can't overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+pub(crate) fn encode(value: &JsonValue) -> Vec<u8> {
+  let mut out = Vec::new();
+  write(&mut out, value);
+  out
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, n: u64) {
+  let major = major << 5;
+  match n {
+    0..=23 => out.push(major | n as u8),
+    24..=0xff => out.extend_from_slice(&[major | 24, n as u8]),
+    0x100..=0xffff => {
+      out.push(major | 25);
+      out.extend_from_slice(&(n as u16).to_be_bytes());
+    }
+    0x1_0000..=0xffff_ffff => {
+      out.push(major | 26);
+      out.extend_from_slice(&(n as u32).to_be_bytes());
+    }
+    _ => {
+      out.push(major | 27);
+      out.extend_from_slice(&n.to_be_bytes());
+    }
+  }
+}
+
+fn write(out: &mut Vec<u8>, value: &JsonValue) {
+  match value {
+    JsonValue::Null => out.push(0xf6),
+    JsonValue::Bool(false) => out.push(0xf4),
+    JsonValue::Bool(true) => out.push(0xf5),
+    JsonValue::Number(n) => {
+      if let Some(n) = n.as_u64() {
+        write_head(out, 0, n);
+      } else if let Some(n) = n.as_i64() {
+        // as_u64 failed, so n is negative and -1 - n can't overflow
+        write_head(out, 1, (-1 - n) as u64);
+      } else {
+        out.push(0xfb);
+        out.extend_from_slice(&n.as_f64().unwrap_or_default().to_be_bytes());
+      }
+    }
+    JsonValue::String(s) => {
+      write_head(out, 3, s.len() as u64);
+      out.extend_from_slice(s.as_bytes());
+    }
+    JsonValue::Array(a) => {
+      write_head(out, 4, a.len() as u64);
+      for v in a {
+        write(out, v);
+      }
+    }
+    JsonValue::Object(o) => {
+      write_head(out, 5, o.len() as u64);
+      for (k, v) in o {
+        write_head(out, 3, k.len() as u64);
+        out.extend_from_slice(k.as_bytes());
+        write(out, v);
+      }
+    }
+  }
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<JsonValue, String> {
+  let mut reader = Reader {
+    bytes,
+    offset: 0,
+    depth: 0,
+  };
+  let value = reader.read()?;
+  if reader.offset != bytes.len() {
+    return Err("trailing bytes after CBOR value".into());
+  }
+  Ok(value)
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  offset: usize,
+  /// The arrays, maps and tags being read around the current value.
+  depth: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    let end = self
+      .offset
+      .checked_add(len)
+      .filter(|end| *end <= self.bytes.len())
+      .ok_or("unexpected end of CBOR data")?;
+    let slice = &self.bytes[self.offset..end];
+    self.offset = end;
+    Ok(slice)
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.bytes.get(self.offset).copied()
+  }
+
+  fn uint(&mut self, size: usize) -> Result<u64, String> {
+    Ok(
+      self
+        .take(size)?
+        .iter()
+        .fold(0u64, |n, b| (n << 8) | *b as u64),
+    )
+  }
+
+  /// Reads the argument of an initial byte, `None` meaning an indefinite length.
+  fn argument(&mut self, info: u8) -> Result<Option<u64>, String> {
+    match info {
+      0..=23 => Ok(Some(info as u64)),
+      24 => self.uint(1).map(Some),
+      25 => self.uint(2).map(Some),
+      26 => self.uint(4).map(Some),
+      27 => self.uint(8).map(Some),
+      31 => Ok(None),
+      _ => Err(format!("invalid CBOR additional information {}", info)),
+    }
+  }
+
+  fn definite(&mut self, info: u8) -> Result<usize, String> {
+    self
+      .argument(info)?
+      .ok_or_else(|| "unexpected indefinite length".to_string())
+      .map(|n| n as usize)
+  }
+
+  fn is_break(&mut self) -> bool {
+    if self.peek() == Some(BREAK) {
+      self.offset += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// Collects a byte or text string, concatenating the chunks of indefinite-length strings.
+  fn string(&mut self, major: u8, info: u8) -> Result<Vec<u8>, String> {
+    match self.argument(info)? {
+      Some(len) => Ok(self.take(len as usize)?.to_vec()),
+      None => {
+        let mut data = Vec::new();
+        while !self.is_break() {
+          let byte = self.take(1)?[0];
+          if byte >> 5 != major {
+            return Err("invalid chunk in indefinite-length CBOR string".into());
+          }
+          let len = self.definite(byte & 0x1f)?;
+          data.extend_from_slice(self.take(len)?);
+        }
+        Ok(data)
+      }
+    }
+  }
+
+  /// Reads the content of an array, map or tag with `read`, one level deeper.
+  fn nested(
+    &mut self,
+    read: impl FnOnce(&mut Self) -> Result<JsonValue, String>,
+  ) -> Result<JsonValue, String> {
+    if self.depth == MAX_DEPTH {
+      return Err(format!("CBOR data nested deeper than {} levels", MAX_DEPTH));
+    }
+    self.depth += 1;
+    let value = read(self);
+    self.depth -= 1;
+    value
+  }
+
+  fn read(&mut self) -> Result<JsonValue, String> {
+    let byte = self.take(1)?[0];
+    let (major, info) = (byte >> 5, byte & 0x1f);
+    match major {
+      0 => Ok(
+        self
+          .argument(info)?
+          .ok_or("unexpected indefinite length")?
+          .into(),
+      ),
+      1 => {
+        let n = -1 - self.argument(info)?.ok_or("unexpected indefinite length")? as i128;
+        Ok(match i64::try_from(n) {
+          Ok(n) => n.into(),
+          Err(_) => float(n as f64),
+        })
+      }
+      2 => Ok(JsonValue::Array(
+        self
+          .string(major, info)?
+          .into_iter()
+          .map(Into::into)
+          .collect(),
+      )),
+      3 => String::from_utf8(self.string(major, info)?)
+        .map(JsonValue::String)
+        .map_err(|e| e.to_string()),
+      4 => self.nested(|reader| {
+        let mut array = Vec::new();
+        match reader.argument(info)? {
+          Some(len) => {
+            for _ in 0..len {
+              array.push(reader.read()?);
+            }
+          }
+          None => {
+            while !reader.is_break() {
+              array.push(reader.read()?);
+            }
+          }
+        }
+        Ok(JsonValue::Array(array))
+      }),
+      5 => self.nested(|reader| {
+        let len = reader.argument(info)?;
+        let mut map = Map::new();
+        let mut count = 0;
+        while len.map_or(!reader.is_break(), |len| count < len) {
+          let key = match reader.read()? {
+            JsonValue::String(key) => key,
+            _ => return Err("CBOR map keys must be text strings".into()),
+          };
+          map.insert(key, reader.read()?);
+          count += 1;
+        }
+        Ok(JsonValue::Object(map))
+      }),
+      6 => {
+        // tags only carry semantics on top of the value, which JSON can't express
+        self.argument(info)?;
+        self.nested(Self::read)
+      }
+      _ => match info {
+        20 => Ok(false.into()),
+        21 => Ok(true.into()),
+        22 | 23 => Ok(JsonValue::Null),
+        25 => Ok(float(f16_to_f64(self.uint(2)? as u16))),
+        26 => Ok(float(f32::from_bits(self.uint(4)? as u32) as f64)),
+        27 => Ok(float(f64::from_bits(self.uint(8)?))),
+        _ => Err(format!("unsupported CBOR simple value {}", info)),
+      },
+    }
+  }
+}
+
+fn float(n: f64) -> JsonValue {
+  Number::from_f64(n).map_or(JsonValue::Null, JsonValue::Number)
+}
+
+fn f16_to_f64(half: u16) -> f64 {
+  let exponent = (half >> 10) & 0x1f;
+  let mantissa = (half & 0x3ff) as f64;
+  let value = match exponent {
+    0 => mantissa * 2f64.powi(-24),
+    31 if mantissa == 0.0 => f64::INFINITY,
+    31 => f64::NAN,
+    _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+  };
+  if half & 0x8000 != 0 {
+    -value
+  } else {
+    value
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trips_json_values() {
+    let value = serde_json::json!({
+      "null": null,
+      "bools": [true, false],
+      "ints": [0, 23, 24, 255, 256, 65535, 65536, 4294967296u64, u64::MAX],
+      "negatives": [-1, -24, -25, -256, -257, -65537, i64::MIN],
+      "float": 1.5,
+      "strings": ["", "a".repeat(24), "b".repeat(256), "c".repeat(65536)],
+      "nested": { "deeper": [{ "value": "é" }] },
+    });
+    assert_eq!(decode(&encode(&value)), Ok(value));
+  }
+
+  #[test]
+  fn decodes_indefinite_lengths() {
+    // (_ h'01', h'0203'), (_ "a", "bc"), [_ 1, [2]] and {_ "a": 1}
+    assert_eq!(
+      decode(&[0x5f, 0x41, 1, 0x42, 2, 3, 0xff]),
+      Ok(serde_json::json!([1, 2, 3]))
+    );
+    assert_eq!(
+      decode(&[0x7f, 0x61, b'a', 0x62, b'b', b'c', 0xff]),
+      Ok(serde_json::json!("abc"))
+    );
+    assert_eq!(
+      decode(&[0x9f, 0x01, 0x81, 0x02, 0xff]),
+      Ok(serde_json::json!([1, [2]]))
+    );
+    assert_eq!(
+      decode(&[0xbf, 0x61, b'a', 0x01, 0xff]),
+      Ok(serde_json::json!({ "a": 1 }))
+    );
+    // a text chunk inside a byte string
+    assert!(decode(&[0x5f, 0x61, b'a', 0xff]).is_err());
+    assert!(decode(&[0x9f, 0x01]).is_err());
+  }
+
+  #[test]
+  fn decodes_floats_and_simple_values() {
+    // the RFC 8949 appendix A examples of half-precision floats
+    assert_eq!(decode(&[0xf9, 0x3c, 0x00]), Ok(serde_json::json!(1.0)));
+    assert_eq!(decode(&[0xf9, 0x3e, 0x00]), Ok(serde_json::json!(1.5)));
+    assert_eq!(decode(&[0xf9, 0xc4, 0x00]), Ok(serde_json::json!(-4.0)));
+    assert_eq!(
+      decode(&[0xf9, 0x00, 0x01]),
+      Ok(serde_json::json!(5.960464477539063e-8))
+    );
+    assert_eq!(decode(&[0xf9, 0x7c, 0x00]), Ok(JsonValue::Null));
+    assert_eq!(
+      decode(&[0xfa, 0x47, 0xc3, 0x50, 0x00]),
+      Ok(serde_json::json!(100000.0))
+    );
+    assert_eq!(decode(&[0xf7]), Ok(JsonValue::Null));
+    assert!(decode(&[0xf0]).is_err());
+  }
+
+  #[test]
+  fn decodes_negative_integers_beyond_i64() {
+    // -18446744073709551616, the most negative CBOR integer
+    assert_eq!(
+      decode(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+      Ok(serde_json::json!(-18446744073709551616.0))
+    );
+    assert_eq!(
+      decode(&[0x3b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+      Ok(serde_json::json!(i64::MIN))
+    );
+  }
+
+  #[test]
+  fn skips_tags() {
+    // 1(1363896240), an epoch date
+    assert_eq!(
+      decode(&[0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0]),
+      Ok(serde_json::json!(1363896240))
+    );
+  }
+
+  #[test]
+  fn rejects_malformed_input() {
+    let bytes = encode(&serde_json::json!({ "name": "tests", "list": [1, 2, 3] }));
+    for len in 0..bytes.len() {
+      assert!(decode(&bytes[..len]).is_err(), "{} bytes", len);
+    }
+    assert_eq!(
+      decode(&[0xf6, 0xf6]),
+      Err("trailing bytes after CBOR value".into())
+    );
+    assert!(decode(&[0xa1, 0x01, 0xf6]).is_err());
+    assert!(decode(&[0x1c]).is_err());
+  }
+
+  #[test]
+  fn rejects_deep_nesting() {
+    for byte in [0x81, 0x9f, 0xc1] {
+      let nested = |depth: usize| {
+        let mut bytes = vec![byte; depth];
+        bytes.push(0xf6);
+        if byte == 0x9f {
+          bytes.extend(std::iter::repeat(BREAK).take(depth));
+        }
+        bytes
+      };
+      assert!(decode(&nested(MAX_DEPTH)).is_ok());
+      assert!(decode(&nested(MAX_DEPTH + 1)).is_err());
+      // far beyond what the stack would survive
+      assert!(decode(&nested(200_000)).is_err());
+    }
+    let mut maps = [0xa1, 0x61, b'k'].repeat(200_000);
+    maps.push(0xf6);
+    assert!(decode(&maps).is_err());
+  }
+}
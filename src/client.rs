@@ -0,0 +1,232 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! A client invoking commands over HTTP, for integration tests and companion processes.
+//!
+//! It speaks HTTP/1.0, so responses are never chunked and the connection is closed after each
+//! invoke. TLS isn't supported.
+
+use {
+  crate::{INVOKE_ERROR_HEADER, INVOKE_KEY_HEADER},
+  serde::{de::DeserializeOwned, Serialize},
+  serde_json::Value as JsonValue,
+  std::{
+    fmt,
+    io::{Read, Write},
+    net::TcpStream,
+  },
+  tauri::Url,
+};
+
+/// Why an invoke sent by an [`InvokeHttpClient`] failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum InvokeClientError {
+  /// The base URL isn't an `http` URL with a host.
+  InvalidUrl(String),
+  /// The server couldn't be reached or the connection failed.
+  Io(std::io::Error),
+  /// The command returned an error, which is `body`.
+  Command(JsonValue),
+  /// The server refused the invoke with `status`, e.g. `403` for a command that isn't allowed.
+  Rejected { status: u16, body: String },
+  /// The response couldn't be read or deserialized.
+  Decode(String),
+}
+
+impl fmt::Display for InvokeClientError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::InvalidUrl(url) => write!(f, "invalid invoke server URL: {}", url),
+      Self::Io(e) => write!(f, "failed to reach the invoke server: {}", e),
+      Self::Command(error) => write!(f, "the command failed: {}", error),
+      Self::Rejected { status, body } => {
+        write!(f, "the invoke was refused with {}: {}", status, body)
+      }
+      Self::Decode(e) => write!(f, "malformed invoke response: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for InvokeClientError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for InvokeClientError {
+  fn from(error: std::io::Error) -> Self {
+    Self::Io(error)
+  }
+}
+
+/// Invokes commands on a running [`crate::Invoke`] server.
+///
+/// The invokes are sent as the window `main` from no origin, set them with
+/// [`Self::with_window`] and [`Self::with_origin`]. Servers requiring a session token or a JWT
+/// get it from [`Self::with_token`].
+///
+/// ```rust,ignore
+/// let client = InvokeHttpClient::new(http.base_url(), app.invoke_key())?
+///   .with_origin("tauri://localhost");
+/// let greeting: String = client.invoke("greet", &serde_json::json!({ "name": "tests" }))?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct InvokeHttpClient {
+  host: String,
+  port: u16,
+  path: String,
+  invoke_key: String,
+  window: String,
+  origin: Option<String>,
+  token: Option<String>,
+}
+
+impl InvokeHttpClient {
+  /// Creates a client for the server at `base_url`, e.g. [`crate::Invoke::base_url`], with the
+  /// invoke key of the app.
+  pub fn new<U: AsRef<str>, K: Into<String>>(
+    base_url: U,
+    invoke_key: K,
+  ) -> Result<Self, InvokeClientError> {
+    let invalid = || InvokeClientError::InvalidUrl(base_url.as_ref().to_string());
+    let url = Url::parse(base_url.as_ref()).map_err(|_| invalid())?;
+    if url.scheme() != "http" {
+      return Err(invalid());
+    }
+    Ok(Self {
+      host: url.host_str().ok_or_else(invalid)?.to_string(),
+      port: url.port_or_known_default().ok_or_else(invalid)?,
+      path: url.path().trim_end_matches('/').to_string(),
+      invoke_key: invoke_key.into(),
+      window: "main".into(),
+      origin: None,
+      token: None,
+    })
+  }
+
+  /// Sends the invokes as the window with `label`.
+  pub fn with_window<L: Into<String>>(mut self, label: L) -> Self {
+    self.window = label.into();
+    self
+  }
+
+  /// Sends the invokes from `origin`, which has to be allowed by the server.
+  pub fn with_origin<O: Into<String>>(mut self, origin: O) -> Self {
+    self.origin = Some(origin.into());
+    self
+  }
+
+  /// Sends `token` as a bearer token with every invoke.
+  pub fn with_token<T: Into<String>>(mut self, token: T) -> Self {
+    self.token = Some(token.into());
+    self
+  }
+
+  /// Invokes `cmd` with `args` and deserializes its response.
+  pub fn invoke<A: Serialize, T: DeserializeOwned>(
+    &self,
+    cmd: &str,
+    args: &A,
+  ) -> Result<T, InvokeClientError> {
+    let request = self.request(cmd, args)?;
+    let mut stream = TcpStream::connect(self.address())?;
+    stream.write_all(&request)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    decode(&response)
+  }
+
+  /// Invokes `cmd` with `args` and deserializes its response, without blocking the runtime.
+  #[cfg(feature = "async-server")]
+  pub async fn invoke_async<A: Serialize, T: DeserializeOwned>(
+    &self,
+    cmd: &str,
+    args: &A,
+  ) -> Result<T, InvokeClientError> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let request = self.request(cmd, args)?;
+    let mut stream = tokio::net::TcpStream::connect(self.address()).await?;
+    stream.write_all(&request).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    decode(&response)
+  }
+
+  /// The host and port to connect to, without the brackets of IPv6 addresses.
+  fn address(&self) -> (&str, u16) {
+    let host = self.host.trim_start_matches('[').trim_end_matches(']');
+    (host, self.port)
+  }
+
+  /// The HTTP request invoking `cmd` with `args`.
+  fn request<A: Serialize>(&self, cmd: &str, args: &A) -> Result<Vec<u8>, InvokeClientError> {
+    let mut ids = [0; 8];
+    getrandom::fill(&mut ids).expect("failed to generate callback ids");
+    let payload =
+      serde_json::to_value(args).map_err(|e| InvokeClientError::Decode(e.to_string()))?;
+    let body = serde_json::json!({
+      "cmd": cmd,
+      "callback": u32::from_le_bytes([ids[0], ids[1], ids[2], ids[3]]),
+      "error": u32::from_le_bytes([ids[4], ids[5], ids[6], ids[7]]),
+      "payload": payload,
+    })
+    .to_string();
+    let mut head = format!(
+      "POST {}/{} HTTP/1.0\r\nHost: {}:{}\r\nContent-Type: application/json\r\nAccept: application/json\r\nContent-Length: {}\r\n{}: {}\r\n",
+      self.path,
+      self.window,
+      self.host,
+      self.port,
+      body.len(),
+      INVOKE_KEY_HEADER,
+      self.invoke_key
+    );
+    if let Some(origin) = &self.origin {
+      head.push_str(&format!("Origin: {}\r\n", origin));
+    }
+    if let Some(token) = &self.token {
+      head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    head.push_str("\r\n");
+    head.push_str(&body);
+    Ok(head.into_bytes())
+  }
+}
+
+/// Decodes the HTTP response to an invoke.
+fn decode<T: DeserializeOwned>(response: &[u8]) -> Result<T, InvokeClientError> {
+  let malformed = || InvokeClientError::Decode("incomplete HTTP response".into());
+  let split = response
+    .windows(4)
+    .position(|w| w == b"\r\n\r\n")
+    .ok_or_else(malformed)?;
+  let head = String::from_utf8_lossy(&response[..split]);
+  let body = &response[split + 4..];
+  let mut lines = head.lines();
+  let status = lines
+    .next()
+    .and_then(|line| line.split(' ').nth(1))
+    .and_then(|status| status.parse::<u16>().ok())
+    .ok_or_else(malformed)?;
+  let command_error = lines.any(|line| {
+    line.split_once(':').map_or(false, |(name, value)| {
+      name.trim().eq_ignore_ascii_case(INVOKE_ERROR_HEADER) && value.trim() == "true"
+    })
+  });
+  if status == 200 {
+    return serde_json::from_slice(body).map_err(|e| InvokeClientError::Decode(e.to_string()));
+  }
+  match serde_json::from_slice(body) {
+    Ok(error) if command_error => Err(InvokeClientError::Command(error)),
+    _ => Err(InvokeClientError::Rejected {
+      status,
+      body: String::from_utf8_lossy(body).into_owned(),
+    }),
+  }
+}
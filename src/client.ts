@@ -0,0 +1,97 @@
+// Generated by tauri-invoke-http.
+//
+// Invokes the commands of a Tauri app over HTTP, for frontends running without
+// `__TAURI_INTERNALS__`, e.g. in a browser on the LAN. The page's origin must be allowed by the
+// server.
+
+export interface InvokeHttpOptions {
+  /** The app's invoke key, sent in `Tauri-Invoke-Key`. */
+  invokeKey: string
+  /** The session token or JWT the server requires, if any. */
+  token?: string
+  /** The label of the window the invokes are sent to, `main` by default. */
+  window?: string
+  /** The base URL of the server, the one this module was generated for by default. */
+  baseUrl?: string
+}
+
+/** The command returned an error, which is `error`. */
+export class CommandError extends Error {
+  constructor(readonly error: unknown) {
+    super(typeof error === 'string' ? error : JSON.stringify(error))
+    this.name = 'CommandError'
+  }
+}
+
+/** The server refused the invoke with `status`, e.g. `403` for a command that isn't allowed. */
+export class InvokeRejectedError extends Error {
+  constructor(readonly status: number, readonly body: string) {
+    super(`the invoke was refused with ${status}: ${body}`)
+    this.name = 'InvokeRejectedError'
+  }
+}
+
+export interface InvokeHttpClient {
+  invoke<T>(cmd: string, args?: Record<string, unknown>): Promise<T>
+}
+
+const BASE_URL: string = __BASE_URL__
+const CSRF_PROTECTION: boolean = __CSRF_PROTECTION__
+const ERROR_ENVELOPE: boolean = __ERROR_ENVELOPE__
+
+export function createClient(options: InvokeHttpOptions): InvokeHttpClient {
+  const windowUrl = (options.baseUrl ?? BASE_URL).replace(/\/$/, '') + '/' + (options.window ?? 'main')
+  const authorization = (): Record<string, string> =>
+    options.token ? { Authorization: 'Bearer ' + options.token } : {}
+  const nonces: string[] = []
+
+  // an unused nonce, fetching one if none is left
+  async function nonce(): Promise<string | null> {
+    if (!CSRF_PROTECTION) {
+      return null
+    }
+    const unused = nonces.pop()
+    if (unused) {
+      return unused
+    }
+    const response = await fetch(windowUrl + '/nonce', { headers: authorization() })
+    return response.ok ? response.text() : null
+  }
+
+  async function invoke<T>(cmd: string, args: Record<string, unknown> = {}): Promise<T> {
+    const [callback, error] = crypto.getRandomValues(new Uint32Array(2))
+    const headers: Record<string, string> = {
+      ...authorization(),
+      'Content-Type': 'application/json',
+      Accept: 'application/json',
+      'Tauri-Invoke-Key': options.invokeKey
+    }
+    const next = await nonce()
+    if (next) {
+      headers['Tauri-Nonce'] = next
+    }
+    const response = await fetch(windowUrl, {
+      method: 'POST',
+      headers,
+      body: JSON.stringify({ cmd, callback, error, payload: args })
+    })
+    const issued = response.headers.get('Tauri-Next-Nonce')
+    if (issued) {
+      nonces.push(issued)
+    }
+    const body = await response.text()
+    if (response.ok) {
+      return JSON.parse(body) as T
+    }
+    if (response.headers.get('Tauri-Invoke-Error') === 'true') {
+      let failure = JSON.parse(body)
+      if (ERROR_ENVELOPE && failure && typeof failure === 'object' && 'error' in failure) {
+        failure = failure.error
+      }
+      throw new CommandError(failure)
+    }
+    throw new InvokeRejectedError(response.status, body)
+  }
+
+  return { invoke }
+}
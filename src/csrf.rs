@@ -0,0 +1,55 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Single-use nonces guarding invokes against requests forged by other websites.
+//!
+//! Each invoke spends a nonce sent in `Tauri-Nonce` and its response hands out the next one in
+//! `Tauri-Next-Nonce`. Pages get their first nonces from `GET /{label}/nonce`.
+
+use {
+  crate::{generate_token, LockExt},
+  std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+  },
+};
+
+pub(crate) const NONCE_HEADER: &str = "Tauri-Nonce";
+pub(crate) const NEXT_NONCE_HEADER: &str = "Tauri-Next-Nonce";
+
+/// How long an issued nonce stays valid.
+const TTL: Duration = Duration::from_secs(300);
+/// How many nonces may be outstanding before the oldest are dropped.
+const MAX_OUTSTANDING: usize = 1024;
+
+#[derive(Clone, Default)]
+pub(crate) struct Nonces(Arc<Mutex<HashMap<String, Instant>>>);
+
+impl Nonces {
+  /// Issues a new nonce.
+  pub(crate) fn issue(&self) -> String {
+    let nonce = generate_token();
+    let mut nonces = self.0.locked();
+    nonces.retain(|_, issued| issued.elapsed() < TTL);
+    if nonces.len() >= MAX_OUTSTANDING {
+      if let Some(oldest) = nonces
+        .iter()
+        .min_by_key(|(_, issued)| **issued)
+        .map(|(nonce, _)| nonce.clone())
+      {
+        nonces.remove(&oldest);
+      }
+    }
+    nonces.insert(nonce.clone(), Instant::now());
+    nonce
+  }
+
+  /// Spends `nonce`, returning whether it was issued and not used or expired yet.
+  pub(crate) fn spend(&self, nonce: Option<&str>) -> bool {
+    nonce
+      .and_then(|nonce| self.0.locked().remove(nonce))
+      .map_or(false, |issued| issued.elapsed() < TTL)
+  }
+}
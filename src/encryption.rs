@@ -0,0 +1,190 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! AES-256-GCM encryption of invoke bodies, for machines where loopback traffic can be observed.
+//!
+//! Encrypted bodies are a random 12 byte nonce followed by the ciphertext and its tag, as
+//! produced by the Web Crypto API. The key is generated with the [`crate::Invoke`] and reaches
+//! pages through the initialization script.
+
+use {
+  aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+  },
+  base64::Engine,
+  std::io::{Result as IoResult, Write},
+};
+
+/// Marks encrypted responses, whose body is sealed with the invoke key.
+pub(crate) const ENCRYPTION_HEADER: &str = "Tauri-Encryption";
+pub(crate) const ALGORITHM: &str = "aes-256-gcm";
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// How much larger than its plaintext an encrypted body is.
+pub(crate) const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+#[derive(Clone)]
+pub(crate) struct Cipher {
+  key: [u8; 32],
+  aead: Aes256Gcm,
+}
+
+impl Cipher {
+  pub(crate) fn new(key: [u8; 32]) -> Self {
+    Self {
+      key,
+      aead: Aes256Gcm::new(&key.into()),
+    }
+  }
+
+  pub(crate) fn generate() -> Self {
+    let mut key = [0u8; 32];
+    getrandom::fill(&mut key).expect("failed to generate an encryption key");
+    Self::new(key)
+  }
+
+  /// The key, base64 encoded for the initialization script.
+  pub(crate) fn key(&self) -> String {
+    base64::engine::general_purpose::STANDARD.encode(self.key)
+  }
+
+  pub(crate) fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::fill(&mut nonce).expect("failed to generate an encryption nonce");
+    self.seal_with(nonce, plaintext)
+  }
+
+  fn seal_with(&self, nonce: [u8; NONCE_SIZE], plaintext: &[u8]) -> Vec<u8> {
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+      self
+        .aead
+        .encrypt(&nonce.into(), plaintext)
+        .expect("the body is too large to encrypt"),
+    );
+    sealed
+  }
+
+  pub(crate) fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < OVERHEAD {
+      return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    self.aead.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+  }
+}
+
+/// Collects an encrypted body, and writes it decrypted to `inner` when flushed.
+pub(crate) struct Decrypting<W> {
+  inner: W,
+  cipher: Cipher,
+  sealed: Option<Vec<u8>>,
+}
+
+impl<W: Write> Decrypting<W> {
+  pub(crate) fn new(inner: W, cipher: &Cipher) -> Self {
+    Self {
+      inner,
+      cipher: cipher.clone(),
+      sealed: Some(Vec::new()),
+    }
+  }
+}
+
+impl<W: Write> Write for Decrypting<W> {
+  fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+    match &mut self.sealed {
+      Some(sealed) => sealed.extend_from_slice(buf),
+      None => return self.inner.write(buf),
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> IoResult<()> {
+    if let Some(sealed) = self.sealed.take() {
+      let plaintext = self.cipher.open(&sealed).ok_or_else(|| {
+        std::io::Error::new(
+          std::io::ErrorKind::InvalidData,
+          "invalid encrypted request body",
+        )
+      })?;
+      self.inner.write_all(&plaintext)?;
+    }
+    self.inner.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+      .collect()
+  }
+
+  // test cases 13 to 15 of the GCM specification, which have no additional data
+  const VECTORS: [(&str, &str, &str, &str, &str); 3] = [
+    (
+      "0000000000000000000000000000000000000000000000000000000000000000",
+      "000000000000000000000000",
+      "",
+      "",
+      "530f8afbc74536b9a963b4f1c4cb738b",
+    ),
+    (
+      "0000000000000000000000000000000000000000000000000000000000000000",
+      "000000000000000000000000",
+      "00000000000000000000000000000000",
+      "cea7403d4d606b6e074ec5d3baf39d18",
+      "d0d1c8a799996bf0265b98b5d48ab919",
+    ),
+    (
+      "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+      "cafebabefacedbaddecaf888",
+      "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+       1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255",
+      "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+       8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015ad",
+      "b094dac5d93471bdec1a502270e3cc6c",
+    ),
+  ];
+
+  #[test]
+  fn matches_the_known_answers() {
+    for (key, nonce, plaintext, ciphertext, tag) in VECTORS {
+      let cipher = Cipher::new(hex(key).try_into().unwrap());
+      let sealed = cipher.seal_with(hex(nonce).try_into().unwrap(), &hex(plaintext));
+      let expected = [hex(nonce), hex(ciphertext), hex(tag)].concat();
+      assert_eq!(sealed, expected);
+      assert_eq!(cipher.open(&expected), Some(hex(plaintext)));
+    }
+  }
+
+  #[test]
+  fn opens_what_it_seals() {
+    let body = br#"{"cmd":"greet"}"#;
+    let cipher = Cipher::generate();
+    let sealed = cipher.seal(body);
+    assert_eq!(sealed.len(), body.len() + OVERHEAD);
+    assert_eq!(cipher.open(&sealed).unwrap(), body);
+  }
+
+  #[test]
+  fn rejects_tampered_bodies() {
+    let cipher = Cipher::generate();
+    let sealed = cipher.seal(b"hello");
+    for i in 0..sealed.len() {
+      let mut tampered = sealed.clone();
+      tampered[i] ^= 1;
+      assert_eq!(cipher.open(&tampered), None, "byte {}", i);
+    }
+    assert_eq!(cipher.open(&sealed[..OVERHEAD - 1]), None);
+    assert_eq!(Cipher::generate().open(&sealed), None);
+  }
+}
@@ -0,0 +1,63 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! The error returned when an [`crate::Invoke`] can't be created or started.
+
+use std::{fmt, net::IpAddr};
+
+/// Why an [`crate::Invoke`] couldn't be created or started.
+///
+/// Failures of single requests never surface here, they are answered with an HTTP error status.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum InvokeHttpError {
+  /// No unused port could be found for the server.
+  NoFreePort,
+  /// A listener couldn't be bound or set up.
+  Io(std::io::Error),
+  /// Strict local mode refused to listen on an address beyond loopback.
+  NotLocal(IpAddr),
+  /// The TLS certificate couldn't be generated or loaded.
+  Tls(String),
+  /// [`crate::CorsConfig`] names a header or method that can't be sent in a response.
+  InvalidCors(String),
+  /// A [`crate::Cidr`] couldn't be parsed.
+  InvalidCidr(String),
+  /// [`crate::Transport::WebSocket`] was combined with a protection it can't apply to its
+  /// invokes, e.g. request signing.
+  WebSocketUnsupported(&'static str),
+}
+
+impl fmt::Display for InvokeHttpError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::NoFreePort => f.write_str("failed to get an unused port for invoke"),
+      Self::Io(e) => write!(f, "failed to set up the invoke server: {}", e),
+      Self::NotLocal(address) => write!(f, "strict local mode refuses to listen on {}", address),
+      Self::Tls(e) => write!(f, "failed to set up TLS for invoke: {}", e),
+      Self::InvalidCors(name) => {
+        write!(f, "invalid header or method in the CORS config: {:?}", name)
+      }
+      Self::InvalidCidr(block) => write!(f, "invalid CIDR block: {:?}", block),
+      Self::WebSocketUnsupported(protection) => {
+        write!(f, "the WebSocket transport doesn't support {}", protection)
+      }
+    }
+  }
+}
+
+impl std::error::Error for InvokeHttpError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for InvokeHttpError {
+  fn from(error: std::io::Error) -> Self {
+    Self::Io(error)
+  }
+}
@@ -0,0 +1,212 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Delivery of Tauri events to frontends through `GET /{label}/events` streams, and the
+//! `POST /{label}/events/{action}` endpoints standing in for the `plugin:event|*` commands.
+//!
+//! A stream asks for its events in `event` query parameters, or follows the listeners
+//! registered for its webview with `POST /{label}/events/listen` when it names none. The first
+//! time a webview needs an event, a listener is registered on that webview, which forwards every
+//! emit to the streams of the webview that asked for it.
+
+use {
+  crate::{assets, sse::EventStream, Context, LockExt},
+  serde::Deserialize,
+  serde_json::Value as JsonValue,
+  std::{
+    collections::{HashMap, HashSet},
+    sync::{
+      atomic::{AtomicU32, Ordering},
+      Arc, Mutex,
+    },
+  },
+  tauri::{AppHandle, Emitter, EventTarget, Listener, Runtime, Webview},
+};
+
+struct Subscriber {
+  /// The events asked for by the stream, empty to follow the registered listeners.
+  events: Vec<String>,
+  stream: EventStream,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Bridge {
+  subscribers: Arc<Mutex<HashMap<String, Vec<Subscriber>>>>,
+  /// The listeners registered over HTTP, by webview label and id.
+  registrations: Arc<Mutex<HashMap<String, HashMap<u32, String>>>>,
+  next_id: Arc<AtomicU32>,
+  /// The webview labels and event names a listener was registered for.
+  listening: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+#[derive(Deserialize)]
+struct EventBody {
+  event: String,
+  #[serde(default)]
+  payload: JsonValue,
+  target: Option<EventTarget>,
+  id: Option<u32>,
+}
+
+impl Bridge {
+  /// Streams the `events` emitted to `webview` into `stream`.
+  pub(crate) fn subscribe<R: Runtime>(
+    &self,
+    webview: &Webview<R>,
+    events: Vec<String>,
+    stream: EventStream,
+  ) {
+    for event in &events {
+      self.listen(webview, event);
+    }
+    self
+      .subscribers
+      .locked()
+      .entry(webview.label().to_string())
+      .or_default()
+      .push(Subscriber { events, stream });
+  }
+
+  /// Registers the listener forwarding `event` to the streams of `webview`, unless it exists.
+  fn listen<R: Runtime>(&self, webview: &Webview<R>, event: &str) {
+    let label = webview.label().to_string();
+    if !self
+      .listening
+      .locked()
+      .insert((label.clone(), event.to_string()))
+    {
+      return;
+    }
+    let bridge = self.clone();
+    let name = event.to_string();
+    webview.listen(event, move |event| {
+      let data = format!(
+        "{{\"event\":{},\"payload\":{}}}",
+        JsonValue::from(name.as_str()),
+        event.payload()
+      );
+      bridge.broadcast(&label, &name, &data);
+    });
+  }
+
+  /// Sends `data` to the streams of `label` subscribed to `event`, dropping the closed ones.
+  fn broadcast(&self, label: &str, event: &str, data: &str) {
+    let registered = self
+      .registrations
+      .locked()
+      .get(label)
+      .map_or(false, |listeners| listeners.values().any(|e| e == event));
+    if let Some(streams) = self.subscribers.locked().get_mut(label) {
+      *streams = streams
+        .drain(..)
+        .filter_map(|mut s| {
+          let wanted = if s.events.is_empty() {
+            registered
+          } else {
+            s.events.iter().any(|e| e == event)
+          };
+          if wanted {
+            s.stream.send(data).ok()?;
+          }
+          Some(s)
+        })
+        .collect();
+    }
+  }
+
+  /// Answers `POST /{label}/events/{action}` with the status and JSON body of the response.
+  ///
+  /// Each action is checked against the allowed commands as the `plugin:event|{action}` command
+  /// it replaces.
+  pub(crate) fn handle<R: Runtime>(
+    &self,
+    app: &AppHandle<R>,
+    context: &Context,
+    label: &str,
+    action: &str,
+    body: &[u8],
+  ) -> (u16, String) {
+    if !["listen", "unlisten", "emit", "emit_to"].contains(&action) {
+      return (404, String::new());
+    }
+    let command = format!("plugin:event|{}", action);
+    if !context.is_allowed_command(label, &command) {
+      return (403, format!("command {} is not allowed", command));
+    }
+    let body: EventBody = match serde_json::from_slice(body) {
+      Ok(body) => body,
+      Err(e) => return (400, format!("malformed event request: {}", e)),
+    };
+    if !is_valid(&body.event) {
+      return (400, "invalid event name".into());
+    }
+    match action {
+      "listen" => {
+        let webview = match crate::find_webview(app, label) {
+          Some(webview) => webview,
+          None => return (404, "window not found".into()),
+        };
+        self.listen(&webview, &body.event);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self
+          .registrations
+          .locked()
+          .entry(label.to_string())
+          .or_default()
+          .insert(id, body.event);
+        (200, id.to_string())
+      }
+      "unlisten" => {
+        if let (Some(id), Some(listeners)) = (body.id, self.registrations.locked().get_mut(label)) {
+          listeners.remove(&id);
+        }
+        (200, "null".into())
+      }
+      _ => {
+        let emitted = match body.target {
+          Some(target) => app.emit_to(target, &body.event, body.payload),
+          None => app.emit(&body.event, body.payload),
+        };
+        match emitted {
+          Ok(()) => (200, "null".into()),
+          Err(e) => (500, JsonValue::from(e.to_string()).to_string()),
+        }
+      }
+    }
+  }
+
+  /// Closes every stream and forgets the registered listeners, e.g. when the server shuts
+  /// down.
+  pub(crate) fn close(&self) {
+    self.subscribers.locked().clear();
+    self.registrations.locked().clear();
+  }
+}
+
+/// Whether `event` is a valid Tauri event name.
+fn is_valid(event: &str) -> bool {
+  !event.is_empty()
+    && event
+      .chars()
+      .all(|c| c.is_alphanumeric() || c == '-' || c == '/' || c == ':' || c == '_')
+}
+
+/// The event names asked for in the `event` query parameters of `url`, or `None` if one isn't a
+/// valid Tauri event name.
+pub(crate) fn requested(url: &str) -> Option<Vec<String>> {
+  let query = url.split_once('?').map_or("", |(_, query)| query);
+  let mut events = Vec::new();
+  for param in query.split('&') {
+    if let Some(event) = param.strip_prefix("event=") {
+      let event = assets::decode(event)?;
+      if !is_valid(&event) {
+        return None;
+      }
+      if !events.contains(&event) {
+        events.push(event);
+      }
+    }
+  }
+  Some(events)
+}
@@ -0,0 +1,116 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Negotiation of the body encoding of invoke requests and responses.
+
+use {
+  crate::{cbor, msgpack},
+  serde_json::Value as JsonValue,
+};
+
+/// The response encoding chosen by [`negotiate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Negotiated {
+  /// Raw results are sent as `application/octet-stream` bytes.
+  Raw,
+  Format(WireFormat),
+  /// Nothing the `Accept` header allows can represent the result.
+  NotAcceptable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WireFormat {
+  Json,
+  MessagePack,
+  Cbor,
+}
+
+impl WireFormat {
+  /// Parses a `Content-Type`, ignoring its parameters.
+  pub(crate) fn from_content_type(content_type: &str) -> Option<Self> {
+    match content_type
+      .split(';')
+      .next()
+      .unwrap_or_default()
+      .trim()
+      .to_ascii_lowercase()
+      .as_str()
+    {
+      "application/json" => Some(Self::Json),
+      "application/msgpack" | "application/x-msgpack" => Some(Self::MessagePack),
+      "application/cbor" => Some(Self::Cbor),
+      _ => None,
+    }
+  }
+
+  pub(crate) fn content_type(self) -> &'static str {
+    match self {
+      Self::Json => "application/json",
+      Self::MessagePack => msgpack::CONTENT_TYPE,
+      Self::Cbor => cbor::CONTENT_TYPE,
+    }
+  }
+
+  pub(crate) fn encode(self, value: &JsonValue) -> Vec<u8> {
+    match self {
+      Self::Json => serde_json::to_vec(value).unwrap(),
+      Self::MessagePack => msgpack::encode(value),
+      Self::Cbor => cbor::encode(value),
+    }
+  }
+
+  pub(crate) fn decode(self, bytes: &[u8]) -> Result<JsonValue, String> {
+    match self {
+      Self::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+      Self::MessagePack => msgpack::decode(bytes),
+      Self::Cbor => cbor::decode(bytes),
+    }
+  }
+}
+
+/// Picks the encoding of a result from an `Accept` header, honoring its quality values.
+///
+/// A missing header sends raw results as bytes and everything else as JSON. Raw results sent
+/// to clients that don't accept bytes are encoded as an array of numbers.
+pub(crate) fn negotiate(accept: Option<&str>, raw: bool) -> Negotiated {
+  let accept = match accept {
+    Some(accept) => accept,
+    None if raw => return Negotiated::Raw,
+    None => return Negotiated::Format(WireFormat::Json),
+  };
+  let mut ranges = accept
+    .split(',')
+    .filter_map(|range| {
+      let mut params = range.split(';');
+      let media_type = params.next()?.trim().to_ascii_lowercase();
+      let quality = params
+        .filter_map(|p| p.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+      Some((media_type, quality))
+    })
+    .filter(|(_, quality)| *quality > 0.0)
+    .collect::<Vec<_>>();
+  // stable, so equally preferred types keep the order they were listed in
+  ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+  for (media_type, _) in ranges {
+    match media_type.as_str() {
+      "application/octet-stream" if raw => return Negotiated::Raw,
+      "*/*" | "application/*" => {
+        return if raw {
+          Negotiated::Raw
+        } else {
+          Negotiated::Format(WireFormat::Json)
+        }
+      }
+      media_type => {
+        if let Some(format) = WireFormat::from_content_type(media_type) {
+          return Negotiated::Format(format);
+        }
+      }
+    }
+  }
+  Negotiated::NotAcceptable
+}
@@ -0,0 +1,19 @@
+// Generated by tauri-invoke-http.
+
+export interface InstallOptions {
+  /** The app's invoke key, sent in `Tauri-Invoke-Key`. */
+  invokeKey: string
+  /** The session token or JWT the server requires, the one of the generating server by default. */
+  token?: string | null
+  /** The base URL of the server, the one of the generating server by default. */
+  baseUrl?: string
+  /** The label of the window the invokes are sent to, `main` by default. */
+  window?: string
+}
+
+/**
+ * Sets up `window.__TAURI_INTERNALS__` to send invokes to the tauri-invoke-http server.
+ *
+ * Does nothing if the invoke system is already set up, e.g. in a Tauri webview.
+ */
+export function install(options: InstallOptions): void
@@ -0,0 +1,54 @@
+// Generated by tauri-invoke-http.
+//
+// Sets up the invoke system of pages the app can't inject its initialization script into,
+// e.g. remote frontends, so `@tauri-apps/api` sends its invokes to the tauri-invoke-http server.
+
+export function install(options) {
+  if (window.__TAURI_INVOKE_HTTP__) {
+    // already set up, e.g. by the initialization script of a Tauri webview
+    return
+  }
+  const internals = (window.__TAURI_INTERNALS__ = window.__TAURI_INTERNALS__ || {})
+  if (!internals.metadata) {
+    const label = options.window || 'main'
+    internals.metadata = {
+      currentWindow: { label },
+      currentWebview: { label, windowLabel: label }
+    }
+  }
+  if (!internals.transformCallback) {
+    const callbacks = new Map()
+    internals.transformCallback = (callback, once = false) => {
+      const id = crypto.getRandomValues(new Uint32Array(1))[0]
+      callbacks.set(id, (data) => {
+        if (once) {
+          callbacks.delete(id)
+        }
+        return callback && callback(data)
+      })
+      return id
+    }
+    internals.unregisterCallback = (id) => callbacks.delete(id)
+    internals.runCallback = (id, data) => {
+      const callback = callbacks.get(id)
+      if (callback) {
+        callback(data)
+      }
+    }
+  }
+  if (!internals.invoke) {
+    internals.invoke = (cmd, payload = {}, options) => new Promise((resolve, reject) => {
+      const callback = internals.transformCallback((result) => {
+        internals.unregisterCallback(error)
+        resolve(result)
+      }, true)
+      const error = internals.transformCallback((e) => {
+        internals.unregisterCallback(callback)
+        reject(e)
+      }, true)
+      internals.postMessage({ cmd, callback, error, payload, options })
+    })
+  }
+  const __INVOKE_KEY__ = options.invokeKey
+  __INVOKE_HTTP_SCRIPT__
+}
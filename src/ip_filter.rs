@@ -0,0 +1,137 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Filtering of clients by address, applied before their requests are read.
+
+use {crate::Cidr, std::net::IpAddr};
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IpFilter {
+  pub(crate) allowed: Vec<Cidr>,
+  pub(crate) denied: Vec<Cidr>,
+}
+
+impl IpFilter {
+  /// Whether `peer` may connect. Unix domain sockets have no address and are always allowed.
+  pub(crate) fn allows(&self, peer: Option<IpAddr>) -> bool {
+    let peer = match peer {
+      Some(peer) => canonical(peer),
+      None => return true,
+    };
+    !self.denied.iter().any(|c| c.contains(peer))
+      && (self.allowed.is_empty() || self.allowed.iter().any(|c| c.contains(peer)))
+  }
+}
+
+/// IPv4 clients of dual-stack listeners show up as IPv4-mapped IPv6 addresses.
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+  match ip {
+    IpAddr::V6(v6) => match v6.segments() {
+      [0, 0, 0, 0, 0, 0xffff, ..] => v6.to_ipv4().map_or(ip, IpAddr::V4),
+      _ => ip,
+    },
+    ip => ip,
+  }
+}
+
+impl Cidr {
+  pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+    match (self.address, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        let mask = u32::MAX
+          .checked_shl(32 - u32::from(self.prefix))
+          .unwrap_or(0);
+        u32::from(net) & mask == u32::from(ip) & mask
+      }
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        let mask = u128::MAX
+          .checked_shl(128 - u32::from(self.prefix))
+          .unwrap_or(0);
+        u128::from(net) & mask == u128::from(ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::InvokeHttpError, std::net::Ipv4Addr};
+
+  fn cidr(block: &str) -> Cidr {
+    block.parse().unwrap()
+  }
+
+  fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+  }
+
+  #[test]
+  fn matches_prefixes() {
+    let lan = cidr("192.168.1.0/24");
+    assert!(lan.contains(ip("192.168.1.0")));
+    assert!(lan.contains(ip("192.168.1.255")));
+    assert!(!lan.contains(ip("192.168.2.1")));
+    assert!(!lan.contains(ip("::1")));
+    // the host bits of the block are ignored
+    assert!(cidr("192.168.1.77/24").contains(ip("192.168.1.3")));
+
+    let host = cidr("10.0.0.1/32");
+    assert_eq!(host, cidr("10.0.0.1"));
+    assert!(host.contains(ip("10.0.0.1")));
+    assert!(!host.contains(ip("10.0.0.2")));
+
+    let any = cidr("0.0.0.0/0");
+    assert!(any.contains(ip("255.255.255.255")));
+    assert!(any.contains(ip("0.0.0.0")));
+    assert!(!any.contains(ip("::")));
+    assert!(cidr("::/0").contains(ip("ffff::1")));
+
+    let single = cidr("fd00::1/128");
+    assert_eq!(single, cidr("fd00::1"));
+    assert!(single.contains(ip("fd00::1")));
+    assert!(!single.contains(ip("fd00::2")));
+    assert!(cidr("fd00::/8").contains(ip("fdab::1")));
+    assert!(!cidr("fd00::/8").contains(ip("fe80::1")));
+  }
+
+  #[test]
+  fn matches_ipv4_mapped_addresses_as_ipv4() {
+    let filter = IpFilter {
+      allowed: vec![cidr("192.168.1.0/24")],
+      denied: vec![cidr("::ffff:192.168.1.66")],
+    };
+    assert!(filter.allows(Some(ip("::ffff:192.168.1.10"))));
+    assert!(filter.allows(Some(ip("192.168.1.10"))));
+    assert!(!filter.allows(Some(ip("192.168.1.66"))));
+    assert!(!filter.allows(Some(ip("::ffff:192.168.1.66"))));
+    assert!(!filter.allows(Some(ip("::ffff:10.0.0.1"))));
+    assert_eq!(
+      cidr("::ffff:10.0.0.0/104"),
+      Cidr::new(Ipv4Addr::new(10, 0, 0, 0), 8)
+    );
+    // and Unix domain sockets are always allowed
+    assert!(filter.allows(None));
+  }
+
+  #[test]
+  fn rejects_malformed_blocks() {
+    for block in [
+      "10.0.0.0/33",
+      "::/129",
+      "/24",
+      "",
+      "10.0.0.0/",
+      "10.0.0.0/-1",
+      "10.0.0/8",
+      "localhost/8",
+    ] {
+      assert!(
+        matches!(block.parse::<Cidr>(), Err(InvokeHttpError::InvalidCidr(_))),
+        "{}",
+        block
+      );
+    }
+  }
+}
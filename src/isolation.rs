@@ -0,0 +1,96 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Opening of the payloads sealed by the frame of Tauri's isolation pattern.
+//!
+//! The isolation frame replaces each invoke payload with `{ nonce, payload, contentType }`, the
+//! payload encrypted with AES-256-GCM under the key of the app's pattern.
+
+use {
+  crate::encryption::Cipher, serde::Deserialize, serde_json::Value as JsonValue,
+  tauri::ipc::InvokeBody,
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SealedPayload {
+  nonce: Vec<u8>,
+  payload: Vec<u8>,
+  content_type: String,
+}
+
+/// Whether `payload` has the shape of a payload sealed by the isolation frame.
+fn is_sealed(payload: &JsonValue) -> bool {
+  payload.as_object().map_or(false, |fields| {
+    fields.len() == 3
+      && ["nonce", "payload", "contentType"]
+        .iter()
+        .all(|field| fields.contains_key(*field))
+  })
+}
+
+/// Returns the invoke body of `payload`, opening it with the isolation `key` if set.
+pub(crate) fn open(key: Option<&Cipher>, payload: JsonValue) -> Result<InvokeBody, String> {
+  let key = match key {
+    Some(key) => key,
+    None if is_sealed(&payload) => {
+      return Err(
+        "received a payload sealed by the isolation pattern, see `Invoke::with_isolation_key`"
+          .into(),
+      )
+    }
+    None => return Ok(InvokeBody::Json(payload)),
+  };
+  let sealed: SealedPayload = serde_json::from_value(payload)
+    .map_err(|_| "the invoke payload wasn't sealed by the isolation frame")?;
+  if sealed.nonce.len() != 12 {
+    return Err("invalid isolation payload nonce".into());
+  }
+  let mut bytes = sealed.nonce;
+  bytes.extend(sealed.payload);
+  let plaintext = key
+    .open(&bytes)
+    .ok_or("failed to decrypt the isolation payload")?;
+  if sealed.content_type == "application/octet-stream" {
+    Ok(InvokeBody::Raw(plaintext))
+  } else {
+    serde_json::from_slice(&plaintext)
+      .map(InvokeBody::Json)
+      .map_err(|e| e.to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, serde_json::json};
+
+  /// Seals `plaintext` the way the isolation frame does.
+  fn seal(key: &Cipher, plaintext: &[u8], content_type: &str) -> JsonValue {
+    let sealed = key.seal(plaintext);
+    let (nonce, payload) = sealed.split_at(12);
+    json!({ "nonce": nonce, "payload": payload, "contentType": content_type })
+  }
+
+  #[test]
+  fn opens_sealed_payloads() {
+    let key = Cipher::generate();
+    let payload = seal(&key, br#"{"name":"tests"}"#, "application/json");
+    match open(Some(&key), payload).unwrap() {
+      InvokeBody::Json(body) => assert_eq!(body, json!({ "name": "tests" })),
+      InvokeBody::Raw(_) => panic!("expected a JSON body"),
+    }
+    let payload = seal(&key, b"raw", "application/octet-stream");
+    match open(Some(&key), payload).unwrap() {
+      InvokeBody::Raw(body) => assert_eq!(body, b"raw"),
+      InvokeBody::Json(_) => panic!("expected a raw body"),
+    }
+  }
+
+  #[test]
+  fn rejects_payloads_sealed_with_another_key() {
+    let payload = seal(&Cipher::generate(), b"{}", "application/json");
+    assert!(open(Some(&Cipher::generate()), payload.clone()).is_err());
+    assert!(open(None, payload).is_err());
+  }
+}
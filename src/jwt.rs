@@ -0,0 +1,147 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Validation of the JSON Web Tokens configured by [`crate::JwtConfig`].
+//!
+//! Only the HMAC algorithms are supported, the crate doesn't carry the bignum and curve
+//! arithmetic the asymmetric ones need.
+
+use {
+  crate::JwtConfig,
+  base64::Engine,
+  hmac::{digest::KeyInit, Hmac, Mac},
+  serde_json::Value as JsonValue,
+  sha2::{Sha256, Sha384, Sha512},
+  std::time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Returns the claims of `token` if it is signed with the configured secret, currently valid and
+/// issued by and for the configured parties.
+pub(crate) fn validate(config: &JwtConfig, token: &str) -> Result<JsonValue, &'static str> {
+  let mut parts = token.split('.');
+  let (header, claims, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+    (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+    _ => return Err("malformed token"),
+  };
+  let header: JsonValue = decode(header)?;
+  let signing_input = &token[..token.len() - signature.len() - 1];
+  let verify = match header.get("alg").and_then(JsonValue::as_str) {
+    Some("HS256") => verify::<Hmac<Sha256>>,
+    Some("HS384") => verify::<Hmac<Sha384>>,
+    Some("HS512") => verify::<Hmac<Sha512>>,
+    _ => return Err("unsupported algorithm"),
+  };
+  let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+    .decode(signature)
+    .map_err(|_| "malformed signature")?;
+  if !verify(&config.secret, signing_input.as_bytes(), &signature) {
+    return Err("invalid signature");
+  }
+
+  let claims: JsonValue = decode(claims)?;
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs_f64();
+  let leeway = config.leeway.as_secs_f64();
+  let time = |name| claims.get(name).and_then(JsonValue::as_f64);
+  if time("exp").map_or(false, |exp| now >= exp + leeway) {
+    return Err("token expired");
+  }
+  if time("nbf").map_or(false, |nbf| now + leeway < nbf) {
+    return Err("token not valid yet");
+  }
+  if let Some(issuer) = &config.issuer {
+    if claims.get("iss").and_then(JsonValue::as_str) != Some(issuer.as_str()) {
+      return Err("invalid issuer");
+    }
+  }
+  if let Some(audience) = &config.audience {
+    let matches = match claims.get("aud") {
+      Some(JsonValue::String(aud)) => aud == audience,
+      Some(JsonValue::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+      _ => false,
+    };
+    if !matches {
+      return Err("invalid audience");
+    }
+  }
+  Ok(claims)
+}
+
+/// Decodes a base64url encoded JSON part of a token.
+fn decode(part: &str) -> Result<JsonValue, &'static str> {
+  let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+    .decode(part)
+    .map_err(|_| "malformed token")?;
+  serde_json::from_slice(&bytes).map_err(|_| "malformed token")
+}
+
+/// Whether `signature` is the MAC of `data` under `key`, compared in constant time so it can't be
+/// guessed a byte at a time.
+fn verify<M: Mac + KeyInit>(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+  let mut mac = <M as KeyInit>::new_from_slice(key).expect("HMAC takes keys of any size");
+  mac.update(data);
+  mac.verify_slice(signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, std::time::Duration};
+
+  // the HS256 example of RFC 7515, appendix A.1
+  const KEY: &str =
+    "AyM1SysPpbyDfgZld3umj1qzKObwVMkoqQ-EstJQLr_T-1qS0gZH75aKtMN3Yj0iPS4hcgUuTwjAzZr1Z9CAow";
+  const TOKEN: &str = "eyJ0eXAiOiJKV1QiLA0KICJhbGciOiJIUzI1NiJ9.\
+    eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ.\
+    dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+  fn config() -> JwtConfig {
+    let secret = base64::engine::general_purpose::URL_SAFE_NO_PAD
+      .decode(KEY)
+      .unwrap();
+    JwtConfig {
+      issuer: Some("joe".into()),
+      // the example expired in 2011
+      leeway: Duration::from_secs(u32::MAX.into()),
+      ..JwtConfig::new(secret)
+    }
+  }
+
+  #[test]
+  fn accepts_the_example_token() {
+    let claims = validate(&config(), TOKEN).unwrap();
+    assert_eq!(claims["http://example.com/is_root"], true);
+  }
+
+  #[test]
+  fn rejects_tampered_tokens() {
+    let (input, signature) = TOKEN.rsplit_once('.').unwrap();
+    let tampered = format!("{}.{}", input, signature.replacen('d', "e", 1));
+    assert_eq!(validate(&config(), &tampered), Err("invalid signature"));
+    let (header, _) = input.split_once('.').unwrap();
+    // `{"iss":"eve"}`
+    let claims = format!("{}.eyJpc3MiOiJldmUifQ.{}", header, signature);
+    assert_eq!(validate(&config(), &claims), Err("invalid signature"));
+    let other = JwtConfig {
+      secret: b"another secret".to_vec(),
+      ..config()
+    };
+    assert_eq!(validate(&other, TOKEN), Err("invalid signature"));
+  }
+
+  #[test]
+  fn checks_the_claims() {
+    let expired = JwtConfig {
+      leeway: Duration::ZERO,
+      ..config()
+    };
+    assert_eq!(validate(&expired, TOKEN), Err("token expired"));
+    let other_issuer = JwtConfig {
+      issuer: Some("eve".into()),
+      ..config()
+    };
+    assert_eq!(validate(&other_issuer, TOKEN), Err("invalid issuer"));
+  }
+}
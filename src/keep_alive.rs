@@ -0,0 +1,106 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Enforcement of [`crate::KeepAlive`], which tiny_http doesn't expose any settings for.
+
+use {
+  crate::{response_head, KeepAlive, LockExt},
+  std::{
+    collections::HashMap,
+    io::{Read, Result as IoResult, Write},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+  },
+  tiny_http::{Header, Request, Response},
+};
+
+/// Connections idle for longer than this are assumed closed when no timeout is configured.
+const DEFAULT_IDLE: Duration = Duration::from_secs(60);
+
+/// Counts the responses sent on each connection.
+#[derive(Clone)]
+pub(crate) struct Tracker {
+  config: KeepAlive,
+  connections: Arc<Mutex<HashMap<SocketAddr, (usize, Instant)>>>,
+}
+
+impl Tracker {
+  pub(crate) fn new(config: KeepAlive) -> Self {
+    Self {
+      config,
+      connections: Default::default(),
+    }
+  }
+
+  /// Counts a response on the connection of `request`, returning how many more it may carry.
+  ///
+  /// Connections without a peer address (Unix sockets) are only limited by a maximum of one.
+  pub(crate) fn remaining(&self, request: &Request) -> Option<usize> {
+    let max = self.config.max_requests?;
+    let addr = match request.remote_addr() {
+      Some(addr) if max > 1 => *addr,
+      _ => return Some(0).filter(|_| max <= 1),
+    };
+    let idle = self.config.timeout.unwrap_or(DEFAULT_IDLE);
+    let mut connections = self.connections.locked();
+    connections.retain(|_, (_, last)| last.elapsed() < idle);
+    let (count, last) = connections.entry(addr).or_insert((0, Instant::now()));
+    *count += 1;
+    *last = Instant::now();
+    let remaining = max.saturating_sub(*count);
+    if remaining == 0 {
+      connections.remove(&addr);
+    }
+    Some(remaining)
+  }
+
+  #[cfg(feature = "async-server")]
+  pub(crate) fn config(&self) -> &KeepAlive {
+    &self.config
+  }
+
+  /// The `Keep-Alive` header advertising the limits to the client.
+  pub(crate) fn header(&self, remaining: Option<usize>) -> Option<Header> {
+    self
+      .value(remaining)
+      .and_then(|value| Header::from_str(&format!("Keep-Alive: {}", value)).ok())
+  }
+
+  /// The value of the `Keep-Alive` header, if there is anything to advertise.
+  pub(crate) fn value(&self, remaining: Option<usize>) -> Option<String> {
+    let mut params = Vec::new();
+    if let Some(timeout) = self.config.timeout {
+      params.push(format!("timeout={}", timeout.as_secs()));
+    }
+    if let Some(remaining) = remaining {
+      params.push(format!("max={}", remaining));
+    }
+    if params.is_empty() {
+      None
+    } else {
+      Some(params.join(", "))
+    }
+  }
+}
+
+/// Sends `response` with `body` and `Connection: close`, so the client drops the connection.
+///
+/// tiny_http ignores `Connection` headers set on a response, so it is written by hand.
+pub(crate) fn respond_and_close<R: Read>(
+  request: Request,
+  response: Response<R>,
+  body: &[u8],
+) -> IoResult<()> {
+  let mut head = response_head(&response);
+  head.push_str(&format!(
+    "Content-Length: {}\r\nConnection: close\r\n\r\n",
+    body.len()
+  ));
+  let mut writer = request.into_writer();
+  writer.write_all(head.as_bytes())?;
+  writer.write_all(body)?;
+  writer.flush()
+}
@@ -2,200 +2,5115 @@
 // SPDX-License-Identifier: Apache-2.0
 // SPDX-License-Identifier: MIT
 
+mod assets;
+#[cfg(feature = "async-server")]
+mod async_server;
+mod audit;
+mod builder;
+mod cancel;
+mod cbor;
+mod client;
+mod csrf;
+mod encryption;
+mod error;
+mod events;
+mod format;
+mod ip_filter;
+mod isolation;
+mod jwt;
+mod keep_alive;
+mod metrics;
+mod msgpack;
+mod multipart;
+mod plugin;
+mod poll;
+mod rate_limit;
+mod reload;
+mod replay;
+mod session;
+mod signing;
+mod sse;
+mod trace;
+#[cfg(feature = "typescript")]
+mod typescript;
+mod ws;
+
 use {
+  base64::Engine,
+  format::{Negotiated, WireFormat},
   serde::Deserialize,
   serde_json::Value as JsonValue,
   std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{
+      atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering},
+      Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime},
   },
+  subtle::ConstantTimeEq,
   tauri::{
-    ipc::{CallbackFn, InvokeBody, InvokeResponder, InvokeResponse},
+    ipc::{
+      CallbackFn, InvokeBody, InvokeError, InvokeResponder, InvokeResponse, InvokeResponseBody,
+    },
     webview::InvokeRequest,
-    AppHandle, Manager, Runtime, Url,
+    AppHandle, Emitter, Manager, Runtime, Url, Webview,
   },
   tiny_http::{Header, Method, Request, Response},
 };
+
+pub use {
+  builder::InvokeBuilder,
+  client::{InvokeClientError, InvokeHttpClient},
+  error::InvokeHttpError,
+  plugin::{init, BuilderExt},
+};
+
 #[derive(Debug, Deserialize)]
 pub struct RecievedMessage {
   pub cmd: String,
   pub callback: CallbackFn,
   pub error: CallbackFn,
   pub payload: JsonValue,
+  /// The app's invoke key, sent in the message where headers aren't available.
+  #[serde(default, rename = "__TAURI_INVOKE_KEY__")]
+  pub invoke_key: Option<String>,
 }
-fn cors<R: std::io::Read>(request: &Request, r: &mut Response<R>, allowed_origins: &[String]) {
-  if allowed_origins.iter().any(|s| s == "*") {
-    r.add_header(Header::from_str("Access-Control-Allow-Origin: *").unwrap());
-  } else if let Some(origin) = request.headers().iter().find(|h| h.field.equiv("Origin")) {
-    if allowed_origins.iter().any(|o| o == &origin.value) {
-      r.add_header(
-        Header::from_str(&format!("Access-Control-Allow-Origin: {}", origin.value)).unwrap(),
-      );
+
+/// The header carrying the app's invoke key, which Tauri checks on every invoke.
+pub(crate) const INVOKE_KEY_HEADER: &str = "Tauri-Invoke-Key";
+
+/// The command fetching the data of [`tauri::ipc::Channel`] messages, whichever commands are
+/// allowed.
+const FETCH_CHANNEL_DATA_COMMAND: &str = "plugin:__TAURI_CHANNEL__|fetch";
+
+/// The methods the invoke endpoints answer, sent in the `Allow` header of `405` responses.
+pub(crate) const INVOKE_METHODS: &str = "POST, OPTIONS";
+
+/// The header marking the responses carrying an error returned by a command.
+pub(crate) const INVOKE_ERROR_HEADER: &str = "Tauri-Invoke-Error";
+
+fn header(request: &Request, name: &'static str) -> Option<String> {
+  request
+    .headers()
+    .iter()
+    .find(|h| h.field.equiv(name))
+    .map(|h| h.value.to_string())
+}
+
+/// How the frontend delivers invoke messages to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+  /// One `POST` request per invoke.
+  Http,
+  /// A persistent WebSocket connection per window, served on a separate port.
+  WebSocket,
+}
+
+impl Default for Transport {
+  fn default() -> Self {
+    Self::Http
+  }
+}
+
+/// Body encoding used by the generated initialization script.
+///
+/// The server accepts every encoding regardless of this setting; requests pick theirs with the
+/// `Content-Type` header and responses follow the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+  Json,
+  /// MessagePack, which is cheaper to decode than JSON for large payloads.
+  MessagePack,
+}
+
+impl Default for Encoding {
+  fn default() -> Self {
+    Self::Json
+  }
+}
+
+/// How channel messages reach the frontend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelDelivery {
+  /// A server-sent events stream, falling back to long-polling when the webview can't open it.
+  EventStream,
+  /// Long-polling only, for webviews that accept the stream but never deliver its events.
+  LongPolling,
+}
+
+impl Default for ChannelDelivery {
+  fn default() -> Self {
+    Self::EventStream
+  }
+}
+
+/// How the errors returned by commands are sent.
+///
+/// Error responses carry the `Tauri-Invoke-Error: true` header either way, so clients can tell
+/// them from successful results that look alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+  /// The error value as the command returned it.
+  Plain,
+  /// `{"error": <value>, "code": <code>}`, where the code is `not_allowed` for invokes denied
+  /// by the capabilities and `command_failed` for errors returned by the command. The
+  /// initialization script unwraps the error before rejecting the invoke.
+  Envelope,
+}
+
+impl Default for ErrorFormat {
+  fn default() -> Self {
+    Self::Plain
+  }
+}
+
+/// What to do with invokes that don't send an `Origin` header, as non-browser clients such as
+/// tests, `curl` or native companions usually don't.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MissingOrigin {
+  /// Answer them with `400 Bad Request`.
+  Reject,
+  /// Treat them as sent from this URL, e.g. `tauri://localhost`. It must be one of the allowed
+  /// origins for the invokes to be accepted and is the URL the commands see.
+  Fallback(String),
+}
+
+impl Default for MissingOrigin {
+  fn default() -> Self {
+    Self::Reject
+  }
+}
+
+/// Connection reuse settings for the HTTP listener.
+///
+/// The limits apply to invoke responses; connections are closed by answering with
+/// `Connection: close`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeepAlive {
+  /// Idle time advertised to clients with the `Keep-Alive` header.
+  ///
+  /// tiny_http can't time out idle connections itself, so this relies on the client.
+  pub timeout: Option<Duration>,
+  /// Number of responses after which a connection is closed.
+  pub max_requests: Option<usize>,
+}
+
+impl KeepAlive {
+  /// Closes every connection after its first response.
+  pub fn disabled() -> Self {
+    Self {
+      timeout: None,
+      max_requests: Some(1),
     }
   }
-  r.add_header(Header::from_str("Access-Control-Allow-Headers: *").unwrap());
-  r.add_header(Header::from_str("Access-Control-Allow-Methods: POST, OPTIONS").unwrap());
 }
 
-pub struct Invoke {
-  allowed_origins: Vec<String>,
-  port: u16,
-  requests: Arc<Mutex<HashMap<u32, Request>>>,
+/// Caps on the load the server accepts before answering `429 Too Many Requests`.
+///
+/// A cap of `n` lets `n` connections or invokes through at once, and the request that would be
+/// one more is refused. Connections are counted per listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+  /// Number of open connections, including idle keep-alive ones. tiny_http doesn't report its
+  /// connections, so the default server counts those with a request in progress, including
+  /// invokes waiting for their response.
+  pub max_connections: Option<usize>,
+  /// Number of invokes dispatched and waiting for their response.
+  pub max_pending: Option<usize>,
+  /// Delay sent in the `Retry-After` header of rejected requests.
+  pub retry_after: Duration,
+  /// Size of JSON, MessagePack and CBOR message bodies, answered with
+  /// `413 Payload Too Large` beyond it.
+  pub max_message_size: Option<usize>,
+  /// Size of raw and multipart bodies, after undoing their `Content-Encoding`.
+  pub max_raw_size: Option<usize>,
 }
 
-impl Invoke {
-  pub fn new<I: Into<String>, O: IntoIterator<Item = I>>(allowed_origins: O) -> Self {
-    let port = portpicker::pick_unused_port().expect("failed to get unused port for invoke");
-    let requests = Arc::new(Mutex::new(HashMap::new()));
+impl Default for Limits {
+  fn default() -> Self {
     Self {
-      allowed_origins: allowed_origins.into_iter().map(|o| o.into()).collect(),
-      port,
-      requests,
+      max_connections: None,
+      max_pending: None,
+      retry_after: Duration::from_secs(1),
+      max_message_size: None,
+      max_raw_size: None,
     }
   }
+}
 
-  pub fn start<R: Runtime>(&self, app: AppHandle<R>) {
-    let server = tiny_http::Server::http(format!("localhost:{}", self.port)).unwrap();
-    let requests = self.requests.clone();
-    let allowed_origins = self.allowed_origins.clone();
-    std::thread::spawn(move || {
-      for mut request in server.incoming_requests() {
-        let requests = requests.clone();
-        let allowed_origins = allowed_origins.clone();
-        if request.method() == &Method::Options {
-          let mut r = Response::empty(200u16);
-          cors(&request, &mut r, &allowed_origins);
-          request.respond(r).unwrap();
-          continue;
-        }
-        let url = request.url().to_string();
-        let pieces = url.split('/').collect::<Vec<_>>();
-        let window_label = pieces[1];
-
-        if let Some(window) = app.get_webview_window(window_label) {
-          let content_type = request
-            .headers()
-            .iter()
-            .find(|h| h.field.equiv("Content-Type"))
-            .map(|h| h.value.to_string())
-            .unwrap_or_else(|| "application/json".into());
-
-          let payload: InvokeRequest = if content_type == "application/json" {
-            let mut content = String::new();
-            request.as_reader().read_to_string(&mut content).unwrap();
-            let origin = request
-              .headers()
-              .iter()
-              .find(|h| h.field.equiv("Origin"))
-              .map(|h| h.value.to_string())
-              .expect("Invalid IPC request - No Origin");
-            let message: RecievedMessage = serde_json::from_str(&content).unwrap();
-            InvokeRequest {
-              cmd: message.cmd,
-              callback: message.callback,
-              error: message.error,
-              url: Url::parse(&origin).expect("invalid IPC request URL"),
-              body: InvokeBody::Json(message.payload),
-              headers: (&request
-                .headers()
-                .iter()
-                .map(|h| (h.field.to_string(), h.value.to_string()))
-                .collect::<HashMap<_, _>>())
-                .try_into()
-                .unwrap_or_default(),
-              invoke_key: format!("FIXME: {}:{}:", file!(), line!()), //FIXME
-            }
-          } else {
-            unimplemented!()
-          };
-          let req_key = payload.callback.0;
-          requests.lock().unwrap().insert(req_key, request);
-          window.on_message(
-            payload,
-            Box::new(move |_webview, _cmd, response, callback, _error| {
-              let request = requests.lock().unwrap().remove(&callback.0).unwrap();
-              let response = match response {
-                InvokeResponse::Ok(r) => Ok(r),
-                InvokeResponse::Err(e) => Err(e),
-              };
-              let status: u16 = if response.is_ok() { 200 } else { 400 };
-
-              let mut r = match response {
-                Ok(tauri::ipc::InvokeBody::Json(r)) => {
-                  Response::from_string(serde_json::to_string(&r).unwrap())
-                }
-                Ok(tauri::ipc::InvokeBody::Raw(r)) => Response::from_data(r),
-                Err(tauri::ipc::InvokeError(e)) => {
-                  Response::from_string(serde_json::to_string(&e).unwrap())
-                }
-              }
-              .with_status_code(status);
-              cors(&request, &mut r, &allowed_origins);
+/// Connections holding a thread of their own served at once when [`Limits::max_connections`]
+/// isn't set.
+pub(crate) const DEFAULT_MAX_CONNECTIONS: usize = 256;
 
-              request.respond(r).unwrap();
-            }),
-          );
-        } else {
-          let mut r = Response::empty(404u16);
-          cors(&request, &mut r, &allowed_origins);
-          request.respond(r).unwrap();
-        }
+impl Limits {
+  /// Whether a request can't be served next to the `connections` other ones of its listener.
+  pub(crate) fn too_many_connections(&self, connections: usize) -> bool {
+    self.max_connections.map_or(false, |max| connections >= max)
+  }
+
+  /// Whether a connection needing a thread of its own can't be served next to the `connections`
+  /// other ones, at most [`DEFAULT_MAX_CONNECTIONS`] without [`Self::max_connections`].
+  pub(crate) fn too_many_threads(&self, connections: usize) -> bool {
+    connections >= self.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS)
+  }
+
+  /// Whether an invoke can't be dispatched next to the `pending` ones.
+  pub(crate) fn too_many_pending(&self, pending: usize) -> bool {
+    self.max_pending.map_or(false, |max| pending >= max)
+  }
+
+  pub(crate) fn body_limit(&self, kind: &BodyKind) -> Option<usize> {
+    match kind {
+      BodyKind::Message(_) => self.max_message_size,
+      BodyKind::Multipart(_) | BodyKind::Raw => self.max_raw_size,
+    }
+  }
+
+  /// The `Retry-After` value, in whole seconds.
+  pub(crate) fn retry_after_secs(&self) -> String {
+    self.retry_after.as_secs().max(1).to_string()
+  }
+}
+
+/// How the initialization script retries requests that can't reach the server, e.g. while it
+/// is still binding its port. Requests answered by the server are never retried.
+///
+/// The delay doubles after each attempt up to `max_delay`, and a random part of it is skipped
+/// so pages don't retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientRetry {
+  /// Number of retries after the first attempt.
+  pub retries: u32,
+  /// Delay before the first retry.
+  pub initial_delay: Duration,
+  pub max_delay: Duration,
+}
+
+impl Default for ClientRetry {
+  fn default() -> Self {
+    Self {
+      retries: 3,
+      initial_delay: Duration::from_millis(100),
+      max_delay: Duration::from_secs(2),
+    }
+  }
+}
+
+/// Limits on how long a client may take to send a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadTimeouts {
+  /// Time allowed to send the request line and headers, including the wait for the next
+  /// request on a kept-alive connection.
+  ///
+  /// Only enforced by the `async-server` feature, tiny_http doesn't expose its connections.
+  pub header: Option<Duration>,
+  /// Time allowed to send the whole body of an invoke, past which it is answered with
+  /// `408 Request Timeout`.
+  pub body: Option<Duration>,
+}
+
+/// What a [`RateLimit`] counts requests by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKey {
+  /// The origin of the page sending the invokes.
+  Origin,
+  /// The address of the client, forwarded by a trusted proxy if any.
+  ClientIp,
+}
+
+/// Limits how many invokes each origin or client may send, so a buggy render loop can't starve
+/// the rest of the app. The excess is answered with `429 Too Many Requests`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+  pub key: RateLimitKey,
+  /// How many invokes may be sent at once and per `per`, refilled continuously.
+  pub requests: u32,
+  pub per: Duration,
+}
+
+/// Settings for the invokes sent to one window, see [`Invoke::with_window_config`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowConfig {
+  /// The commands the window may invoke instead of those of [`Invoke::with_allowed_commands`],
+  /// `None` to keep them.
+  pub allowed_commands: Option<Vec<String>>,
+  /// Commands refused to the window on top of those of [`Invoke::with_denied_commands`].
+  pub denied_commands: Vec<String>,
+  /// The rate limit of the window instead of the one of [`Invoke::with_rate_limit`], counted
+  /// separately from the other windows.
+  pub rate_limit: Option<RateLimit>,
+}
+
+/// A [`WindowConfig`] with its rate limit buckets.
+#[derive(Clone)]
+pub(crate) struct WindowPolicy {
+  config: WindowConfig,
+  rate_limiter: Option<rate_limit::Limiter>,
+}
+
+/// The CORS headers sent to frontends on other origins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsConfig {
+  /// The request headers frontends may send, `None` to allow any.
+  pub allowed_headers: Option<Vec<String>>,
+  /// The methods allowed by preflights, `POST` and `OPTIONS` by default.
+  pub allowed_methods: Vec<String>,
+  /// The response headers scripts may read besides the safelisted ones.
+  pub exposed_headers: Vec<String>,
+  /// Allows requests with credentials, e.g. cookies set by a reverse proxy.
+  ///
+  /// Browsers refuse `*` in that case, so the origin is echoed for a wildcard allowed origin and
+  /// the requested headers for `allowed_headers: None`.
+  pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+  fn default() -> Self {
+    Self {
+      allowed_headers: None,
+      allowed_methods: vec!["POST".into(), "OPTIONS".into()],
+      exposed_headers: Vec::new(),
+      allow_credentials: false,
+    }
+  }
+}
+
+impl CorsConfig {
+  /// Fails with [`InvokeHttpError::InvalidCors`] on a header name or method that can't be sent
+  /// in the CORS response headers.
+  pub(crate) fn validate(&self) -> Result<(), InvokeHttpError> {
+    let headers = self
+      .allowed_headers
+      .iter()
+      .flatten()
+      .chain(&self.exposed_headers);
+    for name in headers {
+      tauri::http::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| InvokeHttpError::InvalidCors(name.clone()))?;
+    }
+    for method in &self.allowed_methods {
+      tauri::http::Method::from_bytes(method.as_bytes())
+        .map_err(|_| InvokeHttpError::InvalidCors(method.clone()))?;
+    }
+    Ok(())
+  }
+}
+
+/// Options of the listening TCP sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+  /// Disables Nagle's algorithm, which can delay small responses.
+  ///
+  /// tiny_http relies on the accepted connections inheriting it from the listener, as they do
+  /// on most platforms.
+  pub nodelay: bool,
+  /// Allows binding the port while connections of a previous run linger in `TIME_WAIT`.
+  ///
+  /// Enabled by default on Unix only, on Windows it would let other processes share the port.
+  pub reuse_address: bool,
+  /// How many connections may wait to be accepted, 128 by default.
+  pub backlog: i32,
+}
+
+impl Default for SocketOptions {
+  fn default() -> Self {
+    Self {
+      nodelay: false,
+      reuse_address: cfg!(unix),
+      backlog: 128,
+    }
+  }
+}
+
+/// An address served next to the main listener, with its own access policy, e.g. a LAN address
+/// for a companion UI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraListener {
+  pub address: SocketAddr,
+  /// The origins allowed to invoke through this listener.
+  pub allowed_origins: Vec<String>,
+  /// When set, requests must send `Authorization: Bearer {token}` or get `401 Unauthorized`.
+  pub token: Option<String>,
+}
+
+/// A block of IP addresses, e.g. `192.168.1.0/24`, for [`Invoke::with_allowed_ips`] and
+/// [`Invoke::with_denied_ips`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+  address: IpAddr,
+  prefix: u8,
+}
+
+impl Cidr {
+  /// The addresses sharing the first `prefix` bits with `address`.
+  pub fn new(address: impl Into<IpAddr>, prefix: u8) -> Self {
+    let address = address.into();
+    // IPv4 clients of dual-stack listeners are matched as IPv4, so mapped blocks are too
+    let (address, prefix) = match ip_filter::canonical(address) {
+      IpAddr::V4(v4) if address.is_ipv6() && prefix >= 96 => (IpAddr::V4(v4), prefix - 96),
+      _ => (address, prefix),
+    };
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+    Self {
+      address,
+      prefix: prefix.min(bits),
+    }
+  }
+}
+
+/// Parses a block such as `192.168.1.0/24` or `fd00::/8`, or a single address such as `::1`.
+impl FromStr for Cidr {
+  type Err = InvokeHttpError;
+
+  fn from_str(block: &str) -> Result<Self, Self::Err> {
+    let invalid = || InvokeHttpError::InvalidCidr(block.to_string());
+    let (address, prefix) = match block.split_once('/') {
+      Some((address, prefix)) => (address, Some(prefix)),
+      None => (block, None),
+    };
+    let address: IpAddr = address.parse().map_err(|_| invalid())?;
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix
+        .parse()
+        .ok()
+        .filter(|&prefix| prefix <= bits)
+        .ok_or_else(invalid)?,
+      None => bits,
+    };
+    Ok(Self::new(address, prefix))
+  }
+}
+
+impl From<IpAddr> for Cidr {
+  fn from(address: IpAddr) -> Self {
+    Self::new(address, 128)
+  }
+}
+
+impl From<Ipv4Addr> for Cidr {
+  fn from(address: Ipv4Addr) -> Self {
+    Self::new(address, 32)
+  }
+}
+
+impl From<Ipv6Addr> for Cidr {
+  fn from(address: Ipv6Addr) -> Self {
+    Self::new(address, 128)
+  }
+}
+
+/// Where [`Invoke::with_assets`] serves the frontend from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assets {
+  /// The frontend embedded in the app, resolved as for its windows.
+  Embedded,
+  /// A directory, such as the built `dist` folder.
+  Dir(PathBuf),
+}
+
+/// The `Authorization: Bearer` JSON Web Tokens accepted by [`Invoke::with_jwt`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JwtConfig {
+  /// The secret the tokens are signed with using HS256, HS384 or HS512. Asymmetric algorithms
+  /// aren't supported.
+  pub secret: Vec<u8>,
+  /// The required `iss` claim, if any.
+  pub issuer: Option<String>,
+  /// The audience the `aud` claim must contain, if any.
+  pub audience: Option<String>,
+  /// Clock skew tolerated when checking the `exp` and `nbf` claims.
+  pub leeway: Duration,
+}
+
+impl JwtConfig {
+  /// Accepts tokens signed with `secret`, from any issuer and for any audience.
+  pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+    Self {
+      secret: secret.into(),
+      issuer: None,
+      audience: None,
+      leeway: Duration::from_secs(60),
+    }
+  }
+}
+
+/// An invoke recorded by an [`AuditLog`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+  /// When the invoke was received.
+  pub timestamp: SystemTime,
+  pub origin: Option<String>,
+  /// The label of the window the invoke was sent to.
+  pub window: String,
+  /// The invoked command, `None` if the invoke was refused before it was read.
+  pub cmd: Option<String>,
+  /// The HTTP status the invoke was answered with. Invokes over the WebSocket transport get the
+  /// status an HTTP invoke would have.
+  pub status: u16,
+  /// Time from receiving the invoke to answering it.
+  pub latency: Duration,
+  /// The JSON payload of the invoke, if [`AuditLog::payloads`] is set.
+  pub payload: Option<JsonValue>,
+}
+
+/// Counters of the invokes served since the server was created, returned by
+/// [`Invoke::metrics`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+  /// The invokes received, including the refused ones.
+  pub requests: u64,
+  /// The number of invokes answered with each HTTP status.
+  pub responses: BTreeMap<u16, u64>,
+  /// The invokes dispatched and waiting for their response.
+  pub in_flight: usize,
+  /// The requests refused because their origin isn't allowed.
+  pub rejected_origins: u64,
+  /// The mean time from receiving an invoke to answering it, zero until one is answered.
+  pub average_latency: Duration,
+}
+
+/// Where an [`AuditLog`] writes its records.
+#[derive(Clone)]
+pub enum AuditSink {
+  /// Appends each record to the file as a line of JSON, with the timestamp in milliseconds
+  /// since the Unix epoch and the latency in `latencyMs`.
+  File(PathBuf),
+  /// Hands each record to the callback, on the thread that answered the invoke.
+  Callback(Arc<dyn Fn(&AuditRecord) + Send + Sync>),
+}
+
+/// Records every invoke, with its origin, window, command, status and latency, for
+/// [`Invoke::with_audit_log`].
+#[derive(Clone)]
+pub struct AuditLog {
+  pub sink: AuditSink,
+  /// Records the JSON payloads of the invokes too. Raw and multipart bodies are never recorded.
+  pub payloads: bool,
+  /// The payload fields whose values are recorded as `"[redacted]"`, at any depth, where `*`
+  /// stands for any run of characters, e.g. `password` or `*token`.
+  pub redact: Vec<String>,
+}
+
+impl AuditLog {
+  /// Records invokes to `sink`, without their payloads.
+  pub fn new(sink: AuditSink) -> Self {
+    Self {
+      sink,
+      payloads: false,
+      redact: Vec::new(),
+    }
+  }
+}
+
+/// Where the invoke server listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listener {
+  /// A TCP port, on `localhost` unless [`Invoke::with_bind_address`] is set.
+  Tcp,
+  /// A Unix domain socket at `path`, so no port is exposed to other users on the machine.
+  ///
+  /// Webviews can't connect to the socket directly, so the initialization script sends invokes
+  /// to `proxy_url`, e.g. a local reverse proxy forwarding to the socket.
+  /// TLS and the WebSocket transport are not available on this listener.
+  #[cfg(unix)]
+  Unix {
+    path: std::path::PathBuf,
+    proxy_url: String,
+  },
+  /// The already bound sockets passed with `LISTEN_FDS`, e.g. by systemd socket activation, so
+  /// privileged ports can be used without running the app as root.
+  ///
+  /// The port is taken from the first TCP socket. The sockets are handed over once, so the
+  /// server can't be restarted on them.
+  #[cfg(unix)]
+  Activated,
+}
+
+/// PEM encoded certificate chain and private key used to serve the invoke endpoint over HTTPS.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+  pub certificate: Vec<u8>,
+  pub private_key: Vec<u8>,
+}
+
+#[cfg(feature = "self-signed")]
+impl TlsConfig {
+  /// Generates an ephemeral self-signed certificate valid for `localhost`, `127.0.0.1` and `::1`.
+  ///
+  /// Use [`Invoke::certificate_der`] to install it into the webview trust store.
+  pub fn self_signed() -> Result<Self, InvokeHttpError> {
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec![
+      "localhost".to_string(),
+      "127.0.0.1".to_string(),
+      "::1".to_string(),
+    ])
+    .map_err(|e| InvokeHttpError::Tls(e.to_string()))?;
+    Ok(Self {
+      certificate: cert.pem().into_bytes(),
+      private_key: key_pair.serialize_pem().into_bytes(),
+    })
+  }
+}
+
+pub(crate) fn is_allowed_origin(origin: &str, allowed_origins: &[String]) -> bool {
+  allowed_origins
+    .iter()
+    .any(|o| o == "*" || matches_origin(o, origin))
+}
+
+/// The origin whose capabilities apply to invokes from `origin`, see [`Invoke::with_acl_origin`].
+pub(crate) fn acl_origin(origin: String, acl_origins: &[(String, String)]) -> String {
+  acl_origins
+    .iter()
+    .find(|(pattern, _)| matches_origin(pattern, &origin))
+    .map_or(origin, |(_, mapped)| mapped.clone())
+}
+
+/// Whether `response` is Tauri's rejection by the capabilities of the invoke of `cmd` from `url`
+/// to `webview`, rather than an error returned by the command itself.
+///
+/// Tauri rejects with a bare message, so this rebuilds the messages it writes for the labels and
+/// URL of the invoke. Only debug builds, as this crate is built with the same profile, describe
+/// why the command was denied.
+pub(crate) fn is_acl_denial<R: Runtime>(
+  webview: &Webview<R>,
+  cmd: &str,
+  url: &str,
+  response: &InvokeResponse,
+) -> bool {
+  let error = match response {
+    InvokeResponse::Err(InvokeError(JsonValue::String(error))) => error,
+    _ => return false,
+  };
+  if cfg!(not(debug_assertions)) {
+    return *error == format!("Command {} not allowed by ACL", cmd);
+  }
+  // plugin commands are written as `plugin.command`
+  let name = match cmd.strip_prefix("plugin:") {
+    Some(command) => command.replacen('|', ".", 1),
+    None => cmd.to_string(),
+  };
+  // Tauri evaluates the URL as local or remote, which can't be told from here
+  let origins = ["local".to_string(), format!("remote: {}", url)];
+  let contexts = ["local", url];
+  let window = webview.window();
+  origins.iter().any(|origin| {
+    error.starts_with(&format!(
+      "{} explicitly denied on origin {}\n\n",
+      name, origin
+    )) || error.starts_with(&format!("{} not allowed on origin [{}]. ", name, origin))
+  }) || contexts.iter().any(|context| {
+    error.starts_with(&format!(
+      "{} not allowed on window \"{}\", webview \"{}\", URL: {}\n\n",
+      name,
+      window.label(),
+      webview.label(),
+      context
+    ))
+  }) || error
+    .strip_prefix(&format!("{} not allowed. ", name))
+    .map_or(false, |detail| {
+      detail == "Command not found"
+        || detail == "Plugin not found"
+        || detail.starts_with("Permissions associated with this command: ")
+    })
+}
+
+/// Whether the `Host` header `host` names a loopback address.
+fn is_loopback_host(host: &str) -> bool {
+  let name = match host.strip_prefix('[') {
+    Some(rest) => rest.split(']').next().unwrap_or_default(),
+    None => host.split(':').next().unwrap_or_default(),
+  };
+  name.eq_ignore_ascii_case("localhost")
+    || name
+      .parse::<IpAddr>()
+      .map_or(false, |address| address.is_loopback())
+}
+
+/// Matches `origin` against an allowed origin, in which `*` stands for any run of characters
+/// other than `/`, e.g. `http://localhost:*` or `https://*.internal.corp`.
+fn matches_origin(pattern: &str, origin: &str) -> bool {
+  match pattern.split_once('*') {
+    None => pattern == origin,
+    Some((prefix, rest)) => match origin.strip_prefix(prefix) {
+      Some(origin) => (0..=origin.len())
+        .filter(|&i| origin.is_char_boundary(i))
+        .take_while(|&i| !origin[..i].contains('/'))
+        .any(|i| matches_origin(rest, &origin[i..])),
+      None => false,
+    },
+  }
+}
+
+/// Locking that recovers from poisoning, so a panic while a lock is held doesn't make every
+/// later request panic as well. The guarded maps and queues are left consistent between
+/// statements, so their data stays usable.
+pub(crate) trait LockExt<T> {
+  fn locked(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+  fn locked(&self) -> MutexGuard<'_, T> {
+    self.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+/// Generates a random URL safe token.
+pub(crate) fn generate_token() -> String {
+  let mut bytes = [0u8; 32];
+  getrandom::fill(&mut bytes).expect("failed to generate a random token");
+  base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Whether a request carries `token`, as `Authorization: Bearer {token}` or, for clients that
+/// can't set headers such as `EventSource`, as the `access_token` query parameter of `url`.
+pub(crate) fn has_token(token: Option<&str>, authorization: Option<&str>, url: &str) -> bool {
+  match token {
+    // compared in constant time, so the token can't be guessed a byte at a time
+    Some(token) => {
+      presented_tokens(authorization, url).any(|t| bool::from(t.as_bytes().ct_eq(token.as_bytes())))
+    }
+    None => true,
+  }
+}
+
+/// The tokens a request presents, in its `Authorization` header and `access_token` query
+/// parameter.
+fn presented_tokens<'a>(
+  authorization: Option<&'a str>,
+  url: &'a str,
+) -> impl Iterator<Item = &'a str> {
+  let bearer = authorization
+    .and_then(|a| a.strip_prefix("Bearer "))
+    .map(str::trim);
+  let query = url
+    .split_once('?')
+    .map_or("", |(_, query)| query)
+    .split('&')
+    .find_map(|param| param.strip_prefix("access_token="));
+  bearer.into_iter().chain(query)
+}
+
+/// The headers a reverse proxy describes the original request with.
+const FORWARDED_HEADERS: [&str; 4] = [
+  "Forwarded",
+  "X-Forwarded-For",
+  "X-Forwarded-Host",
+  "X-Forwarded-Proto",
+];
+
+/// Removes the forwarded headers of a request that didn't come from a trusted proxy, so commands
+/// can't be misled by them.
+pub(crate) fn strip_forwarded(headers: &mut tauri::http::HeaderMap) {
+  for name in FORWARDED_HEADERS {
+    headers.remove(name);
+  }
+}
+
+/// The origin a proxied request was sent to, from `X-Forwarded-Proto` and `X-Forwarded-Host`.
+fn forwarded_origin(header: &impl Fn(&'static str) -> Option<String>) -> Option<String> {
+  // each proxy appends its own entry, the first one is the client facing proxy
+  let first = |value: String| {
+    value
+      .split(',')
+      .next()
+      .unwrap_or_default()
+      .trim()
+      .to_string()
+  };
+  let host = header("X-Forwarded-Host").map(first)?;
+  let scheme = header("X-Forwarded-Proto")
+    .map(first)
+    .unwrap_or_else(|| "http".into());
+  Some(format!("{}://{}", scheme, host))
+}
+
+/// The `Access-Control-*` headers answering a request sent from `origin`.
+pub(crate) fn cors_headers(origin: Option<&str>, context: &Context) -> Vec<(&'static str, String)> {
+  let config = &context.cors;
+  let mut headers = Vec::new();
+  let allow_all = context.allowed_origins.iter().any(|s| s == "*");
+  // browsers refuse `*` on requests with credentials, so the origin is echoed instead
+  if allow_all && !config.allow_credentials {
+    headers.push(("Access-Control-Allow-Origin", "*".to_string()));
+  } else if let Some(origin) = origin.filter(|o| is_allowed_origin(o, &context.allowed_origins)) {
+    headers.push(("Access-Control-Allow-Origin", origin.to_string()));
+    headers.push(("Vary", "Origin".to_string()));
+  }
+  headers.push((
+    "Access-Control-Allow-Headers",
+    config
+      .allowed_headers
+      .as_ref()
+      .map_or_else(|| "*".to_string(), |h| h.join(", ")),
+  ));
+  headers.push((
+    "Access-Control-Allow-Methods",
+    config.allowed_methods.join(", "),
+  ));
+  let mut exposed = config.exposed_headers.clone();
+  exposed.push(INVOKE_ERROR_HEADER.to_string());
+  if context.nonces.is_some() {
+    exposed.push(csrf::NEXT_NONCE_HEADER.to_string());
+  }
+  if context.cipher.is_some() {
+    exposed.push(encryption::ENCRYPTION_HEADER.to_string());
+  }
+  if !exposed.is_empty() {
+    headers.push(("Access-Control-Expose-Headers", exposed.join(", ")));
+  }
+  if config.allow_credentials {
+    headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+  }
+  headers
+}
+
+/// The CORS headers answering the preflight of a request sent from `origin`.
+///
+/// Unless the allowed headers are configured, the requested ones are echoed since `*` isn't
+/// accepted by every browser nor with credentials. The max age lets the browser skip the
+/// preflight of the following invokes.
+pub(crate) fn preflight_headers(
+  origin: Option<&str>,
+  requested_headers: Option<&str>,
+  context: &Context,
+) -> Vec<(&'static str, String)> {
+  let mut cors = cors_headers(origin, context);
+  if let (Some(requested), None) = (requested_headers, &context.cors.allowed_headers) {
+    for (field, value) in &mut cors {
+      if *field == "Access-Control-Allow-Headers" {
+        *value = requested.to_string();
       }
-    });
+    }
   }
+  if let Some(max_age) = context.preflight_max_age {
+    cors.push(("Access-Control-Max-Age", max_age.as_secs().to_string()));
+  }
+  cors
+}
 
-  pub fn responder<R: Runtime>(&self) -> Box<InvokeResponder<R>> {
-    let requests = self.requests.clone();
-    let allowed_origins = self.allowed_origins.clone();
-    Box::new(move |_webview, _cmd, response, callback, _error| {
-      let request = requests.lock().unwrap().remove(&callback.0).unwrap();
-      let response = match response {
-        InvokeResponse::Ok(r) => Ok(r),
-        InvokeResponse::Err(e) => Err(e),
-      };
-      let status: u16 = if response.is_ok() { 200 } else { 400 };
+fn cors<R: Read>(request: &Request, r: &mut Response<R>, context: &Context) {
+  for (field, value) in cors_headers(header(request, "Origin").as_deref(), context) {
+    r.add_header(Header::from_bytes(field, value).unwrap());
+  }
+}
 
-      let mut r = match response {
-        Ok(tauri::ipc::InvokeBody::Json(r)) => {
-          Response::from_string(serde_json::to_string(&r).unwrap())
-        }
-        Ok(tauri::ipc::InvokeBody::Raw(r)) => Response::from_data(r.clone()),
-        Err(tauri::ipc::InvokeError(e)) => {
-          Response::from_string(serde_json::to_string(&e).unwrap())
-        }
+/// The error inside the [`std::io::Error`] returned when a body exceeds its size limit.
+#[derive(Debug)]
+pub(crate) struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("request body too large")
+  }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// Fails writes once more than the limit has been written.
+struct Limited<W> {
+  inner: W,
+  remaining: usize,
+}
+
+impl<W: Write> Write for Limited<W> {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    if buf.len() > self.remaining {
+      return Err(std::io::Error::new(std::io::ErrorKind::Other, BodyTooLarge));
+    }
+    self.remaining -= buf.len();
+    self.inner.write_all(buf)?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// The protections a body is read through, outermost first.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Envelope<'a> {
+  /// The signing key and the signature sent with the body.
+  pub(crate) signing: Option<(&'a str, Option<&'a str>)>,
+  /// Signed ahead of the body, binding the replay protection headers to the signature.
+  pub(crate) signed_prefix: Option<&'a str>,
+  pub(crate) cipher: Option<&'a encryption::Cipher>,
+}
+
+/// Wraps `sink` so the bytes written to it are decoded from the codings listed in a
+/// `Content-Encoding` header, and fail once more than `limit` decoded bytes were written.
+///
+/// tiny_http and hyper already decode `Transfer-Encoding: chunked`.
+pub(crate) fn body_decoder<'a, W: Write + Send + 'a>(
+  encoding: Option<&str>,
+  sink: W,
+  limit: Option<usize>,
+  envelope: Envelope<'_>,
+) -> std::io::Result<Box<dyn Write + Send + 'a>> {
+  let mut body: Box<dyn Write + Send + 'a> = Box::new(Limited {
+    inner: sink,
+    remaining: limit.unwrap_or(usize::MAX),
+  });
+  // codings are listed in the order they were applied, so the last one is undone first
+  for coding in encoding
+    .unwrap_or_default()
+    .split(',')
+    .map(|c| c.trim().to_ascii_lowercase())
+  {
+    body = match coding.as_str() {
+      "" | "identity" => body,
+      "gzip" | "x-gzip" => Box::new(flate2::write::MultiGzDecoder::new(body)),
+      "deflate" => Box::new(flate2::write::ZlibDecoder::new(body)),
+      "br" => Box::new(brotli_decompressor::DecompressorWriter::new(body, 4096)),
+      _ => {
+        return Err(std::io::Error::new(
+          std::io::ErrorKind::Unsupported,
+          format!("unsupported content encoding `{}`", coding),
+        ))
       }
-      .with_status_code(status);
-      cors(&request, &mut r, &allowed_origins);
+    };
+  }
+  if let Some(cipher) = envelope.cipher {
+    body = Box::new(Limited {
+      inner: encryption::Decrypting::new(body, cipher),
+      remaining: limit.map_or(usize::MAX, |limit| {
+        limit.saturating_add(encryption::OVERHEAD)
+      }),
+    });
+  }
+  // the signature covers the body as sent, so it is checked before any decoding
+  if let Some((key, signature)) = envelope.signing {
+    let prefix = envelope.signed_prefix.unwrap_or_default().as_bytes();
+    body = Box::new(signing::Verifier::new(body, key, signature, prefix));
+  }
+  Ok(body)
+}
 
-      request.respond(r).unwrap();
-    })
+/// How an invoke request body is laid out, according to its `Content-Type`.
+pub(crate) enum BodyKind {
+  /// A message envelope in one of the wire formats.
+  Message(WireFormat),
+  /// A `multipart/form-data` body with the given boundary.
+  Multipart(String),
+  /// Raw bytes, with the envelope in headers.
+  Raw,
+}
+
+impl BodyKind {
+  pub(crate) fn from_content_type(content_type: Option<&str>) -> Self {
+    let content_type = match content_type {
+      Some(content_type) => content_type,
+      None => return Self::Message(WireFormat::Json),
+    };
+    if let Some(format) = WireFormat::from_content_type(content_type) {
+      Self::Message(format)
+    } else if let Some(boundary) = multipart::boundary(content_type) {
+      Self::Multipart(boundary)
+    } else {
+      Self::Raw
+    }
   }
+}
 
-  pub fn initialization_script(&self) -> String {
-    format!(
-      "
-        Object.defineProperty(__TAURI_INTERNALS__, 'postMessage', {{
-          value: (message) => {{
-            const request = new XMLHttpRequest();
-            request.addEventListener('load', function () {{
-              let arg
-              let success = this.status === 200
-              try {{
-                arg = JSON.parse(this.response)
-              }} catch (e) {{
-                arg = e
-                success = false
-              }}
-              window[`_${{success ? message.callback : message.error}}`](arg)
-            }})
-            request.open('POST', 'http://localhost:{}/' + window.__TAURI_INTERNALS__.metadata.currentWindow.label, true)
-            request.setRequestHeader('Content-Type', 'application/json')
-            request.send(JSON.stringify(message))
-          }}
-        }})
-    ",
-      self.port
-    )
+/// Counter used to name spooled uploads.
+static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Bodies at least this large are sent with chunked transfer encoding by default.
+const DEFAULT_STREAM_THRESHOLD: usize = 32 * 1024;
+
+/// Number of threads serving requests by default.
+const DEFAULT_WORKERS: usize = 4;
+
+/// How long browsers may cache a preflight by default.
+const DEFAULT_PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(600);
+/// How long dropping or restarting a running [`Invoke`] waits for in-flight invokes.
+pub(crate) const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The event emitted to the frontend with the new base URL when [`Invoke::restart`] rebinds
+/// the server.
+pub const BASE_URL_EVENT: &str = "tauri-invoke-http://base-url";
+
+/// The request header carrying the session of an invoke to the commands when
+/// [`Invoke::with_sessions`] is enabled.
+pub const SESSION_HEADER: &str = "Tauri-Session";
+
+/// A request to the invoke endpoints as seen by a [`Middleware`].
+#[derive(Debug, Clone)]
+pub struct MiddlewareRequest {
+  pub method: String,
+  /// The path and query of the request.
+  pub url: String,
+  /// The request headers. Changes are seen by the invoked command, while the server's own
+  /// checks keep using the headers as received.
+  pub headers: tauri::http::HeaderMap,
+  /// The address of the client, `None` over Unix sockets.
+  pub peer: Option<IpAddr>,
+}
+
+/// A response sent by a [`Middleware`] instead of handling the request.
+#[derive(Debug, Clone)]
+pub struct MiddlewareResponse {
+  pub status: u16,
+  pub headers: tauri::http::HeaderMap,
+  pub body: Vec<u8>,
+}
+
+impl MiddlewareResponse {
+  /// An empty response with `status`.
+  pub fn new(status: u16) -> Self {
+    Self {
+      status,
+      headers: Default::default(),
+      body: Vec::new(),
+    }
+  }
+}
+
+/// The app a [`Middleware`] runs for, e.g. to check a session store kept in its state.
+pub struct MiddlewareContext<'a> {
+  app: &'a (dyn std::any::Any + Send + Sync),
+}
+
+impl<'a> MiddlewareContext<'a> {
+  pub(crate) fn new<R: Runtime>(app: &'a AppHandle<R>) -> Self {
+    Self { app }
+  }
+
+  /// The handle of the app, or `None` if it doesn't run on the runtime `R`.
+  pub fn app_handle<R: Runtime>(&self) -> Option<&'a AppHandle<R>> {
+    self.app.downcast_ref()
+  }
+
+  /// The state of type `T` managed by the app, or `None` if it isn't managed or the app doesn't
+  /// run on the runtime `R`, e.g. `context.state::<tauri::Wry, ApiKeys>()`.
+  pub fn state<R: Runtime, T: Send + Sync + 'static>(&self) -> Option<tauri::State<'a, T>> {
+    self.app_handle::<R>()?.try_state()
+  }
+}
+
+/// Inspects the requests to the invoke endpoints and the responses to invokes, e.g. for custom
+/// auth, logging or header injection. Register it with [`Invoke::with_middleware`].
+pub trait Middleware: Send + Sync {
+  /// Called with every request but preflights, after the built-in authorization. Returning a
+  /// response sends it right away instead of handling the request.
+  fn on_request(
+    &self,
+    request: &mut MiddlewareRequest,
+    context: &MiddlewareContext<'_>,
+  ) -> Option<MiddlewareResponse> {
+    let _ = (request, context);
+    None
+  }
+
+  /// Called before the response to an invoke of `cmd` is sent with `status`. The `headers`
+  /// added are sent along with it.
+  fn on_response(
+    &self,
+    cmd: &str,
+    status: u16,
+    headers: &mut tauri::http::HeaderMap,
+    context: &MiddlewareContext<'_>,
+  ) {
+    let _ = (cmd, status, headers, context);
+  }
+}
+
+/// The [`Middleware`] of [`Invoke::with_response_hook`].
+struct ResponseHook<F>(F);
+
+impl<F: Fn(&str, u16, &mut tauri::http::HeaderMap) + Send + Sync> Middleware for ResponseHook<F> {
+  fn on_response(
+    &self,
+    cmd: &str,
+    status: u16,
+    headers: &mut tauri::http::HeaderMap,
+    _context: &MiddlewareContext<'_>,
+  ) {
+    (self.0)(cmd, status, headers)
+  }
+}
+
+/// Answers a route added with [`Invoke::with_route`], given the request and its body.
+pub(crate) type RouteHandler =
+  Arc<dyn Fn(&MiddlewareRequest, &[u8]) -> MiddlewareResponse + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct Route {
+  pub(crate) method: String,
+  pub(crate) path: String,
+  pub(crate) handler: RouteHandler,
+}
+
+/// Called with the origin of each request refused for it, see
+/// [`Invoke::with_rejected_origin_hook`].
+pub(crate) type OriginHook = Arc<dyn Fn(Option<&str>) + Send + Sync>;
+
+/// Called with the changes in the state of the server, see [`Invoke::with_lifecycle_hook`].
+pub(crate) type LifecycleHook = Arc<dyn Fn(&LifecycleEvent<'_>) + Send + Sync>;
+
+/// A change in the state of the server, reported to [`Invoke::with_lifecycle_hook`].
+#[derive(Debug)]
+pub enum LifecycleEvent<'a> {
+  /// A listener was bound to an address, e.g. to show the picked port. Reported for each TCP
+  /// listener of [`Invoke::with_listener`] before [`LifecycleEvent::Started`].
+  Bound(SocketAddr),
+  /// [`Invoke::start`] started the server, serving at `base_url`.
+  Started { base_url: &'a str },
+  /// [`Invoke::shutdown`] stopped the server.
+  Stopped,
+  /// [`Invoke::start`] failed, e.g. because the port is taken.
+  Error(&'a InvokeHttpError),
+}
+
+/// An invoke whose command didn't answer yet, returned by [`Invoke::pending_invokes`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingInvoke {
+  /// The callback id of the invoke, also sent to the command in `Tauri-Invocation-Id`.
+  pub callback: u32,
+  pub cmd: String,
+  /// The label of the window the invoke was sent to.
+  pub window: String,
+  /// Time since the invoke was dispatched.
+  pub age: Duration,
+}
+
+/// Tells a command whether the client that invoked it went away, so long-running work can stop
+/// early. Get it with [`Invoke::cancellation_token`].
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+  /// Whether the invoke was abandoned, because its client disconnected, it timed out or the
+  /// server was shut down.
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::SeqCst)
+  }
+
+  pub(crate) fn cancel(&self) {
+    self.0.store(true, Ordering::SeqCst);
+  }
+}
+
+/// The state shared by the request handlers of a running server.
+#[derive(Clone)]
+pub(crate) struct Context {
+  pub(crate) allowed_origins: Vec<String>,
+  pub(crate) origin_hook: Option<OriginHook>,
+  pub(crate) middleware: Vec<Arc<dyn Middleware>>,
+  pub(crate) routes: Vec<Route>,
+  pub(crate) missing_origin: MissingOrigin,
+  pub(crate) error_format: ErrorFormat,
+  pub(crate) channels: sse::Subscribers,
+  pub(crate) mailboxes: poll::Mailboxes,
+  pub(crate) stream_threshold: usize,
+  pub(crate) keep_alive: keep_alive::Tracker,
+  pub(crate) upload_dir: Option<PathBuf>,
+  /// Set once the server starts shutting down, from then on invokes are refused.
+  pub(crate) closing: Arc<AtomicBool>,
+  pub(crate) limits: Limits,
+  pub(crate) read_timeouts: ReadTimeouts,
+  pub(crate) window_grace: Option<Duration>,
+  /// The webviews dispatching the invokes of virtual windows, by virtual label.
+  pub(crate) virtual_windows: Arc<HashMap<String, String>>,
+  pub(crate) invoke_timeout: Option<Duration>,
+  pub(crate) cancellations: cancel::Tokens,
+  pub(crate) cors: CorsConfig,
+  pub(crate) preflight_max_age: Option<Duration>,
+  /// Applied to the connections accepted by hyper and the WebSocket listener, tiny_http leaves
+  /// them to the listener.
+  pub(crate) socket_options: SocketOptions,
+  pub(crate) base_url: String,
+  pub(crate) path_prefix: String,
+  pub(crate) trusted_proxies: Vec<IpAddr>,
+  pub(crate) acl_origins: Vec<(String, String)>,
+  pub(crate) allowed_commands: Option<Vec<String>>,
+  pub(crate) denied_commands: Vec<String>,
+  /// The bearer token required by the listener, if any.
+  pub(crate) token: Option<String>,
+  /// Accepts JWTs as bearer tokens too.
+  pub(crate) jwt: Option<JwtConfig>,
+  pub(crate) nonces: Option<csrf::Nonces>,
+  pub(crate) events: Option<events::Bridge>,
+  pub(crate) replay: Option<replay::Guard>,
+  /// The key invoke bodies are signed with, if signatures are required.
+  pub(crate) signing_key: Option<String>,
+  pub(crate) cipher: Option<encryption::Cipher>,
+  /// The key of the isolation pattern, which seals the payloads of the app's invokes.
+  pub(crate) isolation_key: Option<encryption::Cipher>,
+  pub(crate) rate_limiter: Option<rate_limit::Limiter>,
+  pub(crate) windows: Arc<HashMap<String, WindowPolicy>>,
+  pub(crate) ip_filter: ip_filter::IpFilter,
+  pub(crate) assets: Option<Assets>,
+  pub(crate) auditor: audit::Auditor,
+  pub(crate) metrics: metrics::Recorder,
+  /// Refuses requests whose `Host` isn't a loopback address.
+  pub(crate) strict_local: bool,
+  pub(crate) sessions: Option<session::Sessions>,
+  #[cfg(feature = "async-server")]
+  pub(crate) pending: async_server::Pending,
+}
+
+impl Context {
+  /// Whether a `GET` of `url` is for the frontend assets rather than the invoke endpoints.
+  pub(crate) fn is_asset(&self, url: &str) -> bool {
+    if self.assets.is_none() {
+      return false;
+    }
+    let path = match self.route(url) {
+      Some(path) => path.split('?').next().unwrap_or_default(),
+      None => return true,
+    };
+    let pieces = path.split('/').collect::<Vec<_>>();
+    !(path == "/health"
+      || path == "/ready"
+      || (pieces.len() == 3 && ["channel", "poll", "nonce", "events"].contains(&pieces[2]))
+      || (pieces.len() == 4 && pieces[2] == "events"))
+  }
+
+  /// Whether a request for `url` with the `authorization` header passes the listener's token
+  /// check.
+  pub(crate) fn is_authorized(&self, authorization: Option<&str>, url: &str) -> bool {
+    let config = match &self.jwt {
+      Some(config) => config,
+      None => return has_token(self.token.as_deref(), authorization, url),
+    };
+    (self.token.is_some() && has_token(self.token.as_deref(), authorization, url))
+      || presented_tokens(authorization, url).any(|token| jwt::validate(config, token).is_ok())
+  }
+
+  /// Takes a token from the rate limit bucket of a request, or returns the `Retry-After` value.
+  pub(crate) fn rate_limit(
+    &self,
+    label: &str,
+    peer: Option<IpAddr>,
+    trusted: bool,
+    origin: Option<&str>,
+    header: &impl Fn(&'static str) -> Option<String>,
+  ) -> Result<(), String> {
+    let limiter = match self
+      .windows
+      .get(label)
+      .and_then(|w| w.rate_limiter.as_ref())
+      .or(self.rate_limiter.as_ref())
+    {
+      Some(limiter) => limiter,
+      None => return Ok(()),
+    };
+    let key = match limiter.config().key {
+      RateLimitKey::Origin => origin.unwrap_or_default().to_string(),
+      RateLimitKey::ClientIp => header("X-Forwarded-For")
+        .filter(|_| trusted)
+        .and_then(|f| f.split(',').next().map(|c| c.trim().to_string()))
+        .or_else(|| peer.map(|p| p.to_string()))
+        .unwrap_or_default(),
+    };
+    limiter.acquire(&key).map_err(rate_limit::retry_after)
+  }
+
+  /// The protections of an invoke body sent with `signature` and the replay protection
+  /// `stamp`.
+  pub(crate) fn envelope<'a>(
+    &'a self,
+    signature: Option<&'a str>,
+    stamp: Option<&'a str>,
+  ) -> Envelope<'a> {
+    Envelope {
+      signing: self.signing_key.as_deref().map(|key| (key, signature)),
+      signed_prefix: stamp,
+      cipher: self.cipher.as_ref(),
+    }
+  }
+
+  /// Refuses replayed invokes, returning why.
+  pub(crate) fn check_replay(
+    &self,
+    header: &impl Fn(&'static str) -> Option<String>,
+  ) -> Result<(), &'static str> {
+    match &self.replay {
+      Some(guard) => guard.admit(
+        header(replay::REQUEST_ID_HEADER).as_deref(),
+        header(replay::TIMESTAMP_HEADER).as_deref(),
+      ),
+      None => Ok(()),
+    }
+  }
+
+  /// The protection enabled that the WebSocket transport can't apply to its invokes, if any.
+  pub(crate) fn unsupported_over_websocket(&self) -> Option<&'static str> {
+    if self.signing_key.is_some() {
+      return Some("request signing");
+    }
+    if self.replay.is_some() {
+      return Some("replay protection");
+    }
+    if self.cipher.is_some() {
+      return Some("encryption");
+    }
+    None
+  }
+
+  /// The replay protection headers signed ahead of an invoke body, if enabled.
+  pub(crate) fn replay_stamp(
+    &self,
+    header: &impl Fn(&'static str) -> Option<String>,
+  ) -> Option<String> {
+    self.replay.as_ref().map(|_| {
+      replay::signed_prefix(
+        header(replay::REQUEST_ID_HEADER).as_deref(),
+        header(replay::TIMESTAMP_HEADER).as_deref(),
+      )
+    })
+  }
+
+  /// Encrypts an invoke response body if encryption is enabled, returning the header marking it.
+  pub(crate) fn seal(&self, body: Vec<u8>) -> (Vec<u8>, Option<(&'static str, &'static str)>) {
+    if let Some(cipher) = &self.cipher {
+      return (
+        cipher.seal(&body),
+        Some((encryption::ENCRYPTION_HEADER, encryption::ALGORITHM)),
+      );
+    }
+    (body, None)
+  }
+
+  /// Whether a request from `peer` for `host` passes the strict local mode.
+  ///
+  /// Requests over Unix sockets have no `peer` and can't come from a browser, so they pass.
+  pub(crate) fn is_local_request(&self, peer: Option<IpAddr>, host: Option<&str>) -> bool {
+    !self.strict_local || peer.is_none() || host.map_or(false, is_loopback_host)
+  }
+
+  /// The session of a request sending the `cookie` header, with the `Set-Cookie` value if it
+  /// was just started, or `None` if sessions are disabled.
+  pub(crate) fn session(&self, cookie: Option<&str>) -> Option<(String, Option<String>)> {
+    let secure = self.base_url.starts_with("https:");
+    self
+      .sessions
+      .as_ref()
+      .map(|sessions| sessions.resolve(cookie, secure))
+  }
+
+  /// Whether requests from `origin` may be served, calling the rejected origin hook if not.
+  pub(crate) fn allows_origin(&self, origin: Option<&str>) -> bool {
+    let allowed = is_allowed_origin(origin.unwrap_or_default(), &self.allowed_origins);
+    if !allowed {
+      log::warn!(
+        "refused a request from the origin {}, which isn't allowed",
+        origin.unwrap_or("(none)")
+      );
+      self.metrics.rejected_origin();
+      if let Some(hook) = &self.origin_hook {
+        hook(origin);
+      }
+    }
+    allowed
+  }
+
+  /// The route added for `method` requests to `url`, which is matched without its query.
+  pub(crate) fn custom_route(&self, method: &str, url: &str) -> Option<&Route> {
+    let path = url.split('?').next().unwrap_or_default();
+    self
+      .routes
+      .iter()
+      .find(|route| route.method.eq_ignore_ascii_case(method) && route.path == path)
+  }
+
+  /// Runs the middleware on `request`, returning the response of the first one answering it.
+  pub(crate) fn intercept<R: Runtime>(
+    &self,
+    app: &AppHandle<R>,
+    request: &mut MiddlewareRequest,
+  ) -> Option<MiddlewareResponse> {
+    let context = MiddlewareContext::new(app);
+    self
+      .middleware
+      .iter()
+      .find_map(|middleware| middleware.on_request(request, &context))
+  }
+
+  /// The headers the middleware add to the response to an invoke of `cmd`, last registered
+  /// first.
+  pub(crate) fn annotate<R: Runtime>(
+    &self,
+    app: &AppHandle<R>,
+    cmd: &str,
+    status: u16,
+  ) -> tauri::http::HeaderMap {
+    let context = MiddlewareContext::new(app);
+    let mut headers = Default::default();
+    for middleware in self.middleware.iter().rev() {
+      middleware.on_response(cmd, status, &mut headers, &context);
+    }
+    headers
+  }
+
+  /// The label of the webview dispatching the invokes for the window `label`.
+  pub(crate) fn dispatching_webview<'a>(&'a self, label: &'a str) -> &'a str {
+    self
+      .virtual_windows
+      .get(label)
+      .map_or(label, String::as_str)
+  }
+
+  /// Whether `cmd` may be invoked through this server by the window with `label`.
+  pub(crate) fn is_allowed_command(&self, label: &str, cmd: &str) -> bool {
+    let window = self.windows.get(label).map(|w| &w.config);
+    let allowed = window
+      .and_then(|w| w.allowed_commands.as_ref())
+      .or(self.allowed_commands.as_ref());
+    let mut denied = self
+      .denied_commands
+      .iter()
+      .chain(window.into_iter().flat_map(|w| &w.denied_commands));
+    cmd == FETCH_CHANNEL_DATA_COMMAND
+      || (!denied.any(|c| matches_origin(c, cmd))
+        && allowed.map_or(true, |allowed| {
+          allowed.iter().any(|c| matches_origin(c, cmd))
+        }))
+  }
+
+  /// Whether `peer` is a trusted proxy, whose forwarded headers describe the request.
+  pub(crate) fn is_trusted_proxy(&self, peer: Option<IpAddr>) -> bool {
+    peer.map_or(false, |peer| self.trusted_proxies.contains(&peer))
+  }
+
+  /// The origin of a request.
+  ///
+  /// Same-origin requests carry no `Origin`, so behind a trusted proxy it is rebuilt from the
+  /// forwarded headers.
+  pub(crate) fn origin(
+    &self,
+    trusted: bool,
+    header: &impl Fn(&'static str) -> Option<String>,
+  ) -> Option<String> {
+    let origin = header("Origin").or_else(|| {
+      if trusted {
+        forwarded_origin(header)
+      } else {
+        None
+      }
+    });
+    self.or_fallback_origin(origin)
+  }
+
+  /// `origin`, or the fallback of [`MissingOrigin`] if the request didn't send one.
+  pub(crate) fn or_fallback_origin(&self, origin: Option<String>) -> Option<String> {
+    origin.or_else(|| match &self.missing_origin {
+      MissingOrigin::Reject => None,
+      MissingOrigin::Fallback(url) => Some(url.clone()),
+    })
+  }
+
+  /// The base URL clients reach the server at, which a trusted proxy may rewrite.
+  pub(crate) fn public_base_url(
+    &self,
+    trusted: bool,
+    header: &impl Fn(&'static str) -> Option<String>,
+  ) -> String {
+    match forwarded_origin(header).filter(|_| trusted) {
+      Some(origin) => format!("{}{}", origin, self.path_prefix),
+      None => self.base_url.clone(),
+    }
+  }
+
+  /// Strips the path prefix from `path`, or returns `None` if it is outside of it.
+  pub(crate) fn route<'a>(&self, path: &'a str) -> Option<&'a str> {
+    let rest = path.strip_prefix(self.path_prefix.as_str())?;
+    if rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') {
+      Some(rest)
+    } else {
+      None
+    }
+  }
+
+  /// The file a raw upload of `body_length` bytes (unknown when chunked) is spooled to, if any.
+  pub(crate) fn spool_path(&self, kind: &BodyKind, body_length: Option<usize>) -> Option<PathBuf> {
+    // large raw uploads go to a file instead of memory when an upload directory is set
+    match (&self.upload_dir, kind) {
+      (Some(dir), BodyKind::Raw)
+        if body_length.map_or(true, |len| len >= self.stream_threshold) =>
+      {
+        Some(dir.join(format!(
+          "tauri-invoke-{}-{}",
+          std::process::id(),
+          UPLOADS.fetch_add(1, Ordering::Relaxed)
+        )))
+      }
+      _ => None,
+    }
+  }
+}
+
+/// Answers `GET /health` and `GET /ready` with the state of the server as JSON.
+///
+/// `/health` succeeds whenever the server answers, `/ready` fails with `503` while it shuts down
+/// or before any window is registered. Returns `None` for other paths.
+pub(crate) fn status_route<R: Runtime>(
+  app: &AppHandle<R>,
+  context: &Context,
+  path: &str,
+  base_url: &str,
+  pending: usize,
+) -> Option<(u16, String)> {
+  let readiness = match path.split('?').next() {
+    Some("/health") => false,
+    Some("/ready") => true,
+    _ => return None,
+  };
+  let mut windows = app.webview_windows().into_keys().collect::<Vec<_>>();
+  windows.extend(context.virtual_windows.keys().cloned());
+  windows.sort();
+  let bound = !context.closing.load(Ordering::SeqCst);
+  let ready = bound && !windows.is_empty();
+  let body = serde_json::json!({
+    "bound": bound,
+    "ready": ready,
+    "baseUrl": base_url,
+    "windows": windows,
+    "pending": pending,
+  });
+  let status = if readiness && !ready { 503 } else { 200 };
+  Some((status, body.to_string()))
+}
+
+/// Builds the invoke message from the headers and decoded body of a request.
+///
+/// `content` is empty when the body was spooled to `spool`. `path_cmd` is the percent-encoded
+/// segment after the window label in the URL path, if any.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn invoke_request(
+  invoke_key: &str,
+  isolation_key: Option<&encryption::Cipher>,
+  origin: Option<String>,
+  header: impl Fn(&'static str) -> Option<String>,
+  path_cmd: Option<&str>,
+  mut headers: tauri::http::HeaderMap,
+  kind: &BodyKind,
+  content: Vec<u8>,
+  spool: Option<&Path>,
+) -> Result<InvokeRequest, String> {
+  let origin = origin.ok_or("Invalid IPC request - No Origin")?;
+  let url = Url::parse(&origin).map_err(|e| format!("invalid IPC request URL: {}", e))?;
+  let (cmd, callback, error, body) = match kind {
+    BodyKind::Message(format) => {
+      // the message is answered with 400 and the parse error, the server keeps going
+      let message: RecievedMessage = format
+        .decode(&content)
+        .and_then(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+        .map_err(|e| format!("malformed invoke payload: {}", e))?;
+      (
+        message.cmd,
+        message.callback,
+        message.error,
+        isolation::open(isolation_key, message.payload)?,
+      )
+    }
+    BodyKind::Multipart(_) | BodyKind::Raw => {
+      // binary payloads can't carry the message envelope,
+      // so it is sent in headers the same way the official IPC protocol does,
+      // which puts the command in the path, e.g. `/main/plugin%3Afs%7Cwrite_file`
+      let cmd = header("Tauri-Cmd")
+        .or_else(|| path_cmd.and_then(assets::decode))
+        .filter(|cmd| !cmd.is_empty())
+        .ok_or("Invalid IPC request - No Tauri-Cmd")?;
+      let callback = header("Tauri-Callback")
+        .and_then(|c| c.parse().ok())
+        .ok_or("Invalid IPC request - No Tauri-Callback")?;
+      let error = header("Tauri-Error")
+        .and_then(|e| e.parse().ok())
+        .ok_or("Invalid IPC request - No Tauri-Error")?;
+      let body = match (spool, kind) {
+        (Some(path), _) => InvokeBody::Json(serde_json::json!({ "path": path })),
+        (None, BodyKind::Multipart(boundary)) => {
+          let (files, form) = multipart::parse(&content, boundary)?;
+          headers.insert(
+            tauri::http::HeaderName::from_bytes(multipart::FORM_HEADER.as_bytes()).unwrap(),
+            form.parse().map_err(|_| "invalid multipart form")?,
+          );
+          InvokeBody::Raw(files)
+        }
+        (None, _) => InvokeBody::Raw(content),
+      };
+      (cmd, CallbackFn(callback), CallbackFn(error), body)
+    }
+  };
+  Ok(InvokeRequest {
+    cmd,
+    callback,
+    error,
+    url,
+    body,
+    headers,
+    invoke_key: invoke_key.to_string(),
+  })
+}
+
+/// Encodes `response` as negotiated with an `Accept` header.
+///
+/// Returns the status, content type and body, or `None` if nothing acceptable can represent it.
+/// Errors are sent in `errors`, and are the capabilities' rejection of the invoke if `denied`.
+pub(crate) fn encode_response(
+  accept: Option<&str>,
+  response: InvokeResponse,
+  denied: bool,
+  errors: ErrorFormat,
+) -> Option<(u16, &'static str, Vec<u8>)> {
+  let raw = matches!(response, InvokeResponse::Ok(InvokeResponseBody::Raw(_)));
+  let format = match format::negotiate(accept, raw) {
+    Negotiated::Raw => None,
+    Negotiated::Format(format) => Some(format),
+    Negotiated::NotAcceptable => return None,
+  };
+  Some(match (response, format) {
+    (InvokeResponse::Ok(InvokeResponseBody::Raw(r)), None) => {
+      (200u16, "application/octet-stream", r)
+    }
+    (InvokeResponse::Ok(InvokeResponseBody::Raw(r)), Some(format)) => {
+      (200, format.content_type(), format.encode(&r.into()))
+    }
+    (InvokeResponse::Ok(InvokeResponseBody::Json(r)), Some(WireFormat::Json) | None) => {
+      (200, "application/json", r.into_bytes())
+    }
+    (InvokeResponse::Ok(InvokeResponseBody::Json(r)), Some(format)) => (
+      200,
+      format.content_type(),
+      format.encode(&serde_json::from_str(&r).unwrap_or_default()),
+    ),
+    (InvokeResponse::Err(InvokeError(e)), format) => {
+      let format = format.unwrap_or(WireFormat::Json);
+      let status = if denied { 403 } else { 400 };
+      let e = match errors {
+        ErrorFormat::Plain => e,
+        ErrorFormat::Envelope => serde_json::json!({
+          "error": e,
+          "code": if denied { "not_allowed" } else { "command_failed" },
+        }),
+      };
+      (status, format.content_type(), format.encode(&e))
+    }
+  })
+}
+
+/// The status line and headers of `response`, for connections written to by hand.
+pub(crate) fn response_head<R: Read>(response: &Response<R>) -> String {
+  let mut head = format!(
+    "HTTP/1.1 {} {}\r\n",
+    response.status_code().0,
+    response.status_code().default_reason_phrase()
+  );
+  for header in response.headers() {
+    head.push_str(&format!("{}: {}\r\n", header.field, header.value));
+  }
+  head
+}
+
+/// Answers `request` with the `response` to an invoke, returning the status it was sent with.
+///
+/// `cookie` is the `Set-Cookie` value of a session started by the invoke, and `denied` whether
+/// the response is the capabilities' rejection of it.
+fn respond<R: Runtime>(
+  app: &AppHandle<R>,
+  request: Request,
+  cmd: &str,
+  response: InvokeResponse,
+  denied: bool,
+  cookie: Option<String>,
+  context: &Context,
+) -> u16 {
+  let (status, content_type, body) = match encode_response(
+    header(&request, "Accept").as_deref(),
+    response,
+    denied,
+    context.error_format,
+  ) {
+    Some(encoded) => encoded,
+    None => {
+      let mut r = Response::empty(406u16);
+      cors(&request, &mut r, context);
+      if let Err(e) = request.respond(r) {
+        report_disconnect(cmd, &e);
+      }
+      return 406;
+    }
+  };
+
+  let mut r = Response::empty(status)
+    .with_header(Header::from_str(&format!("Content-Type: {}", content_type)).unwrap());
+  cors(&request, &mut r, context);
+  let (body, sealed) = context.seal(body);
+  if let Some((field, value)) = sealed {
+    r.add_header(Header::from_bytes(field, value).unwrap());
+  }
+  if let Some(nonces) = &context.nonces {
+    r.add_header(Header::from_bytes(csrf::NEXT_NONCE_HEADER, nonces.issue()).unwrap());
+  }
+  if let Some(cookie) = cookie {
+    r.add_header(Header::from_bytes("Set-Cookie", cookie).unwrap());
+  }
+  if status != 200 {
+    r.add_header(Header::from_bytes(INVOKE_ERROR_HEADER, "true").unwrap());
+  }
+  add_headers(&mut r, &context.annotate(app, cmd, status));
+
+  let remaining = context.keep_alive.remaining(&request);
+  if remaining == Some(0) {
+    if let Err(e) = keep_alive::respond_and_close(request, r, &body) {
+      report_disconnect(cmd, &e);
+    }
+    return status;
+  }
+  if let Some(header) = context.keep_alive.header(remaining) {
+    r.add_header(header);
+  }
+  // tiny_http writes the body straight from the buffer in chunks once it crosses the
+  // threshold, so large results aren't copied into a single framed response.
+  let length = body.len();
+  let r = r
+    .with_data(std::io::Cursor::new(body), Some(length))
+    .with_chunked_threshold(context.stream_threshold);
+  if let Err(e) = request.respond(r) {
+    report_disconnect(cmd, &e);
+  }
+  status
+}
+
+/// Adds `headers` to `response`, skipping those tiny_http can't send.
+fn add_headers<R: Read>(response: &mut Response<R>, headers: &tauri::http::HeaderMap) {
+  for (name, value) in headers {
+    if let Ok(header) = Header::from_bytes(name.as_str(), value.as_bytes()) {
+      response.add_header(header);
+    }
+  }
+}
+
+/// Notes an invoke whose client went away before its response was written, e.g. because the
+/// webview navigated while the command ran. The request is already out of the pending map.
+fn report_disconnect(cmd: &str, error: &std::io::Error) {
+  log::error!(
+    "the client disconnected before the response to `{}` was written: {}",
+    cmd,
+    error
+  );
+}
+
+/// How often a held invoke checks whether its window was created.
+pub(crate) const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Looks up the webview `label`, a webview window or, with the `unstable` feature, a child
+/// webview added with `Window::add_child`.
+pub(crate) fn find_webview<R: Runtime>(app: &AppHandle<R>, label: &str) -> Option<Webview<R>> {
+  #[cfg(feature = "unstable")]
+  if let Some(webview) = app.get_webview(label) {
+    return Some(webview);
+  }
+  app
+    .get_webview_window(label)
+    .map(|window| window.as_ref().clone())
+}
+
+/// Every webview of `app`, including child webviews with the `unstable` feature.
+fn all_webviews<R: Runtime>(app: &AppHandle<R>) -> Vec<Webview<R>> {
+  #[cfg(feature = "unstable")]
+  return app.webviews().into_values().collect();
+  #[cfg(not(feature = "unstable"))]
+  app
+    .webview_windows()
+    .into_values()
+    .map(|window| window.as_ref().clone())
+    .collect()
+}
+
+/// Looks up the webview `label`, waiting for the grace period if it isn't created yet.
+fn wait_for_window<R: Runtime>(
+  app: &AppHandle<R>,
+  context: &Context,
+  label: &str,
+) -> Option<Webview<R>> {
+  let deadline = std::time::Instant::now() + context.window_grace.unwrap_or_default();
+  loop {
+    if let Some(window) = find_webview(app, context.dispatching_webview(label)) {
+      return Some(window);
+    }
+    if std::time::Instant::now() >= deadline || context.closing.load(Ordering::SeqCst) {
+      return None;
+    }
+    std::thread::sleep(WINDOW_POLL_INTERVAL);
+  }
+}
+
+/// Points the pages open in the windows of `app` at `base_url` and emits [`BASE_URL_EVENT`].
+fn announce_base_url<R: Runtime>(app: &AppHandle<R>, base_url: &str) {
+  let script = format!(
+    "window.__TAURI_INVOKE_HTTP__ && window.__TAURI_INVOKE_HTTP__.setBaseUrl({})",
+    JsonValue::from(base_url)
+  );
+  for window in all_webviews(app) {
+    let _ = window.eval(&script);
+  }
+  let _ = app.emit(BASE_URL_EVENT, base_url);
+}
+
+/// A bound listening socket, handed over to one of the servers.
+pub(crate) enum Socket {
+  Tcp(std::net::TcpListener),
+  #[cfg(unix)]
+  Unix(std::os::unix::net::UnixListener),
+}
+
+impl From<Socket> for tiny_http::Listener {
+  fn from(socket: Socket) -> Self {
+    match socket {
+      Socket::Tcp(listener) => listener.into(),
+      #[cfg(unix)]
+      Socket::Unix(listener) => listener.into(),
+    }
+  }
+}
+
+/// Binds a TCP listener on the first address `address` resolves to that can be bound.
+fn bind_tcp(address: &str, options: SocketOptions) -> std::io::Result<std::net::TcpListener> {
+  let mut error = None;
+  for address in std::net::ToSocketAddrs::to_socket_addrs(address)? {
+    match bind_socket(address, options) {
+      Ok(listener) => return Ok(listener),
+      Err(e) => error = Some(e),
+    }
+  }
+  Err(error.unwrap_or_else(|| {
+    std::io::Error::new(
+      std::io::ErrorKind::InvalidInput,
+      "could not resolve to any addresses",
+    )
+  }))
+}
+
+fn bind_socket(
+  address: SocketAddr,
+  options: SocketOptions,
+) -> std::io::Result<std::net::TcpListener> {
+  use socket2::{Domain, Protocol, Type};
+  let socket = socket2::Socket::new(
+    Domain::for_address(address),
+    Type::STREAM,
+    Some(Protocol::TCP),
+  )?;
+  socket.set_reuse_address(options.reuse_address)?;
+  socket.set_tcp_nodelay(options.nodelay)?;
+  socket.bind(&address.into())?;
+  socket.listen(options.backlog)?;
+  Ok(socket.into())
+}
+
+/// Takes the sockets passed to the process with `LISTEN_PID` and `LISTEN_FDS`.
+#[cfg(unix)]
+fn activated_sockets() -> std::io::Result<Vec<Socket>> {
+  use std::os::unix::{
+    io::{FromRawFd, IntoRawFd},
+    net::UnixListener,
+  };
+  // the first passed file descriptor, following stdin, stdout and stderr
+  const LISTEN_FDS_START: i32 = 3;
+  let for_us = std::env::var("LISTEN_PID")
+    .ok()
+    .and_then(|pid| pid.parse::<u32>().ok())
+    == Some(std::process::id());
+  let count = std::env::var("LISTEN_FDS")
+    .ok()
+    .and_then(|n| n.parse::<i32>().ok())
+    .filter(|_| for_us)
+    .unwrap_or(0);
+  // like sd_listen_fds, so child processes don't take the sockets too
+  for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+    std::env::remove_var(name);
+  }
+  if count <= 0 {
+    return Err(std::io::Error::new(
+      std::io::ErrorKind::NotFound,
+      "no sockets were passed with LISTEN_FDS",
+    ));
+  }
+  Ok(
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+      .map(|fd| {
+        // SAFETY: the passed descriptors are open and owned by this process from now on
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        if listener.local_addr().is_ok() {
+          Socket::Tcp(listener)
+        } else {
+          Socket::Unix(unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) })
+        }
+      })
+      .collect(),
+  )
+}
+
+/// Removes a Unix socket left over by a previous run, never a regular file.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) {
+  use std::os::unix::fs::FileTypeExt;
+  if std::fs::metadata(path).map_or(false, |m| m.file_type().is_socket()) {
+    let _ = std::fs::remove_file(path);
+  }
+}
+
+/// Reads the body of `request` into memory, or into `spool` if set, undoing its
+/// `Content-Encoding` and checking its signature with the `signing` key.
+fn read_body(
+  request: &mut Request,
+  encoding: Option<&str>,
+  spool: Option<&Path>,
+  limit: Option<usize>,
+  deadline: Option<Instant>,
+  context: &Context,
+) -> std::io::Result<Vec<u8>> {
+  let signature = header(request, signing::SIGNATURE_HEADER);
+  let stamp = context.replay_stamp(&|name| header(request, name));
+  let envelope = context.envelope(signature.as_deref(), stamp.as_deref());
+  let mut content = Vec::new();
+  match spool {
+    Some(path) => {
+      let mut sink = body_decoder(encoding, std::fs::File::create(path)?, limit, envelope)?;
+      std::io::copy(&mut Deadline::new(request.as_reader(), deadline), &mut sink)?;
+      sink.flush()?;
+    }
+    None => {
+      let mut sink = body_decoder(encoding, &mut content, limit, envelope)?;
+      std::io::copy(&mut Deadline::new(request.as_reader(), deadline), &mut sink)?;
+      sink.flush()?;
+    }
+  }
+  Ok(content)
+}
+
+/// Reads from `inner` until `deadline`, past which reads fail with `TimedOut`. A read already
+/// blocked on the client isn't interrupted.
+struct Deadline<R> {
+  inner: R,
+  deadline: Option<Instant>,
+}
+
+impl<R: Read> Deadline<R> {
+  fn new(inner: R, deadline: Option<Instant>) -> Self {
+    Self { inner, deadline }
+  }
+}
+
+impl<R: Read> Read for Deadline<R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if self
+      .deadline
+      .map_or(false, |deadline| Instant::now() >= deadline)
+    {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "the request body took too long to send",
+      ));
+    }
+    self.inner.read(buf)
+  }
+}
+
+/// The status answering a request whose body couldn't be read.
+pub(crate) fn read_error_status(error: &std::io::Error) -> u16 {
+  if error.get_ref().map_or(false, |e| e.is::<BodyTooLarge>()) {
+    return 413;
+  }
+  match error.kind() {
+    std::io::ErrorKind::Unsupported => 415,
+    std::io::ErrorKind::PermissionDenied => 403,
+    std::io::ErrorKind::TimedOut => 408,
+    _ => 400,
+  }
+}
+
+/// Turns `request` away with `429 Too Many Requests`, closing its connection to shed load.
+fn reject(request: Request, context: &Context) {
+  let mut r = Response::empty(429u16).with_header(
+    Header::from_str(&format!(
+      "Retry-After: {}",
+      context.limits.retry_after_secs()
+    ))
+    .unwrap(),
+  );
+  cors(&request, &mut r, context);
+  let _ = keep_alive::respond_and_close(request, r, &[]);
+}
+
+/// The invokes waiting for a response by callback, with their audit entry, the session cookie
+/// to set, when they were dispatched and their count among the requests of their listener.
+type Requests = Arc<Mutex<HashMap<u32, (Request, audit::Entry, Option<String>, Instant, Busy)>>>;
+
+/// Counts a request of a tiny_http listener as in progress until dropped, standing in for its
+/// connection, which tiny_http doesn't report. Also counts the connections and invokes of the
+/// WebSocket listener.
+pub(crate) struct Busy(Arc<AtomicUsize>);
+
+impl Busy {
+  pub(crate) fn new(counter: &Arc<AtomicUsize>) -> Self {
+    counter.fetch_add(1, Ordering::SeqCst);
+    Self(counter.clone())
+  }
+
+  /// The other requests in progress on the listener.
+  pub(crate) fn others(&self) -> usize {
+    self.0.load(Ordering::SeqCst).saturating_sub(1)
+  }
+}
+
+impl Drop for Busy {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/// Answers the invokes pending for longer than `timeout` with `504 Gateway Timeout`.
+fn expire_requests(requests: &Requests, timeout: Duration, context: &Context) {
+  let expired = {
+    let mut requests = requests.locked();
+    let keys = requests
+      .iter()
+      .filter(|(_, (_, _, _, dispatched, _))| dispatched.elapsed() >= timeout)
+      .map(|(key, _)| *key)
+      .collect::<Vec<_>>();
+    keys
+      .into_iter()
+      .filter_map(|key| requests.remove(&key).map(|pending| (key, pending)))
+      .collect::<Vec<_>>()
+  };
+  for (callback, (request, entry, _, _, _)) in expired {
+    log::warn!(
+      "the invoke with callback {} got no response within {:?}",
+      callback,
+      timeout
+    );
+    context.cancellations.cancel(callback);
+    let mut r = Response::empty(504u16);
+    cors(&request, &mut r, context);
+    let _ = request.respond(r);
+    entry.finish(504);
+  }
+}
+
+/// The headers of `request` as passed to commands.
+fn request_headers(request: &Request) -> tauri::http::HeaderMap {
+  (&request
+    .headers()
+    .iter()
+    .map(|h| (h.field.to_string(), h.value.to_string()))
+    .collect::<HashMap<_, _>>())
+    .try_into()
+    .unwrap_or_default()
+}
+
+/// Answers a request received by the tiny_http server.
+fn handle<R: Runtime>(
+  app: &AppHandle<R>,
+  context: &Context,
+  requests: &Requests,
+  mut busy: Busy,
+  mut request: Request,
+) {
+  log::debug!(
+    "{} {} from {:?}",
+    request.method(),
+    request.url(),
+    request.remote_addr()
+  );
+  // tiny_http accepts connections itself, so refused clients get their first request answered
+  if !context
+    .ip_filter
+    .allows(request.remote_addr().map(SocketAddr::ip))
+  {
+    let _ = request.respond(Response::empty(403u16));
+    return;
+  }
+  if !context.is_local_request(
+    request.remote_addr().map(SocketAddr::ip),
+    header(&request, "Host").as_deref(),
+  ) {
+    let _ = request.respond(Response::from_string("invalid host").with_status_code(403));
+    return;
+  }
+  if let Some(route) = context.custom_route(request.method().as_str(), request.url()) {
+    let limit = context
+      .limits
+      .max_raw_size
+      .map_or(u64::MAX, |max| max as u64);
+    let mut body = Vec::new();
+    let read = request
+      .as_reader()
+      .take(limit.saturating_add(1))
+      .read_to_end(&mut body);
+    let mut r = if read.is_err() {
+      Response::from_data(Vec::new()).with_status_code(400)
+    } else if body.len() as u64 > limit {
+      Response::from_data(Vec::new()).with_status_code(413)
+    } else {
+      let route_request = MiddlewareRequest {
+        method: request.method().to_string(),
+        url: request.url().to_string(),
+        headers: request_headers(&request),
+        peer: request.remote_addr().map(SocketAddr::ip),
+      };
+      let response = (route.handler)(&route_request, &body);
+      let mut r = Response::from_data(response.body).with_status_code(response.status);
+      add_headers(&mut r, &response.headers);
+      r
+    };
+    cors(&request, &mut r, context);
+    let _ = request.respond(r);
+    return;
+  }
+  if request.method() == &Method::Get && context.is_asset(request.url()) {
+    let asset = context
+      .assets
+      .as_ref()
+      .and_then(|assets| assets::resolve(app, assets, request.url()));
+    let _ = match asset {
+      Some(asset) => {
+        let mut r = Response::from_data(asset.bytes)
+          .with_header(Header::from_bytes("Content-Type", asset.mime_type).unwrap());
+        if let Some(csp) = asset.csp {
+          r.add_header(Header::from_bytes("Content-Security-Policy", csp).unwrap());
+        }
+        let session = context.session(header(&request, "Cookie").as_deref());
+        if let Some((_, Some(cookie))) = session {
+          r.add_header(Header::from_bytes("Set-Cookie", cookie).unwrap());
+        }
+        request.respond(r)
+      }
+      None => request.respond(Response::empty(404u16)),
+    };
+    return;
+  }
+  let url = match context.route(request.url()) {
+    Some(path) => path.to_string(),
+    None => {
+      let mut r = Response::empty(404u16);
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      return;
+    }
+  };
+  if request.method() != &Method::Options
+    && !context.is_authorized(header(&request, "Authorization").as_deref(), request.url())
+  {
+    let mut r = Response::empty(401u16);
+    cors(&request, &mut r, context);
+    let _ = request.respond(r);
+    return;
+  }
+  let peer = request.remote_addr().map(SocketAddr::ip);
+  // the headers as changed by the middleware
+  let mut intercepted = None;
+  if request.method() != &Method::Options && !context.middleware.is_empty() {
+    let mut middleware_request = MiddlewareRequest {
+      method: request.method().to_string(),
+      url: request.url().to_string(),
+      headers: request_headers(&request),
+      peer,
+    };
+    if let Some(response) = context.intercept(app, &mut middleware_request) {
+      let mut r = Response::from_data(response.body).with_status_code(response.status);
+      add_headers(&mut r, &response.headers);
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      return;
+    }
+    intercepted = Some(middleware_request.headers);
+  }
+  let trusted = context.is_trusted_proxy(peer);
+  let origin = context.origin(trusted, &|name| header(&request, name));
+  if request.method() == &Method::Get {
+    let pending = requests.locked().len();
+    let base_url = context.public_base_url(trusted, &|name| header(&request, name));
+    if let Some((status, body)) = status_route(app, context, &url, &base_url, pending) {
+      let mut r = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_str("Content-Type: application/json").unwrap());
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      return;
+    }
+  }
+  if context.closing.load(Ordering::SeqCst) {
+    let mut r = Response::empty(503u16);
+    cors(&request, &mut r, context);
+    let _ = request.respond(r);
+    return;
+  }
+  if request.method() == &Method::Options {
+    let mut r = Response::empty(200u16);
+    for (field, value) in preflight_headers(
+      header(&request, "Origin").as_deref(),
+      header(&request, "Access-Control-Request-Headers").as_deref(),
+      context,
+    ) {
+      r.add_header(Header::from_bytes(field, value).unwrap());
+    }
+    let _ = request.respond(r);
+    return;
+  }
+  if context.limits.too_many_connections(busy.others()) {
+    reject(request, context);
+    return;
+  }
+  let pieces = url
+    .split('?')
+    .next()
+    .unwrap_or_default()
+    .split('/')
+    .collect::<Vec<_>>();
+  // the path prefix on its own, e.g. `/api?x`, names no window
+  let window_label = match pieces.get(1) {
+    Some(label) => *label,
+    None => {
+      let mut r = Response::empty(404u16);
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      return;
+    }
+  };
+
+  if request.method() == &Method::Get && pieces.get(2) == Some(&"nonce") {
+    if let Some(nonces) = &context.nonces {
+      if !context.allows_origin(origin.as_deref()) {
+        let _ = request.respond(Response::empty(403u16));
+        return;
+      }
+      let mut r = Response::from_string(nonces.issue())
+        .with_header(Header::from_str("Cache-Control: no-store").unwrap());
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      return;
+    }
+  }
+
+  if request.method() == &Method::Get
+    && (pieces.get(2) == Some(&"channel") || pieces.get(2) == Some(&"poll"))
+  {
+    if !context.allows_origin(origin.as_deref()) {
+      let _ = request.respond(Response::empty(403u16));
+      return;
+    }
+    let stream_format = sse::StreamFormat::from_accept(header(&request, "Accept").as_deref());
+    let mut r = Response::empty(200u16);
+    cors(&request, &mut r, context);
+    if pieces[2] == "poll" {
+      let headers = r.headers().to_vec();
+      poll::poll(
+        &context.mailboxes,
+        window_label,
+        Box::new(move |messages| {
+          let mut r = Response::from_string(messages)
+            .with_header(Header::from_str("Content-Type: application/json").unwrap());
+          for header in headers {
+            r.add_header(header);
+          }
+          let _ = request.respond(r);
+        }),
+      );
+    } else if let Ok(stream) = sse::EventStream::open(request, r, stream_format) {
+      context
+        .channels
+        .locked()
+        .entry(window_label.to_string())
+        .or_default()
+        .push(stream);
+    }
+    return;
+  }
+
+  if request.method() == &Method::Get && pieces.get(2) == Some(&"events") {
+    if let Some(events) = &context.events {
+      if !context.allows_origin(origin.as_deref()) {
+        let _ = request.respond(Response::empty(403u16));
+        return;
+      }
+      let names = match events::requested(request.url()) {
+        Some(names) => names,
+        None => {
+          let mut r = Response::from_string("invalid event name").with_status_code(400);
+          cors(&request, &mut r, context);
+          let _ = request.respond(r);
+          return;
+        }
+      };
+      let webview = match find_webview(app, window_label) {
+        Some(webview) => webview,
+        None => {
+          let mut r = Response::empty(404u16);
+          cors(&request, &mut r, context);
+          let _ = request.respond(r);
+          return;
+        }
+      };
+      let stream_format = sse::StreamFormat::from_accept(header(&request, "Accept").as_deref());
+      let mut r = Response::empty(200u16);
+      cors(&request, &mut r, context);
+      if let Ok(stream) = sse::EventStream::open(request, r, stream_format) {
+        events.subscribe(&webview, names, stream);
+      }
+      return;
+    }
+  }
+
+  if request.method() == &Method::Post && pieces.len() == 4 && pieces[2] == "events" {
+    if let Some(events) = &context.events {
+      if !context.allows_origin(origin.as_deref()) {
+        let _ = request.respond(Response::from_string("origin not allowed").with_status_code(403));
+        return;
+      }
+      let (status, body) =
+        if header(&request, INVOKE_KEY_HEADER).as_deref() != Some(app.invoke_key()) {
+          (403, "invalid invoke key".to_string())
+        } else {
+          let limit = context
+            .limits
+            .max_raw_size
+            .map_or(u64::MAX, |max| max as u64);
+          let mut body = Vec::new();
+          let read = request
+            .as_reader()
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut body);
+          if read.is_err() {
+            (400, String::new())
+          } else if body.len() as u64 > limit {
+            (413, String::new())
+          } else {
+            events.handle(app, context, window_label, pieces[3], &body)
+          }
+        };
+      let mut r = Response::from_string(body).with_status_code(status);
+      if status == 200 {
+        r.add_header(Header::from_str("Content-Type: application/json").unwrap());
+      }
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      return;
+    }
+  }
+
+  if request.method() != &Method::Post {
+    let mut r =
+      Response::empty(405u16).with_header(Header::from_bytes("Allow", INVOKE_METHODS).unwrap());
+    cors(&request, &mut r, context);
+    let _ = request.respond(r);
+    return;
+  }
+
+  let mut entry = context
+    .auditor
+    .begin(&context.metrics, origin.as_deref(), window_label);
+  if origin.is_none() {
+    let _ = request.respond(Response::from_string("missing origin").with_status_code(400));
+    entry.finish(400);
+    return;
+  }
+  // browsers enforce the allowed origins through CORS, other clients are stopped here
+  if !context.allows_origin(origin.as_deref()) {
+    let _ = request.respond(Response::from_string("origin not allowed").with_status_code(403));
+    entry.finish(403);
+    return;
+  }
+  if let Some(window) = wait_for_window(app, context, window_label) {
+    // Tauri drops invokes with a wrong key without answering them
+    if header(&request, INVOKE_KEY_HEADER).as_deref() != Some(app.invoke_key()) {
+      let mut r = Response::from_string("invalid invoke key").with_status_code(403);
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      entry.finish(403);
+      return;
+    }
+    if let Some(nonces) = &context.nonces {
+      if !nonces.spend(header(&request, csrf::NONCE_HEADER).as_deref()) {
+        let mut r = Response::from_string("invalid nonce").with_status_code(403);
+        cors(&request, &mut r, context);
+        let _ = request.respond(r);
+        entry.finish(403);
+        return;
+      }
+    }
+    if let Err(e) = context.check_replay(&|name| header(&request, name)) {
+      let mut r = Response::from_string(e).with_status_code(403);
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      entry.finish(403);
+      return;
+    }
+    if let Err(retry_after) =
+      context.rate_limit(window_label, peer, trusted, origin.as_deref(), &|name| {
+        header(&request, name)
+      })
+    {
+      let mut r = Response::empty(429u16)
+        .with_header(Header::from_bytes("Retry-After", retry_after).unwrap());
+      cors(&request, &mut r, context);
+      let _ = request.respond(r);
+      entry.finish(429);
+      return;
+    }
+    if context.limits.too_many_pending(requests.locked().len()) {
+      reject(request, context);
+      entry.finish(429);
+      return;
+    }
+    let kind = BodyKind::from_content_type(header(&request, "Content-Type").as_deref());
+    let mut headers = intercepted.unwrap_or_else(|| request_headers(&request));
+    if !trusted {
+      strip_forwarded(&mut headers);
+    }
+    let cookie = match context.session(header(&request, "Cookie").as_deref()) {
+      Some((session, cookie)) => {
+        if let Ok(session) = session.parse() {
+          headers.insert(
+            tauri::http::HeaderName::from_bytes(SESSION_HEADER.as_bytes()).unwrap(),
+            session,
+          );
+        }
+        cookie
+      }
+      None => None,
+    };
+    let limit = context.limits.body_limit(&kind);
+    if limit.map_or(false, |limit| {
+      request.body_length().map_or(false, |len| len > limit)
+    }) {
+      let mut r = Response::empty(413u16);
+      cors(&request, &mut r, context);
+      let _ = keep_alive::respond_and_close(request, r, &[]);
+      entry.finish(413);
+      return;
+    }
+    let parse = entry.parse();
+    let spool = context.spool_path(&kind, request.body_length());
+    let encoding = header(&request, "Content-Encoding");
+    let read = match context.read_timeouts.body {
+      Some(timeout) => {
+        // a blocking read can't be interrupted, so it runs on its own thread, which counts as a
+        // connection until the read returns
+        if context.limits.too_many_threads(busy.others()) {
+          reject(request, context);
+          entry.finish(429);
+          return;
+        }
+        let deadline = Instant::now() + timeout;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let path = spool.clone();
+        let context = context.clone();
+        std::thread::spawn(move || {
+          let read = read_body(
+            &mut request,
+            encoding.as_deref(),
+            path.as_deref(),
+            limit,
+            Some(deadline),
+            &context,
+          );
+          // past the deadline the worker has moved on, so the request is answered here, where
+          // the rest of its body is drained once the response is sent
+          let answer = |request: Request| {
+            if let Some(path) = &path {
+              let _ = std::fs::remove_file(path);
+            }
+            let mut r = Response::empty(408u16);
+            cors(&request, &mut r, &context);
+            let _ = request.respond(r);
+          };
+          match read {
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => answer(request),
+            read => {
+              if let Err(std::sync::mpsc::SendError((request, busy, _))) =
+                sender.send((request, busy, read))
+              {
+                answer(request);
+                drop(busy);
+              }
+            }
+          }
+        });
+        match receiver.recv_timeout(timeout) {
+          Ok((r, b, read)) => {
+            request = r;
+            busy = b;
+            read
+          }
+          Err(_) => {
+            entry.finish(408);
+            return;
+          }
+        }
+      }
+      None => read_body(
+        &mut request,
+        encoding.as_deref(),
+        spool.as_deref(),
+        limit,
+        None,
+        context,
+      ),
+    };
+    let payload = read
+      .map_err(|e| (read_error_status(&e), e.to_string()))
+      .and_then(|content| {
+        invoke_request(
+          app.invoke_key(),
+          context.isolation_key.as_ref(),
+          origin.map(|origin| acl_origin(origin, &context.acl_origins)),
+          |name| header(&request, name),
+          pieces.get(2).copied(),
+          headers,
+          &kind,
+          content,
+          spool.as_deref(),
+        )
+        .map_err(|e| (400, e))
+      });
+    parse.end();
+    let payload = payload.and_then(|payload| {
+      entry.invoke(&payload.cmd, Some(&payload.body));
+      if context.is_allowed_command(window_label, &payload.cmd) {
+        Ok(payload)
+      } else {
+        Err((403, format!("command {} is not allowed", payload.cmd)))
+      }
+    });
+    let payload = match payload {
+      Ok(payload) => payload,
+      Err((status, e)) => {
+        if let Some(path) = &spool {
+          let _ = std::fs::remove_file(path);
+        }
+        let mut r = Response::from_string(e).with_status_code(status);
+        cors(&request, &mut r, context);
+        let _ = request.respond(r);
+        entry.finish(status);
+        return;
+      }
+    };
+    let mut payload = payload;
+    let req_key = payload.callback.0;
+    payload.headers.insert(
+      tauri::http::HeaderName::from_bytes(cancel::INVOCATION_HEADER.as_bytes()).unwrap(),
+      req_key.into(),
+    );
+    context
+      .cancellations
+      .register(req_key, &payload.cmd, window_label);
+    entry.dispatch();
+    requests
+      .locked()
+      .insert(req_key, (request, entry, cookie, Instant::now(), busy));
+    let requests = requests.clone();
+    let context = context.clone();
+    let url = payload.url.to_string();
+    window.on_message(
+      payload,
+      Box::new(move |webview, cmd, response, callback, _error| {
+        // the request is gone if the server was shut down in the meantime
+        if let Some((request, mut entry, cookie, _, _)) = requests.locked().remove(&callback.0) {
+          context.cancellations.finish(callback.0);
+          let status = {
+            let _respond = entry.respond();
+            let denied = is_acl_denial(&webview, &cmd, &url, &response);
+            respond(
+              webview.app_handle(),
+              request,
+              &cmd,
+              response,
+              denied,
+              cookie,
+              &context,
+            )
+          };
+          entry.finish(status);
+        }
+        if let Some(path) = &spool {
+          let _ = std::fs::remove_file(path);
+        }
+      }),
+    );
+  } else {
+    log::warn!("no window `{}` to send the invoke to", window_label);
+    let mut r = Response::empty(404u16);
+    cors(&request, &mut r, context);
+    let _ = request.respond(r);
+    entry.finish(404);
+  }
+}
+
+/// The threads and tasks of a started [`Invoke`].
+struct Running {
+  servers: Vec<Server>,
+  ws: Vec<ws::Handle>,
+  expire: Arc<AtomicBool>,
+  /// The addresses of the TCP listeners serving invokes.
+  local_addrs: Vec<SocketAddr>,
+}
+
+impl Running {
+  /// Closes the listeners, letting the requests already received through until `deadline`.
+  ///
+  /// The workers still handling a request at `deadline` are detached, and exit once done.
+  fn stop(self, deadline: Instant) {
+    self.expire.store(false, Ordering::SeqCst);
+    self.ws.into_iter().for_each(ws::Handle::stop);
+    for server in self.servers {
+      match server {
+        Server::Blocking {
+          server,
+          workers,
+          finished,
+        } => {
+          // each unblock releases one worker, after the requests already queued are handled
+          for _ in &workers {
+            server.unblock();
+          }
+          let done = (0..workers.len())
+            .take_while(|_| {
+              finished
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .is_ok()
+            })
+            .count();
+          if done == workers.len() {
+            for worker in workers {
+              let _ = worker.join();
+            }
+          } else {
+            log::warn!(
+              "{} workers are still busy at the shutdown deadline, leaving them to finish",
+              workers.len() - done
+            );
+          }
+        }
+        #[cfg(feature = "async-server")]
+        Server::Async(task) => task.abort(),
+      }
+    }
+  }
+}
+
+/// The server accepting connections on one socket.
+enum Server {
+  Blocking {
+    server: Arc<tiny_http::Server>,
+    workers: Vec<std::thread::JoinHandle<()>>,
+    /// Receives a message from each worker once it stops serving.
+    finished: std::sync::mpsc::Receiver<()>,
+  },
+  #[cfg(feature = "async-server")]
+  Async(tauri::async_runtime::JoinHandle<()>),
+}
+
+pub struct Invoke {
+  allowed_origins: Vec<String>,
+  origin_hook: Option<OriginHook>,
+  lifecycle_hook: Option<LifecycleHook>,
+  /// The settings applied by [`Self::reconfigure`] to the running server.
+  reconfigured: reload::Reconfigured,
+  middleware: Vec<Arc<dyn Middleware>>,
+  routes: Vec<Route>,
+  missing_origin: MissingOrigin,
+  error_format: ErrorFormat,
+  port: AtomicU16,
+  ports: Vec<u16>,
+  dual_stack: bool,
+  address: Option<IpAddr>,
+  url_host: Option<String>,
+  url_scheme: Option<String>,
+  ws_port: Option<u16>,
+  encoding: Encoding,
+  listener: Listener,
+  requests: Requests,
+  channels: sse::Subscribers,
+  mailboxes: poll::Mailboxes,
+  channel_delivery: ChannelDelivery,
+  stream_threshold: usize,
+  keep_alive: keep_alive::Tracker,
+  upload_dir: Option<PathBuf>,
+  path_prefix: String,
+  trusted_proxies: Vec<IpAddr>,
+  acl_origins: Vec<(String, String)>,
+  allowed_commands: Option<Vec<String>>,
+  denied_commands: Vec<String>,
+  extra_listeners: Vec<ExtraListener>,
+  session_token: Option<String>,
+  script_template: Option<String>,
+  client_retry: Option<ClientRetry>,
+  client_timeout: Option<Duration>,
+  ready_handshake: bool,
+  pub(crate) native_first: bool,
+  extra_scripts: Vec<String>,
+  nonces: Option<csrf::Nonces>,
+  events: Option<events::Bridge>,
+  replay: Option<replay::Guard>,
+  signing_key: Option<String>,
+  cipher: Option<encryption::Cipher>,
+  isolation_key: Option<encryption::Cipher>,
+  rate_limiter: Option<rate_limit::Limiter>,
+  windows: HashMap<String, WindowPolicy>,
+  ip_filter: ip_filter::IpFilter,
+  assets: Option<Assets>,
+  auditor: audit::Auditor,
+  metrics: metrics::Recorder,
+  strict_local: bool,
+  sessions: Option<session::Sessions>,
+  jwt: Option<JwtConfig>,
+  workers: usize,
+  limits: Limits,
+  read_timeouts: ReadTimeouts,
+  window_grace: Option<Duration>,
+  virtual_windows: HashMap<String, String>,
+  invoke_timeout: Option<Duration>,
+  cancellations: cancel::Tokens,
+  cors: CorsConfig,
+  preflight_max_age: Option<Duration>,
+  socket_options: SocketOptions,
+  closing: Arc<AtomicBool>,
+  #[cfg(feature = "async-server")]
+  pending: async_server::Pending,
+  running: Mutex<Option<Running>>,
+  #[cfg(feature = "tls")]
+  tls: Option<TlsConfig>,
+}
+
+impl Invoke {
+  /// Creates an invoke server for the `allowed_origins`, on a free port picked right away since
+  /// the initialization script needs it. Fails if no port is free.
+  pub fn new<I: Into<String>, O: IntoIterator<Item = I>>(
+    allowed_origins: O,
+  ) -> Result<Self, InvokeHttpError> {
+    let port = portpicker::pick_unused_port().ok_or(InvokeHttpError::NoFreePort)?;
+    Ok(Self::unbound(allowed_origins, port))
+  }
+
+  /// Returns an [`InvokeBuilder`] to configure the server step by step, with the settings
+  /// checked when it is built instead of when the server starts.
+  pub fn builder() -> InvokeBuilder {
+    InvokeBuilder::new()
+  }
+
+  /// Creates the server for the `allowed_origins` on `port`, `0` if none was picked yet.
+  fn unbound<I: Into<String>, O: IntoIterator<Item = I>>(allowed_origins: O, port: u16) -> Self {
+    let requests = Arc::new(Mutex::new(HashMap::new()));
+    Self {
+      allowed_origins: allowed_origins.into_iter().map(|o| o.into()).collect(),
+      origin_hook: None,
+      lifecycle_hook: None,
+      reconfigured: Default::default(),
+      middleware: Vec::new(),
+      routes: Vec::new(),
+      missing_origin: Default::default(),
+      error_format: Default::default(),
+      port: AtomicU16::new(port),
+      ports: Vec::new(),
+      dual_stack: false,
+      address: None,
+      url_host: None,
+      url_scheme: None,
+      ws_port: None,
+      encoding: Encoding::Json,
+      listener: Listener::Tcp,
+      requests,
+      channels: Default::default(),
+      mailboxes: Default::default(),
+      channel_delivery: ChannelDelivery::EventStream,
+      stream_threshold: DEFAULT_STREAM_THRESHOLD,
+      keep_alive: keep_alive::Tracker::new(KeepAlive::default()),
+      upload_dir: None,
+      path_prefix: String::new(),
+      trusted_proxies: Vec::new(),
+      acl_origins: Vec::new(),
+      allowed_commands: None,
+      denied_commands: Vec::new(),
+      extra_listeners: Vec::new(),
+      session_token: Some(generate_token()),
+      script_template: None,
+      client_retry: Some(ClientRetry::default()),
+      client_timeout: None,
+      ready_handshake: false,
+      native_first: false,
+      extra_scripts: Vec::new(),
+      nonces: None,
+      events: None,
+      replay: None,
+      signing_key: None,
+      cipher: None,
+      isolation_key: None,
+      rate_limiter: None,
+      windows: HashMap::new(),
+      ip_filter: Default::default(),
+      assets: None,
+      auditor: Default::default(),
+      metrics: Default::default(),
+      strict_local: false,
+      sessions: None,
+      jwt: None,
+      workers: DEFAULT_WORKERS,
+      limits: Limits::default(),
+      read_timeouts: ReadTimeouts::default(),
+      window_grace: None,
+      virtual_windows: HashMap::new(),
+      invoke_timeout: None,
+      cancellations: Default::default(),
+      cors: CorsConfig::default(),
+      preflight_max_age: Some(DEFAULT_PREFLIGHT_MAX_AGE),
+      socket_options: SocketOptions::default(),
+      closing: Default::default(),
+      #[cfg(feature = "async-server")]
+      pending: Default::default(),
+      running: Default::default(),
+      #[cfg(feature = "tls")]
+      tls: None,
+    }
+  }
+
+  /// Serves the invoke endpoint over HTTPS with the given certificate.
+  ///
+  /// The WebSocket transport listener is not covered and keeps using `ws://`.
+  #[cfg(feature = "tls")]
+  pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+    self.tls.replace(tls);
+    self
+  }
+
+  /// Returns the DER encoding of the first certificate in the TLS chain, if TLS is enabled.
+  #[cfg(feature = "tls")]
+  pub fn certificate_der(&self) -> Option<Vec<u8>> {
+    let pem = String::from_utf8_lossy(&self.tls.as_ref()?.certificate).into_owned();
+    let start = pem.find("-----BEGIN CERTIFICATE-----")? + "-----BEGIN CERTIFICATE-----".len();
+    let end = start + pem[start..].find("-----END CERTIFICATE-----")?;
+    let body = pem[start..end]
+      .chars()
+      .filter(|c| !c.is_whitespace())
+      .collect::<String>();
+    base64::engine::general_purpose::STANDARD.decode(body).ok()
+  }
+
+  /// Sets the body encoding used by the initialization script for the HTTP transport.
+  pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+    self.encoding = encoding;
+    self
+  }
+
+  /// Sets how the initialization script receives channel messages.
+  pub fn with_channel_delivery(mut self, channel_delivery: ChannelDelivery) -> Self {
+    self.channel_delivery = channel_delivery;
+    self
+  }
+
+  /// Delivers Tauri events to the frontend through a `GET /{label}/events` event stream, for
+  /// pages that can't be reached by the webview's script evaluation.
+  ///
+  /// The initialization script sends the `plugin:event|*` commands of `@tauri-apps/api/event` to
+  /// `POST /{label}/events/{listen,unlisten,emit,emit_to}` instead, with the same JSON arguments,
+  /// and receives the events listened to on the stream, whatever target they were listened with.
+  /// These endpoints require the invoke key and count as the commands they replace for
+  /// [`Invoke::with_allowed_commands`].
+  ///
+  /// Other clients can name their events in `event` query parameters of the stream instead of
+  /// registering listeners, and get one JSON document per line by accepting
+  /// `application/x-ndjson`.
+  pub fn with_event_bridge(mut self) -> Self {
+    self.events = Some(Default::default());
+    self
+  }
+
+  /// Sets where the invoke server listens.
+  pub fn with_listener(mut self, listener: Listener) -> Self {
+    self.listener = listener;
+    self
+  }
+
+  /// Sets the body size from which responses use chunked transfer encoding and raw uploads are
+  /// spooled to [`Self::with_upload_dir`], 32 KiB by default.
+  pub fn with_stream_threshold(mut self, bytes: usize) -> Self {
+    self.stream_threshold = bytes;
+    self
+  }
+
+  /// Sets how HTTP connections are reused between invokes.
+  pub fn with_keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+    self.keep_alive = keep_alive::Tracker::new(keep_alive);
+    self
+  }
+
+  /// Writes raw request bodies that are chunked or larger than the stream threshold to a file
+  /// in `dir` as they arrive instead of buffering them.
+  ///
+  /// The command then receives a `{ "path": "..." }` JSON payload instead of the raw body.
+  /// The file is removed once the response has been sent.
+  pub fn with_upload_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+    self.upload_dir.replace(dir.into());
+    self
+  }
+
+  /// Serves the endpoints under `prefix`, e.g. `/__tauri_invoke__/{label}` instead of `/{label}`,
+  /// so other routes can share the port. Requests outside of it get `404 Not Found`.
+  ///
+  /// The WebSocket transport has its own port and isn't affected.
+  pub fn with_path_prefix<P: AsRef<str>>(mut self, prefix: P) -> Self {
+    let prefix = prefix.as_ref().trim_matches('/');
+    self.path_prefix = if prefix.is_empty() {
+      String::new()
+    } else {
+      format!("/{}", prefix)
+    };
+    self
+  }
+
+  /// Sets the token every request to the main listener must carry, `None` to accept requests
+  /// from any local process.
+  ///
+  /// A random token is generated by default and embedded in the initialization script, so only
+  /// the app's pages can invoke. Clients send it as `Authorization: Bearer {token}`, or as the
+  /// `access_token` query parameter where headers can't be set.
+  pub fn with_session_token(mut self, token: Option<String>) -> Self {
+    self.session_token = token;
+    self
+  }
+
+  /// Builds [`Self::initialization_script`] from `template` instead of returning the generated
+  /// script as is, e.g. to wrap it in the app's own bootstrapping.
+  ///
+  /// These placeholders are replaced with JavaScript literals:
+  /// - `__INVOKE_HTTP_SCRIPT__`, the generated script, which the template must include for
+  ///   invokes to reach the server.
+  /// - `__INVOKE_HTTP_BASE_URL__`, `__INVOKE_HTTP_HOST__` and `__INVOKE_HTTP_PORT__`, the base
+  ///   URL of the server, its host and its port.
+  /// - `__INVOKE_HTTP_TOKEN__`, the session token, `null` if there is none.
+  ///
+  /// ```rust,ignore
+  /// let http = Invoke::new(["tauri://localhost"])?.with_script_template(
+  ///   "if (!window.__DISABLE_HTTP_IPC__) { __INVOKE_HTTP_SCRIPT__ }",
+  /// );
+  /// ```
+  pub fn with_script_template<T: Into<String>>(mut self, template: T) -> Self {
+    self.script_template = Some(template.into());
+    self
+  }
+
+  /// Sets how the initialization script retries requests that can't reach the server, `None`
+  /// to fail the invoke right away. [`ClientRetry::default`] is used by default.
+  ///
+  /// A connection dropped after the request was sent looks the same to the page, so such an
+  /// invoke may run twice. Enable [`Self::with_replay_protection`] to refuse the second one.
+  pub fn with_client_retry(mut self, retry: Option<ClientRetry>) -> Self {
+    self.client_retry = retry;
+    self
+  }
+
+  /// Fails invokes sent by the initialization script that aren't answered within `timeout`
+  /// with `the invoke timed out after {ms} ms`, aborting their request. The timeout covers
+  /// retries and the wait for a nonce.
+  ///
+  /// Pages can also abort an invoke by passing an `AbortSignal` as the `signal` option of
+  /// `invoke`, which fails it with `the invoke was aborted`. Both apply to the HTTP transport.
+  pub fn with_client_timeout(mut self, timeout: Duration) -> Self {
+    self.client_timeout.replace(timeout);
+    self
+  }
+
+  /// Makes the initialization script hold the page's invokes until the server answers
+  /// `GET /health`, then send them in order, so invokes sent before [`Self::start`] bound the
+  /// port don't fail.
+  ///
+  /// The health check is retried with a growing delay of up to a second. An invoke that keeps
+  /// waiting is only failed by [`Self::with_client_timeout`]. Applies to the HTTP transport.
+  pub fn with_ready_handshake(mut self) -> Self {
+    self.ready_handshake = true;
+    self
+  }
+
+  /// Keeps Tauri's own IPC in the app's webviews and serves HTTP invokes only to pages without
+  /// it, e.g. external browsers that [`Self::write_npm_package`] set up.
+  ///
+  /// [`crate::BuilderExt::invoke_http`] then leaves the invoke system alone, and
+  /// [`Self::initialization_script`] does nothing where `__TAURI_INTERNALS__.postMessage` and
+  /// `window.ipc` are available. Native invokes are answered by Tauri and don't go through the
+  /// server's checks, so they are governed by the app's capabilities only.
+  pub fn with_native_ipc_first(mut self) -> Self {
+    self.native_first = true;
+    self
+  }
+
+  /// Appends `script` to [`Self::initialization_script`], after the invoke system is set up,
+  /// e.g. to log or retry invokes through `window.__TAURI_INTERNALS__.invoke`. Scripts added
+  /// repeatedly run in order.
+  pub fn with_extra_script<S: Into<String>>(mut self, script: S) -> Self {
+    self.extra_scripts.push(script.into());
+    self
+  }
+
+  /// Requires every invoke to spend a single-use nonce, so a website open in the user's browser
+  /// can't forge invokes even if it finds the port and the session token leaks.
+  ///
+  /// Pages fetch nonces from `GET /{label}/nonce` and each invoke response hands out the next
+  /// one. The initialization script does this on its own; the WebSocket transport checks the
+  /// origin on its handshake instead.
+  pub fn with_csrf_protection(mut self) -> Self {
+    self.nonces = Some(Default::default());
+    self
+  }
+
+  /// Refuses replayed invokes, so requests captured e.g. by a debugging proxy can't be sent
+  /// again to re-run commands with side effects.
+  ///
+  /// Every invoke must carry a random id in `Tauri-Request-Id` and the time it was sent, in
+  /// milliseconds since the Unix epoch, in `Tauri-Timestamp`. Invokes sent more than `window`
+  /// away from the current time, or repeating an id seen within it, are answered with
+  /// `403 Forbidden`. With [`Self::with_request_signing`], the signature covers
+  /// `{id}.{timestamp}.` followed by the body, so the headers can't be swapped. The
+  /// initialization script handles both. Messages over the WebSocket transport carry no
+  /// headers, so [`Self::start`] refuses it along with replay protection.
+  pub fn with_replay_protection(mut self, window: Duration) -> Self {
+    self.replay = Some(replay::Guard::new(window));
+    self
+  }
+
+  /// Requires every invoke to carry an HMAC-SHA256 signature of its body in `Tauri-Signature`,
+  /// computed with a secret generated here and embedded in the initialization script. Other
+  /// local processes then can't spoof invokes even if they sniff the token off the port.
+  /// Invokes with a missing or invalid signature are answered with `403 Forbidden`.
+  ///
+  /// The signature is hex encoded and covers the body as sent. The page must run in a secure
+  /// context for the Web Crypto API. The WebSocket transport doesn't sign its invokes, so
+  /// [`Self::start`] refuses it along with signing, and its handshakes are answered with
+  /// `403 Forbidden` if signing is enabled by [`Self::reconfigure`].
+  pub fn with_request_signing(mut self) -> Self {
+    self.signing_key = Some(generate_token());
+    self
+  }
+
+  /// Encrypts the invoke request and response bodies with AES-256-GCM, for machines where
+  /// loopback traffic can be observed, e.g. by other users or debugging proxies.
+  ///
+  /// The key is generated here and handed to pages by the initialization script. Encrypted
+  /// responses are marked with `Tauri-Encryption: aes-256-gcm`. Channel streams are not
+  /// encrypted, and the page must run in a secure context for the Web Crypto API. The WebSocket
+  /// transport doesn't encrypt its messages, so [`Self::start`] refuses it along with encryption.
+  pub fn with_encryption(mut self) -> Self {
+    self.cipher = Some(encryption::Cipher::generate());
+    self
+  }
+
+  /// Opens the invoke payloads sealed by the frame of Tauri's isolation pattern with `key`, as
+  /// the native IPC does. Apps configured with the isolation pattern need this, take the key
+  /// from their context:
+  ///
+  /// ```rust,ignore
+  /// let context = tauri::generate_context!();
+  /// let mut http = tauri_invoke_http::Invoke::new(["tauri://localhost"])?;
+  /// if let tauri::Pattern::Isolation { crypto_keys, .. } = context.pattern() {
+  ///   http = http.with_isolation_key(*crypto_keys.aes_gcm().raw());
+  /// }
+  /// ```
+  ///
+  /// Payloads that weren't sealed are then refused.
+  pub fn with_isolation_key(mut self, key: [u8; 32]) -> Self {
+    self.isolation_key = Some(encryption::Cipher::new(key));
+    self
+  }
+
+  /// The secret invoke bodies are signed with, if [`Invoke::with_request_signing`] is enabled.
+  pub fn signing_key(&self) -> Option<&str> {
+    self.signing_key.as_deref()
+  }
+
+  /// The token requests to the main listener must carry, if any.
+  pub fn session_token(&self) -> Option<&str> {
+    self.session_token.as_deref()
+  }
+
+  /// Also serves invokes on `listener`, with its own allowed origins and token.
+  ///
+  /// The initialization script keeps targeting the main listener. Extra listeners are plain TCP
+  /// and share the TLS configuration.
+  pub fn with_extra_listener(mut self, listener: ExtraListener) -> Self {
+    self.extra_listeners.push(listener);
+    self
+  }
+
+  /// Records every invoke to the sink of `audit_log`, for deployments that have to keep track
+  /// of what the frontend did.
+  ///
+  /// ```rust,ignore
+  /// let http = tauri_invoke_http::Invoke::new(["tauri://localhost"])?.with_audit_log(AuditLog {
+  ///   payloads: true,
+  ///   redact: vec!["password".into()],
+  ///   ..AuditLog::new(AuditSink::File("audit.jsonl".into()))
+  /// });
+  /// ```
+  pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+    self.auditor = audit::Auditor::new(audit_log);
+    self
+  }
+
+  /// Accepts JWTs from the organization's identity provider as bearer tokens, so remote
+  /// frontends can reuse their existing auth. Requests must send either the session token or
+  /// a valid JWT, or only the JWT if the session token is disabled, and get
+  /// `401 Unauthorized` otherwise:
+  ///
+  /// ```rust,ignore
+  /// let http = tauri_invoke_http::Invoke::new(["https://app.example.com"])?
+  ///   .with_session_token(None)
+  ///   .with_jwt(JwtConfig {
+  ///     issuer: Some("https://auth.example.com".into()),
+  ///     audience: Some("desktop-app".into()),
+  ///     ..JwtConfig::new(std::env::var("JWT_SECRET").unwrap())
+  ///   });
+  /// ```
+  pub fn with_jwt(mut self, jwt: JwtConfig) -> Self {
+    self.jwt = Some(jwt);
+    self
+  }
+
+  /// Issues a session cookie to each client with its first asset or invoke response, and passes
+  /// the session ID of later invokes to the commands in the [`SESSION_HEADER`], so the browsers
+  /// using a remote frontend can be told apart:
+  ///
+  /// ```rust,ignore
+  /// #[tauri::command]
+  /// fn whoami(request: tauri::ipc::Request<'_>) -> Option<String> {
+  ///   let session = request.headers().get(tauri_invoke_http::SESSION_HEADER)?;
+  ///   session.to_str().ok().map(Into::into)
+  /// }
+  /// ```
+  ///
+  /// Sessions expire after a day without invokes. Tabs of one browser share its cookies and
+  /// therefore their session. Frontends on another origin only send the cookie with
+  /// [`CorsConfig::allow_credentials`].
+  pub fn with_sessions(mut self) -> Self {
+    self.sessions = Some(Default::default());
+    self
+  }
+
+  /// Serves the frontend `assets` on `GET` requests outside of the invoke endpoints, so one port
+  /// serves both the UI and the invoke API, e.g. for remote or LAN frontends.
+  ///
+  /// Unknown paths without an extension get `index.html`, for client side routing. Add the base
+  /// URL to the allowed origins so the served pages can invoke.
+  pub fn with_assets(mut self, assets: Assets) -> Self {
+    self.assets.replace(assets);
+    self
+  }
+
+  /// Answers `method` requests to `path` with `handler`, e.g. `GET /version` or a download
+  /// endpoint, on the same listener as the invokes. The path is matched exactly, without the
+  /// query and regardless of [`Self::with_path_prefix`].
+  ///
+  /// Routes only go through the IP filter and the host check of strict local mode, so handlers
+  /// do their own authorization. Their bodies are capped by [`Limits::max_raw_size`].
+  ///
+  /// ```rust,ignore
+  /// let http = tauri_invoke_http::Invoke::new(["tauri://localhost"])?.with_route(
+  ///   "GET",
+  ///   "/version",
+  ///   |_request, _body| MiddlewareResponse {
+  ///     body: env!("CARGO_PKG_VERSION").into(),
+  ///     ..MiddlewareResponse::new(200)
+  ///   },
+  /// );
+  /// ```
+  pub fn with_route<
+    F: Fn(&MiddlewareRequest, &[u8]) -> MiddlewareResponse + Send + Sync + 'static,
+  >(
+    mut self,
+    method: &str,
+    path: &str,
+    handler: F,
+  ) -> Self {
+    self.routes.push(Route {
+      method: method.to_string(),
+      path: path.to_string(),
+      handler: Arc::new(handler),
+    });
+    self
+  }
+
+  /// Adds `middleware` to inspect requests and invoke responses. Middleware see requests in
+  /// the order they were added and responses in the reverse order. Over the
+  /// [`Transport::WebSocket`] they see the handshake, and responses aren't annotated.
+  ///
+  /// ```rust,ignore
+  /// struct RequireApiKey;
+  ///
+  /// impl tauri_invoke_http::Middleware for RequireApiKey {
+  ///   fn on_request(
+  ///     &self,
+  ///     request: &mut MiddlewareRequest,
+  ///     context: &MiddlewareContext<'_>,
+  ///   ) -> Option<MiddlewareResponse> {
+  ///     let keys = context.state::<tauri::Wry, ApiKeys>();
+  ///     match request.headers.get("X-Api-Key") {
+  ///       Some(key) if keys.map_or(false, |keys| keys.contains(key)) => None,
+  ///       _ => Some(MiddlewareResponse::new(401)),
+  ///     }
+  ///   }
+  /// }
+  ///
+  /// let http = tauri_invoke_http::Invoke::new(["tauri://localhost"])?.with_middleware(RequireApiKey);
+  /// ```
+  pub fn with_middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+    self.middleware.push(Arc::new(middleware));
+    self
+  }
+
+  /// Calls `hook` before the response to each invoke is sent, with the command, the status
+  /// and the headers to add, e.g. a cache policy, feature flags or COOP/COEP headers.
+  ///
+  /// A shorthand for a [`Middleware`] only implementing [`Middleware::on_response`].
+  ///
+  /// ```rust,ignore
+  /// let http = tauri_invoke_http::Invoke::new(["tauri://localhost"])?.with_response_hook(
+  ///   |_cmd, _status, headers| {
+  ///     headers.insert("Cache-Control", "no-store".parse().unwrap());
+  ///   },
+  /// );
+  /// ```
+  pub fn with_response_hook<
+    F: Fn(&str, u16, &mut tauri::http::HeaderMap) + Send + Sync + 'static,
+  >(
+    self,
+    hook: F,
+  ) -> Self {
+    self.with_middleware(ResponseHook(hook))
+  }
+
+  /// Calls `hook` with the origin of every request refused because its origin isn't allowed,
+  /// e.g. to log or alert on probing. Such requests are answered with `403 Forbidden` before
+  /// they are dispatched, since CORS only stops browsers from reading the response.
+  pub fn with_rejected_origin_hook<F: Fn(Option<&str>) + Send + Sync + 'static>(
+    mut self,
+    hook: F,
+  ) -> Self {
+    self.origin_hook = Some(Arc::new(hook));
+    self
+  }
+
+  /// Calls `hook` when the server starts, binds a listener, stops or fails to start, e.g. to
+  /// show the port in a debug overlay, retry on another port or flush logs on shutdown.
+  ///
+  /// ```rust,ignore
+  /// let http = Invoke::new(["tauri://localhost"])?.with_lifecycle_hook(|event| match event {
+  ///   LifecycleEvent::Started { base_url } => println!("serving invokes at {}", base_url),
+  ///   LifecycleEvent::Error(e) => eprintln!("the invoke server failed to start: {}", e),
+  ///   _ => {}
+  /// });
+  /// ```
+  pub fn with_lifecycle_hook<F: Fn(&LifecycleEvent<'_>) + Send + Sync + 'static>(
+    mut self,
+    hook: F,
+  ) -> Self {
+    self.lifecycle_hook = Some(Arc::new(hook));
+    self
+  }
+
+  /// Sets how invokes without an `Origin` header are handled, [`MissingOrigin::Reject`] by
+  /// default.
+  ///
+  /// ```rust,ignore
+  /// use tauri_invoke_http::MissingOrigin;
+  ///
+  /// let http = tauri_invoke_http::Invoke::new(["tauri://localhost"])?
+  ///   .with_missing_origin(MissingOrigin::Fallback("tauri://localhost".into()));
+  /// ```
+  pub fn with_missing_origin(mut self, policy: MissingOrigin) -> Self {
+    self.missing_origin = policy;
+    self
+  }
+
+  /// Sets how the errors returned by commands are sent, [`ErrorFormat::Plain`] by default for
+  /// compatibility with existing clients.
+  pub fn with_error_format(mut self, format: ErrorFormat) -> Self {
+    self.error_format = format;
+    self
+  }
+
+  /// Evaluates the capabilities of invokes sent from `origin` as if they came from `url`.
+  ///
+  /// Tauri checks invokes against the capabilities of the URL of the page sending them, which
+  /// for HTTP invokes is the request origin. Origins the app doesn't know as its own, e.g. the
+  /// origin of [`Invoke::with_assets`], only get the capabilities with a matching `remote` URL;
+  /// mapping them to `tauri://localhost` grants them the local ones instead. `origin` may contain
+  /// `*` as in the allowed origins, and the first match applies.
+  ///
+  /// Invokes of commands denied by the capabilities are answered with `403 Forbidden`.
+  pub fn with_acl_origin(mut self, origin: impl Into<String>, url: impl Into<String>) -> Self {
+    self.acl_origins.push((origin.into(), url.into()));
+    self
+  }
+
+  /// Only lets the given commands be invoked through the server, whatever the capabilities
+  /// allow, e.g. to expose a handful of safe commands on the LAN. Other invokes are answered with
+  /// `403 Forbidden`.
+  ///
+  /// Plugin commands are named `plugin:{plugin}|{command}`, and `*` matches any run of
+  /// characters, e.g. `plugin:clipboard-manager|*`.
+  pub fn with_allowed_commands<C: Into<String>, I: IntoIterator<Item = C>>(
+    mut self,
+    commands: I,
+  ) -> Self {
+    self.allowed_commands = Some(commands.into_iter().map(Into::into).collect());
+    self
+  }
+
+  /// Refuses invokes of the given commands through the server, even if they are allowed by
+  /// [`Invoke::with_allowed_commands`].
+  pub fn with_denied_commands<C: Into<String>, I: IntoIterator<Item = C>>(
+    mut self,
+    commands: I,
+  ) -> Self {
+    self.denied_commands = commands.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// Trusts the `X-Forwarded-*` headers of requests sent from `proxies`, e.g. nginx or Caddy in
+  /// front of a remote frontend.
+  ///
+  /// Requests from a trusted proxy without an `Origin` are checked against the forwarded
+  /// scheme and host, and `GET /health` reports the forwarded base URL. The forwarded headers of
+  /// other peers are removed before the invoke reaches the command.
+  pub fn with_trusted_proxies<A: Into<IpAddr>, P: IntoIterator<Item = A>>(
+    mut self,
+    proxies: P,
+  ) -> Self {
+    self.trusted_proxies = proxies.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// Sets how many threads read and dispatch requests concurrently, 4 by default.
+  ///
+  /// Ignored by the `async-server` feature, which runs on [`tauri::async_runtime`].
+  pub fn with_workers(mut self, workers: usize) -> Self {
+    self.workers = workers.max(1);
+    self
+  }
+
+  /// Sets how much load the server accepts before rejecting requests with
+  /// `429 Too Many Requests`.
+  pub fn with_limits(mut self, limits: Limits) -> Self {
+    self.limits = limits;
+    self
+  }
+
+  /// Only serves clients with an address in `ips`, e.g. the devices of the LAN when binding
+  /// beyond loopback. Connections from other addresses are dropped before a request is read.
+  ///
+  /// Applies to every listener. Unix domain sockets have no address and are always served.
+  pub fn with_allowed_ips<C: Into<Cidr>, I: IntoIterator<Item = C>>(mut self, ips: I) -> Self {
+    self.ip_filter.allowed = ips.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// Drops the connections of clients with an address in `ips`, even if they are allowed by
+  /// [`Invoke::with_allowed_ips`].
+  pub fn with_denied_ips<C: Into<Cidr>, I: IntoIterator<Item = C>>(mut self, ips: I) -> Self {
+    self.ip_filter.denied = ips.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// Overrides the allowed commands and rate limit for the window with `label`, e.g. to give a
+  /// settings window more commands than the main one.
+  ///
+  /// The settings are resolved when an invoke is dispatched to the window; windows without
+  /// settings use those of the server.
+  ///
+  /// ```rust,ignore
+  /// let http = Invoke::new(["tauri://localhost"])?
+  ///   .with_allowed_commands(["greet"])
+  ///   .with_window_config("settings", WindowConfig {
+  ///     allowed_commands: Some(vec!["greet".into(), "save_settings".into()]),
+  ///     ..Default::default()
+  ///   });
+  /// ```
+  pub fn with_window_config<L: Into<String>>(mut self, label: L, config: WindowConfig) -> Self {
+    let rate_limiter = config.rate_limit.map(rate_limit::Limiter::new);
+    self.windows.insert(
+      label.into(),
+      WindowPolicy {
+        config,
+        rate_limiter,
+      },
+    );
+    self
+  }
+
+  /// Limits how many invokes each origin or client may send.
+  pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+    self.rate_limiter = Some(rate_limit::Limiter::new(rate_limit));
+    self
+  }
+
+  /// Sets how long clients may take to send requests before their connection is failed.
+  pub fn with_read_timeouts(mut self, read_timeouts: ReadTimeouts) -> Self {
+    self.read_timeouts = read_timeouts;
+    self
+  }
+
+  /// Holds invokes for windows that don't exist yet for up to `grace`, e.g. when a page invokes
+  /// during startup, instead of failing them with `404 Not Found`.
+  ///
+  /// A held invoke occupies its worker thread unless the `async-server` feature is enabled.
+  pub fn with_window_grace_period(mut self, grace: Duration) -> Self {
+    self.window_grace.replace(grace);
+    self
+  }
+
+  /// Serves invokes for `label` without a webview of that label, e.g. in CI or when the app
+  /// runs without windows. They go through the normal command handling on the webview
+  /// `host`, such as a hidden window, since Tauri needs a webview to dispatch commands.
+  ///
+  /// The allowed commands, rate limit and audit log apply as for a window named `label`, and
+  /// `GET /health` lists it. Commands see the `host` webview.
+  ///
+  /// ```rust,ignore
+  /// let http = Invoke::new(["tauri://localhost"])?.with_virtual_window("ci", "main");
+  /// ```
+  pub fn with_virtual_window<L: Into<String>, H: Into<String>>(
+    mut self,
+    label: L,
+    host: H,
+  ) -> Self {
+    self.virtual_windows.insert(label.into(), host.into());
+    self
+  }
+
+  /// Answers invokes whose command hasn't resolved after `timeout` with
+  /// `504 Gateway Timeout`, e.g. when a command never returns, so their connection isn't held
+  /// forever. The command keeps running and its result is dropped.
+  ///
+  /// Pending invokes are swept every second. Invokes over the WebSocket transport don't expire.
+  pub fn with_invoke_timeout(mut self, timeout: Duration) -> Self {
+    self.invoke_timeout.replace(timeout);
+    self
+  }
+
+  /// Sets the CORS headers sent to the allowed origins.
+  pub fn with_cors(mut self, cors: CorsConfig) -> Self {
+    self.cors = cors;
+    self
+  }
+
+  /// Sets how long browsers may cache the answer to a CORS preflight, 10 minutes by default.
+  ///
+  /// Cross-origin frontends otherwise send a preflight before each invoke. `None` leaves it to
+  /// the browser, which caches it for a few seconds at most.
+  pub fn with_preflight_max_age(mut self, max_age: Option<Duration>) -> Self {
+    self.preflight_max_age = max_age;
+    self
+  }
+
+  /// Sets the options of the listening TCP sockets, including the one of
+  /// [`Transport::WebSocket`].
+  ///
+  /// Sockets passed with [`Listener::Activated`] keep the options they were created with.
+  pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+    self.socket_options = socket_options;
+    self
+  }
+
+  /// Listens on `port` instead of the free port picked by [`Self::new`].
+  ///
+  /// `0` picks another free port right away, since the initialization script needs it before
+  /// the server is started, and keeps the current one if none is free.
+  pub fn with_port(mut self, port: u16) -> Self {
+    let port = if port == 0 {
+      portpicker::pick_unused_port().unwrap_or_else(|| self.port())
+    } else {
+      port
+    };
+    self.port = AtomicU16::new(port);
+    self
+  }
+
+  /// Listens on the first free port of `ports`, e.g. a small range the frontend can probe.
+  ///
+  /// The port is picked right away for the initialization script. If it was taken by the time
+  /// the server starts, the next ones are tried and open pages are pointed at the one that
+  /// binds, see [`Self::port`].
+  pub fn with_ports<I: IntoIterator<Item = u16>>(mut self, ports: I) -> Self {
+    self.ports = ports.into_iter().collect();
+    if let Some(port) = self
+      .ports
+      .iter()
+      .copied()
+      .find(|p| portpicker::is_free_tcp(*p))
+    {
+      self.port = AtomicU16::new(port);
+    }
+    self
+  }
+
+  /// Sets the IP address the server listens on instead of `localhost`.
+  ///
+  /// Use `127.0.0.1` or `::1` to pin a loopback family, or an unspecified address such as
+  /// `0.0.0.0` to serve remote frontends. Not used by [`Listener::Unix`].
+  pub fn with_bind_address<A: Into<IpAddr>>(mut self, address: A) -> Self {
+    self.address.replace(address.into());
+    self
+  }
+
+  /// Sets the host pages reach the server at in [`Self::base_url`] and the initialization
+  /// script, e.g. `10.0.2.2` for an Android emulator or the LAN address of the machine for
+  /// remote frontends. By default it follows the bind address, or is `localhost`.
+  ///
+  /// IPv6 addresses need their brackets, e.g. `[fd00::1]`.
+  pub fn with_url_host<H: Into<String>>(mut self, host: H) -> Self {
+    self.url_host = Some(host.into());
+    self
+  }
+
+  /// Sets the scheme of [`Self::base_url`] and the initialization script, e.g. `https` behind a
+  /// proxy terminating TLS. By default it is `https` when serving TLS and `http` otherwise. The
+  /// WebSocket transport keeps using `ws://`.
+  pub fn with_url_scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+    self.url_scheme = Some(scheme.into());
+    self
+  }
+
+  /// Listens on both `127.0.0.1` and `::1`, so the page connects whichever address `localhost`
+  /// resolves to. Takes precedence over [`Self::with_bind_address`].
+  pub fn with_dual_stack(mut self) -> Self {
+    self.dual_stack = true;
+    self
+  }
+
+  /// Binds only to `127.0.0.1`, answers requests whose `Host` isn't a loopback address with
+  /// `403 Forbidden`, and makes [`Self::start`] fail if any listener is set to an address beyond
+  /// loopback. A guard rail for apps that never intend remote access, which also shuts out DNS
+  /// rebinding attacks.
+  pub fn with_strict_local(mut self) -> Self {
+    self.strict_local = true;
+    self
+  }
+
+  /// The configured address, `127.0.0.1` by default in strict local mode.
+  fn address(&self) -> Option<IpAddr> {
+    self
+      .address
+      .or_else(|| Some(Ipv4Addr::LOCALHOST.into()).filter(|_| self.strict_local))
+  }
+
+  /// Refuses to listen on `address` if it is beyond loopback in strict local mode.
+  fn ensure_local(&self, address: IpAddr) -> Result<(), InvokeHttpError> {
+    if self.strict_local && !address.is_loopback() {
+      return Err(InvokeHttpError::NotLocal(address));
+    }
+    Ok(())
+  }
+
+  /// Refuses the WebSocket transport along with a protection it can't apply to its invokes.
+  fn ensure_transport(&self) -> Result<(), InvokeHttpError> {
+    if self.ws_port.is_none() {
+      return Ok(());
+    }
+    match self.configured_context().unsupported_over_websocket() {
+      Some(protection) => Err(InvokeHttpError::WebSocketUnsupported(protection)),
+      None => Ok(()),
+    }
+  }
+
+  fn bind_address(&self, port: u16) -> String {
+    match self.address() {
+      Some(address) => SocketAddr::new(address, port).to_string(),
+      None => format!("localhost:{}", port),
+    }
+  }
+
+  /// The host the initialization script connects to, loopback when listening on every interface.
+  fn url_host(&self) -> String {
+    if let Some(host) = &self.url_host {
+      return host.clone();
+    }
+    if self.dual_stack {
+      return "localhost".to_string();
+    }
+    match self.address() {
+      Some(IpAddr::V4(address)) if !address.is_unspecified() => address.to_string(),
+      Some(IpAddr::V6(address)) if !address.is_unspecified() => format!("[{}]", address),
+      _ => "localhost".to_string(),
+    }
+  }
+
+  /// The port the server listens on, or will try first if it isn't started.
+  pub fn port(&self) -> u16 {
+    self.port.load(Ordering::SeqCst)
+  }
+
+  /// The address the server is bound to while it runs, e.g. to log it or show it to remote
+  /// clients. `None` before [`Self::start`], after [`Self::shutdown`] and for Unix sockets.
+  ///
+  /// With [`Self::with_dual_stack`] this is the first of the bound addresses, extra listeners
+  /// aren't included.
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self
+      .running
+      .locked()
+      .as_ref()
+      .and_then(|running| running.local_addrs.first().copied())
+  }
+
+  fn scheme(&self) -> &str {
+    if let Some(scheme) = &self.url_scheme {
+      return scheme;
+    }
+    #[cfg(feature = "tls")]
+    if self.tls.is_some() {
+      return "https";
+    }
+    "http"
+  }
+
+  /// The URL the invoke endpoints are served at, including the path prefix, e.g.
+  /// `http://localhost:18436`. It follows the bound port once the server is started.
+  pub fn base_url(&self) -> String {
+    #[cfg(unix)]
+    if let Listener::Unix { proxy_url, .. } = &self.listener {
+      return format!("{}{}", proxy_url.trim_end_matches('/'), self.path_prefix);
+    }
+    format!(
+      "{}://{}:{}{}",
+      self.scheme(),
+      self.url_host(),
+      self.port(),
+      self.path_prefix
+    )
+  }
+
+  /// Sets the transport used by the generated initialization script.
+  ///
+  /// The HTTP `POST` endpoint is always served; [`Transport::WebSocket`] starts an additional
+  /// WebSocket listener on its own port, picked right away. [`Self::start`] fails if none is
+  /// free.
+  pub fn with_transport(mut self, transport: Transport) -> Self {
+    self.ws_port = match transport {
+      Transport::Http => None,
+      // a failed pick is reported by `start`
+      Transport::WebSocket => Some(
+        self
+          .ws_port
+          .or_else(portpicker::pick_unused_port)
+          .unwrap_or_default(),
+      ),
+    };
+    self
+  }
+
+  /// The context new requests are handled with.
+  fn context(&self) -> Context {
+    self.live_context().current()
+  }
+
+  fn live_context(&self) -> reload::LiveContext {
+    reload::LiveContext::new(self.configured_context(), self.reconfigured.clone(), None)
+  }
+
+  /// The context of the settings the server was created with.
+  fn configured_context(&self) -> Context {
+    Context {
+      allowed_origins: self.allowed_origins.clone(),
+      origin_hook: self.origin_hook.clone(),
+      middleware: self.middleware.clone(),
+      routes: self.routes.clone(),
+      missing_origin: self.missing_origin.clone(),
+      error_format: self.error_format,
+      channels: self.channels.clone(),
+      mailboxes: self.mailboxes.clone(),
+      stream_threshold: self.stream_threshold,
+      keep_alive: self.keep_alive.clone(),
+      upload_dir: self.upload_dir.clone(),
+      closing: self.closing.clone(),
+      limits: self.limits,
+      read_timeouts: self.read_timeouts,
+      window_grace: self.window_grace,
+      virtual_windows: Arc::new(self.virtual_windows.clone()),
+      invoke_timeout: self.invoke_timeout,
+      cancellations: self.cancellations.clone(),
+      cors: self.cors.clone(),
+      preflight_max_age: self.preflight_max_age,
+      socket_options: self.socket_options,
+      base_url: self.base_url(),
+      path_prefix: self.path_prefix.clone(),
+      trusted_proxies: self.trusted_proxies.clone(),
+      acl_origins: self.acl_origins.clone(),
+      allowed_commands: self.allowed_commands.clone(),
+      denied_commands: self.denied_commands.clone(),
+      token: self.session_token.clone(),
+      jwt: self.jwt.clone(),
+      nonces: self.nonces.clone(),
+      events: self.events.clone(),
+      replay: self.replay.clone(),
+      signing_key: self.signing_key.clone(),
+      cipher: self.cipher.clone(),
+      isolation_key: self.isolation_key.clone(),
+      rate_limiter: self.rate_limiter.clone(),
+      windows: Arc::new(self.windows.clone()),
+      ip_filter: self.ip_filter.clone(),
+      assets: self.assets.clone(),
+      auditor: self.auditor.clone(),
+      metrics: self.metrics.clone(),
+      strict_local: self.strict_local,
+      sessions: self.sessions.clone(),
+      #[cfg(feature = "async-server")]
+      pending: self.pending.clone(),
+    }
+  }
+
+  /// The addresses to listen on for `port`.
+  fn bind_addresses(&self, port: u16) -> Vec<String> {
+    if self.dual_stack {
+      vec![
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port)).to_string(),
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port)).to_string(),
+      ]
+    } else {
+      vec![self.bind_address(port)]
+    }
+  }
+
+  fn bind_port(&self, port: u16) -> std::io::Result<Vec<Socket>> {
+    self
+      .bind_addresses(port)
+      .into_iter()
+      .map(|address| bind_tcp(&address, self.socket_options).map(Socket::Tcp))
+      .collect()
+  }
+
+  fn bind(&self) -> std::io::Result<Vec<Socket>> {
+    #[cfg(unix)]
+    if let Listener::Unix { path, .. } = &self.listener {
+      remove_stale_socket(path);
+      return std::os::unix::net::UnixListener::bind(path).map(|l| vec![Socket::Unix(l)]);
+    }
+    #[cfg(unix)]
+    if let Listener::Activated = self.listener {
+      let sockets = activated_sockets()?;
+      let port = sockets.iter().find_map(|socket| match socket {
+        Socket::Tcp(listener) => listener.local_addr().ok().map(|a| a.port()),
+        _ => None,
+      });
+      if let Some(port) = port {
+        self.port.store(port, Ordering::SeqCst);
+      }
+      return Ok(sockets);
+    }
+    let port = self.port();
+    let mut result = self.bind_port(port);
+    for fallback in self.ports.iter().copied().filter(|p| *p != port) {
+      if result.is_ok() {
+        break;
+      }
+      result = self.bind_port(fallback);
+      if result.is_ok() {
+        self.port.store(fallback, Ordering::SeqCst);
+      }
+    }
+    result
+  }
+
+  fn serve_blocking(&self, socket: Socket) -> Result<tiny_http::Server, InvokeHttpError> {
+    #[cfg(feature = "tls")]
+    let ssl = self.tls.as_ref().map(|tls| tiny_http::SslConfig {
+      certificate: tls.certificate.clone(),
+      private_key: tls.private_key.clone(),
+    });
+    #[cfg(not(feature = "tls"))]
+    let ssl = None;
+    tiny_http::Server::from_listener(socket, ssl).map_err(|e| InvokeHttpError::Tls(e.to_string()))
+  }
+
+  /// Starts serving invokes for the windows of `app`.
+  ///
+  /// With the `async-server` feature, requests are served by hyper on
+  /// [`tauri::async_runtime`], except over TLS which only the tiny_http server supports.
+  ///
+  /// The server runs until [`Self::shutdown`] is called or the `Invoke` is dropped, so keep it
+  /// alive, e.g. with [`Manager::manage`]. Fails if the port or socket can't be bound, if
+  /// [`Self::with_cors`] names a header or method that can't be sent, or if
+  /// [`Transport::WebSocket`] is combined with [`Self::with_request_signing`],
+  /// [`Self::with_replay_protection`] or [`Self::with_encryption`].
+  ///
+  /// The hook of [`Self::with_lifecycle_hook`] is called once the listeners are bound, or with
+  /// the error. It runs once `start` is done with the server, so it may call [`Self::restart`],
+  /// e.g. to retry on another port.
+  pub fn start<R: Runtime>(&self, app: AppHandle<R>) -> Result<(), InvokeHttpError> {
+    let hook = match &self.lifecycle_hook {
+      Some(hook) => hook,
+      None => return self.start_serving(app).map(|_| ()),
+    };
+    match self.start_serving(app) {
+      Ok(true) => {
+        let local_addrs = self
+          .running
+          .locked()
+          .as_ref()
+          .map(|running| running.local_addrs.clone())
+          .unwrap_or_default();
+        for address in local_addrs {
+          hook(&LifecycleEvent::Bound(address));
+        }
+        hook(&LifecycleEvent::Started {
+          base_url: &self.base_url(),
+        });
+        Ok(())
+      }
+      Ok(false) => Ok(()),
+      Err(e) => {
+        hook(&LifecycleEvent::Error(&e));
+        Err(e)
+      }
+    }
+  }
+
+  /// Starts the server unless it is running, returning whether it was started.
+  fn start_serving<R: Runtime>(&self, app: AppHandle<R>) -> Result<bool, InvokeHttpError> {
+    let mut running = self.running.locked();
+    if running.is_some() {
+      return Ok(false);
+    }
+    if self.ws_port == Some(0) {
+      return Err(InvokeHttpError::NoFreePort);
+    }
+    self.cors.validate()?;
+    self.ensure_transport()?;
+    if let (Some(address), false) = (self.address, self.dual_stack) {
+      self.ensure_local(address)?;
+    }
+    for listener in &self.extra_listeners {
+      self.ensure_local(listener.address.ip())?;
+    }
+    let port = self.port();
+    let sockets = self.bind()?;
+    let mut local_addrs = Vec::new();
+    for socket in &sockets {
+      // activated sockets are bound by the service manager
+      if let Socket::Tcp(listener) = socket {
+        let address = listener.local_addr()?;
+        self.ensure_local(address.ip())?;
+        local_addrs.push(address);
+      }
+    }
+    // the port the OS picked when asked for port 0
+    if let Some(address) = local_addrs.first() {
+      self.port.store(address.port(), Ordering::SeqCst);
+    }
+    if self.port() != port {
+      announce_base_url(&app, &self.base_url());
+    }
+    let mut extra = Vec::new();
+    for listener in &self.extra_listeners {
+      extra.push((
+        bind_tcp(&listener.address.to_string(), self.socket_options)?,
+        listener,
+      ));
+    }
+    let mut ws = Vec::new();
+    for address in self
+      .ws_port
+      .map(|p| self.bind_addresses(p))
+      .unwrap_or_default()
+    {
+      match ws::start(app.clone(), address, self.live_context()) {
+        Ok(handle) => ws.push(handle),
+        Err(e) => {
+          ws.into_iter().for_each(ws::Handle::stop);
+          return Err(e.into());
+        }
+      }
+    }
+    self.closing.store(false, Ordering::SeqCst);
+    let context = self.live_context();
+    let sockets = sockets
+      .into_iter()
+      .map(|socket| (socket, context.clone()))
+      .chain(extra.into_iter().map(|(listener, extra)| {
+        let configured = Context {
+          allowed_origins: extra.allowed_origins.clone(),
+          token: extra.token.clone(),
+          base_url: format!("{}://{}{}", self.scheme(), extra.address, self.path_prefix),
+          ..self.configured_context()
+        };
+        let overrides = reload::ListenerOverrides {
+          allowed_origins: extra.allowed_origins.clone(),
+          token: extra.token.clone(),
+        };
+        let context =
+          reload::LiveContext::new(configured, self.reconfigured.clone(), Some(overrides));
+        (Socket::Tcp(listener), context)
+      }))
+      .collect::<Vec<_>>();
+    let expire = Arc::new(AtomicBool::new(true));
+    {
+      let live = context.clone();
+      let requests = self.requests.clone();
+      let expire = expire.clone();
+      std::thread::spawn(move || {
+        while expire.load(Ordering::SeqCst) {
+          std::thread::sleep(std::time::Duration::from_secs(1));
+          let context = live.current();
+          poll::expire(&context.mailboxes);
+          if let Some(timeout) = context.invoke_timeout {
+            expire_requests(&requests, timeout, &context);
+          }
+        }
+      });
+    }
+
+    #[cfg(feature = "async-server")]
+    {
+      #[cfg(feature = "tls")]
+      let tls = self.tls.is_some();
+      #[cfg(not(feature = "tls"))]
+      let tls = false;
+      if !tls {
+        let servers = sockets
+          .into_iter()
+          .map(|(socket, context)| Server::Async(async_server::start(app.clone(), socket, context)))
+          .collect();
+        running.replace(Running {
+          servers,
+          ws,
+          expire,
+          local_addrs,
+        });
+        return Ok(true);
+      }
+    }
+
+    let mut servers = Vec::new();
+    for (socket, context) in sockets {
+      let server = match self.serve_blocking(socket) {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+          Running {
+            servers,
+            ws,
+            expire,
+            local_addrs,
+          }
+          .stop(Instant::now() + SHUTDOWN_TIMEOUT);
+          return Err(e);
+        }
+      };
+      let in_progress = Arc::new(AtomicUsize::new(0));
+      let (finished, finished_rx) = std::sync::mpsc::channel();
+      let workers = (0..self.workers)
+        .map(|_| {
+          let server = server.clone();
+          let app = app.clone();
+          let live = context.clone();
+          let requests = self.requests.clone();
+          let in_progress = in_progress.clone();
+          let finished = finished.clone();
+          std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+              let busy = Busy::new(&in_progress);
+              let context = live.current();
+              let _accept = trace::accept(request.remote_addr().map(SocketAddr::ip));
+              // a panicking handler drops its request, which tiny_http answers with
+              // `500 Internal Server Error`, and the worker keeps serving
+              let handled = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                handle(&app, &context, &requests, busy, request)
+              }));
+              if handled.is_err() {
+                log::error!("a request handler panicked, its request is answered with 500");
+              }
+            }
+            let _ = finished.send(());
+          })
+        })
+        .collect();
+      servers.push(Server::Blocking {
+        server,
+        workers,
+        finished: finished_rx,
+      });
+    }
+    running.replace(Running {
+      servers,
+      ws,
+      expire,
+      local_addrs,
+    });
+    Ok(true)
+  }
+
+  /// Stops the server, failing the invokes that are still in flight.
+  pub fn stop(&self) {
+    self.shutdown(Duration::from_secs(0));
+  }
+
+  /// Stops accepting requests and frees the port, then waits up to `timeout` for the invokes
+  /// in flight to be answered.
+  ///
+  /// The workers still handling a request after `timeout` are left to finish on their own,
+  /// holding the port until then, so neither this nor dropping the server hangs on them.
+  ///
+  /// The invokes still pending afterwards are failed with `503 Service Unavailable`, and open
+  /// channel streams and polls are closed. Connections of the WebSocket transport are closed
+  /// right away, cancelling their pending invokes. Returns whether every invoke was answered in
+  /// time.
+  pub fn shutdown(&self, timeout: Duration) -> bool {
+    let running = match self.running.locked().take() {
+      Some(running) => running,
+      None => return true,
+    };
+    self.closing.store(true, Ordering::SeqCst);
+    let deadline = Instant::now() + timeout;
+    running.stop(deadline);
+
+    while self.in_flight() > 0 && Instant::now() < deadline {
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    let answered = self.in_flight() == 0;
+    let context = self.context();
+    for (callback, (request, entry, _, _, _)) in self.requests.locked().drain() {
+      context.cancellations.cancel(callback);
+      let mut r = Response::empty(503u16);
+      cors(&request, &mut r, &context);
+      let _ = request.respond(r);
+      entry.finish(503);
+    }
+    #[cfg(feature = "async-server")]
+    for (_, sender) in self.pending.locked().drain() {
+      let _ = sender.send((
+        InvokeResponse::Err(InvokeError::from("the invoke server was shut down")),
+        false,
+      ));
+    }
+    self.channels.locked().clear();
+    if let Some(events) = &self.events {
+      events.close();
+    }
+    poll::close(&self.mailboxes);
+    if let Some(hook) = &self.lifecycle_hook {
+      hook(&LifecycleEvent::Stopped);
+    }
+    answered
+  }
+
+  /// Shuts the server down and starts it again on `port`, e.g. after a network change, and
+  /// returns the new base URL. Fails if `port` can't be bound.
+  ///
+  /// The pages open in the app's windows are pointed at the new URL, and [`BASE_URL_EVENT`] is
+  /// emitted with it. Pages loaded afterwards still get the URL baked into
+  /// [`Self::initialization_script`], so they need another restart or a fixed port.
+  pub fn restart<R: Runtime>(
+    &self,
+    app: AppHandle<R>,
+    port: u16,
+  ) -> Result<String, InvokeHttpError> {
+    self.shutdown(SHUTDOWN_TIMEOUT);
+    self.port.store(port, Ordering::SeqCst);
+    self.start(app.clone())?;
+    let base_url = self.base_url();
+    announce_base_url(&app, &base_url);
+    Ok(base_url)
+  }
+
+  /// Replaces the request handling settings with those of `config`, e.g. to rotate keys or
+  /// tighten limits without restarting the server. Requests received afterwards are handled
+  /// with the new settings, the ones in flight finish with the old.
+  ///
+  /// `config` is an `Invoke` that was never started, built like the running one. This applies
+  /// its allowed origins and commands, CORS, limits, rate limits, middleware, routes, hooks on
+  /// requests, session token, JWT, signing and encryption keys. The port, listeners, transport,
+  /// TLS, workers and [`Self::with_lifecycle_hook`] stay as they are, and so do the issued
+  /// nonces, sessions and event listeners while `config` keeps those features enabled. The
+  /// additional listeners keep their own origins and token.
+  ///
+  /// Pages already loaded keep the token, keys and encoding baked into their
+  /// [`Self::initialization_script`], so a new session token or key only works for pages
+  /// loaded with the script of `config`.
+  ///
+  /// ```rust,ignore
+  /// let limits = Limits { max_message_size: Some(64 * 1024), ..Default::default() };
+  /// http.reconfigure(Invoke::new(["tauri://localhost"])?.with_limits(limits));
+  /// ```
+  pub fn reconfigure(&self, config: Invoke) {
+    let context = reload::merge(self.context(), config.configured_context());
+    self.reconfigured.locked().replace(context);
+  }
+
+  /// Returns the invokes dispatched to their command and not answered yet, the oldest first,
+  /// e.g. to find the command behind a promise that never resolves.
+  ///
+  /// ```rust,ignore
+  /// for invoke in app.state::<Invoke>().pending_invokes() {
+  ///   eprintln!("{} in {} pending for {:?}", invoke.cmd, invoke.window, invoke.age);
+  /// }
+  /// ```
+  pub fn pending_invokes(&self) -> Vec<PendingInvoke> {
+    self.cancellations.pending()
+  }
+
+  /// Returns the counters of the invokes served so far, e.g. for a diagnostics screen. They
+  /// cover every listener and transport, and are kept across restarts.
+  ///
+  /// ```rust,ignore
+  /// let metrics = app.state::<Invoke>().metrics();
+  /// let errors: u64 = metrics.responses.range(500..).map(|(_, count)| count).sum();
+  /// println!("{} invokes, {} failed", metrics.requests, errors);
+  /// ```
+  pub fn metrics(&self) -> Metrics {
+    self.metrics.snapshot(self.in_flight())
+  }
+
+  fn in_flight(&self) -> usize {
+    #[cfg(feature = "async-server")]
+    let pending = self.pending.locked().len();
+    #[cfg(not(feature = "async-server"))]
+    let pending = 0;
+    self.requests.locked().len() + pending
+  }
+
+  /// Returns a responder answering the requests received by the tiny_http server.
+  pub fn responder<R: Runtime>(&self) -> Box<InvokeResponder<R>> {
+    let requests = self.requests.clone();
+    let live = self.live_context();
+    Box::new(move |webview, cmd, response, callback, _error| {
+      let (request, mut entry, cookie, _, _) = match requests.locked().remove(&callback.0) {
+        Some(pending) => pending,
+        None => return,
+      };
+      let context = live.current();
+      context.cancellations.finish(callback.0);
+      let response = match response {
+        InvokeResponse::Ok(body) => InvokeResponse::Ok(body.clone()),
+        InvokeResponse::Err(InvokeError(e)) => InvokeResponse::Err(InvokeError(e.clone())),
+      };
+      // the URL the invoke was sent with, as built from its origin
+      let url = header(&request, "Origin")
+        .map(|origin| acl_origin(origin, &context.acl_origins))
+        .and_then(|origin| Url::parse(&origin).ok())
+        .map(|url| url.to_string())
+        .unwrap_or_default();
+      let denied = is_acl_denial(webview, cmd, &url, &response);
+      let status = {
+        let _respond = entry.respond();
+        respond(
+          webview.app_handle(),
+          request,
+          cmd,
+          response,
+          denied,
+          cookie,
+          &context,
+        )
+      };
+      entry.finish(status);
+    })
+  }
+
+  /// Returns the token telling the command handling `request` whether its invoke was abandoned.
+  ///
+  /// Clients disconnecting are noticed by the `async-server` feature and the WebSocket
+  /// transport. The tiny_http server only cancels invokes that time out or are pending when the
+  /// server shuts down. Requests that weren't sent through this server get a token that is
+  /// never cancelled.
+  ///
+  /// ```rust,ignore
+  /// #[tauri::command]
+  /// fn export(request: tauri::ipc::Request<'_>, http: tauri::State<'_, Invoke>) {
+  ///   let token = http.cancellation_token(&request);
+  ///   for chunk in chunks() {
+  ///     if token.is_cancelled() {
+  ///       return;
+  ///     }
+  ///     write(chunk);
+  ///   }
+  /// }
+  /// ```
+  pub fn cancellation_token(&self, request: &tauri::ipc::Request<'_>) -> CancellationToken {
+    request
+      .headers()
+      .get(cancel::INVOCATION_HEADER)
+      .and_then(|id| id.to_str().ok()?.parse().ok())
+      .and_then(|callback| self.cancellations.get(callback))
+      .unwrap_or_default()
+  }
+
+  /// Returns a channel interceptor that delivers [`tauri::ipc::Channel`] messages to the
+  /// window's `GET /{label}/channel` event stream or `GET /{label}/poll` long-poll.
+  ///
+  /// Register it with [`tauri::Builder::channel_interceptor`]. Messages for windows without a
+  /// connected stream fall back to the default delivery.
+  pub fn channel_interceptor<R: Runtime>(
+    &self,
+  ) -> impl Fn(&Webview<R>, CallbackFn, usize, &InvokeResponseBody) -> bool + Send + Sync + 'static
+  {
+    let channels = self.channels.clone();
+    let mailboxes = self.mailboxes.clone();
+    move |webview, callback, index, body| {
+      let data = match body {
+        InvokeResponseBody::Json(json) => format!(
+          "{{\"id\":{},\"index\":{},\"message\":{}}}",
+          callback.0, index, json
+        ),
+        InvokeResponseBody::Raw(bytes) => format!(
+          "{{\"id\":{},\"index\":{},\"raw\":\"{}\"}}",
+          callback.0,
+          index,
+          base64::engine::general_purpose::STANDARD.encode(bytes)
+        ),
+      };
+      let streamed = sse::broadcast(&channels, webview.label(), &data);
+      let polled = poll::push(&mailboxes, webview.label(), &data);
+      streamed || polled
+    }
+  }
+
+  fn channel_script(&self) -> String {
+    format!(
+      "
+        const channelMessage = ({{ id, index, message, raw }}) => {{
+          window.__TAURI_INTERNALS__.runCallback(id, {{
+            index,
+            message: raw === undefined ? message : Uint8Array.from(atob(raw), (c) => c.charCodeAt(0)).buffer
+          }})
+        }}
+        const pollChannel = () => {{
+          httpRequest('GET', windowUrl() + '/poll', {{}}, null, 'text').then((reply) => {{
+            if (reply.status === 200) {{
+              JSON.parse(reply.body).forEach(channelMessage)
+            }}
+            pollChannel()
+          }}, () => setTimeout(pollChannel, 1000))
+        }}
+        const openChannel = () => {{
+          const channelEvents = new EventSource(withToken(windowUrl() + '/channel'))
+          let channelOpened = false
+          channelEvents.addEventListener('open', () => {{
+            channelOpened = true
+          }})
+          channelEvents.addEventListener('message', (event) => channelMessage(JSON.parse(event.data)))
+          channelEvents.addEventListener('error', () => {{
+            // the stream was blocked before it opened, switch to long-polling
+            if (!channelOpened) {{
+              channelEvents.close()
+              pollChannel()
+            }}
+          }})
+          // EventSource keeps reconnecting to the URL it was opened with
+          baseUrlListeners.push(() => {{
+            if (channelEvents.readyState !== EventSource.CLOSED) {{
+              channelEvents.close()
+              openChannel()
+            }}
+          }})
+        }}
+        if ({long_polling} || typeof EventSource === 'undefined') {{
+          pollChannel()
+        }} else {{
+          openChannel()
+        }}
+      ",
+      long_polling = self.channel_delivery == ChannelDelivery::LongPolling,
+    )
+  }
+
+  fn event_script(&self) -> &'static str {
+    if self.events.is_none() {
+      return "const handleEvent = () => false";
+    }
+    "
+        const eventListeners = Object.create(null)
+        let events = null
+        // the stream follows the listeners registered with the server, so it's opened once
+        const openEvents = () => {
+          if (events) {
+            events.close()
+          }
+          events = new EventSource(withToken(windowUrl() + '/events'))
+          events.addEventListener('message', (message) => {
+            const { event, payload } = JSON.parse(message.data)
+            const listeners = eventListeners[event] || {}
+            Object.keys(listeners).forEach((id) => {
+              window.__TAURI_INTERNALS__.runCallback(listeners[id], { event, payload, id: Number(id) })
+            })
+          })
+        }
+        baseUrlListeners.push(function reopen() {
+          openEvents()
+          baseUrlListeners.push(reopen)
+        })
+        // sends an event plugin command to `POST /{label}/events/{action}`
+        const eventRequest = (message, action, body, done) => {
+          const headers = { 'Content-Type': 'application/json', 'Tauri-Invoke-Key': invokeKey }
+          retriedRequest('POST', windowUrl() + '/events/' + action, headers, JSON.stringify(body), 'text').then((reply) => {
+            if (reply.status === 200) {
+              const result = JSON.parse(reply.body)
+              done(result)
+              window.__TAURI_INTERNALS__.runCallback(message.callback, result)
+            } else {
+              window.__TAURI_INTERNALS__.runCallback(message.error, reply.body)
+            }
+          }, (e) => window.__TAURI_INTERNALS__.runCallback(message.error, e.message))
+        }
+        // answers the event plugin commands here, so events reach the page through the stream
+        const handleEvent = (message) => {
+          const action = message.cmd.startsWith('plugin:event|') && message.cmd.slice('plugin:event|'.length)
+          const { event, handler, eventId: id } = message.payload || {}
+          switch (action) {
+            case 'listen':
+              if (!events) {
+                openEvents()
+              }
+              eventRequest(message, action, { event }, (id) => {
+                eventListeners[event] = eventListeners[event] || Object.create(null)
+                eventListeners[event][id] = handler
+              })
+              return true
+            case 'unlisten':
+              eventRequest(message, action, { event, id }, () => {
+                const listeners = eventListeners[event] || {}
+                if (id in listeners) {
+                  window.__TAURI_INTERNALS__.unregisterCallback(listeners[id])
+                  delete listeners[id]
+                }
+              })
+              return true
+            case 'emit':
+            case 'emit_to':
+              eventRequest(message, action, message.payload, () => {})
+              return true
+            default:
+              return false
+          }
+        }
+      "
+  }
+
+  fn websocket_script(&self, ws_port: u16) -> String {
+    format!(
+      "
+        const socketUrl = withToken('ws://{}:{}/' + window.__TAURI_INTERNALS__.metadata.currentWebview.label)
+        const queue = []
+        let socket = null
+        const connect = (url) => {{
+          socket = new WebSocket(url)
+          socket.binaryType = 'arraybuffer'
+          socket.addEventListener('open', () => {{
+            queue.splice(0).forEach((message) => socket.send(message))
+          }})
+          socket.addEventListener('message', (event) => {{
+            if (typeof event.data === 'string') {{
+              const {{ id, payload }} = JSON.parse(event.data)
+              window.__TAURI_INTERNALS__.runCallback(id, payload)
+            }} else {{
+              const id = new DataView(event.data).getUint32(0, true)
+              window.__TAURI_INTERNALS__.runCallback(id, event.data.slice(4))
+            }}
+          }})
+        }}
+        if ({}) {{
+          // the handshake spends a nonce, which it can only carry in the URL
+          retriedRequest('GET', windowUrl() + '/nonce', {{}}, null, 'text').then(({{ body }}) =>
+            connect(socketUrl + (socketUrl.includes('?') ? '&' : '?') + 'nonce=' + encodeURIComponent(body)))
+        }} else {{
+          connect(socketUrl)
+        }}
+        Object.defineProperty(__TAURI_INTERNALS__, 'postMessage', {{
+          value: (message) => {{
+            if (handleEvent(message)) {{
+              return
+            }}
+            const data = JSON.stringify(Object.assign({{ __TAURI_INVOKE_KEY__: invokeKey }}, message))
+            if (socket && socket.readyState === WebSocket.OPEN) {{
+              socket.send(data)
+            }} else {{
+              queue.push(data)
+            }}
+          }}
+        }})
+      ",
+      self.url_host(),
+      ws_port,
+      self.nonces.is_some()
+    )
+  }
+
+  fn http_script(&self) -> String {
+    // encrypted responses are decrypted from their bytes
+    let (codec, response_type, parse, body) = match self.encoding {
+      Encoding::Json => (
+        "",
+        if self.cipher.is_some() {
+          "arraybuffer"
+        } else {
+          "text"
+        },
+        "JSON.parse(typeof response === 'string' ? response : new TextDecoder().decode(response))",
+        "JSON.stringify(message)",
+      ),
+      Encoding::MessagePack => (
+        concat!(
+          include_str!("msgpack.js"),
+          "
+        const decodeResponse = (reply, response) => {
+          switch (reply.header('Content-Type')) {
+            case 'application/msgpack':
+              return msgpack.decode(response)
+            case 'application/json':
+              return JSON.parse(new TextDecoder().decode(response))
+            default:
+              return response
+          }
+        }"
+        ),
+        "arraybuffer",
+        "decodeResponse(reply, response)",
+        "msgpack.encode(message)",
+      ),
+    };
+    let content_type = match self.encoding {
+      Encoding::Json => "application/json",
+      Encoding::MessagePack => msgpack::CONTENT_TYPE,
+    };
+    // JSON mode reads the response as text, so raw results are asked for as arrays of numbers
+    let accept = match self.encoding {
+      Encoding::Json => "application/json".to_string(),
+      Encoding::MessagePack => format!("{}, application/octet-stream", msgpack::CONTENT_TYPE),
+    };
+    format!(
+      "
+        {codec}
+        const clientTimeout = {timeout}
+        // settles the invoke of `message` once, failing it with `fail` after the timeout or
+        // when the signal passed to `invoke` aborts, which also aborts its requests
+        const invocation = (message, fail) => {{
+          const external = message.options && message.options.signal
+          const controller = typeof AbortController === 'undefined' ? null : new AbortController()
+          let settled = false
+          let timer = null
+          const onAbort = () => abort('the invoke was aborted')
+          const finish = () => {{
+            settled = true
+            clearTimeout(timer)
+            if (external) {{
+              external.removeEventListener('abort', onAbort)
+            }}
+          }}
+          const abort = (reason) => {{
+            if (!settled) {{
+              finish()
+              fail(reason)
+              if (controller) {{
+                controller.abort()
+              }}
+            }}
+          }}
+          if (clientTimeout) {{
+            timer = setTimeout(() => abort('the invoke timed out after ' + clientTimeout + ' ms'), clientTimeout)
+          }}
+          if (external) {{
+            external.aborted ? onAbort() : external.addEventListener('abort', onAbort)
+          }}
+          return {{
+            signal: controller ? controller.signal : undefined,
+            settle: (callback) => {{
+              if (!settled) {{
+                finish()
+                callback()
+              }}
+            }}
+          }}
+        }}
+        // invokes wait for the server to answer `GET /health` first, so the ones sent before it
+        // bound its port are sent in order once it is up instead of failing
+        let serverReady = {handshake} ? null : Promise.resolve()
+        const whenReady = () => {{
+          if (!serverReady) {{
+            const probe = (attempt) => httpRequest('GET', baseUrl + '/health', {{}}, null, 'text')
+              .catch(() => new Promise((resolve) => setTimeout(resolve, Math.min(1000, 50 * Math.pow(2, attempt))))
+                .then(() => probe(attempt + 1)))
+            serverReady = probe(0)
+          }}
+          return serverReady
+        }}
+        const nonces = []
+        // calls `send` with an unused nonce, fetching one if none is left
+        const withNonce = (signal, send) => {{
+          if (!{csrf}) {{
+            return send(null)
+          }}
+          if (nonces.length) {{
+            return send(nonces.pop())
+          }}
+          retriedRequest('GET', windowUrl() + '/nonce', {{}}, null, 'text', signal).then(
+            (reply) => send(reply.status === 200 ? reply.body : null),
+            () => send(null)
+          )
+        }}
+        const hex = (bytes) => Array.from(new Uint8Array(bytes), (b) => b.toString(16).padStart(2, '0')).join('')
+        // the replay protection stamp is signed ahead of the body, so it can't be swapped
+        const sign = (bytes, stamp) => {{
+          const prefix = new TextEncoder().encode(stamp ? stamp.join('.') + '.' : '')
+          const signed = new Uint8Array(prefix.length + bytes.byteLength)
+          signed.set(prefix)
+          signed.set(new Uint8Array(bytes), prefix.length)
+          return crypto.subtle
+            .importKey('raw', new TextEncoder().encode(signingKey), {{ name: 'HMAC', hash: 'SHA-256' }}, false, ['sign'])
+            .then((key) => crypto.subtle.sign('HMAC', key, signed))
+            .then(hex)
+        }}
+        const cipherKey = (usage) => crypto.subtle.importKey(
+          'raw',
+          Uint8Array.from(atob(encryptionKey), (c) => c.charCodeAt(0)),
+          'AES-GCM',
+          false,
+          [usage]
+        )
+        // bodies are sent as a random nonce followed by the ciphertext
+        const encrypt = (bytes) => {{
+          const iv = crypto.getRandomValues(new Uint8Array(12))
+          return cipherKey('encrypt')
+            .then((key) => crypto.subtle.encrypt({{ name: 'AES-GCM', iv }}, key, bytes))
+            .then((ciphertext) => {{
+              const sealed = new Uint8Array(12 + ciphertext.byteLength)
+              sealed.set(iv)
+              sealed.set(new Uint8Array(ciphertext), 12)
+              return sealed
+            }})
+        }}
+        const decrypt = (sealed) => cipherKey('decrypt').then((key) =>
+          crypto.subtle.decrypt({{ name: 'AES-GCM', iv: new Uint8Array(sealed, 0, 12) }}, key, new Uint8Array(sealed, 12))
+        )
+        const isBinary = (payload) => payload instanceof ArrayBuffer || ArrayBuffer.isView(payload)
+        // binary and multipart payloads are sent as is, with the envelope in headers
+        const inHeaders = (message) => message.payload instanceof FormData || isBinary(message.payload)
+        // calls `send` with the body, content type and signature of `message`
+        const withBody = (message, stamp, send, fail) => {{
+          const multipart = message.payload instanceof FormData
+          const binary = isBinary(message.payload)
+          if (!signingKey && !encryptionKey) {{
+            // the browser sets the multipart content type with its boundary
+            if (multipart) {{
+              return send(message.payload, null, null)
+            }}
+            return binary ? send(message.payload, 'application/octet-stream', null) : send({body}, '{content_type}', null)
+          }}
+          // the signed and encrypted bytes must be the ones sent, so form data is encoded up front
+          let encoded
+          if (multipart) {{
+            const response = new Response(message.payload)
+            encoded = response.arrayBuffer().then((bytes) => [bytes, response.headers.get('Content-Type')])
+          }} else if (binary) {{
+            const payload = message.payload
+            const bytes = ArrayBuffer.isView(payload)
+              ? new Uint8Array(payload.buffer, payload.byteOffset, payload.byteLength)
+              : new Uint8Array(payload)
+            encoded = Promise.resolve([bytes, 'application/octet-stream'])
+          }} else {{
+            const body = {body}
+            encoded = Promise.resolve([typeof body === 'string' ? new TextEncoder().encode(body) : body, '{content_type}'])
+          }}
+          encoded
+            .then(([bytes, contentType]) => encryptionKey
+              ? encrypt(bytes).then((sealed) => [sealed, contentType])
+              : [bytes, contentType])
+            .then(([bytes, contentType]) => signingKey
+              ? sign(bytes, stamp).then((signature) => send(bytes, contentType, signature))
+              : send(bytes, contentType, null))
+            .catch((e) => fail(e.message))
+        }}
+        const replayProtection = {replay}
+        const errorEnvelope = {envelope}
+        Object.defineProperty(__TAURI_INTERNALS__, 'postMessage', {{
+          value: (message) => handleEvent(message) || (() => {{
+            const run = window.__TAURI_INTERNALS__.runCallback
+            const invoke = invocation(message, (reason) => run(message.error, reason))
+            const resolve = (id, arg) => invoke.settle(() => run(id, arg))
+            const fail = (reason) => resolve(message.error, reason)
+            return whenReady().then(() => withNonce(invoke.signal, (nonce) => {{
+              const stamp = replayProtection ? [hex(crypto.getRandomValues(new Uint8Array(16))), String(Date.now())] : null
+              return withBody(message, stamp, (body, contentType, signature) => {{
+                // the headers passed to `invoke` are sent too, but can't replace the ones set here
+                const extra = message.options && message.options.headers
+                const headers = Object.assign(
+                  {{}},
+                  typeof Headers !== 'undefined' && extra instanceof Headers ? Object.fromEntries(extra) : extra,
+                  {{ Accept: '{accept}', 'Tauri-Invoke-Key': invokeKey }}
+                )
+                if (nonce) {{
+                  headers['Tauri-Nonce'] = nonce
+                }}
+                if (inHeaders(message)) {{
+                  // binary and multipart bodies can't carry the envelope, so it goes in headers
+                  headers['Tauri-Cmd'] = message.cmd
+                  headers['Tauri-Callback'] = String(message.callback)
+                  headers['Tauri-Error'] = String(message.error)
+                }}
+                if (contentType) {{
+                  headers['Content-Type'] = contentType
+                }}
+                if (signature) {{
+                  headers['Tauri-Signature'] = signature
+                }}
+                if (stamp) {{
+                  headers['Tauri-Request-Id'] = stamp[0]
+                  headers['Tauri-Timestamp'] = stamp[1]
+                }}
+                retriedRequest('POST', windowUrl(), headers, body, '{response_type}', invoke.signal).then((reply) => {{
+                  const next = reply.header('Tauri-Next-Nonce')
+                  if (next) {{
+                    nonces.push(next)
+                  }}
+                  const encrypted = reply.header('Tauri-Encryption')
+                  ;(encrypted ? decrypt(reply.body) : Promise.resolve(reply.body)).then((response) => {{
+                    let arg
+                    let success = reply.status === 200
+                    try {{
+                      arg = {parse}
+                      if (!success && errorEnvelope && arg && typeof arg === 'object' && 'error' in arg) {{
+                        arg = arg.error
+                      }}
+                    }} catch (e) {{
+                      arg = 'malformed invoke response: ' + e.message
+                      success = false
+                    }}
+                    resolve(success ? message.callback : message.error, arg)
+                  }}, () => fail('failed to decrypt the invoke response'))
+                }}, (e) => fail(e.message))
+              }}, fail)
+            }}))
+          }})()
+        }})
+      ",
+      csrf = self.nonces.is_some(),
+      handshake = self.ready_handshake,
+      timeout = self
+        .client_timeout
+        .map_or(JsonValue::Null, |timeout| (timeout.as_millis() as u64).into()),
+      replay = self.replay.is_some(),
+      envelope = self.error_format == ErrorFormat::Envelope,
+      codec = codec,
+      parse = parse,
+      response_type = response_type,
+      content_type = content_type,
+      accept = accept,
+      body = body,
+    )
+  }
+
+  /// Returns a TypeScript module invoking this server's commands with `fetch`, for frontends
+  /// that don't run in a Tauri webview, e.g. a browser on the LAN. Write it into the frontend
+  /// sources at build time.
+  ///
+  /// The module exports `createClient({ invokeKey, token, window, baseUrl })`, whose
+  /// `invoke<T>(cmd, args)` resolves with the command's result. It rejects with a
+  /// `CommandError` holding the error returned by the command, or an `InvokeRejectedError`
+  /// with the status of a refused invoke. It follows [`Self::with_csrf_protection`] and
+  /// [`ErrorFormat::Envelope`], but doesn't sign, encrypt or stamp invokes.
+  ///
+  /// ```rust,ignore
+  /// std::fs::write("../src/invoke-http.ts", http.typescript_client())?;
+  /// ```
+  #[cfg(feature = "typescript")]
+  pub fn typescript_client(&self) -> String {
+    typescript::client(
+      &self.base_url(),
+      self.nonces.is_some(),
+      self.error_format == ErrorFormat::Envelope,
+    )
+  }
+
+  /// Writes an npm package named `tauri-invoke-http-client` into `dir`, for pages the app can't
+  /// inject [`Self::initialization_script`] into, e.g. remote frontends. Call it from a build
+  /// step and add the package to the frontend's dependencies.
+  ///
+  /// The package exports `install({ invokeKey, token, baseUrl, window })`, which sets up
+  /// `window.__TAURI_INTERNALS__` like the initialization script, so `@tauri-apps/api` invokes
+  /// reach this server. The token and base URL default to this server's. The signing and
+  /// encryption keys are written into the package when set, so don't publish it then.
+  ///
+  /// ```rust,ignore
+  /// http.write_npm_package("../node_modules/tauri-invoke-http-client")?;
+  /// ```
+  ///
+  /// ```js
+  /// import { install } from 'tauri-invoke-http-client'
+  ///
+  /// install({ invokeKey, token, baseUrl: 'http://192.168.1.10:18436' })
+  /// ```
+  #[cfg(feature = "typescript")]
+  pub fn write_npm_package<P: AsRef<Path>>(&self, dir: P) -> std::io::Result<()> {
+    let token = JsonValue::from(self.session_token.clone());
+    let base_url = JsonValue::from(self.base_url());
+    let script = self.script(
+      &format!("(options.token === undefined ? {} : options.token)", token),
+      &format!("(options.baseUrl || {})", base_url),
+    );
+    typescript::npm_package(dir.as_ref(), &script)
+  }
+
+  /// The script setting up the invoke system of the app's pages, to pass to
+  /// [`tauri::Builder::invoke_system`]. See [`Self::with_script_template`] and
+  /// [`Self::with_extra_script`] to customize it.
+  pub fn initialization_script(&self) -> String {
+    let generated = self.generated_script();
+    let mut script = match &self.script_template {
+      Some(template) => template
+        .replace(
+          "__INVOKE_HTTP_BASE_URL__",
+          &JsonValue::from(self.base_url()).to_string(),
+        )
+        .replace(
+          "__INVOKE_HTTP_HOST__",
+          &JsonValue::from(self.url_host()).to_string(),
+        )
+        .replace("__INVOKE_HTTP_PORT__", &self.port().to_string())
+        .replace(
+          "__INVOKE_HTTP_TOKEN__",
+          &JsonValue::from(self.session_token.clone()).to_string(),
+        )
+        // last, so the generated script's own text is left alone
+        .replace("__INVOKE_HTTP_SCRIPT__", &generated),
+      None => generated,
+    };
+    for extra in &self.extra_scripts {
+      script.push_str(";\n");
+      script.push_str(extra);
+      script.push('\n');
+    }
+    script
+  }
+
+  fn generated_script(&self) -> String {
+    self.script(
+      &JsonValue::from(self.session_token.clone()).to_string(),
+      &JsonValue::from(self.base_url()).to_string(),
+    )
+  }
+
+  /// The invoke system script, with the JavaScript expressions giving its session token and
+  /// base URL.
+  fn script(&self, session_token: &str, base_url: &str) -> String {
+    let transport = match self.ws_port {
+      Some(ws_port) => self.websocket_script(ws_port),
+      None => self.http_script(),
+    };
+    // wry's `window.ipc` is what the app's own invoke system sends through
+    let native_guard = if self.native_first {
+      "if (window.__TAURI_INTERNALS__ && typeof window.__TAURI_INTERNALS__.postMessage === 'function' && window.ipc) {
+          return
+        }"
+    } else {
+      ""
+    };
+    format!(
+      "
+      ;(function () {{
+        {}
+        // replaced with the app's invoke key by `tauri::Builder::invoke_system`
+        const invokeKey = __INVOKE_KEY__
+        const sessionToken = {}
+        const signingKey = {}
+        const encryptionKey = {}
+        // sends a request with `fetch`, or `XMLHttpRequest` in webviews without it, and resolves
+        // with the status, the response headers and the body read as `responseType`. Failing to
+        // reach the server rejects with an error telling it apart from the server's answers.
+        const httpRequest = (method, url, headers, body, responseType, signal) => {{
+          headers = Object.assign(sessionToken ? {{ Authorization: 'Bearer ' + sessionToken }} : {{}}, headers)
+          const unreachable = () => Object.assign(new Error('failed to reach the invoke server at ' + url), {{ unreachable: true }})
+          const failure = () => signal && signal.aborted ? new Error('the request was aborted') : unreachable()
+          if (typeof fetch === 'function') {{
+            return fetch(url, {{ method, headers, body, signal }})
+              .then((response) => (responseType === 'arraybuffer' ? response.arrayBuffer() : response.text())
+                .then((body) => ({{ status: response.status, header: (name) => response.headers.get(name), body }})))
+              .catch(() => Promise.reject(failure()))
+          }}
+          return new Promise((resolve, reject) => {{
+            const request = new XMLHttpRequest()
+            request.addEventListener('load', () => resolve({{
+              status: request.status,
+              header: (name) => request.getResponseHeader(name),
+              body: request.response
+            }}))
+            request.addEventListener('error', () => reject(unreachable()))
+            request.addEventListener('abort', () => reject(failure()))
+            if (signal) {{
+              signal.addEventListener('abort', () => request.abort())
+            }}
+            request.open(method, url, true)
+            request.responseType = responseType
+            Object.keys(headers).forEach((name) => request.setRequestHeader(name, headers[name]))
+            request.send(body)
+          }})
+        }}
+        const retry = {retry}
+        // `httpRequest`, retried with a growing, jittered delay while the server can't be reached
+        const retriedRequest = (method, url, headers, body, responseType, signal, attempt = 0) =>
+          httpRequest(method, url, headers, body, responseType, signal).catch((e) => {{
+            if (!retry || !e.unreachable || attempt >= retry.retries) {{
+              return Promise.reject(e)
+            }}
+            const delay = Math.min(retry.maxDelay, retry.initialDelay * Math.pow(2, attempt))
+            return new Promise((resolve) => setTimeout(resolve, delay / 2 + Math.random() * delay / 2))
+              .then(() => retriedRequest(method, url, headers, body, responseType, signal, attempt + 1))
+          }})
+        // for EventSource and WebSocket, which can't send headers
+        const withToken = (url) => sessionToken ? url + '?access_token=' + sessionToken : url
+        let baseUrl = {}
+        const baseUrlListeners = []
+        const windowUrl = () => baseUrl + '/' + window.__TAURI_INTERNALS__.metadata.currentWebview.label
+        Object.defineProperty(window, '__TAURI_INVOKE_HTTP__', {{
+          value: {{
+            get baseUrl() {{
+              return baseUrl
+            }},
+            setBaseUrl(url) {{
+              baseUrl = url
+              baseUrlListeners.splice(0).forEach((listener) => listener(url))
+            }}
+          }}
+        }})
+        {}
+        {}
+        {}
+      }})()
+    ",
+      native_guard,
+      session_token,
+      JsonValue::from(self.signing_key.clone()),
+      JsonValue::from(self.cipher.as_ref().map(encryption::Cipher::key)),
+      base_url,
+      self.channel_script(),
+      self.event_script(),
+      transport,
+      retry = self.client_retry.map_or(JsonValue::Null, |retry| {
+        serde_json::json!({
+          "retries": retry.retries,
+          "initialDelay": retry.initial_delay.as_millis() as u64,
+          "maxDelay": retry.max_delay.as_millis() as u64,
+        })
+      }),
+    )
+  }
+}
+
+impl Drop for Invoke {
+  fn drop(&mut self) {
+    self.shutdown(SHUTDOWN_TIMEOUT);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn limits_let_exactly_the_cap_through() {
+    let limits = Limits {
+      max_connections: Some(2),
+      max_pending: Some(2),
+      ..Default::default()
+    };
+    assert!(!limits.too_many_connections(1));
+    assert!(limits.too_many_connections(2));
+    assert!(!limits.too_many_pending(1));
+    assert!(limits.too_many_pending(2));
+    assert!(!Limits::default().too_many_connections(usize::MAX));
+  }
+
+  #[cfg(feature = "self-signed")]
+  #[test]
+  fn self_signed_certificates_are_served() {
+    let http = Invoke::new(["tauri://localhost"])
+      .unwrap()
+      .with_tls(TlsConfig::self_signed().unwrap());
+    // a DER certificate starts with its SEQUENCE tag
+    assert_eq!(http.certificate_der().unwrap().first(), Some(&0x30));
+    let listener = bind_socket(
+      SocketAddr::from(([127, 0, 0, 1], 0)),
+      SocketOptions::default(),
+    )
+    .unwrap();
+    // tiny_http loads the certificate and key into rustls right away
+    assert!(http.serve_blocking(Socket::Tcp(listener)).is_ok());
+  }
+
+  #[test]
+  fn cors_config_rejects_what_cant_be_sent() {
+    assert!(CorsConfig::default().validate().is_ok());
+    let config = CorsConfig {
+      allowed_headers: Some(vec!["Content-Type".into(), "X-Custom".into()]),
+      exposed_headers: vec!["X-Request-Id".into()],
+      ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    for config in [
+      CorsConfig {
+        allowed_headers: Some(vec!["Bad Header".into()]),
+        ..Default::default()
+      },
+      CorsConfig {
+        exposed_headers: vec!["X-Ünicode".into()],
+        ..Default::default()
+      },
+      CorsConfig {
+        allowed_methods: vec!["POST\r\n".into()],
+        ..Default::default()
+      },
+    ] {
+      assert!(matches!(
+        config.validate(),
+        Err(InvokeHttpError::InvalidCors(_))
+      ));
+    }
+  }
+
+  #[test]
+  fn decrypts_bodies_up_to_any_limit() {
+    let cipher = encryption::Cipher::generate();
+    let envelope = Envelope {
+      cipher: Some(&cipher),
+      ..Default::default()
+    };
+    for limit in [Some(5), Some(usize::MAX), None] {
+      let mut body = Vec::new();
+      let mut decoder = body_decoder(None, &mut body, limit, envelope).unwrap();
+      decoder.write_all(&cipher.seal(b"hello")).unwrap();
+      decoder.flush().unwrap();
+      drop(decoder);
+      assert_eq!(body, b"hello");
+    }
+  }
+
+  #[test]
+  fn matches_origin_wildcards() {
+    let pattern = "https://*.example.com";
+    // `*` may span labels, as it only stops at `/`
+    for origin in ["https://a.example.com", "https://a.b.example.com"] {
+      assert!(matches_origin(pattern, origin), "{}", origin);
+    }
+    for origin in [
+      "https://example.com",
+      "https://evil-example.com",
+      "https://a.example.com.evil.com",
+      "https://a.example.com:8443",
+      "http://a.example.com",
+      "https://evil.com/.example.com",
+    ] {
+      assert!(!matches_origin(pattern, origin), "{}", origin);
+    }
+
+    let pattern = "http://localhost:*";
+    assert!(matches_origin(pattern, "http://localhost:1420"));
+    for origin in [
+      "http://localhost",
+      "https://localhost:1420",
+      "http://localhost.evil.com:1420",
+      "http://localhost:1420/path",
+    ] {
+      assert!(!matches_origin(pattern, origin), "{}", origin);
+    }
+    assert!(matches_origin("tauri://localhost", "tauri://localhost"));
+    assert!(!matches_origin("tauri://localhost", "tauri://localhost:80"));
+  }
+
+  #[test]
+  fn matches_the_whole_token() {
+    let token = Some("secret");
+    assert!(has_token(token, Some("Bearer secret"), "/main"));
+    assert!(has_token(token, None, "/main?access_token=secret"));
+    for presented in ["Bearer secre", "Bearer secrets", "Bearer terces", "secret"] {
+      assert!(!has_token(token, Some(presented), "/main"));
+    }
+    assert!(!has_token(token, None, "/main"));
+    assert!(has_token(None, None, "/main"));
+  }
+
+  fn decode(encoding: &str, body: &[u8], limit: Option<usize>) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut sink = body_decoder(Some(encoding), &mut decoded, limit, Envelope::default())?;
+    sink.write_all(body)?;
+    sink.flush()?;
+    drop(sink);
+    Ok(decoded)
+  }
+
+  fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+  }
+
+  fn brotli(body: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+    encoder.write_all(body).unwrap();
+    drop(encoder);
+    compressed
+  }
+
+  #[test]
+  fn inflates_compressed_bodies() {
+    let body = b"hello ".repeat(1000);
+    let mut deflate = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    deflate.write_all(&body).unwrap();
+    let deflate = deflate.finish().unwrap();
+    for (encoding, compressed) in [
+      ("gzip", gzip(&body)),
+      ("X-Gzip", gzip(&body)),
+      ("deflate", deflate),
+      ("br", brotli(&body)),
+      ("identity", body.clone()),
+      // the codings in the order they were applied
+      ("br, gzip", gzip(&brotli(&body))),
+    ] {
+      assert_eq!(
+        decode(encoding, &compressed, None).unwrap(),
+        body,
+        "{}",
+        encoding
+      );
+    }
+    assert_eq!(
+      decode("zstd", &body, None).unwrap_err().kind(),
+      std::io::ErrorKind::Unsupported
+    );
+  }
+
+  #[test]
+  fn limits_bodies_after_decompression() {
+    // 16 MiB of zeros in a few KiB
+    let bomb = vec![0; 16 << 20];
+    for (encoding, compressed) in [("gzip", gzip(&bomb)), ("br", brotli(&bomb))] {
+      assert!(compressed.len() < 64 * 1024, "{}", compressed.len());
+      let error = decode(encoding, &compressed, Some(64 * 1024)).unwrap_err();
+      assert!(
+        error.get_ref().map_or(false, |e| e.is::<BodyTooLarge>()),
+        "{}: {}",
+        encoding,
+        error
+      );
+    }
+    let small = b"{}".repeat(10);
+    assert_eq!(decode("gzip", &gzip(&small), Some(20)).unwrap(), small);
+  }
+
+  #[test]
+  fn busy_counts_the_other_requests() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let first = Busy::new(&counter);
+    assert_eq!(first.others(), 0);
+    let second = Busy::new(&counter);
+    assert_eq!(second.others(), 1);
+    drop(first);
+    assert_eq!(second.others(), 0);
   }
 }
@@ -0,0 +1,63 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Counting of the invokes served, reported by [`crate::Invoke::metrics`].
+
+use {
+  crate::{LockExt, Metrics},
+  std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+  },
+};
+
+#[derive(Default)]
+struct Counters {
+  requests: u64,
+  responses: BTreeMap<u16, u64>,
+  rejected_origins: u64,
+  /// The summed latency of the answered invokes.
+  latency: Duration,
+}
+
+/// The counters shared by the listeners of a server.
+#[derive(Clone, Default)]
+pub(crate) struct Recorder(Arc<Mutex<Counters>>);
+
+impl Recorder {
+  /// Counts an invoke as received.
+  pub(crate) fn request(&self) {
+    self.0.locked().requests += 1;
+  }
+
+  /// Counts an invoke as answered with `status` after `latency`.
+  pub(crate) fn response(&self, status: u16, latency: Duration) {
+    let mut counters = self.0.locked();
+    *counters.responses.entry(status).or_default() += 1;
+    counters.latency += latency;
+  }
+
+  pub(crate) fn rejected_origin(&self) {
+    self.0.locked().rejected_origins += 1;
+  }
+
+  /// The counters so far, with the `in_flight` invokes of the server.
+  pub(crate) fn snapshot(&self, in_flight: usize) -> Metrics {
+    let counters = self.0.locked();
+    let answered: u64 = counters.responses.values().sum();
+    let average_latency = if answered == 0 {
+      Duration::default()
+    } else {
+      Duration::from_secs_f64(counters.latency.as_secs_f64() / answered as f64)
+    };
+    Metrics {
+      requests: counters.requests,
+      responses: counters.responses.clone(),
+      in_flight,
+      rejected_origins: counters.rejected_origins,
+      average_latency,
+    }
+  }
+}
@@ -0,0 +1,148 @@
+const msgpack = (function () {
+  const textEncoder = new TextEncoder()
+  const textDecoder = new TextDecoder()
+
+  function encode(value) {
+    const bytes = []
+    const be = (n, size) => {
+      for (let i = size - 1; i >= 0; i--) {
+        bytes.push(Math.floor(n / 2 ** (8 * i)) % 256)
+      }
+    }
+    const length = (n, fix, fixMax, codes) => {
+      if (n < fixMax) {
+        bytes.push(fix | n)
+      } else if (n < 256 && codes[0]) {
+        bytes.push(codes[0], n)
+      } else if (n < 65536) {
+        bytes.push(codes[1])
+        be(n, 2)
+      } else {
+        bytes.push(codes[2])
+        be(n, 4)
+      }
+    }
+    const write = (v) => {
+      if (v === null || v === undefined) {
+        bytes.push(0xc0)
+      } else if (typeof v === 'boolean') {
+        bytes.push(v ? 0xc3 : 0xc2)
+      } else if (typeof v === 'number') {
+        if (Number.isSafeInteger(v) && v >= 0) {
+          if (v < 128) {
+            bytes.push(v)
+          } else if (v < 256) {
+            bytes.push(0xcc, v)
+          } else if (v < 65536) {
+            bytes.push(0xcd)
+            be(v, 2)
+          } else if (v < 2 ** 32) {
+            bytes.push(0xce)
+            be(v, 4)
+          } else {
+            bytes.push(0xcf)
+            be(v, 8)
+          }
+        } else if (Number.isSafeInteger(v) && v >= -(2 ** 31)) {
+          if (v >= -32) {
+            bytes.push(v & 0xff)
+          } else {
+            bytes.push(0xd2)
+            be(v >>> 0, 4)
+          }
+        } else {
+          const view = new DataView(new ArrayBuffer(8))
+          view.setFloat64(0, v)
+          bytes.push(0xcb)
+          new Uint8Array(view.buffer).forEach((b) => bytes.push(b))
+        }
+      } else if (typeof v === 'string') {
+        const s = textEncoder.encode(v)
+        length(s.length, 0xa0, 32, [0xd9, 0xda, 0xdb])
+        s.forEach((b) => bytes.push(b))
+      } else if (v instanceof ArrayBuffer || ArrayBuffer.isView(v)) {
+        const b = v instanceof ArrayBuffer ? new Uint8Array(v) : new Uint8Array(v.buffer, v.byteOffset, v.byteLength)
+        length(b.length, 0, 0, [0xc4, 0xc5, 0xc6])
+        b.forEach((x) => bytes.push(x))
+      } else if (Array.isArray(v)) {
+        length(v.length, 0x90, 16, [0, 0xdc, 0xdd])
+        v.forEach(write)
+      } else if (typeof v.toJSON === 'function') {
+        write(v.toJSON())
+      } else {
+        const entries = Object.entries(v).filter(([, e]) => e !== undefined)
+        length(entries.length, 0x80, 16, [0, 0xde, 0xdf])
+        entries.forEach(([k, e]) => {
+          write(k)
+          write(e)
+        })
+      }
+    }
+    write(value)
+    return new Uint8Array(bytes)
+  }
+
+  function decode(buffer) {
+    const view = new DataView(buffer)
+    let offset = 0
+    const skip = (n) => {
+      offset += n
+      return offset - n
+    }
+    const uint = (size) => {
+      let n = 0
+      for (let i = 0; i < size; i++) {
+        n = n * 256 + view.getUint8(offset++)
+      }
+      return n
+    }
+    const str = (n) => textDecoder.decode(new Uint8Array(buffer, skip(n), n))
+    const bin = (n) => buffer.slice(skip(n), offset)
+    const array = (n) => Array.from({ length: n }, () => read())
+    const map = (n) => {
+      const o = {}
+      for (let i = 0; i < n; i++) {
+        const k = read()
+        o[k] = read()
+      }
+      return o
+    }
+    const read = () => {
+      const byte = view.getUint8(offset++)
+      if (byte < 0x80) return byte
+      if (byte < 0x90) return map(byte & 0x0f)
+      if (byte < 0xa0) return array(byte & 0x0f)
+      if (byte < 0xc0) return str(byte & 0x1f)
+      if (byte >= 0xe0) return byte - 0x100
+      switch (byte) {
+        case 0xc0: return null
+        case 0xc2: return false
+        case 0xc3: return true
+        case 0xc4: return bin(uint(1))
+        case 0xc5: return bin(uint(2))
+        case 0xc6: return bin(uint(4))
+        case 0xca: return view.getFloat32(skip(4))
+        case 0xcb: return view.getFloat64(skip(8))
+        case 0xcc: return uint(1)
+        case 0xcd: return uint(2)
+        case 0xce: return uint(4)
+        case 0xcf: return uint(8)
+        case 0xd0: return view.getInt8(skip(1))
+        case 0xd1: return view.getInt16(skip(2))
+        case 0xd2: return view.getInt32(skip(4))
+        case 0xd3: return Number(view.getBigInt64(skip(8)))
+        case 0xd9: return str(uint(1))
+        case 0xda: return str(uint(2))
+        case 0xdb: return str(uint(4))
+        case 0xdc: return array(uint(2))
+        case 0xdd: return array(uint(4))
+        case 0xde: return map(uint(2))
+        case 0xdf: return map(uint(4))
+      }
+      throw new Error(`unsupported MessagePack type 0x${byte.toString(16)}`)
+    }
+    return read()
+  }
+
+  return { encode, decode }
+})()
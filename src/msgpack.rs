@@ -0,0 +1,345 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! MessagePack encoding of JSON values, used as an alternative wire format.
+//!
+//! Only the subset that maps onto JSON is supported: binary values decode to arrays of numbers,
+//! map keys must be strings and extension types are rejected.
+
+use serde_json::{Map, Number, Value as JsonValue};
+
+pub(crate) const CONTENT_TYPE: &str = "application/msgpack";
+
+/// How deep arrays and maps may nest, so hostile bodies can't overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+pub(crate) fn encode(value: &JsonValue) -> Vec<u8> {
+  let mut out = Vec::new();
+  write(&mut out, value);
+  out
+}
+
+fn write(out: &mut Vec<u8>, value: &JsonValue) {
+  match value {
+    JsonValue::Null => out.push(0xc0),
+    JsonValue::Bool(false) => out.push(0xc2),
+    JsonValue::Bool(true) => out.push(0xc3),
+    JsonValue::Number(n) => {
+      if let Some(n) = n.as_u64() {
+        match n {
+          0..=0x7f => out.push(n as u8),
+          0x80..=0xff => out.extend_from_slice(&[0xcc, n as u8]),
+          0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+          }
+          0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+          }
+          _ => {
+            out.push(0xcf);
+            out.extend_from_slice(&n.to_be_bytes());
+          }
+        }
+      } else if let Some(n) = n.as_i64() {
+        // as_u64 failed, so n is negative
+        if n >= -32 {
+          out.push(n as u8);
+        } else if n >= i8::MIN as i64 {
+          out.extend_from_slice(&[0xd0, n as u8]);
+        } else if n >= i16::MIN as i64 {
+          out.push(0xd1);
+          out.extend_from_slice(&(n as i16).to_be_bytes());
+        } else if n >= i32::MIN as i64 {
+          out.push(0xd2);
+          out.extend_from_slice(&(n as i32).to_be_bytes());
+        } else {
+          out.push(0xd3);
+          out.extend_from_slice(&n.to_be_bytes());
+        }
+      } else {
+        out.push(0xcb);
+        out.extend_from_slice(&n.as_f64().unwrap_or_default().to_be_bytes());
+      }
+    }
+    JsonValue::String(s) => {
+      write_len(out, s.len(), 0xa0, 32, [0xd9, 0xda, 0xdb]);
+      out.extend_from_slice(s.as_bytes());
+    }
+    JsonValue::Array(a) => {
+      write_len(out, a.len(), 0x90, 16, [0, 0xdc, 0xdd]);
+      for v in a {
+        write(out, v);
+      }
+    }
+    JsonValue::Object(o) => {
+      write_len(out, o.len(), 0x80, 16, [0, 0xde, 0xdf]);
+      for (k, v) in o {
+        write_len(out, k.len(), 0xa0, 32, [0xd9, 0xda, 0xdb]);
+        out.extend_from_slice(k.as_bytes());
+        write(out, v);
+      }
+    }
+  }
+}
+
+/// Writes a length prefix: the `fix` format when `len < fix_max`, otherwise the
+/// 8/16/32-bit `codes` (a zero code means the 8-bit form doesn't exist).
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, codes: [u8; 3]) {
+  if len < fix_max {
+    out.push(fix | len as u8);
+  } else if len <= 0xff && codes[0] != 0 {
+    out.extend_from_slice(&[codes[0], len as u8]);
+  } else if len <= 0xffff {
+    out.push(codes[1]);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+  } else {
+    out.push(codes[2]);
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+  }
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<JsonValue, String> {
+  let mut reader = Reader {
+    bytes,
+    offset: 0,
+    depth: 0,
+  };
+  let value = reader.read()?;
+  if reader.offset != bytes.len() {
+    return Err("trailing bytes after MessagePack value".into());
+  }
+  Ok(value)
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  offset: usize,
+  /// The arrays and maps being read around the current value.
+  depth: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    let end = self
+      .offset
+      .checked_add(len)
+      .filter(|end| *end <= self.bytes.len())
+      .ok_or("unexpected end of MessagePack data")?;
+    let slice = &self.bytes[self.offset..end];
+    self.offset = end;
+    Ok(slice)
+  }
+
+  fn uint(&mut self, size: usize) -> Result<u64, String> {
+    Ok(
+      self
+        .take(size)?
+        .iter()
+        .fold(0u64, |n, b| (n << 8) | *b as u64),
+    )
+  }
+
+  fn int(&mut self, size: usize) -> Result<i64, String> {
+    let n = self.uint(size)?;
+    let shift = 64 - size * 8;
+    Ok(((n << shift) as i64) >> shift)
+  }
+
+  fn str(&mut self, len: usize) -> Result<String, String> {
+    String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+  }
+
+  fn bin(&mut self, len: usize) -> Result<JsonValue, String> {
+    Ok(JsonValue::Array(
+      self.take(len)?.iter().map(|b| (*b).into()).collect(),
+    ))
+  }
+
+  /// Reads the items of an array or map with `read`, one level deeper.
+  fn nested(
+    &mut self,
+    read: impl FnOnce(&mut Self) -> Result<JsonValue, String>,
+  ) -> Result<JsonValue, String> {
+    if self.depth == MAX_DEPTH {
+      return Err(format!(
+        "MessagePack data nested deeper than {} levels",
+        MAX_DEPTH
+      ));
+    }
+    self.depth += 1;
+    let value = read(self);
+    self.depth -= 1;
+    value
+  }
+
+  fn array(&mut self, len: usize) -> Result<JsonValue, String> {
+    self.nested(|reader| {
+      (0..len)
+        .map(|_| reader.read())
+        .collect::<Result<_, _>>()
+        .map(JsonValue::Array)
+    })
+  }
+
+  fn map(&mut self, len: usize) -> Result<JsonValue, String> {
+    self.nested(|reader| {
+      let mut map = Map::new();
+      for _ in 0..len {
+        let key = match reader.read()? {
+          JsonValue::String(key) => key,
+          _ => return Err("MessagePack map keys must be strings".into()),
+        };
+        map.insert(key, reader.read()?);
+      }
+      Ok(JsonValue::Object(map))
+    })
+  }
+
+  fn float(n: f64) -> JsonValue {
+    Number::from_f64(n).map_or(JsonValue::Null, JsonValue::Number)
+  }
+
+  fn read(&mut self) -> Result<JsonValue, String> {
+    let byte = self.take(1)?[0];
+    match byte {
+      0x00..=0x7f => Ok(byte.into()),
+      0x80..=0x8f => self.map((byte & 0x0f) as usize),
+      0x90..=0x9f => self.array((byte & 0x0f) as usize),
+      0xa0..=0xbf => self.str((byte & 0x1f) as usize).map(JsonValue::String),
+      0xc0 => Ok(JsonValue::Null),
+      0xc2 => Ok(false.into()),
+      0xc3 => Ok(true.into()),
+      0xc4 => {
+        let len = self.uint(1)? as usize;
+        self.bin(len)
+      }
+      0xc5 => {
+        let len = self.uint(2)? as usize;
+        self.bin(len)
+      }
+      0xc6 => {
+        let len = self.uint(4)? as usize;
+        self.bin(len)
+      }
+      0xca => {
+        let n = f32::from_bits(self.uint(4)? as u32);
+        Ok(Self::float(n as f64))
+      }
+      0xcb => Ok(Self::float(f64::from_bits(self.uint(8)?))),
+      0xcc => self.uint(1).map(Into::into),
+      0xcd => self.uint(2).map(Into::into),
+      0xce => self.uint(4).map(Into::into),
+      0xcf => self.uint(8).map(Into::into),
+      0xd0 => self.int(1).map(Into::into),
+      0xd1 => self.int(2).map(Into::into),
+      0xd2 => self.int(4).map(Into::into),
+      0xd3 => self.int(8).map(Into::into),
+      0xd9 => {
+        let len = self.uint(1)? as usize;
+        self.str(len).map(JsonValue::String)
+      }
+      0xda => {
+        let len = self.uint(2)? as usize;
+        self.str(len).map(JsonValue::String)
+      }
+      0xdb => {
+        let len = self.uint(4)? as usize;
+        self.str(len).map(JsonValue::String)
+      }
+      0xdc => {
+        let len = self.uint(2)? as usize;
+        self.array(len)
+      }
+      0xdd => {
+        let len = self.uint(4)? as usize;
+        self.array(len)
+      }
+      0xde => {
+        let len = self.uint(2)? as usize;
+        self.map(len)
+      }
+      0xdf => {
+        let len = self.uint(4)? as usize;
+        self.map(len)
+      }
+      0xe0..=0xff => Ok((byte as i8 as i64).into()),
+      _ => Err(format!("unsupported MessagePack type 0x{:x}", byte)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trips_json_values() {
+    let value = serde_json::json!({
+      "null": null,
+      "bools": [true, false],
+      "ints": [0, 127, 128, 255, 256, 65535, 65536, 4294967296u64, u64::MAX],
+      "negatives": [-1, -32, -33, -128, -129, -32768, -32769, -2147483649i64, i64::MIN],
+      "float": 1.5,
+      "strings": ["", "a".repeat(31), "b".repeat(32), "c".repeat(256), "d".repeat(65536)],
+      "long": vec![1; 16],
+      "nested": { "deeper": [{ "value": "é" }] },
+    });
+    assert_eq!(decode(&encode(&value)), Ok(value));
+  }
+
+  #[test]
+  fn decodes_binary_and_f32() {
+    assert_eq!(decode(&[0xc4, 2, 1, 2]), Ok(serde_json::json!([1, 2])));
+    assert_eq!(
+      decode(&[0xca, 0x3f, 0xc0, 0, 0]),
+      Ok(serde_json::json!(1.5))
+    );
+  }
+
+  #[test]
+  fn rejects_truncated_input() {
+    let bytes = encode(&serde_json::json!({ "name": "tests", "list": [1, 2, 3] }));
+    for len in 0..bytes.len() {
+      assert!(decode(&bytes[..len]).is_err(), "{} bytes", len);
+    }
+    // lengths running past the end
+    assert!(decode(&[0xdb, 0xff, 0xff, 0xff, 0xff]).is_err());
+    assert!(decode(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err());
+  }
+
+  #[test]
+  fn rejects_trailing_bytes() {
+    assert_eq!(
+      decode(&[0xc0, 0xc0]),
+      Err("trailing bytes after MessagePack value".into())
+    );
+  }
+
+  #[test]
+  fn rejects_non_string_keys_and_extensions() {
+    assert!(decode(&[0x81, 0x01, 0xc0]).is_err());
+    assert!(decode(&[0xd4, 0x01, 0x00]).is_err());
+  }
+
+  #[test]
+  fn rejects_deep_nesting() {
+    let nested = |depth: usize, byte: u8| {
+      let mut bytes = vec![byte; depth];
+      if byte == 0x81 {
+        // each map has a key before its nested value
+        bytes = [byte, 0xa1, b'k'].repeat(depth);
+      }
+      bytes.push(0xc0);
+      bytes
+    };
+    for byte in [0x91, 0x81] {
+      assert!(decode(&nested(MAX_DEPTH, byte)).is_ok());
+      assert!(decode(&nested(MAX_DEPTH + 1, byte)).is_err());
+      // far beyond what the stack would survive
+      assert!(decode(&nested(200_000, byte)).is_err());
+    }
+  }
+}
@@ -0,0 +1,259 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Parsing of `multipart/form-data` (RFC 7578) request bodies.
+//!
+//! File parts are concatenated into the raw invoke body and described, together with the text
+//! fields, by a JSON document forwarded in the `Tauri-Form` header.
+
+use serde_json::{json, Map, Value as JsonValue};
+
+pub(crate) const FORM_HEADER: &str = "Tauri-Form";
+
+struct Part<'a> {
+  name: String,
+  filename: Option<String>,
+  content_type: Option<String>,
+  data: &'a [u8],
+}
+
+/// Returns the boundary of a `multipart/form-data` content type.
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+  let mut params = split_params(content_type).into_iter();
+  if !params
+    .next()?
+    .trim()
+    .eq_ignore_ascii_case("multipart/form-data")
+  {
+    return None;
+  }
+  params.find_map(|p| param(p, "boundary"))
+}
+
+/// Splits `body` into the concatenated file contents and the `Tauri-Form` header value.
+pub(crate) fn parse(body: &[u8], boundary: &str) -> Result<(Vec<u8>, String), String> {
+  let mut fields = Map::new();
+  let mut files = Vec::new();
+  let mut raw = Vec::new();
+  for part in parts(body, boundary)? {
+    match part.filename {
+      Some(filename) => {
+        files.push(json!({
+          "name": part.name,
+          "filename": filename,
+          "contentType": part.content_type,
+          "offset": raw.len(),
+          "length": part.data.len(),
+        }));
+        raw.extend_from_slice(part.data);
+      }
+      None => {
+        fields.insert(
+          part.name,
+          String::from_utf8_lossy(part.data).into_owned().into(),
+        );
+      }
+    }
+  }
+  let form = json!({ "fields": fields, "files": files });
+  Ok((raw, ascii_json(&form)))
+}
+
+fn parts<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, String> {
+  let delimiter = format!("\r\n--{}", boundary).into_bytes();
+  // the first delimiter may not be preceded by a line break
+  let start = find(body, &delimiter[2..]).ok_or("missing multipart boundary")?;
+  let mut rest = &body[start + delimiter.len() - 2..];
+  let mut parts = Vec::new();
+  loop {
+    if rest.starts_with(b"--") {
+      return Ok(parts);
+    }
+    rest = rest
+      .strip_prefix(b"\r\n")
+      .ok_or("malformed multipart boundary")?;
+    let head_end = find(rest, b"\r\n\r\n").ok_or("malformed multipart part headers")?;
+    let head = std::str::from_utf8(&rest[..head_end]).map_err(|e| e.to_string())?;
+    rest = &rest[head_end + 4..];
+    let end = find(rest, &delimiter).ok_or("unterminated multipart part")?;
+    let data = &rest[..end];
+    rest = &rest[end + delimiter.len()..];
+
+    let mut name = None;
+    let mut filename = None;
+    let mut content_type = None;
+    for line in head.split("\r\n") {
+      let (field, value) = match line.split_once(':') {
+        Some(h) => h,
+        None => continue,
+      };
+      if field.trim().eq_ignore_ascii_case("Content-Disposition") {
+        for p in split_params(value) {
+          name = name.or_else(|| param(p, "name"));
+          filename = filename.or_else(|| param(p, "filename"));
+        }
+      } else if field.trim().eq_ignore_ascii_case("Content-Type") {
+        content_type.replace(value.trim().to_string());
+      }
+    }
+    parts.push(Part {
+      name: name.ok_or("multipart part without a name")?,
+      filename,
+      content_type,
+      data,
+    });
+  }
+}
+
+/// Splits a header value on the `;` separators that aren't inside quotes.
+fn split_params(value: &str) -> Vec<&str> {
+  let mut params = Vec::new();
+  let mut quoted = false;
+  let mut start = 0;
+  for (i, c) in value.char_indices() {
+    match c {
+      '"' => quoted = !quoted,
+      ';' if !quoted => {
+        params.push(&value[start..i]);
+        start = i + 1;
+      }
+      _ => {}
+    }
+  }
+  params.push(&value[start..]);
+  params
+}
+
+fn param(param: &str, name: &str) -> Option<String> {
+  let (key, value) = param.split_once('=')?;
+  if key.trim().eq_ignore_ascii_case(name) {
+    let value = value.trim();
+    Some(
+      value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string(),
+    )
+  } else {
+    None
+  }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Serializes `value` with every non-ASCII character escaped, so it is a valid header value.
+fn ascii_json(value: &JsonValue) -> String {
+  let mut out = String::new();
+  for c in value.to_string().chars() {
+    if c.is_ascii() {
+      out.push(c);
+    } else {
+      let mut units = [0u16; 2];
+      for unit in c.encode_utf16(&mut units) {
+        out.push_str(&format!("\\u{:04x}", unit));
+      }
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn form(parts: &[&str]) -> Vec<u8> {
+    let mut body = String::new();
+    for part in parts {
+      body.push_str(&format!("\r\n--b0und\r\n{}", part));
+    }
+    body.push_str("\r\n--b0und--\r\n");
+    // the first delimiter may not be preceded by a line break
+    body.as_bytes()[2..].to_vec()
+  }
+
+  #[test]
+  fn reads_the_boundary() {
+    assert_eq!(
+      boundary("multipart/form-data; boundary=b0und").as_deref(),
+      Some("b0und")
+    );
+    assert_eq!(
+      boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a;b c\"").as_deref(),
+      Some("a;b c")
+    );
+    assert_eq!(boundary("multipart/form-data"), None);
+    assert_eq!(boundary("multipart/form-data; charset=utf-8"), None);
+    assert_eq!(boundary("multipart/mixed; boundary=b0und"), None);
+  }
+
+  #[test]
+  fn splits_fields_and_files() {
+    let body = form(&[
+      "Content-Disposition: form-data; name=\"title\"\r\n\r\nnotes",
+      "Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\nContent-Type: text/plain\r\n\r\nhello",
+      "content-disposition: form-data; name=b; filename=\"b;c.bin\"\r\n\r\n\r\n--not-it\r\n",
+    ]);
+    let (raw, form) = parse(&body, "b0und").unwrap();
+    assert_eq!(raw, b"hello\r\n--not-it\r\n");
+    let form: JsonValue = serde_json::from_str(&form).unwrap();
+    assert_eq!(
+      form,
+      json!({
+        "fields": { "title": "notes" },
+        "files": [
+          { "name": "a", "filename": "a.txt", "contentType": "text/plain", "offset": 0, "length": 5 },
+          { "name": "b", "filename": "b;c.bin", "contentType": null, "offset": 5, "length": 12 },
+        ],
+      })
+    );
+  }
+
+  #[test]
+  fn escapes_non_ascii_names() {
+    let body = form(&["Content-Disposition: form-data; name=\"title\"\r\n\r\nnotes"]);
+    let body = String::from_utf8(body).unwrap().replace("title", "tìtle😀");
+    let (_, form) = parse(body.as_bytes(), "b0und").unwrap();
+    assert!(form.is_ascii());
+    let form: JsonValue = serde_json::from_str(&form).unwrap();
+    assert_eq!(form["fields"]["tìtle😀"], "notes");
+  }
+
+  #[test]
+  fn rejects_malformed_bodies() {
+    let part = "Content-Disposition: form-data; name=\"title\"\r\n\r\nnotes";
+    let body = form(&[part]);
+    assert_eq!(
+      parse(&body, "other").unwrap_err(),
+      "missing multipart boundary"
+    );
+    // the final part is cut off before its delimiter, or the delimiter before its end
+    let truncated = &body[..body.len() - "\r\n--b0und--\r\n".len()];
+    assert_eq!(
+      parse(truncated, "b0und").unwrap_err(),
+      "unterminated multipart part"
+    );
+    let truncated = &body[..body.len() - "--\r\n".len()];
+    assert_eq!(
+      parse(truncated, "b0und").unwrap_err(),
+      "malformed multipart boundary"
+    );
+    let headless = form(&["Content-Disposition: form-data; name=\"title\"notes"]);
+    assert_eq!(
+      parse(&headless, "b0und").unwrap_err(),
+      "malformed multipart part headers"
+    );
+    for part in [
+      "Content-Type: text/plain\r\n\r\nnotes",
+      "Content-Disposition: form-data; filename=\"a.txt\"\r\n\r\nnotes",
+    ] {
+      assert_eq!(
+        parse(&form(&[part]), "b0und").unwrap_err(),
+        "multipart part without a name"
+      );
+    }
+  }
+}
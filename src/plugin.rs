@@ -0,0 +1,67 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Packaging of [`Invoke`] as a Tauri plugin.
+
+use {
+  crate::{Invoke, SHUTDOWN_TIMEOUT},
+  tauri::{
+    plugin::{Builder as PluginBuilder, TauriPlugin},
+    Builder, Manager, RunEvent, Runtime,
+  },
+};
+
+/// Returns a plugin that starts `invoke` when the app is set up, keeps it in the app state and
+/// shuts it down when the app exits.
+///
+/// Plugins can't replace the invoke system, so the builder still needs
+/// [`Invoke::initialization_script`]; [`BuilderExt::invoke_http`] registers both at once.
+pub fn init<R: Runtime>(invoke: Invoke) -> TauriPlugin<R> {
+  PluginBuilder::new("invoke-http")
+    .setup(move |app, _api| {
+      invoke.start(app.clone())?;
+      app.manage(invoke);
+      Ok(())
+    })
+    .on_event(|app, event| {
+      if let RunEvent::Exit = event {
+        if let Some(invoke) = app.try_state::<Invoke>() {
+          invoke.shutdown(SHUTDOWN_TIMEOUT);
+        }
+      }
+    })
+    .build()
+}
+
+/// Wires an [`Invoke`] server into a [`tauri::Builder`].
+///
+/// ```rust,ignore
+/// use tauri_invoke_http::{BuilderExt, Invoke};
+///
+/// tauri::Builder::default()
+///   .invoke_http(Invoke::new(["tauri://localhost"])?)
+///   .run(tauri::generate_context!())?;
+/// ```
+pub trait BuilderExt {
+  /// Registers the invoke system, the channel interceptor and the [`init`] plugin. The invoke
+  /// system is left to Tauri with [`Invoke::with_native_ipc_first`].
+  ///
+  /// The initialization script is taken before the server starts, so every setting has to be
+  /// applied to `invoke` beforehand. The server is started in the plugin's setup, before the
+  /// setup hook of the builder runs.
+  fn invoke_http(self, invoke: Invoke) -> Self;
+}
+
+impl<R: Runtime> BuilderExt for Builder<R> {
+  fn invoke_http(self, invoke: Invoke) -> Self {
+    let builder = if invoke.native_first {
+      self
+    } else {
+      self.invoke_system(invoke.initialization_script())
+    };
+    builder
+      .channel_interceptor(invoke.channel_interceptor())
+      .plugin(init(invoke))
+  }
+}
@@ -0,0 +1,106 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Long-polling delivery of channel messages, for webviews that block streaming responses.
+
+use {
+  crate::LockExt,
+  std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+  },
+};
+
+/// How long a poll is held open when there is nothing to deliver.
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(25);
+
+pub(crate) type Mailboxes = Arc<Mutex<HashMap<String, Mailbox>>>;
+
+/// Sends the JSON array answering a poll.
+pub(crate) type Respond = Box<dyn FnOnce(String) + Send>;
+
+struct Poll {
+  respond: Respond,
+  since: Instant,
+}
+
+impl Poll {
+  fn answer(self, messages: &[String]) {
+    (self.respond)(format!("[{}]", messages.join(",")));
+  }
+}
+
+pub(crate) struct Mailbox {
+  queued: Vec<String>,
+  waiting: Option<Poll>,
+  last_seen: Instant,
+}
+
+/// Holds a poll until a message for `label` arrives, answering at once if some are queued.
+pub(crate) fn poll(mailboxes: &Mailboxes, label: &str, respond: Respond) {
+  let mut mailboxes = mailboxes.locked();
+  let mailbox = mailboxes
+    .entry(label.to_string())
+    .or_insert_with(|| Mailbox {
+      queued: Vec::new(),
+      waiting: None,
+      last_seen: Instant::now(),
+    });
+  mailbox.last_seen = Instant::now();
+  // a window only polls once at a time, so an older poll belongs to a reloaded page
+  if let Some(previous) = mailbox.waiting.take() {
+    previous.answer(&[]);
+  }
+  let poll = Poll {
+    respond,
+    since: Instant::now(),
+  };
+  if mailbox.queued.is_empty() {
+    mailbox.waiting.replace(poll);
+  } else {
+    poll.answer(&std::mem::take(&mut mailbox.queued));
+  }
+}
+
+/// Queues `data` for the window `label` if it is long-polling, answering its pending poll.
+///
+/// Returns whether the window is long-polling.
+pub(crate) fn push(mailboxes: &Mailboxes, label: &str, data: &str) -> bool {
+  match mailboxes.locked().get_mut(label) {
+    Some(mailbox) => {
+      mailbox.queued.push(data.to_string());
+      if let Some(poll) = mailbox.waiting.take() {
+        poll.answer(&std::mem::take(&mut mailbox.queued));
+      }
+      true
+    }
+    None => false,
+  }
+}
+
+/// Answers the polls that waited for [`TIMEOUT`] and forgets windows that stopped polling.
+pub(crate) fn expire(mailboxes: &Mailboxes) {
+  mailboxes.locked().retain(|_, mailbox| {
+    if mailbox
+      .waiting
+      .as_ref()
+      .map_or(false, |p| p.since.elapsed() >= TIMEOUT)
+    {
+      if let Some(poll) = mailbox.waiting.take() {
+        poll.answer(&[]);
+      }
+    }
+    mailbox.waiting.is_some() || mailbox.last_seen.elapsed() < TIMEOUT * 2
+  });
+}
+
+/// Answers every pending poll, so the pages stop waiting on a server that is shutting down.
+pub(crate) fn close(mailboxes: &Mailboxes) {
+  for (_, mut mailbox) in mailboxes.locked().drain() {
+    if let Some(poll) = mailbox.waiting.take() {
+      poll.answer(&[]);
+    }
+  }
+}
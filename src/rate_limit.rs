@@ -0,0 +1,148 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Token bucket enforcement of [`crate::RateLimit`].
+
+use {
+  crate::{LockExt, RateLimit},
+  std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+  },
+};
+
+/// How many buckets are kept before the full ones are forgotten.
+const MAX_BUCKETS: usize = 1024;
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+#[derive(Clone)]
+pub(crate) struct Limiter {
+  config: RateLimit,
+  buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl Limiter {
+  pub(crate) fn new(config: RateLimit) -> Self {
+    Self {
+      config,
+      buckets: Default::default(),
+    }
+  }
+
+  pub(crate) fn config(&self) -> &RateLimit {
+    &self.config
+  }
+
+  /// Takes a token from the bucket of `key`, or returns how long to wait for the next one.
+  pub(crate) fn acquire(&self, key: &str) -> Result<(), Duration> {
+    self.acquire_at(key, Instant::now())
+  }
+
+  /// [`Self::acquire`] at `now`.
+  fn acquire_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+    let capacity = f64::from(self.config.requests.max(1));
+    let rate = capacity / self.config.per.as_secs_f64().max(f64::EPSILON);
+    let mut buckets = self.buckets.locked();
+    if buckets.len() >= MAX_BUCKETS {
+      buckets.retain(|_, b| {
+        b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * rate < capacity
+      });
+    }
+    let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+      tokens: capacity,
+      updated: now,
+    });
+    let elapsed = now.saturating_duration_since(bucket.updated);
+    bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+  }
+}
+
+/// The `Retry-After` value for a `wait`, in whole seconds rounded up.
+pub(crate) fn retry_after(wait: Duration) -> String {
+  (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::RateLimitKey};
+
+  fn limiter(requests: u32, per: Duration) -> Limiter {
+    Limiter::new(RateLimit {
+      key: RateLimitKey::Origin,
+      requests,
+      per,
+    })
+  }
+
+  #[test]
+  fn lets_a_burst_through_then_refills() {
+    let limiter = limiter(3, Duration::from_secs(3));
+    let start = Instant::now();
+    for _ in 0..3 {
+      assert_eq!(limiter.acquire_at("a", start), Ok(()));
+    }
+    let wait = limiter.acquire_at("a", start).unwrap_err();
+    assert!(
+      wait > Duration::from_millis(999) && wait <= Duration::from_secs(1),
+      "{:?}",
+      wait
+    );
+    // other keys have their own bucket
+    assert_eq!(limiter.acquire_at("b", start), Ok(()));
+
+    // a token per second
+    let later = start + Duration::from_millis(1500);
+    assert_eq!(limiter.acquire_at("a", later), Ok(()));
+    let wait = limiter.acquire_at("a", later).unwrap_err();
+    assert!(
+      wait > Duration::from_millis(499) && wait <= Duration::from_millis(500),
+      "{:?}",
+      wait
+    );
+
+    // and no more than the burst after a long pause
+    let idle = later + Duration::from_secs(60);
+    for _ in 0..3 {
+      assert_eq!(limiter.acquire_at("a", idle), Ok(()));
+    }
+    assert!(limiter.acquire_at("a", idle).is_err());
+  }
+
+  #[test]
+  fn forgets_full_buckets_beyond_the_cap() {
+    let limiter = limiter(2, Duration::from_secs(1));
+    let start = Instant::now();
+    assert_eq!(limiter.acquire_at("busy", start), Ok(()));
+    assert_eq!(limiter.acquire_at("busy", start), Ok(()));
+    for key in 0..MAX_BUCKETS {
+      let _ = limiter.acquire_at(&key.to_string(), start);
+    }
+    // the refilled buckets go once the cap is reached, the drained one stays
+    let later = start + Duration::from_millis(600);
+    let _ = limiter.acquire_at("new", later);
+    let mut buckets = limiter.buckets.locked().keys().cloned().collect::<Vec<_>>();
+    buckets.sort();
+    assert_eq!(buckets, ["busy", "new"]);
+  }
+
+  #[test]
+  fn rounds_retry_after_up() {
+    assert_eq!(retry_after(Duration::from_millis(1)), "1");
+    assert_eq!(retry_after(Duration::from_secs(2)), "2");
+    assert_eq!(retry_after(Duration::from_millis(2001)), "3");
+    assert_eq!(retry_after(Duration::ZERO), "0");
+  }
+}
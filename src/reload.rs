@@ -0,0 +1,91 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Replacement of the request handling settings of a running server, see
+//! [`crate::Invoke::reconfigure`].
+//!
+//! Listeners hold a [`LiveContext`] and take a snapshot of it for each request, so a
+//! replacement applies to the requests received afterwards.
+
+use {
+  crate::{Context, LockExt},
+  std::sync::{Arc, Mutex},
+};
+
+/// The settings applied by the last [`crate::Invoke::reconfigure`], if any.
+pub(crate) type Reconfigured = Arc<Mutex<Option<Context>>>;
+
+/// The settings of the additional listeners of [`crate::Invoke::with_additional_listener`],
+/// which a replacement keeps.
+#[derive(Clone)]
+pub(crate) struct ListenerOverrides {
+  pub(crate) allowed_origins: Vec<String>,
+  pub(crate) token: Option<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct LiveContext {
+  /// The context the listener was started with.
+  configured: Context,
+  reconfigured: Reconfigured,
+  listener: Option<ListenerOverrides>,
+}
+
+impl LiveContext {
+  pub(crate) fn new(
+    configured: Context,
+    reconfigured: Reconfigured,
+    listener: Option<ListenerOverrides>,
+  ) -> Self {
+    Self {
+      configured,
+      reconfigured,
+      listener,
+    }
+  }
+
+  /// The context to handle a new request with.
+  pub(crate) fn current(&self) -> Context {
+    let reconfigured = match &*self.reconfigured.locked() {
+      Some(context) => context.clone(),
+      None => return self.configured.clone(),
+    };
+    let context = Context {
+      base_url: self.configured.base_url.clone(),
+      ..reconfigured
+    };
+    match &self.listener {
+      Some(listener) => Context {
+        allowed_origins: listener.allowed_origins.clone(),
+        token: listener.token.clone(),
+        ..context
+      },
+      None => context,
+    }
+  }
+}
+
+/// The settings of `new` with the state of the server handled with `current`.
+///
+/// Stores that only make sense for the pages and invokes of the running server, such as issued
+/// nonces and sessions, are kept while the feature stays enabled.
+pub(crate) fn merge(current: Context, new: Context) -> Context {
+  Context {
+    channels: current.channels,
+    mailboxes: current.mailboxes,
+    keep_alive: current.keep_alive,
+    closing: current.closing,
+    cancellations: current.cancellations,
+    metrics: current.metrics,
+    base_url: current.base_url,
+    path_prefix: current.path_prefix,
+    socket_options: current.socket_options,
+    #[cfg(feature = "async-server")]
+    pending: current.pending,
+    nonces: new.nonces.as_ref().and(current.nonces),
+    events: new.events.as_ref().and(current.events),
+    sessions: new.sessions.as_ref().and(current.sessions),
+    ..new
+  }
+}
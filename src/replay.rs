@@ -0,0 +1,87 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Refusal of replayed invokes.
+//!
+//! Each invoke carries a random id in `Tauri-Request-Id` and the time it was sent, in
+//! milliseconds since the Unix epoch, in `Tauri-Timestamp`. Invokes sent outside the window
+//! around the current time are refused, and so are the ids seen within it. With request signing,
+//! both are signed ahead of the body as `{id}.{timestamp}.`.
+
+use {
+  crate::LockExt,
+  std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+  },
+};
+
+pub(crate) const REQUEST_ID_HEADER: &str = "Tauri-Request-Id";
+pub(crate) const TIMESTAMP_HEADER: &str = "Tauri-Timestamp";
+
+/// How many ids are remembered before the oldest are forgotten.
+const MAX_SEEN: usize = 16384;
+
+#[derive(Clone)]
+pub(crate) struct Guard {
+  window: Duration,
+  seen: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Guard {
+  pub(crate) fn new(window: Duration) -> Self {
+    Self {
+      window,
+      seen: Default::default(),
+    }
+  }
+
+  /// Admits an invoke sent as `id` at `timestamp`, unless it falls outside the window or its id
+  /// was seen already.
+  pub(crate) fn admit(
+    &self,
+    id: Option<&str>,
+    timestamp: Option<&str>,
+  ) -> Result<(), &'static str> {
+    let id = id.filter(|id| !id.is_empty()).ok_or("missing request id")?;
+    let sent = timestamp
+      .and_then(|t| t.parse::<u64>().ok())
+      .ok_or("missing request timestamp")?;
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis() as u64;
+    if now.max(sent) - now.min(sent) > self.window.as_millis() as u64 {
+      return Err("request timestamp outside of the replay window");
+    }
+    // an id is remembered until its timestamp can't be inside the window anymore
+    let ttl = self.window * 2;
+    let mut seen = self.seen.locked();
+    seen.retain(|_, received| received.elapsed() < ttl);
+    if seen.contains_key(id) {
+      return Err("replayed request");
+    }
+    if seen.len() >= MAX_SEEN {
+      if let Some(oldest) = seen
+        .iter()
+        .min_by_key(|(_, received)| **received)
+        .map(|(id, _)| id.clone())
+      {
+        seen.remove(&oldest);
+      }
+    }
+    seen.insert(id.to_string(), Instant::now());
+    Ok(())
+  }
+}
+
+/// The bytes signed ahead of the body of an invoke sent as `id` at `timestamp`.
+pub(crate) fn signed_prefix(id: Option<&str>, timestamp: Option<&str>) -> String {
+  format!(
+    "{}.{}.",
+    id.unwrap_or_default(),
+    timestamp.unwrap_or_default()
+  )
+}
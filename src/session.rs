@@ -0,0 +1,72 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Cookie sessions telling apart the browsers using a remote frontend.
+//!
+//! Clients without a known `tauri_session` cookie are issued a new session, which their
+//! invokes then carry to the commands in `Tauri-Session`.
+
+use {
+  crate::{generate_token, LockExt},
+  std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+  },
+};
+
+const COOKIE: &str = "tauri_session";
+
+/// How long a session stays valid without invokes.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+/// How many sessions are kept before the least recently used are forgotten.
+const MAX_SESSIONS: usize = 4096;
+
+#[derive(Clone, Default)]
+pub(crate) struct Sessions(Arc<Mutex<HashMap<String, Instant>>>);
+
+impl Sessions {
+  /// Returns the session of a request sending the `cookie` header, along with the
+  /// `Set-Cookie` value if a new one was started.
+  pub(crate) fn resolve(&self, cookie: Option<&str>, secure: bool) -> (String, Option<String>) {
+    let mut sessions = self.0.locked();
+    if let Some(id) = cookie.and_then(session_cookie) {
+      if let Some(seen) = sessions.get_mut(id) {
+        if seen.elapsed() < IDLE_TIMEOUT {
+          *seen = Instant::now();
+          return (id.to_string(), None);
+        }
+      }
+    }
+    sessions.retain(|_, seen| seen.elapsed() < IDLE_TIMEOUT);
+    if sessions.len() >= MAX_SESSIONS {
+      if let Some(oldest) = sessions
+        .iter()
+        .min_by_key(|(_, seen)| **seen)
+        .map(|(id, _)| id.clone())
+      {
+        sessions.remove(&oldest);
+      }
+    }
+    let id = generate_token();
+    sessions.insert(id.clone(), Instant::now());
+    let mut set_cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Strict", COOKIE, id);
+    if secure {
+      set_cookie.push_str("; Secure");
+    }
+    (id, Some(set_cookie))
+  }
+}
+
+/// The value of the session cookie in a `Cookie` header.
+fn session_cookie(header: &str) -> Option<&str> {
+  header.split(';').find_map(|pair| {
+    let (name, value) = pair.trim().split_once('=')?;
+    if name == COOKIE {
+      Some(value)
+    } else {
+      None
+    }
+  })
+}
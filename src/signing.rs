@@ -0,0 +1,137 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! HMAC-SHA256 signatures of invoke bodies, proving they were sent by the initialization script.
+//!
+//! The signature is sent hex encoded in `Tauri-Signature` and covers the body as sent, before its
+//! `Content-Encoding` is undone.
+
+use {
+  hmac::{Hmac, Mac},
+  sha2::Sha256,
+  std::io::{Result as IoResult, Write},
+};
+
+pub(crate) const SIGNATURE_HEADER: &str = "Tauri-Signature";
+
+/// Signs the bytes written through it, and checks the signature sent with them when flushed.
+pub(crate) struct Verifier<W> {
+  inner: W,
+  mac: Hmac<Sha256>,
+  signature: Option<Vec<u8>>,
+}
+
+impl<W: Write> Verifier<W> {
+  /// Checks `signature` against the body, signed after `prefix`.
+  pub(crate) fn new(inner: W, key: &str, signature: Option<&str>, prefix: &[u8]) -> Self {
+    let mut mac =
+      Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(prefix);
+    Self {
+      inner,
+      mac,
+      signature: signature.and_then(decode_hex),
+    }
+  }
+
+  fn is_valid(&self) -> bool {
+    // verified in constant time, so the signature can't be guessed a byte at a time
+    self.signature.as_ref().map_or(false, |signature| {
+      self.mac.clone().verify_slice(signature).is_ok()
+    })
+  }
+}
+
+/// Decodes a hex string of either case.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+  if hex.len() % 2 != 0 {
+    return None;
+  }
+  (0..hex.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+impl<W: Write> Write for Verifier<W> {
+  fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+    let written = self.inner.write(buf)?;
+    self.mac.update(&buf[..written]);
+    Ok(written)
+  }
+
+  /// Fails with [`std::io::ErrorKind::PermissionDenied`] if the signature doesn't match.
+  fn flush(&mut self) -> IoResult<()> {
+    // checked first, so unsigned bodies aren't decoded any further
+    if !self.is_valid() {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "invalid request signature",
+      ));
+    }
+    self.inner.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Writes `data` through a verifier of `signature` under `key`.
+  fn verify(key: &str, signature: &str, data: &[u8]) -> IoResult<()> {
+    let mut verifier = Verifier::new(Vec::new(), key, Some(signature), b"");
+    verifier.write_all(data)?;
+    verifier.flush()
+  }
+
+  // test cases 1 and 2 of RFC 4231, the ones whose keys are text
+  const VECTORS: [(&str, &str, &str); 2] = [
+    (
+      "\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b\x0b",
+      "Hi There",
+      "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+    ),
+    (
+      "Jefe",
+      "what do ya want for nothing?",
+      "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+    ),
+  ];
+
+  #[test]
+  fn accepts_the_known_signatures() {
+    for (key, data, signature) in VECTORS {
+      verify(key, signature, data.as_bytes()).unwrap();
+      verify(key, &signature.to_ascii_uppercase(), data.as_bytes()).unwrap();
+    }
+  }
+
+  #[test]
+  fn rejects_tampered_signatures_and_bodies() {
+    let (key, data, signature) = VECTORS[1];
+    let mut tampered = signature.to_string();
+    tampered.replace_range(..1, "6");
+    for (signature, data) in [
+      (tampered.as_str(), data),
+      (signature, "what do ya want for nothing!"),
+      (&signature[..62], data),
+      ("", data),
+    ] {
+      let error = verify(key, signature, data.as_bytes()).unwrap_err();
+      assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+    let mut verifier = Verifier::new(Vec::new(), key, None, b"");
+    assert!(verifier.flush().is_err());
+  }
+
+  #[test]
+  fn signs_the_prefix_with_the_body() {
+    let (key, data, signature) = VECTORS[1];
+    let (prefix, body) = data.split_at(8);
+    let mut verifier = Verifier::new(Vec::new(), key, Some(signature), prefix.as_bytes());
+    verifier.write_all(body.as_bytes()).unwrap();
+    verifier.flush().unwrap();
+    assert_eq!(verifier.inner, body.as_bytes());
+  }
+}
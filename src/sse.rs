@@ -0,0 +1,115 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Server-sent events and newline-delimited JSON streams used to push data to HTTP clients.
+
+use {
+  crate::{response_head, LockExt},
+  std::{
+    collections::HashMap,
+    io::{Read, Result as IoResult, Write},
+    sync::{Arc, Mutex},
+  },
+  tiny_http::{Request, Response},
+};
+
+pub(crate) type Subscribers = Arc<Mutex<HashMap<String, Vec<EventStream>>>>;
+
+/// Framing of the messages written to an [`EventStream`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamFormat {
+  /// `text/event-stream`, consumed by `EventSource`.
+  EventStream,
+  /// `application/x-ndjson`, one JSON document per line, for non-browser clients.
+  Ndjson,
+}
+
+impl StreamFormat {
+  /// Picks NDJSON when the `Accept` header asks for it.
+  pub(crate) fn from_accept(accept: Option<&str>) -> Self {
+    let ndjson = accept.map_or(false, |accept| {
+      accept.split(',').any(|t| {
+        let t = t.split(';').next().unwrap_or_default().trim();
+        t.eq_ignore_ascii_case("application/x-ndjson")
+          || t.eq_ignore_ascii_case("application/ndjson")
+      })
+    });
+    if ndjson {
+      Self::Ndjson
+    } else {
+      Self::EventStream
+    }
+  }
+
+  pub(crate) fn content_type(self) -> &'static str {
+    match self {
+      Self::EventStream => "text/event-stream",
+      Self::Ndjson => "application/x-ndjson",
+    }
+  }
+}
+
+pub(crate) struct EventStream {
+  writer: Box<dyn Write + Send>,
+  format: StreamFormat,
+}
+
+impl EventStream {
+  /// Streams into `writer`, after the response head was already sent.
+  pub(crate) fn new(writer: Box<dyn Write + Send>, format: StreamFormat) -> Self {
+    Self { writer, format }
+  }
+
+  /// Takes over the connection of `request`, writing the status and headers of `response`.
+  ///
+  /// The body is streamed until the connection is closed, so tiny_http's chunked encoder
+  /// (which buffers until a full chunk is available) is bypassed.
+  pub(crate) fn open<R: Read>(
+    request: Request,
+    response: Response<R>,
+    format: StreamFormat,
+  ) -> IoResult<Self> {
+    let mut head = response_head(&response);
+    head.push_str(&format!(
+      "Content-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+      format.content_type()
+    ));
+
+    let mut writer = request.into_writer();
+    writer.write_all(head.as_bytes())?;
+    writer.flush()?;
+    Ok(Self::new(writer, format))
+  }
+
+  pub(crate) fn send(&mut self, data: &str) -> IoResult<()> {
+    match self.format {
+      StreamFormat::EventStream => {
+        for line in data.lines() {
+          writeln!(self.writer, "data: {}", line)?;
+        }
+        self.writer.write_all(b"\n")?;
+      }
+      // messages are compact JSON, so they never contain a line break
+      StreamFormat::Ndjson => writeln!(self.writer, "{}", data)?,
+    }
+    self.writer.flush()
+  }
+}
+
+/// Sends `data` to every stream subscribed for `label`, dropping the ones that were closed.
+///
+/// Returns whether at least one stream received it.
+pub(crate) fn broadcast(subscribers: &Subscribers, label: &str, data: &str) -> bool {
+  let mut subscribers = subscribers.locked();
+  match subscribers.get_mut(label) {
+    Some(streams) => {
+      *streams = streams
+        .drain(..)
+        .filter_map(|mut s| s.send(data).ok().map(|_| s))
+        .collect();
+      !streams.is_empty()
+    }
+    None => false,
+  }
+}
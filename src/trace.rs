@@ -0,0 +1,135 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! `tracing` spans of the connections and invokes served, with the `tracing` feature.
+//!
+//! An invoke's span carries its window label, origin, command, status and latency, and has a
+//! child span for each of the parsing, dispatch and response of the invoke. Without the feature
+//! the spans are empty.
+
+use std::{net::IpAddr, time::Duration};
+#[cfg(feature = "tracing")]
+use tracing::{field::Empty, Span};
+
+/// The span of an invoke, from its request to its response.
+pub(crate) struct InvokeSpan {
+  #[cfg(feature = "tracing")]
+  span: Span,
+  /// The span of the dispatch to the command, closed once it answers.
+  #[cfg(feature = "tracing")]
+  dispatch: Option<Span>,
+}
+
+/// An entered phase of an invoke, which ends when dropped.
+pub(crate) struct Phase {
+  #[cfg(feature = "tracing")]
+  _entered: tracing::span::EnteredSpan,
+}
+
+impl Phase {
+  /// Ends the phase before the end of its scope.
+  pub(crate) fn end(self) {}
+}
+
+impl InvokeSpan {
+  /// Opens the span of an invoke from `origin` to the window `window`.
+  pub(crate) fn new(origin: Option<&str>, window: &str) -> Self {
+    #[cfg(feature = "tracing")]
+    {
+      Self {
+        span: tracing::info_span!(
+          "invoke",
+          window,
+          origin,
+          cmd = Empty,
+          status = Empty,
+          latency_ms = Empty
+        ),
+        dispatch: None,
+      }
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+      let _ = (origin, window);
+      Self {}
+    }
+  }
+
+  pub(crate) fn cmd(&self, cmd: &str) {
+    #[cfg(feature = "tracing")]
+    self.span.record("cmd", cmd);
+    #[cfg(not(feature = "tracing"))]
+    let _ = cmd;
+  }
+
+  /// Enters the parsing of the request body into the invoke message.
+  pub(crate) fn parse(&self) -> Phase {
+    Phase {
+      #[cfg(feature = "tracing")]
+      _entered: tracing::debug_span!(parent: &self.span, "parse").entered(),
+    }
+  }
+
+  /// Opens the dispatch to the command, which lasts until [`Self::respond`].
+  pub(crate) fn dispatch(&mut self) {
+    #[cfg(feature = "tracing")]
+    {
+      self.dispatch = Some(tracing::debug_span!(parent: &self.span, "dispatch"));
+    }
+  }
+
+  /// Closes the dispatch and enters the writing of the response.
+  pub(crate) fn respond(&mut self) -> Phase {
+    #[cfg(feature = "tracing")]
+    {
+      self.dispatch = None;
+    }
+    Phase {
+      #[cfg(feature = "tracing")]
+      _entered: tracing::debug_span!(parent: &self.span, "response").entered(),
+    }
+  }
+
+  /// Closes the span of an invoke answered with `status` after `latency`.
+  pub(crate) fn finish(self, status: u16, latency: Duration) {
+    #[cfg(feature = "tracing")]
+    {
+      self.span.record("status", status);
+      self
+        .span
+        .record("latency_ms", latency.as_secs_f64() * 1000.0);
+      tracing::debug!(parent: &self.span, status, "invoke answered");
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (status, latency);
+  }
+}
+
+/// Enters the handling of a request accepted from `peer` by the default server.
+pub(crate) fn accept(peer: Option<IpAddr>) -> Phase {
+  #[cfg(not(feature = "tracing"))]
+  let _ = peer;
+  Phase {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::debug_span!("accept", peer = ?peer).entered(),
+  }
+}
+
+/// Spawns `future` serving a connection accepted from `peer`, in its span.
+#[cfg(feature = "async-server")]
+pub(crate) fn spawn_connection<F: std::future::Future<Output = ()> + Send + 'static>(
+  peer: Option<IpAddr>,
+  future: F,
+) -> tauri::async_runtime::JoinHandle<()> {
+  #[cfg(feature = "tracing")]
+  {
+    use tracing::Instrument;
+    tauri::async_runtime::spawn(future.instrument(tracing::debug_span!("connection", peer = ?peer)))
+  }
+  #[cfg(not(feature = "tracing"))]
+  {
+    let _ = peer;
+    tauri::async_runtime::spawn(future)
+  }
+}
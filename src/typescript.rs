@@ -0,0 +1,55 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Generation of the frontend clients of [`crate::Invoke::typescript_client`] and
+//! [`crate::Invoke::write_npm_package`].
+
+use {
+  serde_json::Value as JsonValue,
+  std::{io, path::Path},
+};
+
+const CLIENT: &str = include_str!("client.ts");
+const INSTALL: &str = include_str!("install.js");
+const INSTALL_TYPES: &str = include_str!("install.d.ts");
+
+/// The name of the package written by [`npm_package`].
+const PACKAGE_NAME: &str = "tauri-invoke-http-client";
+
+/// The client module for the server at `base_url`.
+pub(crate) fn client(base_url: &str, csrf_protection: bool, error_envelope: bool) -> String {
+  CLIENT
+    .replace("__BASE_URL__", &JsonValue::from(base_url).to_string())
+    .replace("__CSRF_PROTECTION__", &csrf_protection.to_string())
+    .replace("__ERROR_ENVELOPE__", &error_envelope.to_string())
+}
+
+/// Writes the package installing `script` into `dir`, which is created if missing.
+///
+/// `script` reads the options passed to `install` from `options`.
+pub(crate) fn npm_package(dir: &Path, script: &str) -> io::Result<()> {
+  let manifest = serde_json::json!({
+    "name": PACKAGE_NAME,
+    "version": env!("CARGO_PKG_VERSION"),
+    "type": "module",
+    "main": "index.js",
+    "types": "index.d.ts",
+    "exports": {
+      ".": {
+        "types": "./index.d.ts",
+        "import": "./index.js",
+      },
+    },
+  });
+  std::fs::create_dir_all(dir)?;
+  std::fs::write(
+    dir.join("package.json"),
+    serde_json::to_string_pretty(&manifest)? + "\n",
+  )?;
+  std::fs::write(
+    dir.join("index.js"),
+    INSTALL.replace("__INVOKE_HTTP_SCRIPT__", script),
+  )?;
+  std::fs::write(dir.join("index.d.ts"), INSTALL_TYPES)
+}
@@ -0,0 +1,867 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! Minimal WebSocket (RFC 6455) endpoint used by [`crate::Transport::WebSocket`].
+//!
+//! tiny_http hands out upgraded connections as a single `Read + Write` object that can't be
+//! split, so responses couldn't be written while the connection is blocked reading the next
+//! frame. The socket transport therefore runs on its own listener, and frames are read and
+//! written on the two halves of the connection here for the same reason.
+//!
+//! Connections and invokes go through the same limits, timeouts, nonces and rate limits as the
+//! HTTP endpoint. The middleware see the handshake, whose headers as they leave them are sent
+//! along with every invoke over the connection.
+
+use {
+  crate::{
+    acl_origin, assets, bind_tcp, cancel, csrf, is_acl_denial, isolation, reload::LiveContext,
+    strip_forwarded, Busy, Context, LockExt, MiddlewareRequest, MiddlewareResponse,
+    RecievedMessage, SESSION_HEADER,
+  },
+  base64::Engine,
+  sha1::{Digest, Sha1},
+  std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, ErrorKind, Read, Take, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+      atomic::{AtomicBool, AtomicUsize, Ordering},
+      Arc, Mutex,
+    },
+    time::{Duration, Instant},
+  },
+  tauri::{
+    ipc::{CallbackFn, InvokeResponse, InvokeResponseBody},
+    webview::InvokeRequest,
+    AppHandle, Runtime, Url,
+  },
+};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The most bytes a handshake may take, larger ones are answered with 431.
+const MAX_HANDSHAKE_SIZE: u64 = 16 * 1024;
+/// The most header lines a handshake may have.
+const MAX_HANDSHAKE_HEADERS: usize = 100;
+
+type Writer = Arc<Mutex<TcpStream>>;
+
+/// The upgraded connections of a listener, closed when it stops.
+#[derive(Clone, Default)]
+struct Open {
+  stopped: Arc<AtomicBool>,
+  writers: Arc<Mutex<Vec<Writer>>>,
+}
+
+impl Open {
+  /// Tracks the connection of `writer` until the guard is dropped, or `None` if the listener
+  /// stopped.
+  fn track(&self, writer: &Writer) -> Option<Tracked<'_>> {
+    self.writers.locked().push(writer.clone());
+    let tracked = Tracked {
+      open: self,
+      writer: writer.clone(),
+    };
+    // checked once tracked, as stopping closes the connections tracked by then
+    if self.stopped.load(Ordering::SeqCst) {
+      None
+    } else {
+      Some(tracked)
+    }
+  }
+
+  /// Closes the connections tracked.
+  fn close(&self) {
+    for writer in self.writers.locked().drain(..) {
+      let _ = write_frame(&writer, OPCODE_CLOSE, &CLOSE_GOING_AWAY.to_be_bytes());
+      let _ = writer.locked().shutdown(Shutdown::Both);
+    }
+  }
+}
+
+/// Keeps a connection in the [`Open`] ones of its listener until dropped.
+struct Tracked<'a> {
+  open: &'a Open,
+  writer: Writer,
+}
+
+impl Drop for Tracked<'_> {
+  fn drop(&mut self) {
+    self
+      .open
+      .writers
+      .locked()
+      .retain(|writer| !Arc::ptr_eq(writer, &self.writer));
+  }
+}
+
+/// Stops a listener started with [`start`].
+pub(crate) struct Handle {
+  addr: SocketAddr,
+  open: Open,
+  thread: std::thread::JoinHandle<()>,
+}
+
+impl Handle {
+  /// Closes the listener and its connections, whose invokes still pending are cancelled.
+  pub(crate) fn stop(self) {
+    self.open.stopped.store(true, Ordering::SeqCst);
+    // wake up the blocking accept so it sees the flag
+    let mut addr = self.addr;
+    if addr.ip().is_unspecified() {
+      addr.set_ip(match addr {
+        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+      });
+    }
+    let _ = TcpStream::connect(addr);
+    let _ = self.thread.join();
+    self.open.close();
+  }
+}
+
+pub(crate) fn start<R: Runtime>(
+  app: AppHandle<R>,
+  address: String,
+  live: LiveContext,
+) -> std::io::Result<Handle> {
+  let listener = bind_tcp(&address, live.current().socket_options)?;
+  let addr = listener.local_addr()?;
+  let open = Open::default();
+  let tracked = open.clone();
+  let thread = std::thread::spawn(move || {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let pending = Arc::new(AtomicUsize::new(0));
+    for mut stream in listener.incoming().flatten() {
+      if tracked.stopped.load(Ordering::SeqCst) {
+        break;
+      }
+      let context = live.current();
+      if !context
+        .ip_filter
+        .allows(stream.peer_addr().ok().map(|a| a.ip()))
+      {
+        continue;
+      }
+      let _ = stream.set_nodelay(context.socket_options.nodelay);
+      let busy = Busy::new(&connections);
+      let limits = &context.limits;
+      if limits.too_many_threads(busy.others()) {
+        let _ = stream.write_all(
+          format!(
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            limits.retry_after_secs()
+          )
+          .as_bytes(),
+        );
+        continue;
+      }
+      let app = app.clone();
+      let pending = pending.clone();
+      let open = tracked.clone();
+      std::thread::spawn(move || {
+        serve(app, stream, &context, &pending, &open);
+        drop(busy);
+      });
+    }
+  });
+  Ok(Handle { addr, open, thread })
+}
+
+/// Reads from a connection, failing with [`ErrorKind::TimedOut`] once its deadline passed.
+struct Timed {
+  stream: TcpStream,
+  deadline: Option<Instant>,
+}
+
+impl Timed {
+  /// Starts the time given to what is being read, unless it already started.
+  fn begin(&mut self, timeout: Option<Duration>) {
+    if self.deadline.is_none() {
+      self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+    }
+  }
+
+  /// Waits for the next message without a deadline.
+  fn idle(&mut self) {
+    if self.deadline.take().is_some() {
+      let _ = self.stream.set_read_timeout(None);
+    }
+  }
+}
+
+impl Read for Timed {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if let Some(deadline) = self.deadline {
+      let remaining = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or(ErrorKind::TimedOut)?;
+      self.stream.set_read_timeout(Some(remaining))?;
+    }
+    self.stream.read(buf)
+  }
+}
+
+/// The `Sec-WebSocket-Accept` value answering the handshake `key`.
+fn accept_key(key: &str) -> String {
+  let mut hash = Sha1::new();
+  hash.update(key.as_bytes());
+  hash.update(GUID.as_bytes());
+  base64::engine::general_purpose::STANDARD.encode(hash.finalize())
+}
+
+/// The value of the query parameter `name` of `path`, percent-decoded.
+fn query_param(path: &str, name: &str) -> Option<String> {
+  path
+    .split_once('?')?
+    .1
+    .split('&')
+    .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+    .and_then(assets::decode)
+}
+
+/// Serves a connection, counting its invokes waiting for a response in `pending`.
+fn serve<R: Runtime>(
+  app: AppHandle<R>,
+  stream: TcpStream,
+  context: &Context,
+  pending: &Arc<AtomicUsize>,
+  open: &Open,
+) {
+  let peer = match stream.peer_addr() {
+    Ok(address) => address.ip(),
+    Err(_) => return,
+  };
+  let writer: Writer = match stream.try_clone() {
+    Ok(s) => Arc::new(Mutex::new(s)),
+    Err(_) => return,
+  };
+  let mut timed = Timed {
+    stream,
+    deadline: None,
+  };
+  // the handshake is read under the header timeout, as a request would be
+  timed.begin(context.read_timeouts.header);
+  let mut reader = BufReader::new(timed);
+
+  let (path, headers) = match read_handshake(&mut reader) {
+    Ok(h) => h,
+    Err(status) => {
+      let _ = write_response(&mut *writer.locked(), &MiddlewareResponse::new(status));
+      return;
+    }
+  };
+  let origin = match context.or_fallback_origin(headers.get("origin").cloned()) {
+    Some(origin) => origin,
+    None => {
+      let _ = writer
+        .locked()
+        .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+      return;
+    }
+  };
+  if !context.allows_origin(Some(&origin))
+    || !context.is_local_request(Some(peer), headers.get("host").map(String::as_str))
+  {
+    let _ = writer
+      .locked()
+      .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+    return;
+  }
+  if !context.is_authorized(headers.get("authorization").map(String::as_str), &path) {
+    let _ = writer
+      .locked()
+      .write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n");
+    return;
+  }
+  // pages can't send headers with the handshake, so the nonce comes in the URL
+  if let Some(nonces) = &context.nonces {
+    let nonce = headers
+      .get(&csrf::NONCE_HEADER.to_ascii_lowercase())
+      .cloned()
+      .or_else(|| query_param(&path, "nonce"));
+    if !nonces.spend(nonce.as_deref()) {
+      let _ = writer
+        .locked()
+        .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+      return;
+    }
+  }
+  // e.g. signing enabled by a reconfiguration, which the invokes over the socket can't meet
+  if let Some(protection) = context.unsupported_over_websocket() {
+    log::warn!("refused a WebSocket connection requiring {}", protection);
+    let _ = writer
+      .locked()
+      .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+    return;
+  }
+  let key = match headers.get("sec-websocket-key") {
+    Some(k) => k,
+    None => return,
+  };
+  // the headers as changed by the middleware, sent along with every invoke
+  let mut invoke_headers: tauri::http::HeaderMap = (&headers).try_into().unwrap_or_default();
+  if !context.middleware.is_empty() {
+    let mut middleware_request = MiddlewareRequest {
+      method: "GET".into(),
+      url: path.clone(),
+      headers: invoke_headers,
+      peer: Some(peer),
+    };
+    if let Some(response) = context.intercept(&app, &mut middleware_request) {
+      let _ = write_response(&mut *writer.locked(), &response);
+      return;
+    }
+    invoke_headers = middleware_request.headers;
+  }
+  // closed by a shutdown, so the socket can't outlive the server it was opened to
+  let _tracked = match open.track(&writer) {
+    Some(tracked) => tracked,
+    None => {
+      let _ = write_response(&mut *writer.locked(), &MiddlewareResponse::new(503));
+      return;
+    }
+  };
+  let accept = accept_key(key);
+  let mut set_cookie = String::new();
+  if let Some((session, cookie)) = context.session(headers.get("cookie").map(String::as_str)) {
+    if let Some(cookie) = cookie {
+      set_cookie = format!("Set-Cookie: {}\r\n", cookie);
+    }
+    if let Ok(session) = session.parse() {
+      invoke_headers.insert(
+        tauri::http::HeaderName::from_bytes(SESSION_HEADER.as_bytes()).unwrap(),
+        session,
+      );
+    }
+  }
+  if writer
+    .locked()
+    .write_all(
+      format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+        accept, set_cookie
+      )
+      .as_bytes(),
+    )
+    .is_err()
+  {
+    return;
+  }
+
+  let window_label = path
+    .split('?')
+    .next()
+    .and_then(|path| path.split('/').nth(1))
+    .unwrap_or_default()
+    .to_string();
+  let url = Url::parse(&acl_origin(origin.clone(), &context.acl_origins)).ok();
+  let trusted = context.is_trusted_proxy(Some(peer));
+  let header = |name: &'static str| headers.get(&name.to_ascii_lowercase()).cloned();
+  reader.get_mut().idle();
+
+  // the opcode and data of a message whose last frame is yet to come
+  let mut message: Option<(u8, Vec<u8>)> = None;
+  // the invokes sent over this connection, cancelled if it closes before they are answered
+  let mut invoked = Vec::new();
+  loop {
+    let received = message.as_ref().map_or(0, |(_, data)| data.len());
+    let limit = context
+      .limits
+      .max_message_size
+      .map(|max| max.saturating_sub(received));
+    // a message must come whole within the body timeout once its first frame started
+    let frame = match read_frame(&mut reader, limit, |reader| {
+      reader.get_mut().begin(context.read_timeouts.body)
+    }) {
+      Ok(frame) => frame,
+      Err(FrameError::Io) => break,
+      Err(FrameError::Close(code)) => {
+        let _ = write_frame(&writer, OPCODE_CLOSE, &code.to_be_bytes());
+        break;
+      }
+    };
+    match frame.opcode {
+      OPCODE_PING => {
+        let _ = write_frame(&writer, OPCODE_PONG, &frame.payload);
+      }
+      OPCODE_PONG => {}
+      OPCODE_CLOSE => {
+        let _ = write_frame(&writer, OPCODE_CLOSE, &frame.payload);
+        break;
+      }
+      OPCODE_CONTINUATION => match &mut message {
+        Some((_, data)) => data.extend(frame.payload),
+        None => {
+          let _ = write_frame(&writer, OPCODE_CLOSE, &CLOSE_PROTOCOL_ERROR.to_be_bytes());
+          break;
+        }
+      },
+      opcode => {
+        // a message can't start before the previous one ended
+        if message.is_some() {
+          let _ = write_frame(&writer, OPCODE_CLOSE, &CLOSE_PROTOCOL_ERROR.to_be_bytes());
+          break;
+        }
+        message = Some((opcode, frame.payload));
+      }
+    }
+    let (opcode, data) = match message.take() {
+      Some(message) if frame.fin && frame.opcode < OPCODE_CLOSE => message,
+      pending => {
+        if pending.is_none() {
+          reader.get_mut().idle();
+        }
+        message = pending;
+        continue;
+      }
+    };
+    reader.get_mut().idle();
+    if opcode != OPCODE_TEXT {
+      continue;
+    }
+    let message: RecievedMessage = match serde_json::from_slice(&data) {
+      Ok(m) => m,
+      Err(e) => {
+        log::warn!("dropped a malformed WebSocket message from {}: {}", peer, e);
+        continue;
+      }
+    };
+    log::debug!("WebSocket invoke of `{}` from {}", message.cmd, peer);
+    let url = match &url {
+      Some(url) => url.clone(),
+      None => break,
+    };
+    let mut entry = context
+      .auditor
+      .begin(&context.metrics, Some(&origin), &window_label);
+    let window = match crate::find_webview(&app, context.dispatching_webview(&window_label)) {
+      Some(w) => w,
+      None => {
+        log::warn!("no window `{}` to send the invoke to", window_label);
+        respond(&writer, message.error, Err("\"window not found\"".into()));
+        entry.finish(404);
+        continue;
+      }
+    };
+    // Tauri drops invokes with a wrong key without answering them
+    if message.invoke_key.as_deref() != Some(app.invoke_key()) {
+      respond(&writer, message.error, Err("\"invalid invoke key\"".into()));
+      entry.finish(403);
+      continue;
+    }
+    if !context.is_allowed_command(&window_label, &message.cmd) {
+      let error = format!("command {} is not allowed", message.cmd);
+      respond(
+        &writer,
+        message.error,
+        Err(serde_json::Value::from(error).to_string()),
+      );
+      entry.invoke(&message.cmd, None);
+      entry.finish(403);
+      continue;
+    }
+    if context
+      .rate_limit(&window_label, Some(peer), trusted, Some(&origin), &header)
+      .is_err()
+    {
+      respond(&writer, message.error, Err("\"too many requests\"".into()));
+      entry.invoke(&message.cmd, None);
+      entry.finish(429);
+      continue;
+    }
+    let busy = Busy::new(pending);
+    if context.limits.too_many_pending(busy.others()) {
+      respond(
+        &writer,
+        message.error,
+        Err("\"too many pending invokes\"".into()),
+      );
+      entry.invoke(&message.cmd, None);
+      entry.finish(429);
+      continue;
+    }
+
+    let body = match isolation::open(context.isolation_key.as_ref(), message.payload) {
+      Ok(body) => body,
+      Err(e) => {
+        respond(
+          &writer,
+          message.error,
+          Err(serde_json::Value::from(e).to_string()),
+        );
+        entry.invoke(&message.cmd, None);
+        entry.finish(400);
+        continue;
+      }
+    };
+    entry.invoke(&message.cmd, Some(&body));
+    let mut headers = invoke_headers.clone();
+    if !trusted {
+      strip_forwarded(&mut headers);
+    }
+    headers.insert(
+      tauri::http::HeaderName::from_bytes(cancel::INVOCATION_HEADER.as_bytes()).unwrap(),
+      message.callback.0.into(),
+    );
+    context
+      .cancellations
+      .register(message.callback.0, &message.cmd, &window_label);
+    invoked.push(message.callback.0);
+    let payload = InvokeRequest {
+      cmd: message.cmd,
+      callback: message.callback,
+      error: message.error,
+      url,
+      body,
+      headers,
+      invoke_key: app.invoke_key().to_string(),
+    };
+    let writer = writer.clone();
+    let cancellations = context.cancellations.clone();
+    let url = payload.url.to_string();
+    entry.dispatch();
+    window.on_message(
+      payload,
+      Box::new(move |webview, cmd, response, callback, error| {
+        drop(busy);
+        let denied = is_acl_denial(&webview, &cmd, &url, &response);
+        cancellations.finish(callback.0);
+        let _respond = entry.respond();
+        match response {
+          InvokeResponse::Ok(r) => {
+            respond(&writer, callback, Ok(r));
+            entry.finish(200);
+          }
+          InvokeResponse::Err(e) => {
+            respond(&writer, error, Err(serde_json::to_string(&e.0).unwrap()));
+            entry.finish(if denied { 403 } else { 400 });
+          }
+        }
+      }),
+    );
+  }
+  for callback in invoked {
+    context.cancellations.cancel(callback);
+  }
+}
+
+/// Writes the answer of a middleware to the handshake.
+fn write_response(stream: &mut impl Write, response: &MiddlewareResponse) -> std::io::Result<()> {
+  let reason = tauri::http::StatusCode::from_u16(response.status)
+    .ok()
+    .and_then(|status| status.canonical_reason())
+    .unwrap_or_default();
+  let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason);
+  for (name, value) in &response.headers {
+    if let Ok(value) = value.to_str() {
+      head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+  }
+  head.push_str(&format!("Content-Length: {}\r\n\r\n", response.body.len()));
+  stream.write_all(head.as_bytes())?;
+  stream.write_all(&response.body)
+}
+
+/// Sends the result for `id` as a `{ id, payload }` text frame,
+/// or as a binary frame prefixed with the little-endian id for raw bodies.
+fn respond(writer: &Writer, id: CallbackFn, response: Result<InvokeResponseBody, String>) {
+  let _ = match response {
+    Ok(InvokeResponseBody::Raw(r)) => {
+      let mut data = id.0.to_le_bytes().to_vec();
+      data.extend(r);
+      write_frame(writer, OPCODE_BINARY, &data)
+    }
+    Ok(InvokeResponseBody::Json(r)) | Err(r) => write_frame(
+      writer,
+      OPCODE_TEXT,
+      format!("{{\"id\":{},\"payload\":{}}}", id.0, r).as_bytes(),
+    ),
+  };
+}
+
+/// Reads the handshake, failing with the status to answer it with.
+fn read_handshake<R: BufRead>(reader: &mut R) -> Result<(String, HashMap<String, String>), u16> {
+  let mut reader = reader.take(MAX_HANDSHAKE_SIZE);
+  let mut line = String::new();
+  read_line(&mut reader, &mut line)?;
+  let mut parts = line.split_whitespace();
+  if parts.next() != Some("GET") {
+    return Err(400);
+  }
+  let path = parts.next().ok_or(400u16)?.to_string();
+
+  let mut headers = HashMap::new();
+  for count in 0.. {
+    line.clear();
+    read_line(&mut reader, &mut line)?;
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if count == MAX_HANDSHAKE_HEADERS {
+      return Err(431);
+    }
+    let (name, value) = line.split_once(':').ok_or(400u16)?;
+    headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+  }
+
+  if !headers
+    .get("upgrade")
+    .map_or(false, |u| u.eq_ignore_ascii_case("websocket"))
+  {
+    return Err(400);
+  }
+  Ok((path, headers))
+}
+
+/// Reads a whole line of the handshake, failing with 431 once it runs past the size limit.
+fn read_line<R: BufRead>(reader: &mut Take<R>, line: &mut String) -> Result<(), u16> {
+  match reader.read_line(line) {
+    Ok(_) if line.ends_with('\n') => Ok(()),
+    Ok(_) if reader.limit() == 0 => Err(431),
+    _ => Err(400),
+  }
+}
+
+/// A frame sent by the client, unmasked.
+struct Frame {
+  fin: bool,
+  opcode: u8,
+  payload: Vec<u8>,
+}
+
+enum FrameError {
+  /// The connection failed, timed out or was closed.
+  Io,
+  /// The client broke the protocol, and the connection is closed with this code.
+  Close(u16),
+}
+
+impl From<std::io::Error> for FrameError {
+  fn from(_: std::io::Error) -> Self {
+    Self::Io
+  }
+}
+
+/// Reads a frame, calling `started` once its first bytes arrived. Data frames longer than
+/// `limit` are refused before their payload is read.
+fn read_frame<R: Read>(
+  reader: &mut R,
+  limit: Option<usize>,
+  started: impl FnOnce(&mut R),
+) -> Result<Frame, FrameError> {
+  let mut head = [0u8; 2];
+  reader.read_exact(&mut head)?;
+  started(reader);
+  let fin = head[0] & 0x80 != 0;
+  let opcode = head[0] & 0x0F;
+  let masked = head[1] & 0x80 != 0;
+  // no extension was negotiated, so the reserved bits must be clear, and clients must mask
+  // their frames
+  if head[0] & 0x70 != 0 || !masked {
+    return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR));
+  }
+  let len = match head[1] & 0x7F {
+    126 => {
+      let mut len = [0u8; 2];
+      reader.read_exact(&mut len)?;
+      u16::from_be_bytes(len) as u64
+    }
+    127 => {
+      let mut len = [0u8; 8];
+      reader.read_exact(&mut len)?;
+      u64::from_be_bytes(len)
+    }
+    len => len as u64,
+  };
+  match opcode {
+    OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+      if limit.map_or(false, |limit| len > limit as u64) {
+        return Err(FrameError::Close(CLOSE_TOO_BIG));
+      }
+    }
+    OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+      if !fin || len > 125 {
+        return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR));
+      }
+    }
+    _ => return Err(FrameError::Close(CLOSE_PROTOCOL_ERROR)),
+  }
+  let mut mask = [0u8; 4];
+  reader.read_exact(&mut mask)?;
+  // read as it arrives rather than allocated from the announced length
+  let mut payload = Vec::new();
+  reader.take(len).read_to_end(&mut payload)?;
+  if payload.len() as u64 != len {
+    return Err(FrameError::Io);
+  }
+  for (i, b) in payload.iter_mut().enumerate() {
+    *b ^= mask[i % 4];
+  }
+  Ok(Frame {
+    fin,
+    opcode,
+    payload,
+  })
+}
+
+fn write_frame(writer: &Writer, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+  let mut frame = vec![0x80 | opcode];
+  match payload.len() {
+    len if len < 126 => frame.push(len as u8),
+    len if len <= u16::MAX as usize => {
+      frame.push(126);
+      frame.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    len => {
+      frame.push(127);
+      frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+  }
+  frame.extend_from_slice(payload);
+  writer.locked().write_all(&frame)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A frame as a client sends it, masked with `mask`.
+  fn client_frame(head: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = vec![head];
+    match payload.len() {
+      len if len < 126 => frame.push(0x80 | len as u8),
+      len => {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+      }
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+  }
+
+  fn read(bytes: &[u8], limit: Option<usize>) -> Result<Frame, FrameError> {
+    read_frame(&mut &bytes[..], limit, |_| {})
+  }
+
+  fn close_code(result: Result<Frame, FrameError>) -> Option<u16> {
+    match result {
+      Err(FrameError::Close(code)) => Some(code),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn answers_the_handshake_example() {
+    // from section 1.3 of RFC 6455
+    assert_eq!(
+      accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+      "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+  }
+
+  #[test]
+  fn caps_the_handshake() {
+    let handshake = |headers: &str| {
+      let request = format!(
+        "GET /main HTTP/1.1\r\nUpgrade: websocket\r\n{}\r\n",
+        headers
+      );
+      read_handshake(&mut request.as_bytes()).map(|(path, _)| path)
+    };
+    assert_eq!(handshake("Host: localhost\r\n"), Ok("/main".to_string()));
+    assert_eq!(handshake("Host localhost\r\n"), Err(400));
+    assert_eq!(handshake("Host: localhost"), Err(400));
+    let long = format!("X-Long: {}\r\n", "a".repeat(MAX_HANDSHAKE_SIZE as usize));
+    assert_eq!(handshake(&long), Err(431));
+    // along with `Upgrade`
+    let many = "X-Header: a\r\n".repeat(MAX_HANDSHAKE_HEADERS - 1);
+    assert_eq!(handshake(&many), Ok("/main".to_string()));
+    assert_eq!(handshake(&format!("{}X-Last: a\r\n", many)), Err(431));
+  }
+
+  #[test]
+  fn unmasks_client_frames() {
+    let frame = read(
+      &client_frame(0x81, b"Hello", [0x37, 0xfa, 0x21, 0x3d]),
+      None,
+    )
+    .ok()
+    .unwrap();
+    assert!(frame.fin);
+    assert_eq!(frame.opcode, OPCODE_TEXT);
+    assert_eq!(frame.payload, b"Hello");
+    let payload = vec![7u8; 300];
+    let frame = read(&client_frame(0x02, &payload, [1, 2, 3, 4]), Some(300))
+      .ok()
+      .unwrap();
+    assert!(!frame.fin);
+    assert_eq!(frame.payload, payload);
+  }
+
+  #[test]
+  fn rejects_unmasked_frames() {
+    // the unmasked "Hello" of section 5.7 of RFC 6455
+    let frame = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    assert_eq!(close_code(read(&frame, None)), Some(CLOSE_PROTOCOL_ERROR));
+  }
+
+  #[test]
+  fn refuses_frames_beyond_the_limit_before_reading_them() {
+    let frame = client_frame(0x81, &[0; 300], [1, 2, 3, 4]);
+    assert_eq!(close_code(read(&frame, Some(299))), Some(CLOSE_TOO_BIG));
+    // a frame announcing 2^62 bytes is refused from its header alone
+    let mut huge = vec![0x82, 0x80 | 127];
+    huge.extend_from_slice(&(1u64 << 62).to_be_bytes());
+    assert_eq!(close_code(read(&huge, Some(1024))), Some(CLOSE_TOO_BIG));
+    // and without a limit, only what arrived is allocated
+    huge.extend_from_slice(&[0; 4 + 10]);
+    assert!(matches!(read(&huge, None), Err(FrameError::Io)));
+  }
+
+  #[test]
+  fn rejects_malformed_control_frames() {
+    let fragmented_ping = client_frame(0x09, b"ping", [1, 2, 3, 4]);
+    assert_eq!(
+      close_code(read(&fragmented_ping, None)),
+      Some(CLOSE_PROTOCOL_ERROR)
+    );
+    let long_ping = client_frame(0x89, &[0; 126], [1, 2, 3, 4]);
+    assert_eq!(
+      close_code(read(&long_ping, None)),
+      Some(CLOSE_PROTOCOL_ERROR)
+    );
+    let reserved = client_frame(0xC1, b"x", [1, 2, 3, 4]);
+    assert_eq!(
+      close_code(read(&reserved, None)),
+      Some(CLOSE_PROTOCOL_ERROR)
+    );
+    let unknown = client_frame(0x83, b"x", [1, 2, 3, 4]);
+    assert_eq!(close_code(read(&unknown, None)), Some(CLOSE_PROTOCOL_ERROR));
+  }
+
+  #[test]
+  fn reads_the_nonce_parameter() {
+    assert_eq!(
+      query_param("/main?access_token=a&nonce=b%2Bc", "nonce").as_deref(),
+      Some("b+c")
+    );
+    assert_eq!(query_param("/main?nonces=b", "nonce"), None);
+    assert_eq!(query_param("/main", "nonce"), None);
+  }
+}
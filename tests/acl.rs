@@ -0,0 +1,60 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use {
+  common::{ORIGIN, TOKEN},
+  serde_json::json,
+  tauri_invoke_http::{ErrorFormat, Invoke},
+};
+
+#[test]
+fn answers_a_command_missing_from_the_capabilities_with_403() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let response = common::invoke(http.port(), "plugin:dialog|close", json!({}));
+  assert_eq!(response.status, 403, "{}", response.body);
+}
+
+#[test]
+fn answers_a_command_allowed_on_another_window_with_403() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let response = common::invoke(http.port(), "plugin:dialog|save", json!({}));
+  assert_eq!(response.status, 403, "{}", response.body);
+}
+
+#[test]
+fn answers_a_command_allowed_on_another_origin_with_403() {
+  let http = Invoke::new([ORIGIN, "https://remote.example"])
+    .unwrap()
+    .with_session_token(Some(TOKEN.into()));
+  let _app = common::app(&http);
+  let response = common::invoke_from(
+    http.port(),
+    "https://remote.example",
+    "plugin:dialog|open",
+    json!({ "title": "report" }),
+  );
+  assert_eq!(response.status, 403, "{}", response.body);
+}
+
+#[test]
+fn answers_a_denied_command_with_the_not_allowed_code() {
+  let http = common::server().with_error_format(ErrorFormat::Envelope);
+  let _app = common::app(&http);
+  let response = common::invoke(http.port(), "plugin:dialog|close", json!({}));
+  let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+  assert_eq!(body["code"], "not_allowed");
+}
+
+#[test]
+fn answers_an_allowed_command_failing_with_400() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let response = common::invoke(http.port(), "fail", json!({}));
+  assert_eq!(response.status, 400, "{}", response.body);
+  assert_eq!(response.body, "\"Command fail not allowed by ACL\"");
+}
@@ -0,0 +1,204 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+//! A mock Tauri app serving invokes over HTTP, and a raw HTTP client to talk to it.
+
+#![allow(dead_code)]
+
+use {
+  std::{
+    io::{Read, Write},
+    net::TcpStream,
+  },
+  tauri::{
+    ipc::RuntimeAuthority,
+    plugin::{Builder as PluginBuilder, TauriPlugin},
+    test::{mock_builder, mock_context, noop_assets, MockRuntime},
+    utils::acl::{
+      resolved::{Resolved, ResolvedCommand, ResolvedCommandReference},
+      ExecutionContext,
+    },
+    App, Runtime, WebviewUrl, WebviewWindowBuilder,
+  },
+  tauri_invoke_http::Invoke,
+};
+
+pub const ORIGIN: &str = "tauri://localhost";
+/// The session token the requests of [`send`] carry.
+pub const TOKEN: &str = "test-session-token";
+
+/// A server accepting invokes from [`ORIGIN`] with [`TOKEN`].
+pub fn server() -> Invoke {
+  Invoke::new([ORIGIN])
+    .unwrap()
+    .with_session_token(Some(TOKEN.into()))
+}
+
+#[tauri::command]
+fn greet(name: String) -> String {
+  format!("Hello, {}!", name)
+}
+
+#[tauri::command]
+fn open(title: String) -> String {
+  format!("picked a file for {}", title)
+}
+
+#[tauri::command]
+fn close() {}
+
+#[tauri::command]
+fn save() {}
+
+/// Takes a raw body, as `plugin:fs|write_file` does, and returns its length.
+#[tauri::command]
+fn import(request: tauri::ipc::Request<'_>) -> Result<usize, String> {
+  match request.body() {
+    tauri::ipc::InvokeBody::Raw(bytes) => Ok(bytes.len()),
+    tauri::ipc::InvokeBody::Json(_) => Err("expected a raw body".into()),
+  }
+}
+
+/// Fails with the message Tauri rejects denied commands with in release builds.
+#[tauri::command]
+fn fail() -> Result<(), String> {
+  Err("Command fail not allowed by ACL".into())
+}
+
+/// Stands in for the dialog plugin, whose real implementation needs a windowing system.
+fn dialog<R: Runtime>() -> TauriPlugin<R> {
+  PluginBuilder::new("dialog")
+    .invoke_handler(tauri::generate_handler![open, close, save, import])
+    .build()
+}
+
+/// Grants each command to the windows matching its pattern on the local origin, as a capability
+/// would.
+fn authority(commands: &[(&str, &str)]) -> RuntimeAuthority {
+  let allowed = commands
+    .iter()
+    .map(|(command, windows)| {
+      let windows = vec![glob::Pattern::new(windows).unwrap()];
+      let resolved = ResolvedCommand::new(
+        ExecutionContext::Local,
+        ResolvedCommandReference::new("test".into(), "test:default".into()),
+        windows.clone(),
+        windows,
+        None,
+      );
+      (command.to_string(), vec![resolved])
+    })
+    .collect();
+  tauri::runtime_authority!(
+    Default::default(),
+    Resolved {
+      allowed_commands: allowed,
+      ..Default::default()
+    }
+  )
+}
+
+/// A mock app with a `main` window, the `greet` and `fail` commands and the dialog stand-in, whose
+/// capabilities allow `plugin:dialog|open`, `plugin:dialog|import` and the event plugin, and `plugin:dialog|save` on the
+/// `settings` window only. `http` is started on it.
+pub fn app(http: &Invoke) -> App<MockRuntime> {
+  let mut context = mock_context(noop_assets());
+  *context.runtime_authority_mut() = authority(&[
+    ("plugin:dialog|open", "*"),
+    ("plugin:dialog|save", "settings"),
+    ("plugin:dialog|import", "*"),
+    ("plugin:event|listen", "*"),
+    ("plugin:event|emit", "*"),
+  ]);
+  let app = mock_builder()
+    .plugin(dialog())
+    .invoke_handler(tauri::generate_handler![greet, fail])
+    .build(context)
+    .expect("failed to build the mock app");
+  WebviewWindowBuilder::new(&app, "main", WebviewUrl::default())
+    .build()
+    .expect("failed to create the main window");
+  http
+    .start(app.handle().clone())
+    .expect("failed to start the server");
+  app
+}
+
+/// A response read by [`send`].
+pub struct Response {
+  pub status: u16,
+  pub head: String,
+  pub body: String,
+}
+
+impl Response {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.head.lines().find_map(|line| {
+      let (field, value) = line.split_once(':')?;
+      field.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+  }
+}
+
+/// Sends `method` `path` with `headers`, [`TOKEN`] and `body` to the server on `port`, closing
+/// the connection after the response.
+pub fn send(
+  port: u16,
+  method: &str,
+  path: &str,
+  headers: &[(&str, &str)],
+  body: &[u8],
+) -> Response {
+  let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("failed to connect");
+  let mut head = format!(
+    "{} {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n",
+    method,
+    path,
+    port,
+    TOKEN,
+    body.len()
+  );
+  for (field, value) in headers {
+    head.push_str(&format!("{}: {}\r\n", field, value));
+  }
+  head.push_str("\r\n");
+  stream.write_all(head.as_bytes()).unwrap();
+  stream.write_all(body).unwrap();
+  let mut response = Vec::new();
+  let _ = stream.read_to_end(&mut response);
+  let response = String::from_utf8_lossy(&response).into_owned();
+  let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+  let status = head
+    .split(' ')
+    .nth(1)
+    .and_then(|status| status.parse().ok())
+    .unwrap_or_default();
+  Response {
+    status,
+    head: head.to_string(),
+    body: body.to_string(),
+  }
+}
+
+/// Sends the invoke of `cmd` with `payload` to the `main` window, as the initialization script
+/// does.
+pub fn invoke(port: u16, cmd: &str, payload: serde_json::Value) -> Response {
+  invoke_from(port, ORIGIN, cmd, payload)
+}
+
+/// Sends the invoke of `cmd` with `payload` from a page of `origin` to the `main` window.
+pub fn invoke_from(port: u16, origin: &str, cmd: &str, payload: serde_json::Value) -> Response {
+  let body = serde_json::json!({ "cmd": cmd, "callback": 1, "error": 2, "payload": payload });
+  send(
+    port,
+    "POST",
+    "/main",
+    &[
+      ("Origin", origin),
+      ("Content-Type", "application/json"),
+      ("Tauri-Invoke-Key", tauri::test::INVOKE_KEY),
+    ],
+    body.to_string().as_bytes(),
+  )
+}
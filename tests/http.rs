@@ -0,0 +1,260 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use {
+  common::ORIGIN,
+  std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+  },
+  tauri_invoke_http::{Limits, ReadTimeouts},
+};
+
+#[test]
+fn serves_an_invoke() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let response = common::invoke(http.port(), "greet", serde_json::json!({ "name": "tests" }));
+  assert_eq!(response.status, 200, "{}", response.body);
+  assert_eq!(response.body, "\"Hello, tests!\"");
+}
+
+#[test]
+fn answers_the_bare_path_prefix_with_404() {
+  let http = common::server().with_path_prefix("/api");
+  let _app = common::app(&http);
+  for path in ["/api", "/api?x"] {
+    let response = common::send(http.port(), "POST", path, &[("Origin", ORIGIN)], b"{}");
+    assert_eq!(response.status, 404, "{}", path);
+  }
+}
+
+#[test]
+fn routes_plugin_commands() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let response = common::invoke(
+    http.port(),
+    "plugin:dialog|open",
+    serde_json::json!({ "title": "report" }),
+  );
+  assert_eq!(response.status, 200, "{}", response.body);
+  assert_eq!(response.body, "\"picked a file for report\"");
+}
+
+#[test]
+fn routes_event_plugin_commands() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let response = common::invoke(
+    http.port(),
+    "plugin:event|listen",
+    serde_json::json!({ "event": "saved", "target": { "kind": "Any" }, "handler": 3 }),
+  );
+  assert_eq!(response.status, 200, "{}", response.body);
+  assert!(response.body.parse::<u32>().is_ok(), "{}", response.body);
+  let response = common::invoke(
+    http.port(),
+    "plugin:event|emit",
+    serde_json::json!({ "event": "saved", "payload": null }),
+  );
+  assert_eq!(response.status, 200, "{}", response.body);
+}
+
+#[test]
+fn routes_plugin_commands_named_in_the_path() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let response = common::send(
+    http.port(),
+    "POST",
+    "/main/plugin%3Adialog%7Cimport",
+    &[
+      ("Origin", ORIGIN),
+      ("Content-Type", "application/octet-stream"),
+      ("Tauri-Invoke-Key", tauri::test::INVOKE_KEY),
+      ("Tauri-Callback", "1"),
+      ("Tauri-Error", "2"),
+    ],
+    b"hello",
+  );
+  assert_eq!(response.status, 200, "{}", response.body);
+  assert_eq!(response.body, "5");
+}
+
+/// Sends the head of an invoke with a body of `length` bytes, of which the client sends a byte
+/// every `interval` if any, and reads the status answering it. tiny_http reads bodies of up to
+/// 1 KiB before handing requests over, so `length` should be larger.
+fn send_slowly(port: u16, length: usize, interval: Option<Duration>) -> (u16, String) {
+  let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("failed to connect");
+  stream
+    .set_read_timeout(Some(Duration::from_secs(5)))
+    .unwrap();
+  write!(
+    stream,
+    "POST /main HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nAuthorization: Bearer {}\r\nOrigin: {}\r\nContent-Type: application/json\r\nTauri-Invoke-Key: {}\r\nContent-Length: {}\r\n\r\n",
+    port,
+    common::TOKEN,
+    ORIGIN,
+    tauri::test::INVOKE_KEY,
+    length
+  )
+  .unwrap();
+  if let Some(interval) = interval {
+    let mut writer = stream.try_clone().unwrap();
+    std::thread::spawn(move || {
+      for _ in 0..length {
+        std::thread::sleep(interval);
+        if writer.write_all(b" ").is_err() {
+          break;
+        }
+      }
+    });
+  }
+  let mut response = Vec::new();
+  let _ = stream.read_to_end(&mut response);
+  let response = String::from_utf8_lossy(&response).into_owned();
+  let status = response
+    .split(' ')
+    .nth(1)
+    .and_then(|status| status.parse().ok())
+    .unwrap_or_default();
+  (status, response)
+}
+
+#[test]
+fn answers_stalled_bodies_with_408() {
+  let http = common::server().with_read_timeouts(ReadTimeouts {
+    header: None,
+    body: Some(Duration::from_millis(200)),
+  });
+  let _app = common::app(&http);
+  let port = http.port();
+  let (status, response) = send_slowly(port, 4096, Some(Duration::from_millis(5)));
+  assert_eq!(status, 408, "{}", response);
+  // a client sending nothing more holds its own thread, not a worker
+  let silent = std::thread::spawn(move || send_slowly(port, 4096, None));
+  std::thread::sleep(Duration::from_millis(400));
+  let response = common::invoke(port, "greet", serde_json::json!({ "name": "tests" }));
+  assert_eq!(response.status, 200, "{}", response.body);
+  let _ = silent.join();
+}
+
+#[test]
+fn shuts_down_without_waiting_for_busy_workers() {
+  let http = common::server();
+  let _app = common::app(&http);
+  let port = http.port();
+  // without a body timeout, the worker reading this body waits for the client
+  let stalled = std::thread::spawn(move || send_slowly(port, 4096, None));
+  std::thread::sleep(Duration::from_millis(300));
+  let started = Instant::now();
+  http.shutdown(Duration::from_millis(100));
+  assert!(
+    started.elapsed() < Duration::from_secs(2),
+    "{:?}",
+    started.elapsed()
+  );
+  let _ = stalled.join();
+}
+
+/// A form with the file `a.txt` holding `contents`, and its content type.
+fn upload(contents: &str) -> (String, &'static str) {
+  let body = format!(
+    "--b0und\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nnotes\r\n--b0und\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\n{}\r\n--b0und--\r\n",
+    contents
+  );
+  (body, "multipart/form-data; boundary=b0und")
+}
+
+/// Sends `body` to `plugin:dialog|import` as `content_type` in two writes split at `split`,
+/// returning the response.
+fn send_import(port: u16, content_type: &str, body: &[u8], split: usize) -> String {
+  let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("failed to connect");
+  stream
+    .set_read_timeout(Some(Duration::from_secs(5)))
+    .unwrap();
+  write!(
+    stream,
+    "POST /main/plugin%3Adialog%7Cimport HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\nAuthorization: Bearer {}\r\nOrigin: {}\r\nContent-Type: {}\r\nTauri-Invoke-Key: {}\r\nTauri-Callback: 1\r\nTauri-Error: 2\r\nContent-Length: {}\r\n\r\n",
+    port,
+    common::TOKEN,
+    ORIGIN,
+    content_type,
+    tauri::test::INVOKE_KEY,
+    body.len()
+  )
+  .unwrap();
+  stream.write_all(&body[..split]).unwrap();
+  stream.flush().unwrap();
+  std::thread::sleep(Duration::from_millis(50));
+  let _ = stream.write_all(&body[split..]);
+  let mut response = Vec::new();
+  let _ = stream.read_to_end(&mut response);
+  String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn reads_multipart_bodies_split_anywhere() {
+  let http = common::server();
+  let _app = common::app(&http);
+  // beyond the 1 KiB tiny_http reads before handing the request over
+  let contents = "x".repeat(2048);
+  let (body, content_type) = upload(&contents);
+  // in the middle of the delimiter closing the file, and of its trailing `--`
+  let file_end = body.find(&contents).unwrap() + contents.len();
+  for split in [file_end + 5, body.len() - 4] {
+    let response = send_import(http.port(), content_type, body.as_bytes(), split);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n2048"), "{}", response);
+  }
+}
+
+#[test]
+fn answers_oversized_multipart_bodies_with_413() {
+  let http = common::server().with_limits(Limits {
+    max_raw_size: Some(1024),
+    ..Default::default()
+  });
+  let _app = common::app(&http);
+  let (body, content_type) = upload(&"x".repeat(2048));
+  let response = send_import(http.port(), content_type, body.as_bytes(), 1500);
+  assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+}
+
+#[test]
+fn limits_compressed_bodies_by_their_decompressed_size() {
+  let http = common::server().with_limits(Limits {
+    max_message_size: Some(64 * 1024),
+    ..Default::default()
+  });
+  let _app = common::app(&http);
+  let invoke = |body: &[u8]| {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body).unwrap();
+    common::send(
+      http.port(),
+      "POST",
+      "/main",
+      &[
+        ("Origin", ORIGIN),
+        ("Content-Type", "application/json"),
+        ("Content-Encoding", "gzip"),
+        ("Tauri-Invoke-Key", tauri::test::INVOKE_KEY),
+      ],
+      &encoder.finish().unwrap(),
+    )
+  };
+  let body =
+    serde_json::json!({ "cmd": "greet", "callback": 1, "error": 2, "payload": { "name": "gzip" } });
+  let response = invoke(body.to_string().as_bytes());
+  assert_eq!(response.status, 200, "{}", response.body);
+  // a few KiB on the wire, 16 MiB once inflated
+  let mut bomb = body.to_string().into_bytes();
+  bomb.extend(std::iter::repeat(b' ').take(16 << 20));
+  assert_eq!(invoke(&bomb).status, 413);
+}
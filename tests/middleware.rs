@@ -0,0 +1,56 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use {
+  tauri::{test::MockRuntime, Manager},
+  tauri_invoke_http::{Middleware, MiddlewareContext, MiddlewareRequest, MiddlewareResponse},
+};
+
+/// The API keys managed by the app.
+struct ApiKeys(Vec<String>);
+
+/// Refuses the requests without one of the [`ApiKeys`] in `X-Api-Key`.
+struct RequireApiKey;
+
+impl Middleware for RequireApiKey {
+  fn on_request(
+    &self,
+    request: &mut MiddlewareRequest,
+    context: &MiddlewareContext<'_>,
+  ) -> Option<MiddlewareResponse> {
+    // the app runs on the mock runtime, not the default one
+    assert!(context.state::<tauri::Wry, ApiKeys>().is_none());
+    let keys = context.state::<MockRuntime, ApiKeys>()?;
+    match request.headers.get("X-Api-Key") {
+      Some(key) if keys.0.iter().any(|k| k == key) => None,
+      _ => Some(MiddlewareResponse::new(401)),
+    }
+  }
+}
+
+#[test]
+fn reads_the_state_of_apps_on_any_runtime() {
+  let http = common::server().with_middleware(RequireApiKey);
+  let app = common::app(&http);
+  app.manage(ApiKeys(vec!["secret".into()]));
+  let body = serde_json::json!({ "cmd": "greet", "callback": 1, "error": 2, "payload": { "name": "tests" } })
+    .to_string();
+  for (key, status) in [("secret", 200), ("wrong", 401)] {
+    let response = common::send(
+      http.port(),
+      "POST",
+      "/main",
+      &[
+        ("Origin", common::ORIGIN),
+        ("Content-Type", "application/json"),
+        ("Tauri-Invoke-Key", tauri::test::INVOKE_KEY),
+        ("X-Api-Key", key),
+      ],
+      body.as_bytes(),
+    );
+    assert_eq!(response.status, status, "{}", response.body);
+  }
+}
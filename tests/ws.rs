@@ -0,0 +1,314 @@
+// Copyright 2019-2021 Tauri Programme within The Commons Conservancy
+// SPDX-License-Identifier: Apache-2.0
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use {
+  common::{ORIGIN, TOKEN},
+  std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+  },
+  tauri_invoke_http::{
+    Invoke, InvokeHttpError, Limits, Middleware, MiddlewareContext, MiddlewareRequest,
+    MiddlewareResponse, ReadTimeouts, Transport,
+  },
+};
+
+/// The port of the WebSocket listener, as the initialization script connects to it.
+fn ws_port(http: &Invoke) -> u16 {
+  let script = http.initialization_script();
+  let (_, rest) = script.split_once("ws://").unwrap();
+  let host = rest.split('/').next().unwrap();
+  host.rsplit(':').next().unwrap().parse().unwrap()
+}
+
+/// Sends the handshake for the `main` window with `query`, returning the connection and the
+/// status it was answered with, 0 if it was closed before.
+fn connect(port: u16, query: &str) -> (TcpStream, u16) {
+  let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+  stream
+    .set_read_timeout(Some(Duration::from_secs(10)))
+    .unwrap();
+  // refused connections may be closed before the handshake is written
+  let _ = write!(
+    stream,
+    "GET /main?access_token={}{} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nOrigin: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+    TOKEN, query, port, ORIGIN
+  );
+  let mut head = Vec::new();
+  let mut byte = [0u8];
+  while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+    head.push(byte[0]);
+  }
+  let status = String::from_utf8_lossy(&head)
+    .split(' ')
+    .nth(1)
+    .and_then(|status| status.parse().ok())
+    .unwrap_or_default();
+  (stream, status)
+}
+
+/// Sends a frame masked as clients must, or unmasked.
+fn send_frame(stream: &mut TcpStream, head: u8, payload: &[u8], masked: bool) {
+  let mask = [0x12, 0x34, 0x56, 0x78];
+  let mut frame = vec![head];
+  match payload.len() {
+    len if len < 126 => frame.push(len as u8),
+    len => {
+      frame.push(126);
+      frame.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+  }
+  if masked {
+    frame[1] |= 0x80;
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+  } else {
+    frame.extend_from_slice(payload);
+  }
+  stream.write_all(&frame).unwrap();
+}
+
+/// Reads a frame sent by the server, returning its opcode and payload.
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+  let mut head = [0u8; 2];
+  stream.read_exact(&mut head).unwrap();
+  let len = match head[1] {
+    126 => {
+      let mut len = [0u8; 2];
+      stream.read_exact(&mut len).unwrap();
+      u16::from_be_bytes(len) as usize
+    }
+    len => len as usize,
+  };
+  let mut payload = vec![0u8; len];
+  stream.read_exact(&mut payload).unwrap();
+  (head[0] & 0x0F, payload)
+}
+
+fn invoke_message(name: &str) -> Vec<u8> {
+  serde_json::json!({
+    "cmd": "greet",
+    "callback": 1,
+    "error": 2,
+    "payload": { "name": name },
+    "__TAURI_INVOKE_KEY__": tauri::test::INVOKE_KEY,
+  })
+  .to_string()
+  .into_bytes()
+}
+
+/// Refuses the requests without `key=secret` in the query.
+struct RequireKey;
+
+impl Middleware for RequireKey {
+  fn on_request(
+    &self,
+    request: &mut MiddlewareRequest,
+    _context: &MiddlewareContext<'_>,
+  ) -> Option<MiddlewareResponse> {
+    if request.url.contains("key=secret") {
+      None
+    } else {
+      Some(MiddlewareResponse::new(401))
+    }
+  }
+}
+
+#[test]
+fn serves_invokes() {
+  let http = common::server().with_transport(Transport::WebSocket);
+  let _app = common::app(&http);
+  let (mut stream, status) = connect(ws_port(&http), "");
+  assert_eq!(status, 101);
+  send_frame(&mut stream, 0x81, &invoke_message("ws"), true);
+  let (opcode, payload) = read_frame(&mut stream);
+  assert_eq!(opcode, 0x1);
+  assert_eq!(payload, br#"{"id":1,"payload":"Hello, ws!"}"#);
+}
+
+#[test]
+fn closes_on_unmasked_frames() {
+  let http = common::server().with_transport(Transport::WebSocket);
+  let _app = common::app(&http);
+  let (mut stream, _) = connect(ws_port(&http), "");
+  send_frame(&mut stream, 0x81, &invoke_message("ws"), false);
+  assert_eq!(
+    read_frame(&mut stream),
+    (0x8, 1002u16.to_be_bytes().to_vec())
+  );
+}
+
+#[test]
+fn closes_on_messages_beyond_the_limit() {
+  let limits = Limits {
+    max_message_size: Some(64),
+    ..Default::default()
+  };
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_limits(limits);
+  let _app = common::app(&http);
+  let (mut stream, _) = connect(ws_port(&http), "");
+  // the limit applies to the message, across its frames
+  send_frame(&mut stream, 0x01, &[b' '; 40], true);
+  send_frame(&mut stream, 0x80, &[b' '; 40], true);
+  assert_eq!(
+    read_frame(&mut stream),
+    (0x8, 1009u16.to_be_bytes().to_vec())
+  );
+}
+
+#[test]
+fn refuses_connections_beyond_the_limit() {
+  let limits = Limits {
+    max_connections: Some(1),
+    ..Default::default()
+  };
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_limits(limits);
+  let _app = common::app(&http);
+  let port = ws_port(&http);
+  let (stream, status) = connect(port, "");
+  assert_eq!(status, 101);
+  // answered with a 429 before the handshake is read, which the reset may discard
+  assert!([0, 429].contains(&connect(port, "").1));
+  drop(stream);
+  let served = (0..50).any(|_| {
+    std::thread::sleep(Duration::from_millis(20));
+    connect(port, "").1 == 101
+  });
+  assert!(served, "the closed connection wasn't released");
+}
+
+#[test]
+fn spends_a_nonce_on_the_handshake() {
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_csrf_protection();
+  let _app = common::app(&http);
+  let port = ws_port(&http);
+  assert_eq!(connect(port, "").1, 403);
+  let nonce = common::send(
+    http.port(),
+    "GET",
+    "/main/nonce",
+    &[("Origin", ORIGIN)],
+    b"",
+  )
+  .body;
+  let query = format!("&nonce={}", nonce);
+  assert_eq!(connect(port, &query).1, 101);
+  assert_eq!(connect(port, &query).1, 403);
+}
+
+#[test]
+fn drops_stalled_handshakes_and_messages() {
+  let timeouts = ReadTimeouts {
+    header: Some(Duration::from_millis(100)),
+    body: Some(Duration::from_millis(100)),
+  };
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_read_timeouts(timeouts);
+  let _app = common::app(&http);
+  let port = ws_port(&http);
+  let mut stalled = TcpStream::connect(("127.0.0.1", port)).unwrap();
+  stalled
+    .set_read_timeout(Some(Duration::from_secs(10)))
+    .unwrap();
+  stalled.write_all(b"GET /main HTTP/1.1\r\n").unwrap();
+  // closed after the refusal, rather than waiting for the rest of the handshake
+  assert!(stalled.read_to_end(&mut Vec::new()).is_ok());
+
+  let (mut stream, status) = connect(port, "");
+  assert_eq!(status, 101);
+  // idle sockets stay open, but a started message must be sent whole in time
+  std::thread::sleep(Duration::from_millis(200));
+  send_frame(&mut stream, 0x01, b"{", true);
+  assert!(stream.read_to_end(&mut Vec::new()).is_ok());
+}
+
+#[test]
+fn runs_the_middleware_on_the_handshake() {
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_middleware(RequireKey);
+  let _app = common::app(&http);
+  let port = ws_port(&http);
+  assert_eq!(connect(port, "").1, 401);
+  let (mut stream, status) = connect(port, "&key=secret");
+  assert_eq!(status, 101);
+  send_frame(&mut stream, 0x81, &invoke_message("ws"), true);
+  assert_eq!(
+    read_frame(&mut stream).1,
+    br#"{"id":1,"payload":"Hello, ws!"}"#
+  );
+}
+
+#[test]
+fn refuses_unsigned_invokes() {
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_request_signing();
+  assert!(matches!(
+    Invoke::builder().configure(|_| http).build(),
+    Err(InvokeHttpError::WebSocketUnsupported("request signing"))
+  ));
+
+  // signing enabled once the server runs refuses the connections it can't check
+  let http = common::server().with_transport(Transport::WebSocket);
+  let _app = common::app(&http);
+  http.reconfigure(common::server().with_request_signing());
+  assert_eq!(connect(ws_port(&http), "").1, 403);
+}
+
+#[test]
+fn refuses_replay_protection() {
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_replay_protection(Duration::from_secs(60));
+  assert!(matches!(
+    Invoke::builder().configure(|_| http).build(),
+    Err(InvokeHttpError::WebSocketUnsupported("replay protection"))
+  ));
+
+  let http = common::server().with_transport(Transport::WebSocket);
+  let _app = common::app(&http);
+  http.reconfigure(common::server().with_replay_protection(Duration::from_secs(60)));
+  assert_eq!(connect(ws_port(&http), "").1, 403);
+}
+
+#[test]
+fn refuses_encryption() {
+  let http = common::server()
+    .with_transport(Transport::WebSocket)
+    .with_encryption();
+  assert!(matches!(
+    Invoke::builder().configure(|_| http).build(),
+    Err(InvokeHttpError::WebSocketUnsupported("encryption"))
+  ));
+
+  let http = common::server().with_transport(Transport::WebSocket);
+  let _app = common::app(&http);
+  http.reconfigure(common::server().with_encryption());
+  assert_eq!(connect(ws_port(&http), "").1, 403);
+}
+
+#[test]
+fn closes_connections_on_shutdown() {
+  let http = common::server().with_transport(Transport::WebSocket);
+  let _app = common::app(&http);
+  let (mut stream, status) = connect(ws_port(&http), "");
+  assert_eq!(status, 101);
+  http.shutdown(Duration::from_secs(1));
+  assert_eq!(
+    read_frame(&mut stream),
+    (0x8, 1001u16.to_be_bytes().to_vec())
+  );
+  assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+}